@@ -0,0 +1,6 @@
+-- Reactions are keyed by the reacting Zulip user, so uniqueness must be scoped to the message
+
+ALTER TABLE reaction_mappings DROP CONSTRAINT IF EXISTS reaction_mappings_zulip_reaction_id_emoji_key;
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_reaction_mappings_zulip_reaction
+    ON reaction_mappings(zulip_message_id, zulip_reaction_id, emoji);
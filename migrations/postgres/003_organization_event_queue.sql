@@ -0,0 +1,4 @@
+-- Zulip event queue position, kept so a restart can resume the same queue
+
+ALTER TABLE organizations ADD COLUMN IF NOT EXISTS event_queue_id TEXT;
+ALTER TABLE organizations ADD COLUMN IF NOT EXISTS last_event_id BIGINT;
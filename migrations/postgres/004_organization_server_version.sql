@@ -0,0 +1,4 @@
+-- Zulip server version and API feature level, refreshed on every connect
+
+ALTER TABLE organizations ADD COLUMN IF NOT EXISTS zulip_version TEXT;
+ALTER TABLE organizations ADD COLUMN IF NOT EXISTS zulip_feature_level INTEGER;
@@ -0,0 +1,4 @@
+-- Organizations with a webhook token reach the bridge through a Zulip outgoing-webhook bot
+-- instead of an event queue
+
+ALTER TABLE organizations ADD COLUMN IF NOT EXISTS webhook_token TEXT;
@@ -0,0 +1,19 @@
+-- Each organization gets a Matrix Space carrying its custom emoji as an image pack, and every
+-- realm emoji is uploaded to the media repository once
+
+ALTER TABLE organizations ADD COLUMN IF NOT EXISTS space_room_id TEXT;
+
+CREATE TABLE IF NOT EXISTS emoji_mappings (
+    id BIGSERIAL PRIMARY KEY,
+    organization_id TEXT NOT NULL REFERENCES organizations(id) ON DELETE CASCADE,
+    zulip_emoji_id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    source_url TEXT NOT NULL,
+    mxc_url TEXT NOT NULL,
+    deactivated BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE (organization_id, zulip_emoji_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_emoji_mappings_organization ON emoji_mappings(organization_id);
@@ -0,0 +1,4 @@
+-- A Zulip message may be bridged as several Matrix events: its text and one media event per
+-- attachment
+
+ALTER TABLE message_mappings DROP CONSTRAINT IF EXISTS message_mappings_zulip_message_id_key;
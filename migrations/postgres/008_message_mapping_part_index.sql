@@ -0,0 +1,26 @@
+-- Parts of a multi-event message are numbered in sending order; the primary part (the text,
+-- or the first media event of a message without text) is the one edits and replies target
+
+-- The columns start out nullable so that only rows from before this migration are numbered,
+-- once: every later run finds nothing left to number
+ALTER TABLE message_mappings ADD COLUMN IF NOT EXISTS part_index INTEGER;
+ALTER TABLE message_mappings ADD COLUMN IF NOT EXISTS is_primary BOOLEAN;
+
+UPDATE message_mappings AS m
+SET part_index = numbered.part_index,
+    is_primary = numbered.part_index = 0
+FROM (
+    SELECT id,
+           ROW_NUMBER() OVER (PARTITION BY matrix_room_id, zulip_message_id ORDER BY id) - 1 AS part_index
+    FROM message_mappings
+    WHERE part_index IS NULL
+) AS numbered
+WHERE m.id = numbered.id;
+
+ALTER TABLE message_mappings ALTER COLUMN part_index SET DEFAULT 0;
+ALTER TABLE message_mappings ALTER COLUMN part_index SET NOT NULL;
+ALTER TABLE message_mappings ALTER COLUMN is_primary SET DEFAULT TRUE;
+ALTER TABLE message_mappings ALTER COLUMN is_primary SET NOT NULL;
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_message_mappings_zulip_message_part
+    ON message_mappings(matrix_room_id, zulip_message_id, part_index);
@@ -0,0 +1,21 @@
+-- Files already transferred between Zulip and Matrix, by source URL and by content hash, so a
+-- reposted upload or emoji is not downloaded and uploaded again
+
+CREATE TABLE IF NOT EXISTS media_cache (
+    id BIGSERIAL PRIMARY KEY,
+    organization_id TEXT NOT NULL REFERENCES organizations(id) ON DELETE CASCADE,
+    direction TEXT NOT NULL,
+    source_url TEXT NOT NULL,
+    content_hash TEXT NOT NULL,
+    target_url TEXT NOT NULL,
+    content_type TEXT NOT NULL,
+    size BIGINT NOT NULL,
+    metadata TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_used_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE (organization_id, direction, source_url)
+);
+
+CREATE INDEX IF NOT EXISTS idx_media_cache_content_hash
+    ON media_cache(organization_id, direction, content_hash);
+CREATE INDEX IF NOT EXISTS idx_media_cache_last_used ON media_cache(last_used_at);
@@ -0,0 +1,8 @@
+-- Zulip reactions have no id of their own: one is identified by its message, the reacting user
+-- and the emoji code. Earlier migrations, which run again on every start, still refer to the
+-- user column by its original name, so it keeps it
+
+COMMENT ON COLUMN reaction_mappings.zulip_reaction_id IS 'Zulip user who reacted';
+
+-- Rows from before this migration only know the emoji name and keep an empty code
+ALTER TABLE reaction_mappings ADD COLUMN IF NOT EXISTS emoji_code TEXT NOT NULL DEFAULT '';
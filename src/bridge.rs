@@ -1,11 +1,965 @@
-pub struct BridgeCore;
+pub mod backfill;
+pub mod catch_up;
+pub mod emoji;
+pub mod links;
+pub mod matrix_handler;
+pub mod mentions;
+pub mod zulip_handler;
+
+pub use self::backfill::{BackfillReport, Backfiller};
+pub use self::catch_up::OrganizationCheckpoint;
+pub use self::emoji::RealmEmojiManager;
+pub use self::links::LinkResolver;
+pub use self::matrix_handler::BridgeMatrixEventHandler;
+pub use self::mentions::MentionResolver;
+pub use self::zulip_handler::BridgeZulipEventHandler;
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+use tracing::{debug, error, info, warn};
+
+use crate::config::Config;
+use crate::db::DatabaseManager;
+use crate::db::models::{MessageMapping, NewRoomMapping, Organization, RoomMapping, RoomType};
+use crate::matrix::{GhostUserManager, MatrixAppservice, MatrixEvent};
+use crate::media::MediaHandler;
+use crate::parsers::matrix_parser::{parse_matrix_message, plain_to_zulip};
+use crate::utils::{BridgeError, Result};
+use crate::zulip::event_handler::ZulipEventProcessor;
+use crate::zulip::{
+    EventQueueStatus, EventSource, LongPollEventSource, RecordingEventSource, ReplayEventSource,
+    ServerCapabilities, WebhookEventSource, ZulipClient, ZulipEvent, ZulipMessage,
+};
+
+/// Topic for messages from rooms bridging a whole stream, which have no topic of their own.
+const STREAM_ROOM_TOPIC: &str = "Matrix";
+
+/// Where organization events come from. Record and replay take a directory holding one
+/// `<organization id>.jsonl` file per organization.
+#[derive(Debug, Clone, Default)]
+pub enum EventSourceMode {
+    #[default]
+    LongPoll,
+    Record(PathBuf),
+    Replay(PathBuf),
+}
+
+struct OrganizationConnection {
+    client: Arc<ZulipClient>,
+    events: Arc<dyn EventSource>,
+    /// Set for organizations that deliver messages through an outgoing webhook.
+    webhook: Option<Arc<WebhookEventSource>>,
+}
+
+pub struct BridgeCore {
+    config: Arc<Config>,
+    db_manager: Arc<DatabaseManager>,
+    matrix: Arc<MatrixAppservice>,
+    backfiller: Arc<Backfiller>,
+    emoji: Arc<RealmEmojiManager>,
+    mentions: Arc<MentionResolver>,
+    links: Arc<LinkResolver>,
+    media: Arc<MediaHandler>,
+    event_source_mode: EventSourceMode,
+    organizations: RwLock<HashMap<String, OrganizationConnection>>,
+}
 
 impl BridgeCore {
-    pub fn new() -> Self {
-        Self
+    pub fn new(
+        config: Arc<Config>,
+        db_manager: Arc<DatabaseManager>,
+        matrix: Arc<MatrixAppservice>,
+    ) -> Self {
+        let ghosts = Arc::new(GhostUserManager::new(
+            matrix.clone(),
+            db_manager.user_store(),
+        ));
+        let mentions = Arc::new(MentionResolver::new(
+            ghosts.clone(),
+            db_manager.user_store(),
+        ));
+        let links = Arc::new(LinkResolver::new(
+            db_manager.room_store(),
+            db_manager.message_store(),
+        ));
+        let media = Arc::new(MediaHandler::new(
+            &config,
+            matrix.clone(),
+            db_manager.media_cache_store(),
+        ));
+        let emoji = Arc::new(RealmEmojiManager::new(
+            matrix.clone(),
+            media.clone(),
+            db_manager.organization_store(),
+            db_manager.emoji_store(),
+        ));
+        let backfiller = Arc::new(Backfiller::new(
+            matrix.clone(),
+            ghosts,
+            mentions.clone(),
+            links.clone(),
+            emoji.clone(),
+            media.clone(),
+            db_manager.message_store(),
+            db_manager.reaction_store(),
+        ));
+
+        Self {
+            config,
+            db_manager,
+            matrix,
+            backfiller,
+            emoji,
+            mentions,
+            links,
+            media,
+            event_source_mode: EventSourceMode::default(),
+            organizations: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_event_source_mode(mut self, mode: EventSourceMode) -> Self {
+        self.event_source_mode = mode;
+        self
+    }
+
+    pub fn matrix(&self) -> Arc<MatrixAppservice> {
+        self.matrix.clone()
     }
 
-    pub async fn start(&self) -> crate::utils::Result<()> {
+    pub async fn start(self: &Arc<Self>) -> Result<()> {
+        let organizations = self.db_manager.organization_store().get_all().await?;
+
+        for org in organizations.into_iter().filter(|org| org.connected) {
+            if let Err(e) = self.connect_organization(&org).await {
+                error!("failed to connect organization {}: {}", org.id, e);
+            }
+        }
+
         Ok(())
     }
+
+    pub fn stop(&self) {
+        for (org_id, connection) in self.organizations.write().drain() {
+            info!("disconnecting organization {}", org_id);
+            connection.events.stop();
+        }
+    }
+
+    pub async fn connect_organization(self: &Arc<Self>, org: &Organization) -> Result<()> {
+        if self.organizations.read().contains_key(&org.id) {
+            warn!("organization {} is already connected", org.id);
+            return Ok(());
+        }
+
+        // A replay only reproduces what the bridge does with the recorded events: the server
+        // they were recorded from is never contacted.
+        let mut client = ZulipClient::new(&org.site, &org.email, &org.api_key)?;
+        if let EventSourceMode::Replay(_) = self.event_source_mode {
+            client = client.offline();
+        }
+        let capabilities = self.negotiate_capabilities(org, &client).await?;
+        let client = Arc::new(client.with_capabilities(capabilities));
+
+        let webhook = match (&org.webhook_token, &self.event_source_mode) {
+            (_, EventSourceMode::Replay(_)) | (None, _) => None,
+            (Some(token), _) => {
+                info!(
+                    "organization {} uses an outgoing webhook: only messages mentioning the bot \
+                     are bridged, Zulip edits, deletions and reactions are not",
+                    org.id
+                );
+                Some(Arc::new(WebhookEventSource::new(token)))
+            }
+        };
+        let events = self.event_source(org, client.clone(), webhook.clone()).await?;
+
+        // Registered before any event is processed, so handlers can look the client up.
+        let connection = OrganizationConnection {
+            client: client.clone(),
+            events: events.clone(),
+            webhook: webhook.clone(),
+        };
+        if let Some(previous) = self.organizations.write().insert(org.id.clone(), connection) {
+            previous.events.stop();
+        }
+
+        let handler = BridgeZulipEventHandler::new(&org.id, client.clone(), self.clone());
+        let mut processor = ZulipEventProcessor::new(Arc::new(handler));
+        let org_id = org.id.clone();
+        tokio::spawn(async move {
+            if let Err(e) = processor.run(&org_id, events.as_ref()).await {
+                error!("zulip event processing for organization {} stopped: {}", org_id, e);
+            }
+        });
+
+        // An outgoing-webhook bot cannot read the realm's emoji, so there is nothing to sync.
+        if webhook.is_none() && !matches!(self.event_source_mode, EventSourceMode::Replay(_)) {
+            self.spawn_emoji_sync(&org.id, client);
+        }
+
+        info!("connected organization {} ({})", org.id, org.site);
+        Ok(())
+    }
+
+    fn spawn_emoji_sync(&self, org_id: &str, client: Arc<ZulipClient>) {
+        let emoji = self.emoji.clone();
+        let org_id = org_id.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = emoji.sync(&org_id, &client).await {
+                error!("realm emoji sync for organization {} failed: {}", org_id, e);
+            }
+        });
+    }
+
+    /// Replays run offline, so they reuse the version recorded on the last live connect.
+    async fn negotiate_capabilities(
+        &self,
+        org: &Organization,
+        client: &ZulipClient,
+    ) -> Result<ServerCapabilities> {
+        if let EventSourceMode::Replay(_) = self.event_source_mode {
+            let mut capabilities = ServerCapabilities::default();
+            if let (Some(version), Some(feature_level)) =
+                (&org.zulip_version, org.zulip_feature_level)
+            {
+                capabilities.version = version.clone();
+                capabilities.feature_level = feature_level.into();
+            }
+            return Ok(capabilities);
+        }
+
+        let capabilities = client.fetch_capabilities().await?;
+        info!(
+            "organization {} runs Zulip {} (feature level {})",
+            org.id, capabilities.version, capabilities.feature_level
+        );
+        self.db_manager
+            .organization_store()
+            .set_server_version(
+                &org.id,
+                &capabilities.version,
+                capabilities.feature_level as i32,
+            )
+            .await?;
+
+        Ok(capabilities)
+    }
+
+    async fn event_source(
+        &self,
+        org: &Organization,
+        client: Arc<ZulipClient>,
+        webhook: Option<Arc<WebhookEventSource>>,
+    ) -> Result<Arc<dyn EventSource>> {
+        let file_name = format!("{}.jsonl", org.id);
+        let live = || -> Arc<dyn EventSource> {
+            if let Some(webhook) = &webhook {
+                return webhook.clone();
+            }
+            let checkpoint = OrganizationCheckpoint::new(
+                &org.id,
+                client.clone(),
+                self.backfiller.clone(),
+                self.db_manager.organization_store(),
+                self.db_manager.room_store(),
+                self.db_manager.message_store(),
+            );
+            Arc::new(LongPollEventSource::new(client.clone()).with_checkpoint(Arc::new(checkpoint)))
+        };
+
+        let source: Arc<dyn EventSource> = match &self.event_source_mode {
+            EventSourceMode::LongPoll => live(),
+            EventSourceMode::Record(dir) => {
+                Arc::new(RecordingEventSource::create(live(), &dir.join(file_name)).await?)
+            }
+            EventSourceMode::Replay(dir) => {
+                Arc::new(ReplayEventSource::open(&dir.join(file_name)).await?)
+            }
+        };
+        Ok(source)
+    }
+
+    pub fn disconnect_organization(&self, org_id: &str) {
+        if let Some(connection) = self.organizations.write().remove(org_id) {
+            connection.events.stop();
+        }
+    }
+
+    pub fn zulip_client(&self, org_id: &str) -> Option<Arc<ZulipClient>> {
+        self.organizations
+            .read()
+            .get(org_id)
+            .map(|connection| connection.client.clone())
+    }
+
+    pub fn zulip_webhook(&self, org_id: &str) -> Option<Arc<WebhookEventSource>> {
+        self.organizations
+            .read()
+            .get(org_id)
+            .and_then(|connection| connection.webhook.clone())
+    }
+
+    pub fn event_queue_statuses(&self) -> HashMap<String, EventQueueStatus> {
+        self.organizations
+            .read()
+            .iter()
+            .map(|(org_id, connection)| (org_id.clone(), connection.events.status()))
+            .collect()
+    }
+
+    /// Creates the Matrix room for a stream (or a single topic of it) and backfills its initial
+    /// history, of messages older than `before` if set, before returning. Returns the existing
+    /// mapping if the portal already exists.
+    pub async fn create_portal_room(
+        &self,
+        org_id: &str,
+        client: &ZulipClient,
+        stream_id: i64,
+        stream_name: &str,
+        topic: Option<&str>,
+        before: Option<i64>,
+    ) -> Result<RoomMapping> {
+        let room_store = self.db_manager.room_store();
+        let existing = room_store
+            .get_by_organization(org_id)
+            .await?
+            .into_iter()
+            .find(|room| room.zulip_stream_id == stream_id && room.zulip_topic.as_deref() == topic);
+        if let Some(room) = existing {
+            return Ok(room);
+        }
+
+        let org = self
+            .db_manager
+            .organization_store()
+            .get(org_id)
+            .await?
+            .ok_or_else(|| BridgeError::InvalidState(format!("unknown organization {}", org_id)))?;
+
+        let (name, room_type) = match topic {
+            Some(topic) => (format!("{} / {}", stream_name, topic), RoomType::Topic),
+            None => (stream_name.to_string(), RoomType::Stream),
+        };
+        let is_public = self.config.room.default_visibility == "public";
+        let matrix_room_id = self.matrix.create_room(&name, None, None, is_public).await?;
+
+        let room = room_store
+            .create(NewRoomMapping {
+                matrix_room_id,
+                zulip_stream_id: stream_id,
+                zulip_stream_name: stream_name.to_string(),
+                zulip_topic: topic.map(ToOwned::to_owned),
+                organization_id: org_id.to_string(),
+                room_type: room_type.as_str().to_string(),
+            })
+            .await?;
+        info!("created portal room {} for {}", room.matrix_room_id, name);
+
+        // A failed backfill leaves a usable portal; the history can be fetched again later.
+        let limit = self.backfill_limit(&org, org.max_backfill_amount);
+        if limit > 0
+            && let Err(e) = self.backfiller.backfill_room(client, &room, limit, before).await
+        {
+            error!("backfill for {} failed: {}", room.matrix_room_id, e);
+        }
+
+        Ok(room)
+    }
+
+    /// Relays a stream message from the event queue into the portal room for its topic, or else
+    /// for its stream. Messages the bridge itself posted are not echoed back.
+    pub async fn bridge_zulip_message(
+        &self,
+        org_id: &str,
+        client: &ZulipClient,
+        msg: &ZulipMessage,
+    ) -> Result<()> {
+        if msg.sender_email == client.email() {
+            return Ok(());
+        }
+        let Some(stream_id) = msg.stream_id else {
+            return Err(BridgeError::NotImplemented(
+                "direct messages from Zulip".to_string(),
+            ));
+        };
+
+        // The first message seen in a stream opens its portal, with the history before it.
+        let room = match self.portal_room(org_id, stream_id, msg.topic()).await? {
+            Some(room) => room,
+            None => {
+                let stream_name = msg
+                    .display_recipient
+                    .as_ref()
+                    .and_then(|name| name.as_str())
+                    .map_or_else(|| stream_id.to_string(), ToOwned::to_owned);
+                self.create_portal_room(
+                    org_id,
+                    client,
+                    stream_id,
+                    &stream_name,
+                    None,
+                    Some(msg.id),
+                )
+                .await?
+            }
+        };
+        if self.backfiller.bridge_live_message(client, &room, msg).await? {
+            debug!("bridged zulip message {} into {}", msg.id, room.matrix_room_id);
+        }
+        Ok(())
+    }
+
+    /// Edits the text part of a bridged message when its content changed on Zulip.
+    pub async fn bridge_zulip_edit(
+        &self,
+        org_id: &str,
+        client: &ZulipClient,
+        event: &ZulipEvent,
+    ) -> Result<()> {
+        let (Some(message_id), Some(html)) = (event.message_id, event.rendered_content()) else {
+            return Ok(());
+        };
+        let Some((room, parts)) = self.message_parts(org_id, event.stream_id, message_id).await?
+        else {
+            return Ok(());
+        };
+        self.backfiller.bridge_edit(client, &room, &parts, html).await
+    }
+
+    /// Redacts every part of the deleted messages and forgets them.
+    pub async fn bridge_zulip_deletion(&self, org_id: &str, event: &ZulipEvent) -> Result<()> {
+        for message_id in event.message_ids() {
+            let Some((room, parts)) = self.message_parts(org_id, event.stream_id, message_id).await?
+            else {
+                continue;
+            };
+            for part in &parts {
+                let sender = self.matrix.ghost_user_id(part.zulip_sender_id);
+                self.matrix
+                    .redact_event(&room.matrix_room_id, &sender, &part.matrix_event_id, None)
+                    .await?;
+            }
+            self.db_manager
+                .message_store()
+                .delete_by_zulip_message(&room.matrix_room_id, message_id)
+                .await?;
+            debug!("redacted {} parts of zulip message {}", parts.len(), message_id);
+        }
+        Ok(())
+    }
+
+    /// Mirrors a reaction added on Zulip onto the primary part of the message, or removes it.
+    /// Reactions the bridge itself made are not echoed back.
+    pub async fn bridge_zulip_reaction(
+        &self,
+        org_id: &str,
+        client: &ZulipClient,
+        event: &ZulipEvent,
+    ) -> Result<()> {
+        let (Some(message_id), Some(reaction)) = (event.message_id, event.emoji_reaction()) else {
+            return Ok(());
+        };
+        if event.user_email() == Some(client.email()) {
+            return Ok(());
+        }
+        let Some((room, parts)) = self.message_parts(org_id, None, message_id).await? else {
+            return Ok(());
+        };
+
+        match event.op.as_deref() {
+            Some("add") => {
+                let primary = parts.iter().find(|part| part.is_primary).unwrap_or(&parts[0]);
+                self.backfiller
+                    .bridge_live_reaction(&room, primary, &reaction)
+                    .await
+            }
+            Some("remove") => {
+                self.backfiller
+                    .remove_live_reaction(&room, &parts, &reaction)
+                    .await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The portal room a Zulip message was bridged into and its parts. Reaction events do not
+    /// say which stream the message is in, so every room of the organization is searched then.
+    async fn message_parts(
+        &self,
+        org_id: &str,
+        stream_id: Option<i64>,
+        message_id: i64,
+    ) -> Result<Option<(RoomMapping, Vec<MessageMapping>)>> {
+        let message_store = self.db_manager.message_store();
+        let rooms = self.db_manager.room_store().get_by_organization(org_id).await?;
+        for room in rooms
+            .into_iter()
+            .filter(|room| stream_id.is_none_or(|stream_id| room.zulip_stream_id == stream_id))
+        {
+            let parts = message_store
+                .get_parts_by_zulip_message(&room.matrix_room_id, message_id)
+                .await?;
+            if !parts.is_empty() {
+                return Ok(Some((room, parts)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The room a stream message belongs in: its topic's room, or the stream room for topics
+    /// without one.
+    async fn portal_room(
+        &self,
+        org_id: &str,
+        stream_id: i64,
+        topic: Option<&str>,
+    ) -> Result<Option<RoomMapping>> {
+        let room_store = self.db_manager.room_store();
+        if let Some(topic) = topic
+            && let Some(room) = room_store.get_by_zulip_topic(org_id, stream_id, Some(topic)).await?
+        {
+            return Ok(Some(room));
+        }
+        Ok(room_store.get_by_zulip_topic(org_id, stream_id, None).await?)
+    }
+
+    /// Relays a Matrix text, notice or emote message to the stream and topic of its portal
+    /// room. Edits are not relayed yet.
+    pub async fn bridge_matrix_message(&self, event: &MatrixEvent) -> Result<Option<i64>> {
+        if event.is_edit() {
+            debug!("not relaying edit {:?} to zulip", event.event_id);
+            return Ok(None);
+        }
+        let Some(body) = event.body() else {
+            return Ok(None);
+        };
+        let (room, client) = self.matrix_portal(event, "messages").await?;
+
+        let content = self.matrix_text(event, &room, &client, body).await?;
+        let content = match event.msgtype() {
+            Some("m.emote") => format!("/me {}", content),
+            _ => content,
+        };
+
+        let topic = room.zulip_topic.as_deref().unwrap_or(STREAM_ROOM_TOPIC);
+        let message_id = client
+            .send_stream_message(room.zulip_stream_id, topic, &content)
+            .await?;
+        Ok(Some(message_id))
+    }
+
+    /// Posts a Matrix media message from a portal room to its stream or topic: the file is
+    /// uploaded to Zulip and linked below the converted caption.
+    pub async fn bridge_matrix_media(&self, event: &MatrixEvent) -> Result<i64> {
+        let (room, client) = self.matrix_portal(event, "media").await?;
+
+        let caption = match event.media_caption() {
+            Some(body) => Some(self.matrix_text(event, &room, &client, body).await?),
+            None => None,
+        };
+        let content = self
+            .media
+            .upload_to_zulip(&client, &room.organization_id, event, caption.as_deref())
+            .await?;
+
+        let topic = room.zulip_topic.as_deref().unwrap_or(STREAM_ROOM_TOPIC);
+        client
+            .send_stream_message(room.zulip_stream_id, topic, &content)
+            .await
+    }
+
+    /// The stream portal a Matrix message was sent to, with its organization's client. `what`
+    /// names the kind of message for the error in direct message rooms.
+    async fn matrix_portal(
+        &self,
+        event: &MatrixEvent,
+        what: &str,
+    ) -> Result<(RoomMapping, Arc<ZulipClient>)> {
+        let room = self
+            .db_manager
+            .room_store()
+            .get_by_matrix_room(&event.room_id)
+            .await?
+            .ok_or_else(|| BridgeError::RoomNotFound(event.room_id.clone()))?;
+        if RoomType::from_str(&room.room_type) == Some(RoomType::Direct) {
+            return Err(BridgeError::NotImplemented(format!(
+                "{} in direct message rooms",
+                what
+            )));
+        }
+        let client = self.zulip_client(&room.organization_id).ok_or_else(|| {
+            BridgeError::InvalidState(format!(
+                "organization {} is not connected",
+                room.organization_id
+            ))
+        })?;
+        Ok((room, client))
+    }
+
+    /// Converts the text of a Matrix message, `body` or its formatted version, to Zulip
+    /// Markdown with its mentions and permalinks resolved.
+    async fn matrix_text(
+        &self,
+        event: &MatrixEvent,
+        room: &RoomMapping,
+        client: &ZulipClient,
+        body: &str,
+    ) -> Result<String> {
+        let targets = self.mentions.matrix_targets(event).await?;
+        Ok(match event.formatted_body() {
+            Some(html) => {
+                let links = self
+                    .links
+                    .matrix_links(&room.organization_id, client.site(), html)
+                    .await?;
+                parse_matrix_message(html, &targets, &links)
+            }
+            None => plain_to_zulip(body, &targets),
+        })
+    }
+
+    /// Adds a Matrix reaction that uses the organization's custom emoji pack to the Zulip
+    /// message, whichever of its parts was reacted to. Returns `false` for reactions that do not
+    /// refer to a bridged message or a realm emoji; Unicode emoji are not relayed yet, since
+    /// Zulip wants its own name for them.
+    pub async fn bridge_matrix_reaction(&self, event: &MatrixEvent) -> Result<bool> {
+        let Some(target) = event.relates_to_event_id() else {
+            return Ok(false);
+        };
+        let room = self
+            .db_manager
+            .room_store()
+            .get_by_matrix_room(&event.room_id)
+            .await?
+            .ok_or_else(|| BridgeError::RoomNotFound(event.room_id.clone()))?;
+        let Some(message) = self
+            .db_manager
+            .message_store()
+            .get_primary_by_matrix_event(&target)
+            .await?
+            .filter(|message| message.matrix_room_id == room.matrix_room_id)
+        else {
+            return Ok(false);
+        };
+        let Some(emoji) = self.emoji.reaction_emoji(&room.organization_id, event).await? else {
+            return Ok(false);
+        };
+        let client = self.zulip_client(&room.organization_id).ok_or_else(|| {
+            BridgeError::InvalidState(format!(
+                "organization {} is not connected",
+                room.organization_id
+            ))
+        })?;
+
+        client
+            .add_reaction(
+                message.zulip_message_id,
+                &emoji.name,
+                &emoji.zulip_emoji_id,
+                "realm_emoji",
+            )
+            .await?;
+        Ok(true)
+    }
+
+    /// Pulls up to `requested` messages older than the oldest message bridged into the room.
+    pub async fn backfill_older(&self, matrix_room_id: &str, requested: i32) -> Result<BackfillReport> {
+        let room = self
+            .db_manager
+            .room_store()
+            .get_by_matrix_room(matrix_room_id)
+            .await?
+            .ok_or_else(|| BridgeError::RoomNotFound(matrix_room_id.to_string()))?;
+        let org = self
+            .db_manager
+            .organization_store()
+            .get(&room.organization_id)
+            .await?
+            .ok_or_else(|| {
+                BridgeError::InvalidState(format!("unknown organization {}", room.organization_id))
+            })?;
+        let client = self.zulip_client(&org.id).ok_or_else(|| {
+            BridgeError::InvalidState(format!("organization {} is not connected", org.id))
+        })?;
+
+        let before = self
+            .db_manager
+            .message_store()
+            .get_oldest_by_matrix_room(matrix_room_id)
+            .await?
+            .map(|message| message.zulip_message_id);
+
+        self.backfiller
+            .backfill_room(&client, &room, self.backfill_limit(&org, requested), before)
+            .await
+    }
+
+    /// The organization setting is authoritative, capped by the global `zulip.max_backfill_amount`.
+    fn backfill_limit(&self, org: &Organization, requested: i32) -> i32 {
+        requested
+            .min(org.max_backfill_amount)
+            .min(self.config.zulip.max_backfill_amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::Utc;
+
+    use super::*;
+    use crate::db::models::{MessageType, NewMessageMapping};
+    use crate::matrix::MessageMentions;
+    use crate::testing::{self, FakeHomeserver, FakeZulip};
+
+    const SENDER_ID: i64 = 10;
+    const STREAM_ID: i64 = 5;
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn first_live_message_follows_its_history() {
+        let zulip = FakeZulip::start().await.unwrap();
+        zulip.add_user(SENDER_ID, "Iago");
+        zulip.add_stream(STREAM_ID, "general");
+        zulip.import_message(SENDER_ID, STREAM_ID, "greetings", "older");
+
+        let homeserver = FakeHomeserver::start().await.unwrap();
+        let config = Arc::new(testing::test_config(&homeserver).unwrap());
+        let db_manager = testing::test_database(&config).await.unwrap();
+        let matrix = Arc::new(MatrixAppservice::new(config.clone()).await.unwrap());
+        let bridge = Arc::new(BridgeCore::new(config, db_manager.clone(), matrix.clone()));
+
+        let org = testing::create_organization(&db_manager, &zulip).await.unwrap();
+        bridge.connect_organization(&org).await.unwrap();
+        while zulip.queue_count() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        zulip.post_message(SENDER_ID, STREAM_ID, "greetings", "newer");
+
+        let ghost = matrix.ghost_user_id(SENDER_ID);
+        let bodies = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let rooms = db_manager.room_store().get_by_organization(&org.id).await.unwrap();
+                if let Some(room) = rooms.first() {
+                    let bodies: Vec<String> = homeserver
+                        .events(&room.matrix_room_id)
+                        .into_iter()
+                        .filter(|event| event["sender"] == ghost)
+                        .filter_map(|event| Some(event["content"]["body"].as_str()?.to_owned()))
+                        .collect();
+                    if bodies.len() == 2 {
+                        return bodies;
+                    }
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("portal not filled within 5s");
+        assert_eq!(bodies, ["older", "newer"]);
+
+        bridge.stop();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn replays_recorded_events_without_contacting_zulip() {
+        let zulip = FakeZulip::start().await.unwrap();
+        zulip.add_user(SENDER_ID, "Iago");
+        zulip.add_stream(STREAM_ID, "general");
+        let message_id = zulip.import_message(SENDER_ID, STREAM_ID, "greetings", "replayed");
+
+        let homeserver = FakeHomeserver::start().await.unwrap();
+        let config = Arc::new(testing::test_config(&homeserver).unwrap());
+        let db_manager = testing::test_database(&config).await.unwrap();
+        let matrix = Arc::new(MatrixAppservice::new(config.clone()).await.unwrap());
+        let org = testing::create_organization(&db_manager, &zulip).await.unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let event = serde_json::json!({
+            "type": "message",
+            "id": 0,
+            "message": zulip.message(message_id).unwrap(),
+            "flags": [],
+            "bridge_queue_id": "recorded-queue",
+        });
+        let recording = format!("{}\n", event);
+        std::fs::write(dir.path().join(format!("{}.jsonl", org.id)), recording).unwrap();
+
+        let mode = EventSourceMode::Replay(dir.path().to_path_buf());
+        let bridge = BridgeCore::new(config, db_manager.clone(), matrix.clone());
+        let bridge = Arc::new(bridge.with_event_source_mode(mode));
+        bridge.connect_organization(&org).await.unwrap();
+
+        let ghost = matrix.ghost_user_id(SENDER_ID);
+        let body = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let rooms = db_manager.room_store().get_by_organization(&org.id).await.unwrap();
+                let bodies: Vec<String> = rooms
+                    .iter()
+                    .flat_map(|room| homeserver.events(&room.matrix_room_id))
+                    .filter(|event| event["sender"] == ghost)
+                    .filter_map(|event| Some(event["content"]["body"].as_str()?.to_owned()))
+                    .collect();
+                if let Some(body) = bodies.into_iter().next() {
+                    return body;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("replayed message not bridged within 5s");
+        assert_eq!(body, "replayed");
+
+        // Opening the portal tried to backfill it, which the replay does not let through.
+        let requests: Vec<String> =
+            zulip.requests().into_iter().map(|request| request.path).collect();
+        assert!(requests.is_empty(), "replay contacted zulip: {:?}", requests);
+
+        bridge.stop();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn edits_the_primary_part_and_redacts_every_part() {
+        let zulip = FakeZulip::start().await.unwrap();
+        zulip.add_user(SENDER_ID, "Iago");
+        zulip.add_stream(STREAM_ID, "general");
+
+        let homeserver = FakeHomeserver::start().await.unwrap();
+        let config = Arc::new(testing::test_config(&homeserver).unwrap());
+        let db_manager = testing::test_database(&config).await.unwrap();
+        let matrix = Arc::new(MatrixAppservice::new(config.clone()).await.unwrap());
+        let bridge = BridgeCore::new(config, db_manager.clone(), matrix.clone());
+        let org = testing::create_organization(&db_manager, &zulip).await.unwrap();
+        let client = zulip.client().unwrap();
+
+        let room_id = homeserver.create_room(&matrix.bot_user_id());
+        let ghost = matrix.ghost_user_id(SENDER_ID);
+        matrix.invite_user(&room_id, &ghost).await.unwrap();
+        matrix.join_room_as(&room_id, &ghost).await.unwrap();
+        db_manager
+            .room_store()
+            .create(NewRoomMapping {
+                matrix_room_id: room_id.clone(),
+                zulip_stream_id: STREAM_ID,
+                zulip_stream_name: "general".to_string(),
+                zulip_topic: Some("greetings".to_string()),
+                organization_id: org.id.clone(),
+                room_type: RoomType::Topic.as_str().to_string(),
+            })
+            .await
+            .unwrap();
+
+        // A message of text and two uploads, bridged as three parts; ids of the shared database
+        // are kept apart from other runs by the clock.
+        let message_id = Utc::now().timestamp_micros();
+        let mut events = Vec::new();
+        for (part_index, message_type) in [MessageType::Text, MessageType::Image, MessageType::File]
+            .into_iter()
+            .enumerate()
+        {
+            let event_id = matrix
+                .send_message(
+                    &room_id,
+                    &ghost,
+                    message_type.as_str(),
+                    None,
+                    &MessageMentions::default(),
+                )
+                .await
+                .unwrap();
+            db_manager
+                .message_store()
+                .create(NewMessageMapping {
+                    matrix_event_id: event_id.clone(),
+                    matrix_room_id: room_id.clone(),
+                    zulip_message_id: message_id,
+                    zulip_sender_id: SENDER_ID,
+                    message_type: message_type.as_str().to_string(),
+                    part_index: part_index as i32,
+                    is_primary: part_index == 0,
+                })
+                .await
+                .unwrap();
+            events.push(event_id);
+        }
+        let event = |value: serde_json::Value| -> ZulipEvent {
+            serde_json::from_value(value).unwrap()
+        };
+        let relations = |rel_type: &str| -> Vec<String> {
+            homeserver
+                .events(&room_id)
+                .into_iter()
+                .filter(|event| event["content"]["m.relates_to"]["rel_type"] == rel_type)
+                .filter_map(|event| {
+                    Some(event["content"]["m.relates_to"]["event_id"].as_str()?.to_owned())
+                })
+                .collect()
+        };
+
+        let edit = event(serde_json::json!({
+            "type": "update_message",
+            "message_id": message_id,
+            "stream_id": STREAM_ID,
+            "rendered_content": "<p>edited</p>",
+        }));
+        bridge.bridge_zulip_edit(&org.id, &client, &edit).await.unwrap();
+        assert_eq!(relations("m.replace"), [events[0].clone()]);
+
+        let reaction = |op: &str, emoji_name: &str| {
+            event(serde_json::json!({
+                "type": "reaction",
+                "op": op,
+                "message_id": message_id,
+                "user_id": SENDER_ID,
+                "emoji_name": emoji_name,
+                "emoji_code": "1f44d",
+                "reaction_type": "unicode_emoji",
+            }))
+        };
+        bridge
+            .bridge_zulip_reaction(&org.id, &client, &reaction("add", "+1"))
+            .await
+            .unwrap();
+        assert_eq!(relations("m.annotation"), [events[0].clone()]);
+        // The same reaction under another of the emoji's names is still the same reaction.
+        bridge
+            .bridge_zulip_reaction(&org.id, &client, &reaction("remove", "thumbs_up"))
+            .await
+            .unwrap();
+        let reactions = db_manager
+            .reaction_store()
+            .get_by_zulip_message(message_id)
+            .await
+            .unwrap();
+        assert!(reactions.is_empty(), "reaction left behind: {:?}", reactions);
+
+        let deletion = event(serde_json::json!({
+            "type": "delete_message",
+            "message_ids": [message_id],
+            "stream_id": STREAM_ID,
+        }));
+        bridge.bridge_zulip_deletion(&org.id, &deletion).await.unwrap();
+        let redacted: Vec<String> = homeserver
+            .events(&room_id)
+            .into_iter()
+            .filter(|event| event["type"] == "m.room.redaction")
+            .filter_map(|event| Some(event["redacts"].as_str()?.to_owned()))
+            .collect();
+        for part in &events {
+            assert!(redacted.contains(part), "{} not redacted: {:?}", part, redacted);
+        }
+        let parts = db_manager
+            .message_store()
+            .get_parts_by_zulip_message(&room_id, message_id)
+            .await
+            .unwrap();
+        assert!(parts.is_empty());
+    }
 }
@@ -0,0 +1,732 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use serde_json::{Value, json};
+use tracing::{debug, info, warn};
+
+use super::{LinkResolver, MentionResolver, RealmEmojiManager};
+use crate::db::models::{
+    MessageMapping, MessageType, NewMessageMapping, NewReactionMapping, RoomMapping, RoomType,
+};
+use crate::db::stores::{MessageStore, ReactionStore};
+use crate::matrix::{GhostUserManager, MatrixAppservice, build_media_content};
+use crate::media::{MatrixUpload, MediaHandler};
+use crate::parsers::zulip_parser::{
+    MatrixContent, MentionTargets, ZulipAttachment, parse_zulip_message, reaction_key,
+};
+use crate::utils::{BridgeError, Result};
+use crate::zulip::{
+    Anchor, GetMessagesRequest, Narrow, RequestPriority, ZulipClient, ZulipMessage, ZulipReaction,
+};
+
+/// Messages requested per `GET /messages` call while paging through history.
+const BATCH_SIZE: u32 = 100;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BackfillReport {
+    pub fetched: usize,
+    pub bridged: usize,
+    pub skipped: usize,
+}
+
+/// Replays Zulip history into a portal room through ghost users, either older history
+/// (backfill) or messages missed while the bridge was not receiving events (catch-up). Live
+/// messages take the same path one at a time.
+///
+/// Zulip requests are made at background priority so history never delays live traffic.
+/// Events are sent with the appservice `ts` override so clients show the original Zulip
+/// timestamps. Zulip always returns the latest revision of a message, so edits are folded into
+/// the backfilled event instead of being replayed.
+///
+/// Uploads a message links to are copied to the Matrix media repository and sent as media
+/// events of their own after the text, each recorded as a part of the same Zulip message.
+pub struct Backfiller {
+    matrix: Arc<MatrixAppservice>,
+    ghosts: Arc<GhostUserManager>,
+    mentions: Arc<MentionResolver>,
+    links: Arc<LinkResolver>,
+    emoji: Arc<RealmEmojiManager>,
+    media: Arc<MediaHandler>,
+    message_store: Arc<dyn MessageStore>,
+    reaction_store: Arc<dyn ReactionStore>,
+    /// Ghosts known to be in each portal room, keyed by room and Zulip user, so only the
+    /// first message a ghost sends into a room checks its membership.
+    joined: Mutex<HashSet<(String, i64)>>,
+}
+
+impl Backfiller {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        matrix: Arc<MatrixAppservice>,
+        ghosts: Arc<GhostUserManager>,
+        mentions: Arc<MentionResolver>,
+        links: Arc<LinkResolver>,
+        emoji: Arc<RealmEmojiManager>,
+        media: Arc<MediaHandler>,
+        message_store: Arc<dyn MessageStore>,
+        reaction_store: Arc<dyn ReactionStore>,
+    ) -> Self {
+        Self {
+            matrix,
+            ghosts,
+            mentions,
+            links,
+            emoji,
+            media,
+            message_store,
+            reaction_store,
+            joined: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Bridges up to `limit` messages older than `before` (or the newest messages when `None`).
+    pub async fn backfill_room(
+        &self,
+        client: &ZulipClient,
+        room: &RoomMapping,
+        limit: i32,
+        before: Option<i64>,
+    ) -> Result<BackfillReport> {
+        let mut report = BackfillReport::default();
+        if limit <= 0 {
+            return Ok(report);
+        }
+
+        let client = client.with_priority(RequestPriority::Background);
+        let narrow = room_narrow(room)?;
+        let limit = limit as usize;
+        let mut anchor = before.map_or(Anchor::Newest, Anchor::Message);
+        let mut messages = Vec::new();
+
+        while messages.len() < limit {
+            let batch = (limit - messages.len()).min(BATCH_SIZE as usize) as u32;
+            let request = GetMessagesRequest::new(narrow.clone(), anchor)
+                .before(batch)
+                .apply_markdown(true)
+                .include_anchor(anchor == Anchor::Newest);
+            let response = client.get_messages(&request).await?;
+
+            let Some(oldest) = response.messages.iter().map(|msg| msg.id).min() else {
+                break;
+            };
+            anchor = Anchor::Message(oldest);
+            messages.extend(response.messages);
+            if response.found_oldest {
+                break;
+            }
+        }
+
+        messages.sort_by_key(|msg| std::cmp::Reverse(msg.id));
+        messages.truncate(limit);
+        self.bridge_messages(&client, room, messages, &mut report).await?;
+
+        info!(
+            "backfilled {} of {} messages into {} ({} skipped)",
+            report.bridged, report.fetched, room.matrix_room_id, report.skipped
+        );
+
+        Ok(report)
+    }
+
+    /// Bridges everything newer than `after`, paging forward until the newest message.
+    pub async fn catch_up_room(
+        &self,
+        client: &ZulipClient,
+        room: &RoomMapping,
+        after: i64,
+    ) -> Result<BackfillReport> {
+        let mut report = BackfillReport::default();
+
+        let client = client.with_priority(RequestPriority::Background);
+        let narrow = room_narrow(room)?;
+        let mut anchor = after;
+        loop {
+            let request = GetMessagesRequest::new(narrow.clone(), Anchor::Message(anchor))
+                .after(BATCH_SIZE)
+                .apply_markdown(true)
+                .include_anchor(false);
+            let response = client.get_messages(&request).await?;
+
+            let Some(newest) = response.messages.iter().map(|msg| msg.id).max() else {
+                break;
+            };
+            anchor = newest;
+
+            self.bridge_messages(&client, room, response.messages, &mut report).await?;
+            if response.found_newest {
+                break;
+            }
+        }
+
+        if report.fetched > 0 {
+            info!(
+                "caught up {} of {} missed messages in {} ({} skipped)",
+                report.bridged, report.fetched, room.matrix_room_id, report.skipped
+            );
+        }
+
+        Ok(report)
+    }
+
+    /// Bridges a message delivered by the event queue, through the same conversion as history.
+    /// Returns whether anything was sent, which it is not for a message catch-up already
+    /// bridged.
+    pub async fn bridge_live_message(
+        &self,
+        client: &ZulipClient,
+        room: &RoomMapping,
+        msg: &ZulipMessage,
+    ) -> Result<bool> {
+        let emoji = self.emoji.emoticons(&room.organization_id).await?;
+        self.bridge_message(client, room, msg, &emoji).await
+    }
+
+    async fn bridge_messages(
+        &self,
+        client: &ZulipClient,
+        room: &RoomMapping,
+        mut messages: Vec<ZulipMessage>,
+        report: &mut BackfillReport,
+    ) -> Result<()> {
+        messages.sort_by_key(|msg| msg.id);
+        report.fetched += messages.len();
+
+        let emoji = self.emoji.emoticons(&room.organization_id).await?;
+        for msg in &messages {
+            match self.bridge_message(client, room, msg, &emoji).await {
+                Ok(true) => report.bridged += 1,
+                Ok(false) => report.skipped += 1,
+                Err(e) => {
+                    warn!(
+                        "failed to bridge zulip message {} into {}: {}",
+                        msg.id, room.matrix_room_id, e
+                    );
+                    report.skipped += 1;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends the parts of `msg` that are not recorded in the room yet, so a message cut short
+    /// by a failed send is completed by the next run. Returns whether anything was sent.
+    ///
+    /// Parts are indexed by position: the text first, when there is any, then one per upload.
+    /// An upload that cannot be copied is sent as a link in its own slot, so the indices and
+    /// the text stay the same from one run to the next.
+    async fn bridge_message(
+        &self,
+        client: &ZulipClient,
+        room: &RoomMapping,
+        msg: &ZulipMessage,
+        emoji: &HashMap<String, String>,
+    ) -> Result<bool> {
+        // What the bridge posted on behalf of Matrix users is already in the room.
+        if msg.sender_email == client.email() {
+            return Ok(false);
+        }
+
+        let recorded: HashMap<usize, MessageMapping> = self
+            .message_store
+            .get_parts_by_zulip_message(&room.matrix_room_id, msg.id)
+            .await?
+            .into_iter()
+            .map(|part| (part.part_index as usize, part))
+            .collect();
+
+        // Mentions and links only change how the text reads, not which parts there are, so
+        // a message that is already bridged is laid out without looking them up.
+        let layout = match msg.rendered_html() {
+            Some(html) => parse_zulip_message(
+                html,
+                client.site(),
+                &MentionTargets::default(),
+                &HashMap::new(),
+                emoji,
+            ),
+            None => MatrixContent {
+                body: msg.content.clone(),
+                ..Default::default()
+            },
+        };
+        let has_text = !layout.body.trim().is_empty() || layout.attachments.is_empty();
+        let first_upload = usize::from(has_text);
+        let part_count = first_upload + layout.attachments.len();
+        if (0..part_count).all(|index| recorded.contains_key(&index)) {
+            return Ok(false);
+        }
+
+        let ts = msg.timestamp * 1000;
+        let sender = self
+            .ensure_ghost(room, msg.sender_id, Some(&msg.sender_full_name))
+            .await?;
+
+        let sent = async {
+            let mut parts = Vec::new();
+            if has_text {
+                let part = match recorded.get(&0) {
+                    Some(part) => part.clone(),
+                    None => {
+                        let content = match msg.rendered_html() {
+                            Some(html) => self.convert(client, room, html, emoji).await?,
+                            None => layout.clone(),
+                        };
+                        let event_id = self
+                            .matrix
+                            .send_message_at(
+                                &room.matrix_room_id,
+                                &sender,
+                                &content.body,
+                                content.formatted_body.as_deref(),
+                                &content.mentions,
+                                ts,
+                            )
+                            .await?;
+                        self.record_part(room, msg, &event_id, MessageType::Text, 0)
+                            .await?
+                    }
+                };
+                parts.push(part);
+            }
+            for (position, attachment) in layout.attachments.iter().enumerate() {
+                let part_index = first_upload + position;
+                let part = match recorded.get(&part_index) {
+                    Some(part) => part.clone(),
+                    None => {
+                        self.bridge_attachment(client, room, msg, attachment, &sender, part_index)
+                            .await?
+                    }
+                };
+                parts.push(part);
+            }
+            Ok::<_, BridgeError>(parts)
+        }
+        .await;
+        let parts = match sent {
+            Ok(parts) => parts,
+            Err(e) => {
+                self.forget_joined(room, msg.sender_id);
+                return Err(e);
+            }
+        };
+
+        // Reactions belong to the message as a whole, so they go on its primary part, and were
+        // already bridged if that part was.
+        if !recorded.contains_key(&0) {
+            for reaction in msg.reactions.iter().flatten() {
+                if let Err(e) = self
+                    .bridge_reaction(room, &parts[0], reaction, Some(ts), emoji)
+                    .await
+                {
+                    warn!(
+                        "failed to backfill reaction {} on zulip message {}: {}",
+                        reaction.emoji_name, msg.id, e
+                    );
+                }
+            }
+        }
+
+        let event_ids: Vec<_> = parts.iter().map(|part| part.matrix_event_id.as_str()).collect();
+        debug!("backfilled zulip message {} as {}", msg.id, event_ids.join(", "));
+        Ok(true)
+    }
+
+    /// Copies an upload to the Matrix media repository and sends it as part `part_index`, or
+    /// sends a link to it in its place when it cannot be copied.
+    async fn bridge_attachment(
+        &self,
+        client: &ZulipClient,
+        room: &RoomMapping,
+        msg: &ZulipMessage,
+        attachment: &ZulipAttachment,
+        sender: &str,
+        part_index: usize,
+    ) -> Result<MessageMapping> {
+        let ts = msg.timestamp * 1000;
+        let upload = self
+            .media
+            .upload_to_matrix(client, &room.organization_id, &attachment.url, &attachment.name)
+            .await;
+        let (event_id, message_type) = match upload {
+            Ok(upload) => {
+                let message_type = MessageType::for_content_type(&upload.content_type);
+                let content = build_media_content(
+                    message_type.msgtype(),
+                    &attachment.name,
+                    &upload.mxc_url,
+                    media_info(attachment, &upload),
+                );
+                let event_id = self
+                    .matrix
+                    .send_media_at(&room.matrix_room_id, sender, &content, ts)
+                    .await?;
+                (event_id, message_type)
+            }
+            Err(e) => {
+                warn!(
+                    "failed to copy {} of zulip message {}, linking to it instead: {}",
+                    attachment.url, msg.id, e
+                );
+                let mut content = MatrixContent::default();
+                content.push_link(attachment);
+                let event_id = self
+                    .matrix
+                    .send_message_at(
+                        &room.matrix_room_id,
+                        sender,
+                        &content.body,
+                        content.formatted_body.as_deref(),
+                        &content.mentions,
+                        ts,
+                    )
+                    .await?;
+                (event_id, MessageType::Text)
+            }
+        };
+        self.record_part(room, msg, &event_id, message_type, part_index)
+            .await
+    }
+
+    /// Replaces the text part of a bridged message with its edited content. Only the text can
+    /// be edited in place; uploads added by the edit are linked from it, and media parts stay.
+    pub async fn bridge_edit(
+        &self,
+        client: &ZulipClient,
+        room: &RoomMapping,
+        parts: &[MessageMapping],
+        html: &str,
+    ) -> Result<()> {
+        let Some(text) = parts
+            .iter()
+            .find(|part| part.is_primary && part.message_type == MessageType::Text.as_str())
+        else {
+            debug!(
+                "zulip message {} has no text part to edit",
+                parts.first().map_or(0, |part| part.zulip_message_id)
+            );
+            return Ok(());
+        };
+
+        let emoji = self.emoji.emoticons(&room.organization_id).await?;
+        let mut content = self.convert(client, room, html, &emoji).await?;
+        for attachment in std::mem::take(&mut content.attachments) {
+            content.push_link(&attachment);
+        }
+
+        let sender = self.ghosts.ghost_user_id(text.zulip_sender_id);
+        self.matrix
+            .send_message_edit(
+                &room.matrix_room_id,
+                &sender,
+                &content.body,
+                content.formatted_body.as_deref(),
+                &content.mentions,
+                &text.matrix_event_id,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Adds a reaction made on Zulip to the primary part of the message.
+    pub async fn bridge_live_reaction(
+        &self,
+        room: &RoomMapping,
+        primary: &MessageMapping,
+        reaction: &ZulipReaction,
+    ) -> Result<()> {
+        let emoji = self.emoji.emoticons(&room.organization_id).await?;
+        self.bridge_reaction(room, primary, reaction, None, &emoji).await
+    }
+
+    /// Redacts the annotation a Zulip reaction was bridged as, on whichever part of the message
+    /// it was sent to. Reactions are told apart by user and emoji code, or by emoji name for
+    /// those recorded before codes were.
+    pub async fn remove_live_reaction(
+        &self,
+        room: &RoomMapping,
+        parts: &[MessageMapping],
+        reaction: &ZulipReaction,
+    ) -> Result<()> {
+        let Some(message_id) = parts.first().map(|part| part.zulip_message_id) else {
+            return Ok(());
+        };
+        let bridged = self
+            .reaction_store
+            .get_by_zulip_message(message_id)
+            .await?
+            .into_iter()
+            .filter(|mapping| {
+                mapping.zulip_user_id == reaction.user_id
+                    && (mapping.emoji_code == reaction.emoji_code
+                        || mapping.emoji_code.is_empty() && mapping.emoji == reaction.emoji_name)
+                    && parts
+                        .iter()
+                        .any(|part| part.matrix_event_id == mapping.matrix_event_id)
+            });
+
+        let sender = self.ghosts.ghost_user_id(reaction.user_id);
+        for mapping in bridged {
+            self.matrix
+                .redact_event(
+                    &room.matrix_room_id,
+                    &sender,
+                    &mapping.matrix_reaction_event_id,
+                    None,
+                )
+                .await?;
+            self.reaction_store.delete(mapping.id).await?;
+        }
+        Ok(())
+    }
+
+    async fn convert(
+        &self,
+        client: &ZulipClient,
+        room: &RoomMapping,
+        html: &str,
+        emoji: &HashMap<String, String>,
+    ) -> Result<MatrixContent> {
+        let topic_room = room.room_type == RoomType::Topic.as_str();
+        let targets = self
+            .mentions
+            .zulip_targets(&room.organization_id, client, html, topic_room)
+            .await?;
+        let links = self
+            .links
+            .zulip_links(&room.organization_id, client.site(), html)
+            .await?;
+        Ok(parse_zulip_message(html, client.site(), &targets, &links, emoji))
+    }
+
+    /// The first part sent is the primary one: the text, or the first upload of a message that
+    /// is nothing but uploads.
+    async fn record_part(
+        &self,
+        room: &RoomMapping,
+        msg: &ZulipMessage,
+        event_id: &str,
+        message_type: MessageType,
+        part_index: usize,
+    ) -> Result<MessageMapping> {
+        let part = self
+            .message_store
+            .create(NewMessageMapping {
+                matrix_event_id: event_id.to_string(),
+                matrix_room_id: room.matrix_room_id.clone(),
+                zulip_message_id: msg.id,
+                zulip_sender_id: msg.sender_id,
+                message_type: message_type.as_str().to_string(),
+                part_index: part_index as i32,
+                is_primary: part_index == 0,
+            })
+            .await?;
+        Ok(part)
+    }
+
+    /// Backfilled reactions keep the message's timestamp `ts`; live ones are sent as they come.
+    async fn bridge_reaction(
+        &self,
+        room: &RoomMapping,
+        primary: &MessageMapping,
+        reaction: &ZulipReaction,
+        ts: Option<i64>,
+        emoji: &HashMap<String, String>,
+    ) -> Result<()> {
+        let display_name = reaction.user.as_ref().map(|user| user.full_name.as_str());
+        let sender = self.ensure_ghost(room, reaction.user_id, display_name).await?;
+
+        let key = reaction_key(
+            &reaction.emoji_name,
+            &reaction.emoji_code,
+            &reaction.reaction_type,
+            emoji,
+        );
+        let shortcode = key
+            .starts_with("mxc://")
+            .then(|| format!(":{}:", reaction.emoji_name));
+        let event_id = &primary.matrix_event_id;
+        let sent = match ts {
+            Some(ts) => {
+                self.matrix
+                    .send_reaction_at(
+                        &room.matrix_room_id,
+                        &sender,
+                        event_id,
+                        &key,
+                        shortcode.as_deref(),
+                        ts,
+                    )
+                    .await
+            }
+            None => {
+                self.matrix
+                    .send_reaction(
+                        &room.matrix_room_id,
+                        &sender,
+                        event_id,
+                        &key,
+                        shortcode.as_deref(),
+                    )
+                    .await
+            }
+        };
+        let reaction_event_id = match sent {
+            Ok(event_id) => event_id,
+            Err(e) => {
+                self.forget_joined(room, reaction.user_id);
+                return Err(e);
+            }
+        };
+
+        self.reaction_store
+            .create(NewReactionMapping {
+                matrix_event_id: event_id.clone(),
+                zulip_message_id: primary.zulip_message_id,
+                zulip_user_id: reaction.user_id,
+                emoji: reaction.emoji_name.clone(),
+                matrix_reaction_event_id: reaction_event_id,
+                emoji_code: reaction.emoji_code.clone(),
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    async fn ensure_ghost(
+        &self,
+        room: &RoomMapping,
+        zulip_user_id: i64,
+        display_name: Option<&str>,
+    ) -> Result<String> {
+        let ghost = self
+            .ghosts
+            .get_or_create_ghost(zulip_user_id, display_name, None, false)
+            .await?;
+
+        let key = (room.matrix_room_id.clone(), zulip_user_id);
+        if !self.joined.lock().contains(&key) {
+            self.ghosts
+                .ensure_ghost_in_room(zulip_user_id, &room.matrix_room_id)
+                .await?;
+            self.joined.lock().insert(key);
+        }
+
+        Ok(ghost.matrix_user_id)
+    }
+
+    /// Drops a ghost from the joined cache after a failed send, so the next message checks
+    /// its membership again in case it was removed from the room.
+    fn forget_joined(&self, room: &RoomMapping, zulip_user_id: i64) {
+        self.joined
+            .lock()
+            .remove(&(room.matrix_room_id.clone(), zulip_user_id));
+    }
+}
+
+/// Zulip's preview dimensions stand in for images that could not be decoded locally.
+fn media_info(attachment: &ZulipAttachment, upload: &MatrixUpload) -> Value {
+    let mut info = upload.info();
+    if info.get("w").is_none()
+        && let Some((width, height)) = attachment.dimensions
+    {
+        info["w"] = json!(width);
+        info["h"] = json!(height);
+    }
+    info
+}
+
+/// The narrow selecting a portal room's messages. Direct message rooms do not record their
+/// participants yet, so they cannot be narrowed to.
+fn room_narrow(room: &RoomMapping) -> Result<Narrow> {
+    match RoomType::from_str(&room.room_type) {
+        Some(RoomType::Direct) => Err(BridgeError::NotImplemented(
+            "history for direct message rooms".to_string(),
+        )),
+        _ => {
+            let narrow = Narrow::new().stream(room.zulip_stream_id);
+            Ok(match room.zulip_topic.as_deref() {
+                Some(topic) => narrow.topic(topic),
+                None => narrow,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bridge::BridgeCore;
+    use crate::db::models::NewRoomMapping;
+    use crate::testing::{self, FakeHomeserver, FakeZulip};
+
+    const SENDER_ID: i64 = 10;
+    const STREAM_ID: i64 = 5;
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn backfills_history_with_original_timestamps() {
+        let zulip = FakeZulip::start().await.unwrap();
+        zulip.add_user(SENDER_ID, "Iago");
+        zulip.add_stream(STREAM_ID, "general");
+        let message_ids: Vec<i64> = (1..=3)
+            .map(|n| {
+                zulip.import_message(SENDER_ID, STREAM_ID, "greetings", &format!("hello {}", n))
+            })
+            .collect();
+
+        let homeserver = FakeHomeserver::start().await.unwrap();
+        let config = Arc::new(testing::test_config(&homeserver).unwrap());
+        let db_manager = testing::test_database(&config).await.unwrap();
+        let matrix = Arc::new(MatrixAppservice::new(config.clone()).await.unwrap());
+        let bridge = BridgeCore::new(config, db_manager.clone(), matrix.clone());
+
+        let org = testing::create_organization(&db_manager, &zulip).await.unwrap();
+        let matrix_room_id = matrix.create_room("general", None, None, false).await.unwrap();
+        let room = db_manager
+            .room_store()
+            .create(NewRoomMapping {
+                matrix_room_id,
+                zulip_stream_id: STREAM_ID,
+                zulip_stream_name: "general".to_string(),
+                zulip_topic: None,
+                organization_id: org.id.clone(),
+                room_type: RoomType::Stream.as_str().to_string(),
+            })
+            .await
+            .unwrap();
+
+        let client = zulip.client().unwrap();
+        let report = bridge.backfiller.backfill_room(&client, &room, 10, None).await.unwrap();
+        assert_eq!(report.fetched, 3);
+        assert_eq!(report.bridged, 3);
+
+        let ghost = matrix.ghost_user_id(SENDER_ID);
+        let events: Vec<Value> = homeserver
+            .events(&room.matrix_room_id)
+            .into_iter()
+            .filter(|event| event["type"] == "m.room.message")
+            .collect();
+        assert_eq!(events.len(), 3);
+        for (event, message_id) in events.iter().zip(&message_ids) {
+            let message = zulip.message(*message_id).unwrap();
+            assert_eq!(event["sender"], ghost);
+            assert_eq!(event["content"]["body"], message.content);
+            assert_eq!(event["origin_server_ts"], message.timestamp * 1000);
+
+            let mapping = db_manager
+                .message_store()
+                .get_by_zulip_message(&room.matrix_room_id, *message_id)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(event["event_id"], mapping.matrix_event_id);
+        }
+
+        // A second run finds everything already bridged.
+        let report = bridge.backfiller.backfill_room(&client, &room, 10, None).await.unwrap();
+        assert_eq!(report.bridged, 0);
+        assert_eq!(report.skipped, 3);
+    }
+}
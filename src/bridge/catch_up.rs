@@ -0,0 +1,131 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tracing::{debug, error, info, warn};
+
+use super::Backfiller;
+use crate::db::models::{RoomMapping, RoomType};
+use crate::db::stores::{MessageStore, OrganizationStore, RoomStore};
+use crate::utils::Result;
+use crate::zulip::{EventQueueCheckpoint, ZulipClient, ZulipErrorCode, ZulipQueue};
+
+/// Keeps an organization's event queue position in the database and, whenever the queue had to
+/// be replaced, fetches the messages the old queue never delivered.
+#[derive(Clone)]
+pub struct OrganizationCheckpoint {
+    org_id: String,
+    client: Arc<ZulipClient>,
+    backfiller: Arc<Backfiller>,
+    organization_store: Arc<dyn OrganizationStore>,
+    room_store: Arc<dyn RoomStore>,
+    message_store: Arc<dyn MessageStore>,
+}
+
+impl OrganizationCheckpoint {
+    pub fn new(
+        org_id: &str,
+        client: Arc<ZulipClient>,
+        backfiller: Arc<Backfiller>,
+        organization_store: Arc<dyn OrganizationStore>,
+        room_store: Arc<dyn RoomStore>,
+        message_store: Arc<dyn MessageStore>,
+    ) -> Self {
+        Self {
+            org_id: org_id.to_string(),
+            client,
+            backfiller,
+            organization_store,
+            room_store,
+            message_store,
+        }
+    }
+
+    /// Runs a catch-up pass for every portal room of the organization, anchored at the newest
+    /// message already bridged into it. Rooms without bridged messages are left to backfill.
+    pub async fn catch_up(&self) -> Result<()> {
+        let rooms = self.room_store.get_by_organization(&self.org_id).await?;
+        let mut bridged = 0;
+
+        for room in rooms {
+            match self.catch_up_room(&room).await {
+                Ok(count) => bridged += count,
+                Err(e)
+                    if e.as_zulip().and_then(|e| e.code())
+                        == Some(&ZulipErrorCode::StreamDoesNotExist) =>
+                {
+                    info!(
+                        "stream {} of {} no longer exists, skipping catch-up",
+                        room.zulip_stream_id, room.matrix_room_id
+                    );
+                }
+                Err(e) => warn!("catch-up for {} failed: {}", room.matrix_room_id, e),
+            }
+        }
+
+        info!(
+            "catch-up for organization {} bridged {} missed messages",
+            self.org_id, bridged
+        );
+        Ok(())
+    }
+
+    async fn catch_up_room(&self, room: &RoomMapping) -> Result<usize> {
+        if RoomType::from_str(&room.room_type) == Some(RoomType::Direct) {
+            debug!("skipping catch-up for direct message room {}", room.matrix_room_id);
+            return Ok(0);
+        }
+
+        let Some(newest) = self
+            .message_store
+            .get_newest_by_matrix_room(&room.matrix_room_id)
+            .await?
+        else {
+            return Ok(0);
+        };
+
+        let report = self
+            .backfiller
+            .catch_up_room(&self.client, room, newest.zulip_message_id)
+            .await?;
+        Ok(report.bridged)
+    }
+}
+
+#[async_trait]
+impl EventQueueCheckpoint for OrganizationCheckpoint {
+    async fn load(&self) -> Result<Option<ZulipQueue>> {
+        let queue = self
+            .organization_store
+            .get(&self.org_id)
+            .await?
+            .and_then(|org| {
+                Some(ZulipQueue {
+                    queue_id: org.event_queue_id?,
+                    last_event_id: org.last_event_id?,
+                })
+            });
+        Ok(queue)
+    }
+
+    async fn save(&self, queue: &ZulipQueue) -> Result<()> {
+        self.organization_store
+            .set_event_queue(&self.org_id, Some(&queue.queue_id), Some(queue.last_event_id))
+            .await?;
+        Ok(())
+    }
+
+    async fn clear(&self) -> Result<()> {
+        self.organization_store
+            .set_event_queue(&self.org_id, None, None)
+            .await?;
+        Ok(())
+    }
+
+    /// Catches up before the new queue is polled, so missed history is bridged ahead of the
+    /// live events queued meanwhile and a message is never bridged by both at once.
+    async fn on_fresh_queue(&self) {
+        if let Err(e) = self.catch_up().await {
+            error!("catch-up for organization {} failed: {}", self.org_id, e);
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use serde_json::{Value, json};
+use tracing::{debug, info, warn};
+
+use crate::db::models::{EmojiMapping, NewEmojiMapping, Organization};
+use crate::db::stores::{EmojiStore, OrganizationStore};
+use crate::matrix::{MatrixAppservice, MatrixEvent};
+use crate::media::MediaHandler;
+use crate::utils::{BridgeError, Result};
+use crate::zulip::{ZulipClient, ZulipRealmEmoji};
+
+/// MSC2545 image pack state event.
+const IMAGE_PACK_EVENT: &str = "im.ponies.room_emotes";
+const IMAGE_PACK_STATE_KEY: &str = "zulip";
+
+/// Mirrors each organization's custom realm emoji on Matrix. Every emoji is uploaded to the
+/// media repository once and remembered by its Zulip source URL; the active ones are published
+/// as an image pack in the organization's Space, which is created on first use.
+pub struct RealmEmojiManager {
+    matrix: Arc<MatrixAppservice>,
+    media: Arc<MediaHandler>,
+    organization_store: Arc<dyn OrganizationStore>,
+    emoji_store: Arc<dyn EmojiStore>,
+    /// Startup sync and `realm_emoji` events can publish at the same time; one at a time per
+    /// organization, so the Space is created once and the newest pack is written last.
+    publishing: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl RealmEmojiManager {
+    pub fn new(
+        matrix: Arc<MatrixAppservice>,
+        media: Arc<MediaHandler>,
+        organization_store: Arc<dyn OrganizationStore>,
+        emoji_store: Arc<dyn EmojiStore>,
+    ) -> Self {
+        Self {
+            matrix,
+            media,
+            organization_store,
+            emoji_store,
+            publishing: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Fetches the realm emoji list and brings the uploads and the image pack up to date.
+    pub async fn sync(&self, organization_id: &str, client: &ZulipClient) -> Result<()> {
+        let realm_emoji = client.get_realm_emoji().await?;
+        self.update(organization_id, client, realm_emoji).await
+    }
+
+    /// Applies a complete realm emoji list, as returned by the API or carried by a
+    /// `realm_emoji` event. Deactivated emoji stay uploaded so older messages still render, but
+    /// leave the pack; emoji missing from the list are forgotten.
+    pub async fn update(
+        &self,
+        organization_id: &str,
+        client: &ZulipClient,
+        realm_emoji: HashMap<String, ZulipRealmEmoji>,
+    ) -> Result<()> {
+        let known: HashMap<String, EmojiMapping> = self
+            .emoji_store
+            .get_by_organization(organization_id)
+            .await?
+            .into_iter()
+            .map(|emoji| (emoji.zulip_emoji_id.clone(), emoji))
+            .collect();
+
+        for emoji in realm_emoji.values() {
+            let cached = known
+                .get(&emoji.id)
+                .filter(|cached| cached.source_url == emoji.source_url);
+            if cached.is_some_and(|cached| {
+                cached.name == emoji.name && cached.deactivated == emoji.deactivated
+            }) {
+                continue;
+            }
+
+            let mxc_url = match cached {
+                Some(cached) => cached.mxc_url.clone(),
+                None => match self.upload(organization_id, client, emoji).await {
+                    Ok(mxc_url) => mxc_url,
+                    Err(e) => {
+                        warn!(
+                            "failed to upload realm emoji {} of {}: {}",
+                            emoji.name, organization_id, e
+                        );
+                        continue;
+                    }
+                },
+            };
+            self.emoji_store
+                .upsert(NewEmojiMapping {
+                    organization_id: organization_id.to_string(),
+                    zulip_emoji_id: emoji.id.clone(),
+                    name: emoji.name.clone(),
+                    source_url: emoji.source_url.clone(),
+                    mxc_url,
+                    deactivated: emoji.deactivated,
+                })
+                .await?;
+        }
+
+        for id in known.keys().filter(|id| !realm_emoji.contains_key(*id)) {
+            self.emoji_store.delete(organization_id, id).await?;
+        }
+
+        self.publish_pack(organization_id).await
+    }
+
+    /// Uploaded realm emoji of the organization by name, for rendering messages and reactions.
+    pub async fn emoticons(&self, organization_id: &str) -> Result<HashMap<String, String>> {
+        Ok(self
+            .emoji_store
+            .get_by_organization(organization_id)
+            .await?
+            .into_iter()
+            .map(|emoji| (emoji.name, emoji.mxc_url))
+            .collect())
+    }
+
+    /// The realm emoji a Matrix reaction refers to: custom emoji reactions are keyed by their
+    /// `mxc://` URI, and clients without image packs send the `:name:` shortcode instead.
+    pub async fn reaction_emoji(
+        &self,
+        organization_id: &str,
+        event: &MatrixEvent,
+    ) -> Result<Option<EmojiMapping>> {
+        let Some(key) = event.reaction_key() else {
+            return Ok(None);
+        };
+        let name = event
+            .reaction_shortcode()
+            .unwrap_or(&key)
+            .trim_matches(':')
+            .to_string();
+
+        let emoji = self
+            .emoji_store
+            .get_by_organization(organization_id)
+            .await?
+            .into_iter()
+            .filter(|emoji| !emoji.deactivated)
+            .find(|emoji| emoji.mxc_url == key || emoji.name == name);
+        Ok(emoji)
+    }
+
+    async fn upload(
+        &self,
+        organization_id: &str,
+        client: &ZulipClient,
+        emoji: &ZulipRealmEmoji,
+    ) -> Result<String> {
+        let file_name = emoji
+            .source_url
+            .rsplit('/')
+            .next()
+            .unwrap_or(&emoji.name);
+        let upload = self
+            .media
+            .upload_to_matrix(client, organization_id, &emoji.source_url, file_name)
+            .await?;
+        debug!("uploaded realm emoji {} as {}", emoji.name, upload.mxc_url);
+        Ok(upload.mxc_url)
+    }
+
+    async fn publish_pack(&self, organization_id: &str) -> Result<()> {
+        let lock = self
+            .publishing
+            .lock()
+            .entry(organization_id.to_string())
+            .or_default()
+            .clone();
+        let _publishing = lock.lock().await;
+
+        let org = self
+            .organization_store
+            .get(organization_id)
+            .await?
+            .ok_or_else(|| {
+                BridgeError::InvalidState(format!("unknown organization {}", organization_id))
+            })?;
+        let space_room_id = self.ensure_space(&org).await?;
+
+        let emoji = self.emoji_store.get_by_organization(organization_id).await?;
+        let content = image_pack(&org.name, &emoji);
+        self.matrix
+            .set_room_state(&space_room_id, IMAGE_PACK_EVENT, IMAGE_PACK_STATE_KEY, &content)
+            .await
+    }
+
+    async fn ensure_space(&self, org: &Organization) -> Result<String> {
+        if let Some(space_room_id) = &org.space_room_id {
+            return Ok(space_room_id.clone());
+        }
+
+        let space_room_id = self
+            .matrix
+            .create_space(&org.name, Some(&org.site))
+            .await?;
+        self.organization_store
+            .set_space_room(&org.id, &space_room_id)
+            .await?;
+        info!("created space {} for organization {}", space_room_id, org.id);
+        Ok(space_room_id)
+    }
+}
+
+fn image_pack(display_name: &str, emoji: &[EmojiMapping]) -> Value {
+    let images: serde_json::Map<String, Value> = emoji
+        .iter()
+        .filter(|emoji| !emoji.deactivated)
+        .map(|emoji| {
+            (
+                emoji.name.clone(),
+                json!({ "url": emoji.mxc_url, "body": emoji.name }),
+            )
+        })
+        .collect();
+
+    json!({
+        "pack": {
+            "display_name": display_name,
+            "usage": ["emoticon"],
+        },
+        "images": images,
+    })
+}
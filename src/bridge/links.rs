@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::db::models::RoomType;
+use crate::db::stores::{MessageStore, RoomStore};
+use crate::parsers::links::{MatrixLink, ZulipLink};
+use crate::parsers::matrix_parser::find_permalinks;
+use crate::parsers::zulip_parser::find_links;
+use crate::utils::Result;
+
+/// Looks up where links between the two networks should point: Zulip narrow links to bridged
+/// streams, topics and messages become Matrix permalinks, and Matrix permalinks to bridged
+/// rooms and events become Zulip narrow links. Links without a mapping are left to the parsers,
+/// which keep them pointing at the original network.
+pub struct LinkResolver {
+    room_store: Arc<dyn RoomStore>,
+    message_store: Arc<dyn MessageStore>,
+}
+
+impl LinkResolver {
+    pub fn new(room_store: Arc<dyn RoomStore>, message_store: Arc<dyn MessageStore>) -> Self {
+        Self {
+            room_store,
+            message_store,
+        }
+    }
+
+    /// Matrix permalinks for the narrow links in a rendered message of `organization_id`.
+    pub async fn zulip_links(
+        &self,
+        organization_id: &str,
+        site: &str,
+        rendered: &str,
+    ) -> Result<HashMap<ZulipLink, MatrixLink>> {
+        let mut links = HashMap::new();
+        for link in find_links(rendered, site) {
+            if let Some(target) = self.matrix_target(organization_id, &link).await? {
+                links.insert(link, target);
+            }
+        }
+        Ok(links)
+    }
+
+    /// Zulip narrow URLs for the permalinks in a `formatted_body`. Only rooms of
+    /// `organization_id` are translated, since other organizations live on other sites.
+    pub async fn matrix_links(
+        &self,
+        organization_id: &str,
+        site: &str,
+        html: &str,
+    ) -> Result<HashMap<MatrixLink, String>> {
+        let mut links = HashMap::new();
+        for link in find_permalinks(html) {
+            if let Some(target) = self.zulip_target(organization_id, &link).await? {
+                links.insert(link, target.0.to_url(site, &target.1));
+            }
+        }
+        Ok(links)
+    }
+
+    async fn matrix_target(
+        &self,
+        organization_id: &str,
+        link: &ZulipLink,
+    ) -> Result<Option<MatrixLink>> {
+        // A topic without a room of its own lives in the stream room.
+        let mut rooms = Vec::new();
+        if let Some(topic) = &link.topic {
+            rooms.extend(
+                self.room_store
+                    .get_by_zulip_topic(organization_id, link.stream_id, Some(topic))
+                    .await?,
+            );
+        }
+        rooms.extend(
+            self.room_store
+                .get_by_zulip_topic(organization_id, link.stream_id, None)
+                .await?,
+        );
+
+        // Message ids are only unique per server, so only the portals of the linked stream are
+        // searched for the message.
+        if let Some(message_id) = link.message_id {
+            for room in &rooms {
+                if let Some(message) = self
+                    .message_store
+                    .get_by_zulip_message(&room.matrix_room_id, message_id)
+                    .await?
+                {
+                    return Ok(Some(MatrixLink {
+                        room_id: message.matrix_room_id,
+                        event_id: Some(message.matrix_event_id),
+                    }));
+                }
+            }
+        }
+
+        Ok(rooms.into_iter().next().map(|room| MatrixLink {
+            room_id: room.matrix_room_id,
+            event_id: None,
+        }))
+    }
+
+    /// The narrow link and stream name for a permalink.
+    async fn zulip_target(
+        &self,
+        organization_id: &str,
+        link: &MatrixLink,
+    ) -> Result<Option<(ZulipLink, String)>> {
+        let Some(room) = self.room_store.get_by_matrix_room(&link.room_id).await? else {
+            return Ok(None);
+        };
+        if room.organization_id != organization_id || room.room_type == RoomType::Direct.as_str() {
+            return Ok(None);
+        }
+
+        let mut message_id = None;
+        if let Some(event_id) = &link.event_id
+            && let Some(message) = self.message_store.get_primary_by_matrix_event(event_id).await?
+        {
+            message_id = Some(message.zulip_message_id);
+        }
+
+        let target = ZulipLink {
+            stream_id: room.zulip_stream_id,
+            topic: room.zulip_topic,
+            message_id,
+        };
+        Ok(Some((target, room.zulip_stream_name)))
+    }
+}
@@ -0,0 +1,160 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tracing::{debug, error, info};
+
+use super::BridgeCore;
+use crate::command::BridgeCommand;
+use crate::matrix::event_handler::DefaultMatrixEventHandler;
+use crate::matrix::{MatrixEvent, MatrixEventHandler};
+use crate::utils::{BridgeError, Result};
+
+/// Matrix side of the bridge. Room messages addressed to the bridge are executed as commands,
+/// other messages in portal rooms are relayed to Zulip, files uploaded to it, and custom emoji
+/// reactions are added to the Zulip message; everything else goes to the default handler.
+pub struct BridgeMatrixEventHandler {
+    bridge: Arc<BridgeCore>,
+    fallback: DefaultMatrixEventHandler,
+}
+
+impl BridgeMatrixEventHandler {
+    pub fn new(bridge: Arc<BridgeCore>) -> Self {
+        Self {
+            bridge,
+            fallback: DefaultMatrixEventHandler,
+        }
+    }
+
+    async fn reply(&self, room_id: &str, body: &str) {
+        if let Err(e) = self.bridge.matrix().send_notice(room_id, body).await {
+            error!("failed to send command reply to {}: {}", room_id, e);
+        }
+    }
+
+    async fn bridge_media(&self, event: &MatrixEvent) -> Result<()> {
+        match self.bridge.bridge_matrix_media(event).await {
+            Ok(message_id) => {
+                debug!(
+                    "bridged media {:?} from {} as zulip message {}",
+                    event.event_id, event.room_id, message_id
+                );
+                Ok(())
+            }
+            Err(BridgeError::RoomNotFound(_)) => self.fallback.handle_room_message(event).await,
+            Err(e @ BridgeError::MediaTooLarge { .. }) => {
+                self.reply(&event.room_id, &format!("File not sent to Zulip: {}", e))
+                    .await;
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn bridge_text(&self, event: &MatrixEvent) -> Result<()> {
+        match self.bridge.bridge_matrix_message(event).await {
+            Ok(Some(message_id)) => {
+                debug!(
+                    "bridged {:?} from {} as zulip message {}",
+                    event.event_id, event.room_id, message_id
+                );
+                Ok(())
+            }
+            Ok(None) | Err(BridgeError::RoomNotFound(_)) => {
+                self.fallback.handle_room_message(event).await
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn execute(&self, event: &MatrixEvent, command: BridgeCommand) {
+        info!(
+            "executing {:?} from {} in {}",
+            command, event.sender, event.room_id
+        );
+
+        match command {
+            BridgeCommand::Help => self.reply(&event.room_id, &BridgeCommand::help()).await,
+            BridgeCommand::Backfill { limit } => {
+                let bridge = self.bridge.clone();
+                let room_id = event.room_id.clone();
+                tokio::spawn(async move {
+                    let body = match bridge.backfill_older(&room_id, limit).await {
+                        Ok(report) => format!(
+                            "Backfilled {} messages ({} already bridged or skipped).",
+                            report.bridged, report.skipped
+                        ),
+                        Err(e) => format!("Backfill failed: {}", e),
+                    };
+                    if let Err(e) = bridge.matrix().send_notice(&room_id, &body).await {
+                        error!("failed to send command reply to {}: {}", room_id, e);
+                    }
+                });
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl MatrixEventHandler for BridgeMatrixEventHandler {
+    async fn handle_room_message(&self, event: &MatrixEvent) -> Result<()> {
+        let matrix = self.bridge.matrix();
+        if matrix.is_namespaced_user(&event.sender) || event.sender == matrix.bot_user_id() {
+            return Ok(());
+        }
+
+        if event.is_media() {
+            return self.bridge_media(event).await;
+        }
+
+        let Some(body) = event.body() else {
+            return self.fallback.handle_room_message(event).await;
+        };
+
+        match BridgeCommand::parse(body) {
+            Ok(Some(command)) => self.execute(event, command).await,
+            Ok(None) => return self.bridge_text(event).await,
+            Err(e) => self.reply(&event.room_id, &e.to_string()).await,
+        }
+
+        Ok(())
+    }
+
+    async fn handle_room_member(&self, event: &MatrixEvent) -> Result<()> {
+        self.fallback.handle_room_member(event).await
+    }
+
+    async fn handle_room_redaction(&self, event: &MatrixEvent) -> Result<()> {
+        self.fallback.handle_room_redaction(event).await
+    }
+
+    async fn handle_reaction(&self, event: &MatrixEvent) -> Result<()> {
+        let matrix = self.bridge.matrix();
+        if matrix.is_namespaced_user(&event.sender) || event.sender == matrix.bot_user_id() {
+            return Ok(());
+        }
+
+        match self.bridge.bridge_matrix_reaction(event).await {
+            Ok(true) => Ok(()),
+            Ok(false) | Err(BridgeError::RoomNotFound(_)) => {
+                self.fallback.handle_reaction(event).await
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn handle_room_encryption(&self, event: &MatrixEvent) -> Result<()> {
+        self.fallback.handle_room_encryption(event).await
+    }
+
+    async fn handle_room_name(&self, event: &MatrixEvent) -> Result<()> {
+        self.fallback.handle_room_name(event).await
+    }
+
+    async fn handle_room_topic(&self, event: &MatrixEvent) -> Result<()> {
+        self.fallback.handle_room_topic(event).await
+    }
+
+    async fn handle_room_avatar(&self, event: &MatrixEvent) -> Result<()> {
+        self.fallback.handle_room_avatar(event).await
+    }
+}
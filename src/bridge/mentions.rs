@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+
+use crate::db::stores::UserStore;
+use crate::matrix::{GhostUserManager, MatrixEvent};
+use crate::parsers::matrix_parser::{self, ZulipUserRef, find_pills};
+use crate::parsers::zulip_parser::{self, find_mentions};
+use crate::utils::Result;
+use crate::zulip::ZulipClient;
+
+type GroupMembers = HashMap<i64, Vec<i64>>;
+
+/// Looks up who the mentions in a message are on the other side, so the parsers can write
+/// mentions that actually notify: Matrix pills for Zulip users and user groups, Zulip
+/// `@**Name|id**` for Matrix users with a Zulip account.
+pub struct MentionResolver {
+    ghosts: Arc<GhostUserManager>,
+    user_store: Arc<dyn UserStore>,
+    /// Members of each organization's user groups by group id, fetched on the first group
+    /// mention and dropped when a `user_group` event changes them.
+    groups: RwLock<HashMap<String, Arc<GroupMembers>>>,
+}
+
+impl MentionResolver {
+    pub fn new(ghosts: Arc<GhostUserManager>, user_store: Arc<dyn UserStore>) -> Self {
+        Self {
+            ghosts,
+            user_store,
+            groups: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn forget_user_groups(&self, organization_id: &str) {
+        self.groups.write().remove(organization_id);
+    }
+
+    /// Resolves the mentions of a rendered Zulip message of `organization_id`. `topic_room`
+    /// tells whether the room bridges a single topic.
+    pub async fn zulip_targets(
+        &self,
+        organization_id: &str,
+        client: &ZulipClient,
+        rendered: &str,
+        topic_room: bool,
+    ) -> Result<zulip_parser::MentionTargets> {
+        let found = find_mentions(rendered);
+        let mut targets = zulip_parser::MentionTargets {
+            topic_room,
+            ..Default::default()
+        };
+
+        let mut user_ids = found.user_ids;
+        if !found.group_ids.is_empty() {
+            let groups = self.user_groups(organization_id, client).await?;
+            for group_id in found.group_ids {
+                if let Some(members) = groups.get(&group_id) {
+                    user_ids.extend(members);
+                    targets.groups.insert(group_id, members.clone());
+                }
+            }
+        }
+
+        user_ids.sort_unstable();
+        user_ids.dedup();
+        for user_id in user_ids {
+            let matrix_user_id = self.ghosts.get_matrix_user_id(user_id).await?;
+            targets.users.insert(user_id, matrix_user_id);
+        }
+
+        Ok(targets)
+    }
+
+    async fn user_groups(
+        &self,
+        organization_id: &str,
+        client: &ZulipClient,
+    ) -> Result<Arc<GroupMembers>> {
+        if let Some(groups) = self.groups.read().get(organization_id) {
+            return Ok(groups.clone());
+        }
+
+        let groups: Arc<GroupMembers> = Arc::new(
+            client
+                .get_user_groups()
+                .await?
+                .into_iter()
+                .map(|group| (group.id, group.members))
+                .collect(),
+        );
+        self.groups
+            .write()
+            .insert(organization_id.to_string(), groups.clone());
+        Ok(groups)
+    }
+
+    /// Resolves the pills and `m.mentions` of a Matrix message or media caption.
+    pub async fn matrix_targets(&self, event: &MatrixEvent) -> Result<matrix_parser::MentionTargets> {
+        let mentions = event.mentions();
+        let mut user_ids = event.formatted_body().map(find_pills).unwrap_or_default();
+        user_ids.extend(mentions.user_ids.iter().cloned());
+
+        let mut targets = matrix_parser::MentionTargets {
+            mentions,
+            ..Default::default()
+        };
+        user_ids.sort_unstable();
+        user_ids.dedup();
+        for user_id in user_ids {
+            if let Some(user) = self.zulip_user(&user_id).await? {
+                targets.users.insert(user_id, user);
+            }
+        }
+
+        Ok(targets)
+    }
+
+    async fn zulip_user(&self, matrix_user_id: &str) -> Result<Option<ZulipUserRef>> {
+        if let Some(mapping) = self.user_store.get_by_matrix_user(matrix_user_id).await? {
+            return Ok(Some(ZulipUserRef {
+                id: mapping.zulip_user_id,
+                name: mapping.display_name.unwrap_or_default(),
+            }));
+        }
+
+        // A ghost the store has not seen yet still carries its Zulip id; Zulip accepts
+        // `@**|id**` without a name.
+        Ok(self
+            .ghosts
+            .get_zulip_user_id(matrix_user_id)
+            .await?
+            .map(|id| ZulipUserRef {
+                id,
+                name: String::new(),
+            }))
+    }
+}
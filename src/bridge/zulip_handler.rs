@@ -0,0 +1,90 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use super::BridgeCore;
+use crate::utils::{BridgeError, Result};
+use crate::zulip::event_handler::{DefaultZulipEventHandler, ZulipEventHandler};
+use crate::zulip::{ZulipClient, ZulipEvent};
+
+/// Zulip side of the bridge for one organization. Stream messages, their edits, deletions and
+/// reactions are relayed to their portal rooms and realm emoji changes update the organization's
+/// image pack; everything else goes to the default handler.
+pub struct BridgeZulipEventHandler {
+    organization_id: String,
+    client: Arc<ZulipClient>,
+    bridge: Arc<BridgeCore>,
+    fallback: DefaultZulipEventHandler,
+}
+
+impl BridgeZulipEventHandler {
+    pub fn new(
+        organization_id: &str,
+        client: Arc<ZulipClient>,
+        bridge: Arc<BridgeCore>,
+    ) -> Self {
+        Self {
+            organization_id: organization_id.to_string(),
+            client,
+            bridge,
+            fallback: DefaultZulipEventHandler,
+        }
+    }
+}
+
+#[async_trait]
+impl ZulipEventHandler for BridgeZulipEventHandler {
+    async fn handle_message(&self, event: &ZulipEvent) -> Result<()> {
+        let Some(msg) = &event.message else {
+            return self.fallback.handle_message(event).await;
+        };
+        match self
+            .bridge
+            .bridge_zulip_message(&self.organization_id, &self.client, msg)
+            .await
+        {
+            Err(BridgeError::NotImplemented(_)) => self.fallback.handle_message(event).await,
+            result => result,
+        }
+    }
+
+    async fn handle_reaction(&self, event: &ZulipEvent) -> Result<()> {
+        self.bridge
+            .bridge_zulip_reaction(&self.organization_id, &self.client, event)
+            .await
+    }
+
+    async fn handle_update_message(&self, event: &ZulipEvent) -> Result<()> {
+        self.bridge
+            .bridge_zulip_edit(&self.organization_id, &self.client, event)
+            .await
+    }
+
+    async fn handle_delete_message(&self, event: &ZulipEvent) -> Result<()> {
+        self.bridge
+            .bridge_zulip_deletion(&self.organization_id, event)
+            .await
+    }
+
+    async fn handle_subscription(&self, event: &ZulipEvent) -> Result<()> {
+        self.fallback.handle_subscription(event).await
+    }
+
+    async fn handle_realm_user(&self, event: &ZulipEvent) -> Result<()> {
+        self.fallback.handle_realm_user(event).await
+    }
+
+    async fn handle_realm_emoji(&self, event: &ZulipEvent) -> Result<()> {
+        let Some(realm_emoji) = event.realm_emoji() else {
+            return self.fallback.handle_realm_emoji(event).await;
+        };
+        self.bridge
+            .emoji
+            .update(&self.organization_id, &self.client, realm_emoji)
+            .await
+    }
+    async fn handle_user_group(&self, event: &ZulipEvent) -> Result<()> {
+        self.bridge.mentions.forget_user_groups(&self.organization_id);
+        self.fallback.handle_user_group(event).await
+    }
+}
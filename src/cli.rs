@@ -1,5 +1,9 @@
+use std::path::PathBuf;
+
 use clap::Parser;
 
+use crate::bridge::EventSourceMode;
+
 #[derive(Parser, Debug, Clone)]
 #[command(
     name = "matrix-bridge-zulip",
@@ -72,6 +76,30 @@ pub struct CliArgs {
     )]
     pub owner: Option<String>,
 
+    #[arg(
+        long = "record-events",
+        value_name = "DIR",
+        help = "Record raw Zulip events to <DIR>/<organization>.jsonl",
+        conflicts_with = "replay_events"
+    )]
+    pub record_events: Option<PathBuf>,
+
+    #[arg(
+        long = "replay-events",
+        value_name = "DIR",
+        help = "Replay Zulip events from <DIR>/<organization>.jsonl without contacting Zulip"
+    )]
+    pub replay_events: Option<PathBuf>,
+
+    #[arg(
+        long = "purge-media-cache",
+        value_name = "DAYS",
+        num_args = 0..=1,
+        default_missing_value = "0",
+        help = "Forget transferred media not used for DAYS days (all of it if omitted) and exit"
+    )]
+    pub purge_media_cache: Option<u32>,
+
     #[arg(
         long = "reset",
         help = "Reset ALL bridge configuration from homeserver and exit"
@@ -83,4 +111,12 @@ impl CliArgs {
     pub fn parse_args() -> Self {
         Self::parse()
     }
+
+    pub fn event_source_mode(&self) -> EventSourceMode {
+        match (&self.record_events, &self.replay_events) {
+            (_, Some(dir)) => EventSourceMode::Replay(dir.clone()),
+            (Some(dir), None) => EventSourceMode::Record(dir.clone()),
+            (None, None) => EventSourceMode::LongPoll,
+        }
+    }
 }
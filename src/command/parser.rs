@@ -0,0 +1,55 @@
+use crate::utils::{BridgeError, Result};
+
+pub const COMMAND_PREFIX: &str = "!zulip";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BridgeCommand {
+    Help,
+    Backfill { limit: i32 },
+}
+
+impl BridgeCommand {
+    /// Parses a room message body. Returns `Ok(None)` when the message is not addressed to the
+    /// bridge.
+    pub fn parse(body: &str) -> Result<Option<Self>> {
+        let Some(rest) = body.trim().strip_prefix(COMMAND_PREFIX) else {
+            return Ok(None);
+        };
+        if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+            return Ok(None);
+        }
+
+        let mut args = rest.split_whitespace();
+        let command = match args.next().map(str::to_lowercase).as_deref() {
+            None | Some("help") => BridgeCommand::Help,
+            Some("backfill") => {
+                let limit = args
+                    .next()
+                    .ok_or_else(|| BridgeError::Parse("usage: backfill <n>".to_string()))?
+                    .parse::<i32>()
+                    .ok()
+                    .filter(|limit| *limit > 0)
+                    .ok_or_else(|| {
+                        BridgeError::Parse("backfill amount must be a positive number".to_string())
+                    })?;
+                BridgeCommand::Backfill { limit }
+            }
+            Some(other) => {
+                return Err(BridgeError::Parse(format!("unknown command: {}", other)));
+            }
+        };
+
+        Ok(Some(command))
+    }
+
+    pub fn help() -> String {
+        [
+            format!("{} help - show this message", COMMAND_PREFIX),
+            format!(
+                "{} backfill <n> - bridge up to n older Zulip messages into this room",
+                COMMAND_PREFIX
+            ),
+        ]
+        .join("\n")
+    }
+}
@@ -39,13 +39,15 @@ pub struct DatabaseConfig {
     pub url: String,
     #[serde(default = "default_max_connections")]
     pub max_connections: u32,
+    #[serde(default)]
+    pub min_connections: Option<u32>,
 }
 
 fn default_max_connections() -> u32 {
     10
 }
 
-#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum DbType {
     Postgres,
@@ -107,12 +109,27 @@ pub struct LimitsConfig {
     pub matrix_event_age_limit_ms: u64,
     #[serde(default)]
     pub room_count: i32,
+    /// Largest file, in bytes, bridged in either direction.
+    #[serde(default = "default_max_media_size")]
+    pub max_media_size: u64,
+    /// Total size, in bytes, of the files remembered as already transferred. Least recently used
+    /// files are forgotten beyond it; 0 disables the cache.
+    #[serde(default = "default_media_cache_size")]
+    pub media_cache_size: u64,
 }
 
 fn default_matrix_event_age_limit() -> u64 {
     300000
 }
 
+fn default_max_media_size() -> u64 {
+    25 * 1024 * 1024
+}
+
+fn default_media_cache_size() -> u64 {
+    1024 * 1024 * 1024
+}
+
 #[derive(Debug, Clone, Deserialize, Default)]
 pub struct LoggingConfig {
     #[serde(default = "default_log_level")]
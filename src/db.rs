@@ -1,11 +1,15 @@
 pub mod error;
 pub mod manager;
+#[cfg(feature = "metrics")]
+pub mod metered;
 pub mod models;
+#[cfg(feature = "postgres")]
+pub mod postgres;
 pub mod schema;
 pub mod stores;
 
 pub use error::{DatabaseError, Result};
-pub use manager::DatabaseManager;
+pub use manager::{DatabaseManager, PoolStatus};
 pub use stores::{
     EventStore, MessageStore, OrganizationStore, ReactionStore, RoomStore, UserStore,
 };
@@ -1,5 +1,6 @@
 use std::sync::Arc;
 
+use diesel::connection::SimpleConnection;
 #[cfg(feature = "postgres")]
 use diesel::pg::PgConnection;
 #[cfg(feature = "postgres")]
@@ -8,15 +9,30 @@ use diesel::r2d2::{self, ConnectionManager};
 use crate::config::DatabaseConfig;
 use crate::db::error::{DatabaseError, Result};
 use crate::db::stores::{
-    EventStore, MessageStore, OrganizationStore, ReactionStore, RoomStore, UserStore,
+    EmojiStore, EventStore, MediaCacheStore, MessageStore, OrganizationStore, ReactionStore,
+    RoomStore, UserStore,
 };
 
 #[cfg(feature = "postgres")]
 use crate::db::postgres::{
-    PostgresEventStore, PostgresMessageStore, PostgresOrganizationStore, PostgresReactionStore,
-    PostgresRoomStore, PostgresUserStore,
+    PostgresEmojiStore, PostgresEventStore, PostgresMediaCacheStore, PostgresMessageStore,
+    PostgresOrganizationStore, PostgresReactionStore, PostgresRoomStore, PostgresUserStore,
 };
 
+#[cfg(feature = "postgres")]
+const POSTGRES_MIGRATIONS: &[&str] = &[
+    include_str!("../../migrations/postgres/001_init.sql"),
+    include_str!("../../migrations/postgres/002_reaction_mapping_per_message.sql"),
+    include_str!("../../migrations/postgres/003_organization_event_queue.sql"),
+    include_str!("../../migrations/postgres/004_organization_server_version.sql"),
+    include_str!("../../migrations/postgres/005_organization_webhook_token.sql"),
+    include_str!("../../migrations/postgres/006_realm_emoji.sql"),
+    include_str!("../../migrations/postgres/007_message_mapping_parts.sql"),
+    include_str!("../../migrations/postgres/008_message_mapping_part_index.sql"),
+    include_str!("../../migrations/postgres/009_media_cache.sql"),
+    include_str!("../../migrations/postgres/010_reaction_mapping_identity.sql"),
+];
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum DbType {
     Postgres,
@@ -34,6 +50,15 @@ impl From<crate::config::DbType> for DbType {
     }
 }
 
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct PoolStatus {
+    pub healthy: bool,
+    pub connections: u32,
+    pub idle_connections: u32,
+    pub max_size: u32,
+    pub error: Option<String>,
+}
+
 #[derive(Clone)]
 pub struct DatabaseManager {
     #[cfg(feature = "postgres")]
@@ -44,21 +69,29 @@ pub struct DatabaseManager {
     message_store: Arc<dyn MessageStore>,
     event_store: Arc<dyn EventStore>,
     reaction_store: Arc<dyn ReactionStore>,
+    emoji_store: Arc<dyn EmojiStore>,
+    media_cache_store: Arc<dyn MediaCacheStore>,
     db_type: DbType,
 }
 
 impl DatabaseManager {
     pub async fn new(config: &DatabaseConfig) -> Result<Self> {
+        let manager = Self::connect(config).await?;
+        #[cfg(feature = "metrics")]
+        let manager = manager.metered();
+        Ok(manager)
+    }
+
+    async fn connect(config: &DatabaseConfig) -> Result<Self> {
         let db_type = DbType::from(config.db_type);
 
         match db_type {
             #[cfg(feature = "postgres")]
             DbType::Postgres => {
-                let connection_string = config.connection_string();
-                let max_connections = config.max_connections().unwrap_or(10);
-                let min_connections = config.min_connections().unwrap_or(1);
+                let max_connections = config.max_connections;
+                let min_connections = config.min_connections.unwrap_or(1);
 
-                let manager = ConnectionManager::<PgConnection>::new(connection_string);
+                let manager = ConnectionManager::<PgConnection>::new(&config.url);
 
                 let pool = r2d2::Pool::builder()
                     .max_size(max_connections)
@@ -72,6 +105,8 @@ impl DatabaseManager {
                 let message_store = Arc::new(PostgresMessageStore::new(pool.clone()));
                 let event_store = Arc::new(PostgresEventStore::new(pool.clone()));
                 let reaction_store = Arc::new(PostgresReactionStore::new(pool.clone()));
+                let emoji_store = Arc::new(PostgresEmojiStore::new(pool.clone()));
+                let media_cache_store = Arc::new(PostgresMediaCacheStore::new(pool.clone()));
 
                 Ok(Self {
                     postgres_pool: Some(pool),
@@ -81,6 +116,8 @@ impl DatabaseManager {
                     message_store,
                     event_store,
                     reaction_store,
+                    emoji_store,
+                    media_cache_store,
                     db_type,
                 })
             }
@@ -107,6 +144,26 @@ impl DatabaseManager {
         }
     }
 
+    #[cfg(feature = "metrics")]
+    fn metered(self) -> Self {
+        use crate::db::metered::{
+            MeteredEmojiStore, MeteredEventStore, MeteredMediaCacheStore, MeteredMessageStore,
+            MeteredOrganizationStore, MeteredReactionStore, MeteredRoomStore, MeteredUserStore,
+        };
+
+        Self {
+            organization_store: Arc::new(MeteredOrganizationStore(self.organization_store)),
+            room_store: Arc::new(MeteredRoomStore(self.room_store)),
+            user_store: Arc::new(MeteredUserStore(self.user_store)),
+            message_store: Arc::new(MeteredMessageStore(self.message_store)),
+            event_store: Arc::new(MeteredEventStore(self.event_store)),
+            reaction_store: Arc::new(MeteredReactionStore(self.reaction_store)),
+            emoji_store: Arc::new(MeteredEmojiStore(self.emoji_store)),
+            media_cache_store: Arc::new(MeteredMediaCacheStore(self.media_cache_store)),
+            ..self
+        }
+    }
+
     pub fn organization_store(&self) -> Arc<dyn OrganizationStore> {
         self.organization_store.clone()
     }
@@ -131,10 +188,63 @@ impl DatabaseManager {
         self.reaction_store.clone()
     }
 
+    pub fn emoji_store(&self) -> Arc<dyn EmojiStore> {
+        self.emoji_store.clone()
+    }
+
+    pub fn media_cache_store(&self) -> Arc<dyn MediaCacheStore> {
+        self.media_cache_store.clone()
+    }
+
     pub fn db_type(&self) -> DbType {
         self.db_type
     }
 
+    pub async fn pool_status(&self) -> PoolStatus {
+        match self.db_type {
+            #[cfg(feature = "postgres")]
+            DbType::Postgres => {
+                let Some(pool) = &self.postgres_pool else {
+                    return PoolStatus {
+                        error: Some("PostgreSQL pool not initialized".to_string()),
+                        ..Default::default()
+                    };
+                };
+
+                let state = pool.state();
+                let mut status = PoolStatus {
+                    healthy: false,
+                    connections: state.connections,
+                    idle_connections: state.idle_connections,
+                    max_size: pool.max_size(),
+                    error: None,
+                };
+
+                let pool = pool.clone();
+                let result = tokio::task::spawn_blocking(move || {
+                    let mut conn = pool
+                        .get()
+                        .map_err(|e| DatabaseError::Pool(e.to_string()))?;
+                    conn.batch_execute("SELECT 1")
+                        .map_err(|e| DatabaseError::Query(e.to_string()))
+                })
+                .await
+                .map_err(|e| DatabaseError::Pool(e.to_string()))
+                .and_then(|r| r);
+
+                match result {
+                    Ok(()) => status.healthy = true,
+                    Err(e) => status.error = Some(e.to_string()),
+                }
+                status
+            }
+            _ => PoolStatus {
+                error: Some(format!("{:?} support not yet implemented", self.db_type)),
+                ..Default::default()
+            },
+        }
+    }
+
     pub async fn migrate(&self) -> Result<()> {
         match self.db_type {
             #[cfg(feature = "postgres")]
@@ -143,11 +253,12 @@ impl DatabaseManager {
                     let mut conn = pool
                         .get()
                         .map_err(|e| DatabaseError::Connection(e.to_string()))?;
-                    
+
                     tokio::task::spawn_blocking(move || {
-                        diesel::sql_query(include_str!("../../migrations/postgres/001_init.sql"))
-                            .execute(&mut conn)
-                            .map_err(|e| DatabaseError::Migration(e.to_string()))
+                        POSTGRES_MIGRATIONS.iter().try_for_each(|migration| {
+                            conn.batch_execute(migration)
+                                .map_err(|e| DatabaseError::Migration(e.to_string()))
+                        })
                     })
                     .await
                     .map_err(|e| DatabaseError::Migration(e.to_string()))??;
@@ -162,3 +273,66 @@ impl DatabaseManager {
         }
     }
 }
+
+#[cfg(all(test, feature = "postgres"))]
+mod tests {
+    use diesel::prelude::*;
+
+    use super::*;
+    use crate::db::schema::message_mappings;
+    use crate::testing::DATABASE_URL_VAR;
+
+    /// Applies `migrations` in order on the connection's schema.
+    fn run(conn: &mut PgConnection, migrations: &[&str]) {
+        for migration in migrations {
+            conn.batch_execute(migration).unwrap();
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    fn numbers_message_mappings_from_before_parts() {
+        let url = std::env::var(DATABASE_URL_VAR).unwrap();
+        let mut conn = PgConnection::establish(&url).unwrap();
+        conn.begin_test_transaction().unwrap();
+        conn.batch_execute("CREATE SCHEMA migration_test; SET LOCAL search_path TO migration_test")
+            .unwrap();
+
+        run(&mut conn, &POSTGRES_MIGRATIONS[..6]);
+        conn.batch_execute(
+            "INSERT INTO message_mappings
+                 (matrix_event_id, matrix_room_id, zulip_message_id, zulip_sender_id, message_type)
+             VALUES ('$a', '!room', 1, 10, 'text'), ('$b', '!room', 2, 10, 'image')",
+        )
+        .unwrap();
+        run(&mut conn, &POSTGRES_MIGRATIONS[6..7]);
+        // A second part bridged between 007 and 008, before parts were numbered.
+        conn.batch_execute(
+            "INSERT INTO message_mappings
+                 (matrix_event_id, matrix_room_id, zulip_message_id, zulip_sender_id, message_type)
+             VALUES ('$c', '!room', 2, 10, 'file')",
+        )
+        .unwrap();
+        // Migrations run on every start, so 008 must leave numbered rows alone the second time.
+        run(&mut conn, &POSTGRES_MIGRATIONS[7..8]);
+        run(&mut conn, POSTGRES_MIGRATIONS);
+
+        let parts: Vec<(String, i32, bool)> = message_mappings::table
+            .select((
+                message_mappings::matrix_event_id,
+                message_mappings::part_index,
+                message_mappings::is_primary,
+            ))
+            .order(message_mappings::id)
+            .load(&mut conn)
+            .unwrap();
+        assert_eq!(
+            parts,
+            [
+                ("$a".to_string(), 0, true),
+                ("$b".to_string(), 0, true),
+                ("$c".to_string(), 1, false),
+            ]
+        );
+    }
+}
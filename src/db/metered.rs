@@ -0,0 +1,494 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Instant;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use crate::db::error::Result;
+use crate::db::models::{
+    EmojiMapping, MediaCacheEntry, MediaDirection, MessageMapping, NewEmojiMapping,
+    NewMediaCacheEntry, NewMessageMapping, NewProcessedEvent, NewReactionMapping, NewRoomMapping,
+    NewUserMapping, Organization, OrganizationChangeset, ProcessedEvent, ReactionMapping,
+    RoomMapping, RoomType, UserMapping, UserMappingChangeset,
+};
+use crate::db::stores::{
+    EmojiStore, EventStore, MediaCacheStore, MessageStore, OrganizationStore, ReactionStore,
+    RoomStore, UserStore,
+};
+use crate::utils::metrics::{self, Outcome};
+
+async fn observe<T>(
+    store: &'static str,
+    operation: &'static str,
+    query: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let started = Instant::now();
+    let result = query.await;
+    metrics::record_db_query(store, operation, Outcome::from_result(&result), started.elapsed());
+    result
+}
+
+pub struct MeteredOrganizationStore(pub Arc<dyn OrganizationStore>);
+
+#[async_trait]
+impl OrganizationStore for MeteredOrganizationStore {
+    async fn create(&self, org: Organization) -> Result<Organization> {
+        observe("organization", "create", self.0.create(org)).await
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<Organization>> {
+        observe("organization", "get", self.0.get(id)).await
+    }
+
+    async fn get_all(&self) -> Result<Vec<Organization>> {
+        observe("organization", "get_all", self.0.get_all()).await
+    }
+
+    async fn update(&self, id: &str, changeset: OrganizationChangeset) -> Result<Organization> {
+        observe("organization", "update", self.0.update(id, changeset)).await
+    }
+
+    async fn delete(&self, id: &str) -> Result<()> {
+        observe("organization", "delete", self.0.delete(id)).await
+    }
+
+    async fn set_connected(&self, id: &str, connected: bool) -> Result<()> {
+        observe("organization", "set_connected", self.0.set_connected(id, connected)).await
+    }
+
+    async fn set_event_queue(
+        &self,
+        id: &str,
+        queue_id: Option<&str>,
+        last_event_id: Option<i64>,
+    ) -> Result<()> {
+        observe(
+            "organization",
+            "set_event_queue",
+            self.0.set_event_queue(id, queue_id, last_event_id),
+        )
+        .await
+    }
+
+    async fn set_server_version(&self, id: &str, version: &str, feature_level: i32) -> Result<()> {
+        observe(
+            "organization",
+            "set_server_version",
+            self.0.set_server_version(id, version, feature_level),
+        )
+        .await
+    }
+
+    async fn set_space_room(&self, id: &str, space_room_id: &str) -> Result<()> {
+        observe(
+            "organization",
+            "set_space_room",
+            self.0.set_space_room(id, space_room_id),
+        )
+        .await
+    }
+
+    async fn exists(&self, id: &str) -> Result<bool> {
+        observe("organization", "exists", self.0.exists(id)).await
+    }
+}
+
+pub struct MeteredRoomStore(pub Arc<dyn RoomStore>);
+
+#[async_trait]
+impl RoomStore for MeteredRoomStore {
+    async fn create(&self, room: NewRoomMapping) -> Result<RoomMapping> {
+        observe("room", "create", self.0.create(room)).await
+    }
+
+    async fn get(&self, id: i64) -> Result<Option<RoomMapping>> {
+        observe("room", "get", self.0.get(id)).await
+    }
+
+    async fn get_by_matrix_room(&self, matrix_room_id: &str) -> Result<Option<RoomMapping>> {
+        observe("room", "get_by_matrix_room", self.0.get_by_matrix_room(matrix_room_id)).await
+    }
+
+    async fn get_by_zulip_stream(
+        &self,
+        organization_id: &str,
+        zulip_stream_id: i64,
+    ) -> Result<Option<RoomMapping>> {
+        observe(
+            "room",
+            "get_by_zulip_stream",
+            self.0.get_by_zulip_stream(organization_id, zulip_stream_id),
+        )
+        .await
+    }
+
+    async fn get_by_zulip_topic(
+        &self,
+        organization_id: &str,
+        zulip_stream_id: i64,
+        topic: Option<&str>,
+    ) -> Result<Option<RoomMapping>> {
+        observe(
+            "room",
+            "get_by_zulip_topic",
+            self.0.get_by_zulip_topic(organization_id, zulip_stream_id, topic),
+        )
+        .await
+    }
+
+    async fn get_by_organization(&self, organization_id: &str) -> Result<Vec<RoomMapping>> {
+        observe("room", "get_by_organization", self.0.get_by_organization(organization_id)).await
+    }
+
+    async fn get_by_type(&self, organization_id: &str, room_type: RoomType) -> Result<Vec<RoomMapping>> {
+        observe("room", "get_by_type", self.0.get_by_type(organization_id, room_type)).await
+    }
+
+    async fn delete(&self, id: i64) -> Result<()> {
+        observe("room", "delete", self.0.delete(id)).await
+    }
+
+    async fn delete_by_matrix_room(&self, matrix_room_id: &str) -> Result<()> {
+        observe("room", "delete_by_matrix_room", self.0.delete_by_matrix_room(matrix_room_id)).await
+    }
+
+    async fn exists(&self, matrix_room_id: &str) -> Result<bool> {
+        observe("room", "exists", self.0.exists(matrix_room_id)).await
+    }
+}
+
+pub struct MeteredUserStore(pub Arc<dyn UserStore>);
+
+#[async_trait]
+impl UserStore for MeteredUserStore {
+    async fn create(&self, user: NewUserMapping) -> Result<UserMapping> {
+        observe("user", "create", self.0.create(user)).await
+    }
+
+    async fn get(&self, id: i64) -> Result<Option<UserMapping>> {
+        observe("user", "get", self.0.get(id)).await
+    }
+
+    async fn get_by_matrix_user(&self, matrix_user_id: &str) -> Result<Option<UserMapping>> {
+        observe("user", "get_by_matrix_user", self.0.get_by_matrix_user(matrix_user_id)).await
+    }
+
+    async fn get_by_zulip_user(&self, zulip_user_id: i64) -> Result<Option<UserMapping>> {
+        observe("user", "get_by_zulip_user", self.0.get_by_zulip_user(zulip_user_id)).await
+    }
+
+    async fn update(&self, id: i64, changeset: UserMappingChangeset) -> Result<UserMapping> {
+        observe("user", "update", self.0.update(id, changeset)).await
+    }
+
+    async fn update_by_matrix_user(
+        &self,
+        matrix_user_id: &str,
+        changeset: UserMappingChangeset,
+    ) -> Result<UserMapping> {
+        observe(
+            "user",
+            "update_by_matrix_user",
+            self.0.update_by_matrix_user(matrix_user_id, changeset),
+        )
+        .await
+    }
+
+    async fn delete(&self, id: i64) -> Result<()> {
+        observe("user", "delete", self.0.delete(id)).await
+    }
+
+    async fn delete_by_matrix_user(&self, matrix_user_id: &str) -> Result<()> {
+        observe("user", "delete_by_matrix_user", self.0.delete_by_matrix_user(matrix_user_id)).await
+    }
+
+    async fn exists(&self, matrix_user_id: &str) -> Result<bool> {
+        observe("user", "exists", self.0.exists(matrix_user_id)).await
+    }
+}
+
+pub struct MeteredMessageStore(pub Arc<dyn MessageStore>);
+
+#[async_trait]
+impl MessageStore for MeteredMessageStore {
+    async fn create(&self, message: NewMessageMapping) -> Result<MessageMapping> {
+        observe("message", "create", self.0.create(message)).await
+    }
+
+    async fn get(&self, id: i64) -> Result<Option<MessageMapping>> {
+        observe("message", "get", self.0.get(id)).await
+    }
+
+    async fn get_by_matrix_event(&self, matrix_event_id: &str) -> Result<Option<MessageMapping>> {
+        observe("message", "get_by_matrix_event", self.0.get_by_matrix_event(matrix_event_id)).await
+    }
+
+    async fn get_by_zulip_message(
+        &self,
+        matrix_room_id: &str,
+        zulip_message_id: i64,
+    ) -> Result<Option<MessageMapping>> {
+        observe(
+            "message",
+            "get_by_zulip_message",
+            self.0.get_by_zulip_message(matrix_room_id, zulip_message_id),
+        )
+        .await
+    }
+
+    async fn get_parts_by_zulip_message(
+        &self,
+        matrix_room_id: &str,
+        zulip_message_id: i64,
+    ) -> Result<Vec<MessageMapping>> {
+        observe(
+            "message",
+            "get_parts_by_zulip_message",
+            self.0.get_parts_by_zulip_message(matrix_room_id, zulip_message_id),
+        )
+        .await
+    }
+
+    async fn get_primary_by_matrix_event(&self, matrix_event_id: &str) -> Result<Option<MessageMapping>> {
+        observe(
+            "message",
+            "get_primary_by_matrix_event",
+            self.0.get_primary_by_matrix_event(matrix_event_id),
+        )
+        .await
+    }
+
+    async fn get_by_matrix_room(&self, matrix_room_id: &str, limit: i64) -> Result<Vec<MessageMapping>> {
+        observe("message", "get_by_matrix_room", self.0.get_by_matrix_room(matrix_room_id, limit)).await
+    }
+
+    async fn get_oldest_by_matrix_room(&self, matrix_room_id: &str) -> Result<Option<MessageMapping>> {
+        observe(
+            "message",
+            "get_oldest_by_matrix_room",
+            self.0.get_oldest_by_matrix_room(matrix_room_id),
+        )
+        .await
+    }
+
+    async fn get_newest_by_matrix_room(&self, matrix_room_id: &str) -> Result<Option<MessageMapping>> {
+        observe(
+            "message",
+            "get_newest_by_matrix_room",
+            self.0.get_newest_by_matrix_room(matrix_room_id),
+        )
+        .await
+    }
+
+    async fn delete(&self, id: i64) -> Result<()> {
+        observe("message", "delete", self.0.delete(id)).await
+    }
+
+    async fn delete_by_matrix_event(&self, matrix_event_id: &str) -> Result<()> {
+        observe("message", "delete_by_matrix_event", self.0.delete_by_matrix_event(matrix_event_id)).await
+    }
+
+    async fn delete_by_zulip_message(&self, matrix_room_id: &str, zulip_message_id: i64) -> Result<()> {
+        observe(
+            "message",
+            "delete_by_zulip_message",
+            self.0.delete_by_zulip_message(matrix_room_id, zulip_message_id),
+        )
+        .await
+    }
+
+    async fn exists_by_matrix_event(&self, matrix_event_id: &str) -> Result<bool> {
+        observe("message", "exists_by_matrix_event", self.0.exists_by_matrix_event(matrix_event_id)).await
+    }
+
+    async fn exists_by_zulip_message(&self, matrix_room_id: &str, zulip_message_id: i64) -> Result<bool> {
+        observe(
+            "message",
+            "exists_by_zulip_message",
+            self.0.exists_by_zulip_message(matrix_room_id, zulip_message_id),
+        )
+        .await
+    }
+}
+
+pub struct MeteredEventStore(pub Arc<dyn EventStore>);
+
+#[async_trait]
+impl EventStore for MeteredEventStore {
+    async fn create(&self, event: NewProcessedEvent) -> Result<ProcessedEvent> {
+        observe("event", "create", self.0.create(event)).await
+    }
+
+    async fn get(&self, id: i64) -> Result<Option<ProcessedEvent>> {
+        observe("event", "get", self.0.get(id)).await
+    }
+
+    async fn get_by_event_id(&self, event_id: &str) -> Result<Option<ProcessedEvent>> {
+        observe("event", "get_by_event_id", self.0.get_by_event_id(event_id)).await
+    }
+
+    async fn exists(&self, event_id: &str) -> Result<bool> {
+        observe("event", "exists", self.0.exists(event_id)).await
+    }
+
+    async fn delete_old_events(&self, before: DateTime<Utc>) -> Result<usize> {
+        observe("event", "delete_old_events", self.0.delete_old_events(before)).await
+    }
+}
+
+pub struct MeteredReactionStore(pub Arc<dyn ReactionStore>);
+
+#[async_trait]
+impl ReactionStore for MeteredReactionStore {
+    async fn create(&self, reaction: NewReactionMapping) -> Result<ReactionMapping> {
+        observe("reaction", "create", self.0.create(reaction)).await
+    }
+
+    async fn get(&self, id: i64) -> Result<Option<ReactionMapping>> {
+        observe("reaction", "get", self.0.get(id)).await
+    }
+
+    async fn get_by_matrix_reaction(&self, matrix_reaction_event_id: &str) -> Result<Option<ReactionMapping>> {
+        observe(
+            "reaction",
+            "get_by_matrix_reaction",
+            self.0.get_by_matrix_reaction(matrix_reaction_event_id),
+        )
+        .await
+    }
+
+    async fn get_by_zulip_reaction(
+        &self,
+        zulip_message_id: i64,
+        zulip_user_id: i64,
+        emoji_code: &str,
+    ) -> Result<Option<ReactionMapping>> {
+        observe(
+            "reaction",
+            "get_by_zulip_reaction",
+            self.0.get_by_zulip_reaction(zulip_message_id, zulip_user_id, emoji_code),
+        )
+        .await
+    }
+
+    async fn get_by_zulip_message(&self, zulip_message_id: i64) -> Result<Vec<ReactionMapping>> {
+        observe("reaction", "get_by_zulip_message", self.0.get_by_zulip_message(zulip_message_id)).await
+    }
+
+    async fn delete(&self, id: i64) -> Result<()> {
+        observe("reaction", "delete", self.0.delete(id)).await
+    }
+
+    async fn delete_by_matrix_reaction(&self, matrix_reaction_event_id: &str) -> Result<()> {
+        observe(
+            "reaction",
+            "delete_by_matrix_reaction",
+            self.0.delete_by_matrix_reaction(matrix_reaction_event_id),
+        )
+        .await
+    }
+
+    async fn delete_by_zulip_reaction(
+        &self,
+        zulip_message_id: i64,
+        zulip_user_id: i64,
+        emoji_code: &str,
+    ) -> Result<()> {
+        observe(
+            "reaction",
+            "delete_by_zulip_reaction",
+            self.0.delete_by_zulip_reaction(zulip_message_id, zulip_user_id, emoji_code),
+        )
+        .await
+    }
+
+    async fn exists_by_matrix_reaction(&self, matrix_reaction_event_id: &str) -> Result<bool> {
+        observe(
+            "reaction",
+            "exists_by_matrix_reaction",
+            self.0.exists_by_matrix_reaction(matrix_reaction_event_id),
+        )
+        .await
+    }
+
+    async fn exists_by_zulip_reaction(
+        &self,
+        zulip_message_id: i64,
+        zulip_user_id: i64,
+        emoji_code: &str,
+    ) -> Result<bool> {
+        observe(
+            "reaction",
+            "exists_by_zulip_reaction",
+            self.0.exists_by_zulip_reaction(zulip_message_id, zulip_user_id, emoji_code),
+        )
+        .await
+    }
+}
+
+pub struct MeteredEmojiStore(pub Arc<dyn EmojiStore>);
+
+#[async_trait]
+impl EmojiStore for MeteredEmojiStore {
+    async fn upsert(&self, emoji: NewEmojiMapping) -> Result<EmojiMapping> {
+        observe("emoji", "upsert", self.0.upsert(emoji)).await
+    }
+
+    async fn get_by_organization(&self, organization_id: &str) -> Result<Vec<EmojiMapping>> {
+        observe("emoji", "get_by_organization", self.0.get_by_organization(organization_id)).await
+    }
+
+    async fn delete(&self, organization_id: &str, zulip_emoji_id: &str) -> Result<()> {
+        observe("emoji", "delete", self.0.delete(organization_id, zulip_emoji_id)).await
+    }
+}
+
+pub struct MeteredMediaCacheStore(pub Arc<dyn MediaCacheStore>);
+
+#[async_trait]
+impl MediaCacheStore for MeteredMediaCacheStore {
+    async fn get_by_source(
+        &self,
+        organization_id: &str,
+        direction: MediaDirection,
+        source_url: &str,
+    ) -> Result<Option<MediaCacheEntry>> {
+        observe(
+            "media_cache",
+            "get_by_source",
+            self.0.get_by_source(organization_id, direction, source_url),
+        )
+        .await
+    }
+
+    async fn get_by_hash(
+        &self,
+        organization_id: &str,
+        direction: MediaDirection,
+        content_hash: &str,
+    ) -> Result<Option<MediaCacheEntry>> {
+        observe(
+            "media_cache",
+            "get_by_hash",
+            self.0.get_by_hash(organization_id, direction, content_hash),
+        )
+        .await
+    }
+
+    async fn upsert(&self, entry: NewMediaCacheEntry) -> Result<MediaCacheEntry> {
+        observe("media_cache", "upsert", self.0.upsert(entry)).await
+    }
+
+    async fn touch(&self, id: i64) -> Result<()> {
+        observe("media_cache", "touch", self.0.touch(id)).await
+    }
+
+    async fn evict(&self, max_size: i64) -> Result<usize> {
+        observe("media_cache", "evict", self.0.evict(max_size)).await
+    }
+
+    async fn delete_unused_before(&self, before: DateTime<Utc>) -> Result<usize> {
+        observe("media_cache", "delete_unused_before", self.0.delete_unused_before(before)).await
+    }
+}
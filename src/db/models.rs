@@ -14,6 +14,14 @@ pub struct Organization {
     pub max_backfill_amount: i32,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    pub event_queue_id: Option<String>,
+    pub last_event_id: Option<i64>,
+    pub zulip_version: Option<String>,
+    pub zulip_feature_level: Option<i32>,
+    /// Set when the organization delivers messages through an outgoing-webhook bot.
+    pub webhook_token: Option<String>,
+    /// Space holding the organization's custom emoji pack.
+    pub space_room_id: Option<String>,
 }
 
 #[derive(Debug, Clone, AsChangeset, Serialize, Deserialize)]
@@ -98,6 +106,10 @@ pub struct MessageMapping {
     pub zulip_sender_id: i64,
     pub message_type: String,
     pub created_at: DateTime<Utc>,
+    /// Position among the Matrix events a Zulip message was sent as.
+    pub part_index: i32,
+    /// Whether this is the part edits and replies target: the text, if the message has any.
+    pub is_primary: bool,
 }
 
 #[derive(Debug, Clone, Insertable, Serialize, Deserialize)]
@@ -108,6 +120,8 @@ pub struct NewMessageMapping {
     pub zulip_message_id: i64,
     pub zulip_sender_id: i64,
     pub message_type: String,
+    pub part_index: i32,
+    pub is_primary: bool,
 }
 
 #[derive(Debug, Clone, Queryable, Insertable, Serialize, Deserialize)]
@@ -134,10 +148,13 @@ pub struct ReactionMapping {
     pub id: i64,
     pub matrix_event_id: String,
     pub zulip_message_id: i64,
-    pub zulip_reaction_id: i64,
+    /// The Zulip user who reacted.
+    pub zulip_user_id: i64,
     pub emoji: String,
     pub matrix_reaction_event_id: String,
     pub created_at: DateTime<Utc>,
+    /// Empty for reactions bridged before codes were recorded.
+    pub emoji_code: String,
 }
 
 #[derive(Debug, Clone, Insertable, Serialize, Deserialize)]
@@ -145,9 +162,93 @@ pub struct ReactionMapping {
 pub struct NewReactionMapping {
     pub matrix_event_id: String,
     pub zulip_message_id: i64,
-    pub zulip_reaction_id: i64,
+    pub zulip_user_id: i64,
     pub emoji: String,
     pub matrix_reaction_event_id: String,
+    pub emoji_code: String,
+}
+
+/// A Zulip realm emoji and the Matrix media it was uploaded as.
+#[derive(Debug, Clone, Queryable, Insertable, Serialize, Deserialize)]
+#[diesel(table_name = crate::db::schema::emoji_mappings)]
+pub struct EmojiMapping {
+    pub id: i64,
+    pub organization_id: String,
+    pub zulip_emoji_id: String,
+    pub name: String,
+    pub source_url: String,
+    pub mxc_url: String,
+    pub deactivated: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Insertable, Serialize, Deserialize)]
+#[diesel(table_name = crate::db::schema::emoji_mappings)]
+pub struct NewEmojiMapping {
+    pub organization_id: String,
+    pub zulip_emoji_id: String,
+    pub name: String,
+    pub source_url: String,
+    pub mxc_url: String,
+    pub deactivated: bool,
+}
+
+/// A file transferred once and reused whenever the same source or content comes up again.
+#[derive(Debug, Clone, Queryable, Insertable, Serialize, Deserialize)]
+#[diesel(table_name = crate::db::schema::media_cache)]
+pub struct MediaCacheEntry {
+    pub id: i64,
+    pub organization_id: String,
+    pub direction: String,
+    /// Where the file came from: a Zulip upload or emoji URL, or an `mxc://` URI.
+    pub source_url: String,
+    /// Hex SHA-256 of the content.
+    pub content_hash: String,
+    /// Where it was uploaded to on the other side.
+    pub target_url: String,
+    pub content_type: String,
+    pub size: i64,
+    /// JSON `MediaMetadata` worked out when the file was transferred.
+    pub metadata: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Insertable, Serialize, Deserialize)]
+#[diesel(table_name = crate::db::schema::media_cache)]
+pub struct NewMediaCacheEntry {
+    pub organization_id: String,
+    pub direction: String,
+    pub source_url: String,
+    pub content_hash: String,
+    pub target_url: String,
+    pub content_type: String,
+    pub size: i64,
+    pub metadata: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MediaDirection {
+    ZulipToMatrix,
+    MatrixToZulip,
+}
+
+impl MediaDirection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MediaDirection::ZulipToMatrix => "zulip_to_matrix",
+            MediaDirection::MatrixToZulip => "matrix_to_zulip",
+        }
+    }
+
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "zulip_to_matrix" => Some(MediaDirection::ZulipToMatrix),
+            "matrix_to_zulip" => Some(MediaDirection::MatrixToZulip),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -204,6 +305,28 @@ impl MessageType {
         }
     }
 
+    /// The kind of media message a file of `content_type` is sent as.
+    pub fn for_content_type(content_type: &str) -> Self {
+        match content_type.split('/').next() {
+            Some("image") => MessageType::Image,
+            Some("video") => MessageType::Video,
+            Some("audio") => MessageType::Audio,
+            _ => MessageType::File,
+        }
+    }
+
+    /// The `msgtype` of the Matrix event.
+    pub fn msgtype(&self) -> &'static str {
+        match self {
+            MessageType::Text => "m.text",
+            MessageType::Image => "m.image",
+            MessageType::Video => "m.video",
+            MessageType::Audio => "m.audio",
+            MessageType::File => "m.file",
+            MessageType::Emote => "m.emote",
+        }
+    }
+
     pub fn from_str(s: &str) -> Option<Self> {
         match s {
             "text" => Some(MessageType::Text),
@@ -0,0 +1,77 @@
+use async_trait::async_trait;
+use diesel::prelude::*;
+use diesel::r2d2::{self, ConnectionManager};
+
+use crate::db::error::{DatabaseError, Result};
+use crate::db::models::{EmojiMapping, NewEmojiMapping};
+use crate::db::schema::emoji_mappings;
+use crate::db::stores::EmojiStore;
+
+type Pool = r2d2::Pool<ConnectionManager<PgConnection>>;
+
+#[derive(Clone)]
+pub struct PostgresEmojiStore {
+    pool: Pool,
+}
+
+impl PostgresEmojiStore {
+    pub fn new(pool: Pool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl EmojiStore for PostgresEmojiStore {
+    async fn upsert(&self, emoji: NewEmojiMapping) -> Result<EmojiMapping> {
+        let mut conn = self.pool.get().map_err(|e| DatabaseError::Connection(e.to_string()))?;
+        tokio::task::spawn_blocking(move || {
+            diesel::insert_into(emoji_mappings::table)
+                .values(&emoji)
+                .on_conflict((emoji_mappings::organization_id, emoji_mappings::zulip_emoji_id))
+                .do_update()
+                .set((
+                    emoji_mappings::name.eq(&emoji.name),
+                    emoji_mappings::source_url.eq(&emoji.source_url),
+                    emoji_mappings::mxc_url.eq(&emoji.mxc_url),
+                    emoji_mappings::deactivated.eq(emoji.deactivated),
+                    emoji_mappings::updated_at.eq(diesel::dsl::now),
+                ))
+                .get_result(&mut conn)
+                .map_err(|e| DatabaseError::Query(e.to_string()))
+        })
+        .await
+        .map_err(|e| DatabaseError::Query(e.to_string()))?
+    }
+
+    async fn get_by_organization(&self, organization_id: &str) -> Result<Vec<EmojiMapping>> {
+        let mut conn = self.pool.get().map_err(|e| DatabaseError::Connection(e.to_string()))?;
+        let organization_id = organization_id.to_string();
+        tokio::task::spawn_blocking(move || {
+            emoji_mappings::table
+                .filter(emoji_mappings::organization_id.eq(organization_id))
+                .order(emoji_mappings::name.asc())
+                .load::<EmojiMapping>(&mut conn)
+                .map_err(|e| DatabaseError::Query(e.to_string()))
+        })
+        .await
+        .map_err(|e| DatabaseError::Query(e.to_string()))?
+    }
+
+    async fn delete(&self, organization_id: &str, zulip_emoji_id: &str) -> Result<()> {
+        let mut conn = self.pool.get().map_err(|e| DatabaseError::Connection(e.to_string()))?;
+        let organization_id = organization_id.to_string();
+        let zulip_emoji_id = zulip_emoji_id.to_string();
+        tokio::task::spawn_blocking(move || {
+            diesel::delete(
+                emoji_mappings::table
+                    .filter(emoji_mappings::organization_id.eq(organization_id))
+                    .filter(emoji_mappings::zulip_emoji_id.eq(zulip_emoji_id)),
+            )
+            .execute(&mut conn)
+            .map(|_| ())
+            .map_err(|e| DatabaseError::Query(e.to_string()))
+        })
+        .await
+        .map_err(|e| DatabaseError::Query(e.to_string()))?
+    }
+}
@@ -0,0 +1,252 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use diesel::r2d2::{self, ConnectionManager};
+use diesel::sql_types::BigInt;
+
+use crate::db::error::{DatabaseError, Result};
+use crate::db::models::{MediaCacheEntry, MediaDirection, NewMediaCacheEntry};
+use crate::db::schema::media_cache;
+use crate::db::stores::MediaCacheStore;
+
+type Pool = r2d2::Pool<ConnectionManager<PgConnection>>;
+
+#[derive(Clone)]
+pub struct PostgresMediaCacheStore {
+    pool: Pool,
+}
+
+impl PostgresMediaCacheStore {
+    pub fn new(pool: Pool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl MediaCacheStore for PostgresMediaCacheStore {
+    async fn get_by_source(
+        &self,
+        organization_id: &str,
+        direction: MediaDirection,
+        source_url: &str,
+    ) -> Result<Option<MediaCacheEntry>> {
+        let mut conn = self.pool.get().map_err(|e| DatabaseError::Connection(e.to_string()))?;
+        let organization_id = organization_id.to_string();
+        let source_url = source_url.to_string();
+        tokio::task::spawn_blocking(move || {
+            media_cache::table
+                .filter(media_cache::organization_id.eq(organization_id))
+                .filter(media_cache::direction.eq(direction.as_str()))
+                .filter(media_cache::source_url.eq(source_url))
+                .first::<MediaCacheEntry>(&mut conn)
+                .optional()
+                .map_err(|e| DatabaseError::Query(e.to_string()))
+        })
+        .await
+        .map_err(|e| DatabaseError::Query(e.to_string()))?
+    }
+
+    async fn get_by_hash(
+        &self,
+        organization_id: &str,
+        direction: MediaDirection,
+        content_hash: &str,
+    ) -> Result<Option<MediaCacheEntry>> {
+        let mut conn = self.pool.get().map_err(|e| DatabaseError::Connection(e.to_string()))?;
+        let organization_id = organization_id.to_string();
+        let content_hash = content_hash.to_string();
+        tokio::task::spawn_blocking(move || {
+            media_cache::table
+                .filter(media_cache::organization_id.eq(organization_id))
+                .filter(media_cache::direction.eq(direction.as_str()))
+                .filter(media_cache::content_hash.eq(content_hash))
+                .order(media_cache::last_used_at.desc())
+                .first::<MediaCacheEntry>(&mut conn)
+                .optional()
+                .map_err(|e| DatabaseError::Query(e.to_string()))
+        })
+        .await
+        .map_err(|e| DatabaseError::Query(e.to_string()))?
+    }
+
+    async fn upsert(&self, entry: NewMediaCacheEntry) -> Result<MediaCacheEntry> {
+        let mut conn = self.pool.get().map_err(|e| DatabaseError::Connection(e.to_string()))?;
+        tokio::task::spawn_blocking(move || {
+            diesel::insert_into(media_cache::table)
+                .values(&entry)
+                .on_conflict((
+                    media_cache::organization_id,
+                    media_cache::direction,
+                    media_cache::source_url,
+                ))
+                .do_update()
+                .set((
+                    media_cache::content_hash.eq(&entry.content_hash),
+                    media_cache::target_url.eq(&entry.target_url),
+                    media_cache::content_type.eq(&entry.content_type),
+                    media_cache::size.eq(entry.size),
+                    media_cache::metadata.eq(&entry.metadata),
+                    media_cache::last_used_at.eq(diesel::dsl::now),
+                ))
+                .get_result(&mut conn)
+                .map_err(|e| DatabaseError::Query(e.to_string()))
+        })
+        .await
+        .map_err(|e| DatabaseError::Query(e.to_string()))?
+    }
+
+    async fn touch(&self, id: i64) -> Result<()> {
+        let mut conn = self.pool.get().map_err(|e| DatabaseError::Connection(e.to_string()))?;
+        tokio::task::spawn_blocking(move || {
+            diesel::update(media_cache::table.find(id))
+                .set(media_cache::last_used_at.eq(diesel::dsl::now))
+                .execute(&mut conn)
+                .map(|_| ())
+                .map_err(|e| DatabaseError::Query(e.to_string()))
+        })
+        .await
+        .map_err(|e| DatabaseError::Query(e.to_string()))?
+    }
+
+    async fn evict(&self, max_size: i64) -> Result<usize> {
+        let mut conn = self.pool.get().map_err(|e| DatabaseError::Connection(e.to_string()))?;
+        tokio::task::spawn_blocking(move || {
+            // Oldest first, each entry goes while what is left of the cache with it still
+            // exceeds the limit. The window is skipped while the whole cache is under it.
+            diesel::sql_query(
+                "DELETE FROM media_cache WHERE id IN (
+                     SELECT id FROM (
+                         SELECT id,
+                                SUM(size) OVER () - SUM(size) OVER (ORDER BY last_used_at, id)
+                                    + size AS remaining
+                         FROM media_cache
+                         WHERE (SELECT SUM(size) FROM media_cache) > $1
+                     ) AS oldest_first
+                     WHERE remaining > $1
+                 )",
+            )
+            .bind::<BigInt, _>(max_size)
+            .execute(&mut conn)
+            .map_err(|e| DatabaseError::Query(e.to_string()))
+        })
+        .await
+        .map_err(|e| DatabaseError::Query(e.to_string()))?
+    }
+
+    async fn delete_unused_before(&self, before: DateTime<Utc>) -> Result<usize> {
+        let mut conn = self.pool.get().map_err(|e| DatabaseError::Connection(e.to_string()))?;
+        tokio::task::spawn_blocking(move || {
+            diesel::delete(media_cache::table.filter(media_cache::last_used_at.lt(before)))
+                .execute(&mut conn)
+                .map_err(|e| DatabaseError::Query(e.to_string()))
+        })
+        .await
+        .map_err(|e| DatabaseError::Query(e.to_string()))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use diesel::connection::SimpleConnection;
+    use diesel::r2d2::TestCustomizer;
+
+    use super::*;
+    use crate::testing::DATABASE_URL_VAR;
+
+    const ORG: &str = "media-cache-test";
+
+    /// A store on a single connection whose transaction is never committed, with the cache
+    /// tables in a schema of their own so other tests' entries do not count.
+    fn store(schema: &str) -> (PostgresMediaCacheStore, Pool) {
+        let url = std::env::var(DATABASE_URL_VAR).unwrap();
+        let pool = Pool::builder()
+            .max_size(1)
+            .connection_customizer(Box::new(TestCustomizer))
+            .build(ConnectionManager::new(url))
+            .unwrap();
+        let mut conn = pool.get().unwrap();
+        conn.batch_execute(&format!("CREATE SCHEMA {schema}; SET LOCAL search_path TO {schema}"))
+            .unwrap();
+        conn.batch_execute(include_str!("../../../migrations/postgres/001_init.sql"))
+            .unwrap();
+        conn.batch_execute(include_str!("../../../migrations/postgres/009_media_cache.sql"))
+            .unwrap();
+        conn.batch_execute(&format!(
+            "INSERT INTO organizations (id, name, site, email, api_key)
+             VALUES ('{ORG}', 'Test', 'https://chat.example.org', 'bot@example.org', 'key')"
+        ))
+        .unwrap();
+        (PostgresMediaCacheStore::new(pool.clone()), pool)
+    }
+
+    /// Caches `source` as last used `age` ago.
+    async fn remember(
+        store: &PostgresMediaCacheStore,
+        pool: &Pool,
+        source: &str,
+        size: i64,
+        age: Duration,
+    ) -> i64 {
+        let entry = store
+            .upsert(NewMediaCacheEntry {
+                organization_id: ORG.to_string(),
+                direction: MediaDirection::ZulipToMatrix.as_str().to_string(),
+                source_url: source.to_string(),
+                content_hash: format!("hash-{source}"),
+                target_url: format!("mxc://example.org/{source}"),
+                content_type: "image/png".to_string(),
+                size,
+                metadata: None,
+            })
+            .await
+            .unwrap();
+        diesel::update(media_cache::table.find(entry.id))
+            .set(media_cache::last_used_at.eq(Utc::now() - age))
+            .execute(&mut pool.get().unwrap())
+            .unwrap();
+        entry.id
+    }
+
+    fn sources(pool: &Pool) -> Vec<String> {
+        media_cache::table
+            .select(media_cache::source_url)
+            .order(media_cache::source_url)
+            .load(&mut pool.get().unwrap())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn evicts_least_recently_used_entries() {
+        let (store, pool) = store("media_cache_eviction_test");
+        let a = remember(&store, &pool, "a", 10, Duration::hours(4)).await;
+        remember(&store, &pool, "b", 20, Duration::hours(3)).await;
+        remember(&store, &pool, "c", 30, Duration::hours(2)).await;
+        remember(&store, &pool, "d", 40, Duration::hours(1)).await;
+        store.touch(a).await.unwrap();
+
+        assert_eq!(store.evict(100).await.unwrap(), 0);
+        assert_eq!(sources(&pool), ["a", "b", "c", "d"]);
+
+        // b and c are the oldest once a was used again; without them 50 bytes are left.
+        assert_eq!(store.evict(75).await.unwrap(), 2);
+        assert_eq!(sources(&pool), ["a", "d"]);
+
+        assert_eq!(store.evict(0).await.unwrap(), 2);
+        assert!(sources(&pool).is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn purges_entries_unused_since() {
+        let (store, pool) = store("media_cache_purge_test");
+        remember(&store, &pool, "stale", 10, Duration::days(10)).await;
+        remember(&store, &pool, "fresh", 10, Duration::days(1)).await;
+
+        let before = Utc::now() - Duration::days(5);
+        assert_eq!(store.delete_unused_before(before).await.unwrap(), 1);
+        assert_eq!(sources(&pool), ["fresh"]);
+    }
+}
+
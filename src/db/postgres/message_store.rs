@@ -61,11 +61,64 @@ impl MessageStore for PostgresMessageStore {
         .map_err(|e| DatabaseError::Query(e.to_string()))?
     }
 
-    async fn get_by_zulip_message(&self, zulip_message_id: i64) -> Result<Option<MessageMapping>> {
+    async fn get_by_zulip_message(
+        &self,
+        matrix_room_id: &str,
+        zulip_message_id: i64,
+    ) -> Result<Option<MessageMapping>> {
         let mut conn = self.pool.get().map_err(|e| DatabaseError::Connection(e.to_string()))?;
+        let matrix_room_id = matrix_room_id.to_string();
         tokio::task::spawn_blocking(move || {
             message_mappings::table
+                .filter(message_mappings::matrix_room_id.eq(matrix_room_id))
                 .filter(message_mappings::zulip_message_id.eq(zulip_message_id))
+                .order((message_mappings::is_primary.desc(), message_mappings::part_index.asc()))
+                .first::<MessageMapping>(&mut conn)
+                .optional()
+                .map_err(|e| DatabaseError::Query(e.to_string()))
+        })
+        .await
+        .map_err(|e| DatabaseError::Query(e.to_string()))?
+    }
+
+    async fn get_parts_by_zulip_message(
+        &self,
+        matrix_room_id: &str,
+        zulip_message_id: i64,
+    ) -> Result<Vec<MessageMapping>> {
+        let mut conn = self.pool.get().map_err(|e| DatabaseError::Connection(e.to_string()))?;
+        let matrix_room_id = matrix_room_id.to_string();
+        tokio::task::spawn_blocking(move || {
+            message_mappings::table
+                .filter(message_mappings::matrix_room_id.eq(matrix_room_id))
+                .filter(message_mappings::zulip_message_id.eq(zulip_message_id))
+                .order(message_mappings::part_index.asc())
+                .load::<MessageMapping>(&mut conn)
+                .map_err(|e| DatabaseError::Query(e.to_string()))
+        })
+        .await
+        .map_err(|e| DatabaseError::Query(e.to_string()))?
+    }
+
+    async fn get_primary_by_matrix_event(&self, matrix_event_id: &str) -> Result<Option<MessageMapping>> {
+        let mut conn = self.pool.get().map_err(|e| DatabaseError::Connection(e.to_string()))?;
+        let matrix_event_id = matrix_event_id.to_string();
+        tokio::task::spawn_blocking(move || {
+            let part = message_mappings::table
+                .filter(message_mappings::matrix_event_id.eq(matrix_event_id))
+                .first::<MessageMapping>(&mut conn)
+                .optional()
+                .map_err(|e| DatabaseError::Query(e.to_string()))?;
+            let Some(part) = part else {
+                return Ok(None);
+            };
+            if part.is_primary {
+                return Ok(Some(part));
+            }
+            message_mappings::table
+                .filter(message_mappings::matrix_room_id.eq(&part.matrix_room_id))
+                .filter(message_mappings::zulip_message_id.eq(part.zulip_message_id))
+                .order((message_mappings::is_primary.desc(), message_mappings::part_index.asc()))
                 .first::<MessageMapping>(&mut conn)
                 .optional()
                 .map_err(|e| DatabaseError::Query(e.to_string()))
@@ -89,6 +142,36 @@ impl MessageStore for PostgresMessageStore {
         .map_err(|e| DatabaseError::Query(e.to_string()))?
     }
 
+    async fn get_oldest_by_matrix_room(&self, matrix_room_id: &str) -> Result<Option<MessageMapping>> {
+        let mut conn = self.pool.get().map_err(|e| DatabaseError::Connection(e.to_string()))?;
+        let matrix_room_id = matrix_room_id.to_string();
+        tokio::task::spawn_blocking(move || {
+            message_mappings::table
+                .filter(message_mappings::matrix_room_id.eq(matrix_room_id))
+                .order(message_mappings::zulip_message_id.asc())
+                .first::<MessageMapping>(&mut conn)
+                .optional()
+                .map_err(|e| DatabaseError::Query(e.to_string()))
+        })
+        .await
+        .map_err(|e| DatabaseError::Query(e.to_string()))?
+    }
+
+    async fn get_newest_by_matrix_room(&self, matrix_room_id: &str) -> Result<Option<MessageMapping>> {
+        let mut conn = self.pool.get().map_err(|e| DatabaseError::Connection(e.to_string()))?;
+        let matrix_room_id = matrix_room_id.to_string();
+        tokio::task::spawn_blocking(move || {
+            message_mappings::table
+                .filter(message_mappings::matrix_room_id.eq(matrix_room_id))
+                .order(message_mappings::zulip_message_id.desc())
+                .first::<MessageMapping>(&mut conn)
+                .optional()
+                .map_err(|e| DatabaseError::Query(e.to_string()))
+        })
+        .await
+        .map_err(|e| DatabaseError::Query(e.to_string()))?
+    }
+
     async fn delete(&self, id: i64) -> Result<()> {
         let mut conn = self.pool.get().map_err(|e| DatabaseError::Connection(e.to_string()))?;
         tokio::task::spawn_blocking(move || {
@@ -114,6 +197,27 @@ impl MessageStore for PostgresMessageStore {
         .map_err(|e| DatabaseError::Query(e.to_string()))?
     }
 
+    async fn delete_by_zulip_message(
+        &self,
+        matrix_room_id: &str,
+        zulip_message_id: i64,
+    ) -> Result<()> {
+        let mut conn = self.pool.get().map_err(|e| DatabaseError::Connection(e.to_string()))?;
+        let matrix_room_id = matrix_room_id.to_string();
+        tokio::task::spawn_blocking(move || {
+            diesel::delete(
+                message_mappings::table
+                    .filter(message_mappings::matrix_room_id.eq(matrix_room_id))
+                    .filter(message_mappings::zulip_message_id.eq(zulip_message_id)),
+            )
+                .execute(&mut conn)
+                .map(|_| ())
+                .map_err(|e| DatabaseError::Query(e.to_string()))
+        })
+        .await
+        .map_err(|e| DatabaseError::Query(e.to_string()))?
+    }
+
     async fn exists_by_matrix_event(&self, matrix_event_id: &str) -> Result<bool> {
         let mut conn = self.pool.get().map_err(|e| DatabaseError::Connection(e.to_string()))?;
         let matrix_event_id = matrix_event_id.to_string();
@@ -130,10 +234,16 @@ impl MessageStore for PostgresMessageStore {
         .map_err(|e| DatabaseError::Query(e.to_string()))?
     }
 
-    async fn exists_by_zulip_message(&self, zulip_message_id: i64) -> Result<bool> {
+    async fn exists_by_zulip_message(
+        &self,
+        matrix_room_id: &str,
+        zulip_message_id: i64,
+    ) -> Result<bool> {
         let mut conn = self.pool.get().map_err(|e| DatabaseError::Connection(e.to_string()))?;
+        let matrix_room_id = matrix_room_id.to_string();
         tokio::task::spawn_blocking(move || {
             message_mappings::table
+                .filter(message_mappings::matrix_room_id.eq(matrix_room_id))
                 .filter(message_mappings::zulip_message_id.eq(zulip_message_id))
                 .select(message_mappings::id)
                 .first::<i64>(&mut conn)
@@ -4,6 +4,8 @@ pub mod user_store;
 pub mod message_store;
 pub mod event_store;
 pub mod reaction_store;
+pub mod emoji_store;
+pub mod media_cache_store;
 
 pub use organization_store::PostgresOrganizationStore;
 pub use room_store::PostgresRoomStore;
@@ -11,3 +13,5 @@ pub use user_store::PostgresUserStore;
 pub use message_store::PostgresMessageStore;
 pub use event_store::PostgresEventStore;
 pub use reaction_store::PostgresReactionStore;
+pub use emoji_store::PostgresEmojiStore;
+pub use media_cache_store::PostgresMediaCacheStore;
@@ -127,6 +127,77 @@ impl OrganizationStore for PostgresOrganizationStore {
         .map_err(|e| DatabaseError::Query(e.to_string()))?
     }
 
+    async fn set_event_queue(
+        &self,
+        id: &str,
+        queue_id: Option<&str>,
+        last_event_id: Option<i64>,
+    ) -> Result<()> {
+        let mut conn = self
+            .pool
+            .get()
+            .map_err(|e| DatabaseError::Connection(e.to_string()))?;
+        
+        let id = id.to_string();
+        let queue_id = queue_id.map(ToOwned::to_owned);
+
+        tokio::task::spawn_blocking(move || {
+            diesel::update(organizations::table.find(&id))
+                .set((
+                    organizations::event_queue_id.eq(queue_id),
+                    organizations::last_event_id.eq(last_event_id),
+                ))
+                .execute(&mut conn)
+                .map(|_| ())
+                .map_err(|e| DatabaseError::Query(e.to_string()))
+        })
+        .await
+        .map_err(|e| DatabaseError::Query(e.to_string()))?
+    }
+
+    async fn set_server_version(&self, id: &str, version: &str, feature_level: i32) -> Result<()> {
+        let mut conn = self
+            .pool
+            .get()
+            .map_err(|e| DatabaseError::Connection(e.to_string()))?;
+        
+        let id = id.to_string();
+        let version = version.to_string();
+
+        tokio::task::spawn_blocking(move || {
+            diesel::update(organizations::table.find(&id))
+                .set((
+                    organizations::zulip_version.eq(Some(version)),
+                    organizations::zulip_feature_level.eq(Some(feature_level)),
+                ))
+                .execute(&mut conn)
+                .map(|_| ())
+                .map_err(|e| DatabaseError::Query(e.to_string()))
+        })
+        .await
+        .map_err(|e| DatabaseError::Query(e.to_string()))?
+    }
+
+    async fn set_space_room(&self, id: &str, space_room_id: &str) -> Result<()> {
+        let mut conn = self
+            .pool
+            .get()
+            .map_err(|e| DatabaseError::Connection(e.to_string()))?;
+        
+        let id = id.to_string();
+        let space_room_id = space_room_id.to_string();
+
+        tokio::task::spawn_blocking(move || {
+            diesel::update(organizations::table.find(&id))
+                .set(organizations::space_room_id.eq(Some(space_room_id)))
+                .execute(&mut conn)
+                .map(|_| ())
+                .map_err(|e| DatabaseError::Query(e.to_string()))
+        })
+        .await
+        .map_err(|e| DatabaseError::Query(e.to_string()))?
+    }
+
     async fn exists(&self, id: &str) -> Result<bool> {
         let mut conn = self
             .pool
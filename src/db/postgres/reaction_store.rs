@@ -61,11 +61,19 @@ impl ReactionStore for PostgresReactionStore {
         .map_err(|e| DatabaseError::Query(e.to_string()))?
     }
 
-    async fn get_by_zulip_reaction(&self, zulip_reaction_id: i64) -> Result<Option<ReactionMapping>> {
+    async fn get_by_zulip_reaction(
+        &self,
+        zulip_message_id: i64,
+        zulip_user_id: i64,
+        emoji_code: &str,
+    ) -> Result<Option<ReactionMapping>> {
         let mut conn = self.pool.get().map_err(|e| DatabaseError::Connection(e.to_string()))?;
+        let emoji_code = emoji_code.to_string();
         tokio::task::spawn_blocking(move || {
             reaction_mappings::table
-                .filter(reaction_mappings::zulip_reaction_id.eq(zulip_reaction_id))
+                .filter(reaction_mappings::zulip_message_id.eq(zulip_message_id))
+                .filter(reaction_mappings::zulip_user_id.eq(zulip_user_id))
+                .filter(reaction_mappings::emoji_code.eq(emoji_code))
                 .first::<ReactionMapping>(&mut conn)
                 .optional()
                 .map_err(|e| DatabaseError::Query(e.to_string()))
@@ -114,13 +122,24 @@ impl ReactionStore for PostgresReactionStore {
         .map_err(|e| DatabaseError::Query(e.to_string()))?
     }
 
-    async fn delete_by_zulip_reaction(&self, zulip_reaction_id: i64) -> Result<()> {
+    async fn delete_by_zulip_reaction(
+        &self,
+        zulip_message_id: i64,
+        zulip_user_id: i64,
+        emoji_code: &str,
+    ) -> Result<()> {
         let mut conn = self.pool.get().map_err(|e| DatabaseError::Connection(e.to_string()))?;
+        let emoji_code = emoji_code.to_string();
         tokio::task::spawn_blocking(move || {
-            diesel::delete(reaction_mappings::table.filter(reaction_mappings::zulip_reaction_id.eq(zulip_reaction_id)))
-                .execute(&mut conn)
-                .map(|_| ())
-                .map_err(|e| DatabaseError::Query(e.to_string()))
+            diesel::delete(
+                reaction_mappings::table
+                    .filter(reaction_mappings::zulip_message_id.eq(zulip_message_id))
+                    .filter(reaction_mappings::zulip_user_id.eq(zulip_user_id))
+                    .filter(reaction_mappings::emoji_code.eq(emoji_code)),
+            )
+            .execute(&mut conn)
+            .map(|_| ())
+            .map_err(|e| DatabaseError::Query(e.to_string()))
         })
         .await
         .map_err(|e| DatabaseError::Query(e.to_string()))?
@@ -142,11 +161,19 @@ impl ReactionStore for PostgresReactionStore {
         .map_err(|e| DatabaseError::Query(e.to_string()))?
     }
 
-    async fn exists_by_zulip_reaction(&self, zulip_reaction_id: i64) -> Result<bool> {
+    async fn exists_by_zulip_reaction(
+        &self,
+        zulip_message_id: i64,
+        zulip_user_id: i64,
+        emoji_code: &str,
+    ) -> Result<bool> {
         let mut conn = self.pool.get().map_err(|e| DatabaseError::Connection(e.to_string()))?;
+        let emoji_code = emoji_code.to_string();
         tokio::task::spawn_blocking(move || {
             reaction_mappings::table
-                .filter(reaction_mappings::zulip_reaction_id.eq(zulip_reaction_id))
+                .filter(reaction_mappings::zulip_message_id.eq(zulip_message_id))
+                .filter(reaction_mappings::zulip_user_id.eq(zulip_user_id))
+                .filter(reaction_mappings::emoji_code.eq(emoji_code))
                 .select(reaction_mappings::id)
                 .first::<i64>(&mut conn)
                 .optional()
@@ -76,6 +76,35 @@ impl RoomStore for PostgresRoomStore {
         .map_err(|e| DatabaseError::Query(e.to_string()))?
     }
 
+    async fn get_by_zulip_topic(
+        &self,
+        organization_id: &str,
+        zulip_stream_id: i64,
+        topic: Option<&str>,
+    ) -> Result<Option<RoomMapping>> {
+        let mut conn = self.pool.get().map_err(|e| DatabaseError::Connection(e.to_string()))?;
+        let organization_id = organization_id.to_string();
+        let topic = topic.map(ToOwned::to_owned);
+        tokio::task::spawn_blocking(move || {
+            let query = room_mappings::table
+                .filter(room_mappings::organization_id.eq(organization_id))
+                .filter(room_mappings::zulip_stream_id.eq(zulip_stream_id))
+                .into_boxed();
+            let query = match topic {
+                Some(topic) => query
+                    .filter(room_mappings::room_type.eq(RoomType::Topic.as_str()))
+                    .filter(room_mappings::zulip_topic.eq(topic)),
+                None => query.filter(room_mappings::room_type.eq(RoomType::Stream.as_str())),
+            };
+            query
+                .first::<RoomMapping>(&mut conn)
+                .optional()
+                .map_err(|e| DatabaseError::Query(e.to_string()))
+        })
+        .await
+        .map_err(|e| DatabaseError::Query(e.to_string()))?
+    }
+
     async fn get_by_organization(&self, organization_id: &str) -> Result<Vec<RoomMapping>> {
         let mut conn = self.pool.get().map_err(|e| DatabaseError::Connection(e.to_string()))?;
         let organization_id = organization_id.to_string();
@@ -9,6 +9,12 @@ diesel::table! {
         max_backfill_amount -> Integer,
         created_at -> Timestamptz,
         updated_at -> Timestamptz,
+        event_queue_id -> Nullable<Text>,
+        last_event_id -> Nullable<BigInt>,
+        zulip_version -> Nullable<Text>,
+        zulip_feature_level -> Nullable<Integer>,
+        webhook_token -> Nullable<Text>,
+        space_room_id -> Nullable<Text>,
     }
 }
 
@@ -49,6 +55,8 @@ diesel::table! {
         zulip_sender_id -> BigInt,
         message_type -> Text,
         created_at -> Timestamptz,
+        part_index -> Integer,
+        is_primary -> Bool,
     }
 }
 
@@ -67,10 +75,42 @@ diesel::table! {
         id -> BigInt,
         matrix_event_id -> Text,
         zulip_message_id -> BigInt,
-        zulip_reaction_id -> BigInt,
+        #[sql_name = "zulip_reaction_id"]
+        zulip_user_id -> BigInt,
         emoji -> Text,
         matrix_reaction_event_id -> Text,
         created_at -> Timestamptz,
+        emoji_code -> Text,
+    }
+}
+
+diesel::table! {
+    emoji_mappings (id) {
+        id -> BigInt,
+        organization_id -> Text,
+        zulip_emoji_id -> Text,
+        name -> Text,
+        source_url -> Text,
+        mxc_url -> Text,
+        deactivated -> Bool,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    media_cache (id) {
+        id -> BigInt,
+        organization_id -> Text,
+        direction -> Text,
+        source_url -> Text,
+        content_hash -> Text,
+        target_url -> Text,
+        content_type -> Text,
+        size -> BigInt,
+        metadata -> Nullable<Text>,
+        created_at -> Timestamptz,
+        last_used_at -> Timestamptz,
     }
 }
 
@@ -81,4 +121,6 @@ diesel::allow_tables_to_appear_in_same_query!(
     message_mappings,
     processed_events,
     reaction_mappings,
+    emoji_mappings,
+    media_cache,
 );
@@ -0,0 +1,14 @@
+use async_trait::async_trait;
+
+use crate::db::error::Result;
+use crate::db::models::{EmojiMapping, NewEmojiMapping};
+
+#[async_trait]
+pub trait EmojiStore: Send + Sync {
+    /// Inserts the emoji, or replaces the stored one with the same Zulip id.
+    async fn upsert(&self, emoji: NewEmojiMapping) -> Result<EmojiMapping>;
+    
+    async fn get_by_organization(&self, organization_id: &str) -> Result<Vec<EmojiMapping>>;
+    
+    async fn delete(&self, organization_id: &str, zulip_emoji_id: &str) -> Result<()>;
+}
@@ -0,0 +1,35 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use crate::db::error::Result;
+use crate::db::models::{MediaCacheEntry, MediaDirection, NewMediaCacheEntry};
+
+#[async_trait]
+pub trait MediaCacheStore: Send + Sync {
+    async fn get_by_source(
+        &self,
+        organization_id: &str,
+        direction: MediaDirection,
+        source_url: &str,
+    ) -> Result<Option<MediaCacheEntry>>;
+    
+    async fn get_by_hash(
+        &self,
+        organization_id: &str,
+        direction: MediaDirection,
+        content_hash: &str,
+    ) -> Result<Option<MediaCacheEntry>>;
+    
+    /// Inserts the entry, or replaces the stored one for the same source.
+    async fn upsert(&self, entry: NewMediaCacheEntry) -> Result<MediaCacheEntry>;
+    
+    /// Marks the entry as used now, which keeps it from eviction the longest.
+    async fn touch(&self, id: i64) -> Result<()>;
+    
+    /// Forgets the least recently used entries until the rest add up to at most `max_size`
+    /// bytes. Returns how many were removed.
+    async fn evict(&self, max_size: i64) -> Result<usize>;
+    
+    /// Forgets entries last used before `before`. Returns how many were removed.
+    async fn delete_unused_before(&self, before: DateTime<Utc>) -> Result<usize>;
+}
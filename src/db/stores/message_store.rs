@@ -11,15 +11,46 @@ pub trait MessageStore: Send + Sync {
     
     async fn get_by_matrix_event(&self, matrix_event_id: &str) -> Result<Option<MessageMapping>>;
     
-    async fn get_by_zulip_message(&self, zulip_message_id: i64) -> Result<Option<MessageMapping>>;
+    /// The primary part of a Zulip message in a portal room. Zulip message ids are only unique
+    /// per server, so lookups by them are scoped to a room.
+    async fn get_by_zulip_message(
+        &self,
+        matrix_room_id: &str,
+        zulip_message_id: i64,
+    ) -> Result<Option<MessageMapping>>;
+    
+    /// Every Matrix event a Zulip message was sent as into a room, in part order.
+    async fn get_parts_by_zulip_message(
+        &self,
+        matrix_room_id: &str,
+        zulip_message_id: i64,
+    ) -> Result<Vec<MessageMapping>>;
+    
+    /// The primary part of the Zulip message `matrix_event_id` is any part of.
+    async fn get_primary_by_matrix_event(&self, matrix_event_id: &str) -> Result<Option<MessageMapping>>;
     
     async fn get_by_matrix_room(&self, matrix_room_id: &str, limit: i64) -> Result<Vec<MessageMapping>>;
     
+    async fn get_oldest_by_matrix_room(&self, matrix_room_id: &str) -> Result<Option<MessageMapping>>;
+    
+    async fn get_newest_by_matrix_room(&self, matrix_room_id: &str) -> Result<Option<MessageMapping>>;
+    
     async fn delete(&self, id: i64) -> Result<()>;
     
     async fn delete_by_matrix_event(&self, matrix_event_id: &str) -> Result<()>;
     
+    /// Forgets every part of a Zulip message in a room.
+    async fn delete_by_zulip_message(
+        &self,
+        matrix_room_id: &str,
+        zulip_message_id: i64,
+    ) -> Result<()>;
+    
     async fn exists_by_matrix_event(&self, matrix_event_id: &str) -> Result<bool>;
     
-    async fn exists_by_zulip_message(&self, zulip_message_id: i64) -> Result<bool>;
+    async fn exists_by_zulip_message(
+        &self,
+        matrix_room_id: &str,
+        zulip_message_id: i64,
+    ) -> Result<bool>;
 }
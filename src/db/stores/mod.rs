@@ -4,6 +4,8 @@ pub mod user_store;
 pub mod message_store;
 pub mod event_store;
 pub mod reaction_store;
+pub mod emoji_store;
+pub mod media_cache_store;
 
 pub use organization_store::OrganizationStore;
 pub use room_store::RoomStore;
@@ -11,3 +13,5 @@ pub use user_store::UserStore;
 pub use message_store::MessageStore;
 pub use event_store::EventStore;
 pub use reaction_store::ReactionStore;
+pub use emoji_store::EmojiStore;
+pub use media_cache_store::MediaCacheStore;
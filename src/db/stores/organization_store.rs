@@ -17,5 +17,16 @@ pub trait OrganizationStore: Send + Sync {
     
     async fn set_connected(&self, id: &str, connected: bool) -> Result<()>;
     
+    async fn set_event_queue(
+        &self,
+        id: &str,
+        queue_id: Option<&str>,
+        last_event_id: Option<i64>,
+    ) -> Result<()>;
+    
+    async fn set_server_version(&self, id: &str, version: &str, feature_level: i32) -> Result<()>;
+    
+    async fn set_space_room(&self, id: &str, space_room_id: &str) -> Result<()>;
+    
     async fn exists(&self, id: &str) -> Result<bool>;
 }
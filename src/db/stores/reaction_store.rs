@@ -11,7 +11,12 @@ pub trait ReactionStore: Send + Sync {
     
     async fn get_by_matrix_reaction(&self, matrix_reaction_event_id: &str) -> Result<Option<ReactionMapping>>;
     
-    async fn get_by_zulip_reaction(&self, zulip_reaction_id: i64) -> Result<Option<ReactionMapping>>;
+    async fn get_by_zulip_reaction(
+        &self,
+        zulip_message_id: i64,
+        zulip_user_id: i64,
+        emoji_code: &str,
+    ) -> Result<Option<ReactionMapping>>;
     
     async fn get_by_zulip_message(&self, zulip_message_id: i64) -> Result<Vec<ReactionMapping>>;
     
@@ -19,9 +24,19 @@ pub trait ReactionStore: Send + Sync {
     
     async fn delete_by_matrix_reaction(&self, matrix_reaction_event_id: &str) -> Result<()>;
     
-    async fn delete_by_zulip_reaction(&self, zulip_reaction_id: i64) -> Result<()>;
+    async fn delete_by_zulip_reaction(
+        &self,
+        zulip_message_id: i64,
+        zulip_user_id: i64,
+        emoji_code: &str,
+    ) -> Result<()>;
     
     async fn exists_by_matrix_reaction(&self, matrix_reaction_event_id: &str) -> Result<bool>;
     
-    async fn exists_by_zulip_reaction(&self, zulip_reaction_id: i64) -> Result<bool>;
+    async fn exists_by_zulip_reaction(
+        &self,
+        zulip_message_id: i64,
+        zulip_user_id: i64,
+        emoji_code: &str,
+    ) -> Result<bool>;
 }
@@ -17,6 +17,14 @@ pub trait RoomStore: Send + Sync {
         zulip_stream_id: i64,
     ) -> Result<Option<RoomMapping>>;
     
+    /// The room bridging `topic` of the stream, or the stream room itself when `topic` is `None`.
+    async fn get_by_zulip_topic(
+        &self,
+        organization_id: &str,
+        zulip_stream_id: i64,
+        topic: Option<&str>,
+    ) -> Result<Option<RoomMapping>>;
+    
     async fn get_by_organization(&self, organization_id: &str) -> Result<Vec<RoomMapping>>;
     
     async fn get_by_type(&self, organization_id: &str, room_type: RoomType) -> Result<Vec<RoomMapping>>;
@@ -8,12 +8,27 @@ use std::sync::Arc;
 use anyhow::Result;
 use tracing::{error, info};
 
+mod bridge;
 mod cli;
+mod command;
 mod config;
+mod db;
+mod matrix;
+mod media;
+mod parsers;
+mod rooms;
+#[cfg(any(test, feature = "test-support"))]
+mod testing;
 mod utils;
+mod web;
+mod zulip;
 
+use bridge::{BridgeCore, BridgeMatrixEventHandler};
 use cli::CliArgs;
 use config::Config;
+use db::DatabaseManager;
+use matrix::{MatrixAppservice, MatrixEventProcessor};
+use web::WebServer;
 
 fn generate_registration(args: &CliArgs, compat_mode: bool) -> Result<()> {
     use rand::Rng;
@@ -36,7 +51,7 @@ fn generate_registration(args: &CliArgs, compat_mode: bool) -> Result<()> {
         "sender_localpart": "zulipbridge",
         "namespaces": {
             "users": [{
-                "regex": "@zulip_.*",
+                "regex": matrix::ghost_user_regex(),
                 "exclusive": true
             }],
             "aliases": [],
@@ -61,26 +76,68 @@ fn generate_registration(args: &CliArgs, compat_mode: bool) -> Result<()> {
     file.write_all(yaml_content.as_bytes())?;
 
     info!("Registration file generated and saved to {}", output_path);
+    info!(
+        "The registration sets rate_limited: false so the homeserver exempts the bridge bot and \
+         ghost users from rate limits; backfills send many events in quick succession"
+    );
+    Ok(())
+}
+
+/// Only forgets which files were already transferred; the files themselves stay where they are.
+async fn purge_media_cache(args: &CliArgs, unused_days: u32) -> Result<()> {
+    let config = Config::load(&args.config)?;
+    let db_manager = DatabaseManager::new(&config.database).await?;
+    db_manager.migrate().await?;
+
+    let before = chrono::Utc::now() - chrono::Duration::days(unused_days.into());
+    let purged = db_manager
+        .media_cache_store()
+        .delete_unused_before(before)
+        .await?;
+    info!("purged {} media cache entries", purged);
     Ok(())
 }
 
 async fn run_bridge(args: &CliArgs) -> Result<()> {
     let config = Arc::new(Config::load(&args.config)?);
     config.validate()?;
+    utils::metrics::init();
 
     info!("matrix-zulip bridge starting up");
     info!("Connecting to homeserver at {}", config.bridge.homeserver_url);
 
-    // TODO: Initialize database manager
-    // TODO: Initialize Matrix appservice
-    // TODO: Initialize Zulip client
-    // TODO: Initialize bridge core
-    // TODO: Start web server
+    let db_manager = Arc::new(DatabaseManager::new(&config.database).await?);
+    db_manager.migrate().await?;
+
+    let matrix = Arc::new(MatrixAppservice::new(config.clone()).await?);
+    matrix.start().await?;
+
+    let bridge = Arc::new(
+        BridgeCore::new(config.clone(), db_manager.clone(), matrix.clone())
+            .with_event_source_mode(args.event_source_mode()),
+    );
+    bridge.start().await?;
+
+    let matrix_handler = Arc::new(BridgeMatrixEventHandler::new(bridge.clone()));
+    matrix
+        .set_processor(Arc::new(MatrixEventProcessor::with_age_limit(
+            matrix_handler,
+            config.limits.matrix_event_age_limit_ms,
+        )))
+        .await;
+
+    let web_server = WebServer::new(config.clone(), matrix.clone(), db_manager.clone(), bridge.clone())?;
+    tokio::spawn(async move {
+        if let Err(e) = web_server.start().await {
+            error!("web server stopped: {}", e);
+        }
+    });
 
     info!("matrix-zulip bridge is running");
 
     tokio::signal::ctrl_c().await?;
     info!("received Ctrl+C, beginning shutdown");
+    bridge.stop();
 
     Ok(())
 }
@@ -105,6 +162,10 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
+    if let Some(unused_days) = args.purge_media_cache {
+        return purge_media_cache(&args, unused_days).await;
+    }
+
     if args.reset {
         info!("Reset mode not yet implemented");
         return Ok(());
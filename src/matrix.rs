@@ -1,23 +1,27 @@
 pub mod event_handler;
 pub mod ghost;
+pub mod pacing;
 
 pub use self::event_handler::{MatrixEventHandler, MatrixEventProcessor};
 pub use self::ghost::GhostUserManager;
+pub use self::pacing::GhostPacer;
 
 use std::sync::Arc;
+use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use tokio::sync::RwLock;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 use url::Url;
 
 use matrix_bot_sdk::appservice::{Appservice, AppserviceHandler};
-use matrix_bot_sdk::client::{MatrixAuth, MatrixClient};
+use matrix_bot_sdk::client::{MatrixAuth, MatrixClient, encode_path_component};
 use matrix_bot_sdk::models::CreateRoom;
+use reqwest::Method;
 
 use crate::config::Config;
-use crate::utils::Result;
+use crate::utils::{BridgeError, Result};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MatrixEvent {
@@ -51,6 +55,67 @@ impl MatrixEvent {
         self.content_as_str("body")
     }
 
+    /// The HTML body, when the message is in `org.matrix.custom.html` format.
+    pub fn formatted_body(&self) -> Option<&str> {
+        if self.content_as_str("format")? != "org.matrix.custom.html" {
+            return None;
+        }
+        self.content_as_str("formatted_body")
+    }
+
+    pub fn mentions(&self) -> MessageMentions {
+        self.content
+            .as_ref()
+            .and_then(|content| content.get("m.mentions"))
+            .map(MessageMentions::from_json)
+            .unwrap_or_default()
+    }
+
+    pub fn is_media(&self) -> bool {
+        matches!(
+            self.msgtype(),
+            Some("m.image" | "m.video" | "m.audio" | "m.file")
+        )
+    }
+
+    /// The `mxc://` URI of an unencrypted media message.
+    pub fn media_url(&self) -> Option<&str> {
+        self.content_as_str("url")
+    }
+
+    pub fn media_info(&self, key: &str) -> Option<&Value> {
+        self.content.as_ref()?.get("info")?.get(key)
+    }
+
+    /// Media messages name the file in `filename`, or in `body` when they have no caption.
+    pub fn media_file_name(&self) -> Option<&str> {
+        self.content_as_str("filename").or_else(|| self.body())
+    }
+
+    /// The caption of a media message: its `body`, when a separate `filename` is given.
+    pub fn media_caption(&self) -> Option<&str> {
+        let file_name = self.content_as_str("filename")?;
+        self.body().filter(|body| *body != file_name)
+    }
+
+    /// MSC3245 voice messages are `m.audio` events flagged as such.
+    pub fn is_voice_message(&self) -> bool {
+        self.content
+            .as_ref()
+            .is_some_and(|content| content.get("org.matrix.msc3245.voice").is_some())
+    }
+
+    /// Playback length in milliseconds, from `info` or the MSC1767 audio block.
+    pub fn media_duration_ms(&self) -> Option<u64> {
+        self.media_info("duration").and_then(Value::as_u64).or_else(|| {
+            self.content
+                .as_ref()?
+                .get("org.matrix.msc1767.audio")?
+                .get("duration")?
+                .as_u64()
+        })
+    }
+
     pub fn membership(&self) -> Option<&str> {
         self.content_as_str("membership")
     }
@@ -92,6 +157,14 @@ impl MatrixEvent {
         None
     }
 
+    /// Whether this replaces the content of an earlier event.
+    pub fn is_edit(&self) -> bool {
+        self.content
+            .as_ref()
+            .and_then(|content| content.get("m.relates_to")?.get("rel_type")?.as_str())
+            == Some("m.replace")
+    }
+
     pub fn reaction_key(&self) -> Option<String> {
         let relates_to = self.content.as_ref()?.get("m.relates_to")?;
         if relates_to.get("rel_type")?.as_str()? == "m.annotation" {
@@ -99,6 +172,50 @@ impl MatrixEvent {
         }
         None
     }
+
+    /// The `:shortcode:` of a custom emoji reaction, whose key is an `mxc://` URI.
+    pub fn reaction_shortcode(&self) -> Option<&str> {
+        self.content_as_str(REACTION_SHORTCODE_KEY)
+    }
+}
+
+/// The `m.mentions` of a message: who it notifies, independent of what the body says.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MessageMentions {
+    pub user_ids: Vec<String>,
+    pub room: bool,
+}
+
+impl MessageMentions {
+    pub fn add_user(&mut self, user_id: &str) {
+        if !self.user_ids.iter().any(|existing| existing == user_id) {
+            self.user_ids.push(user_id.to_string());
+        }
+    }
+
+    fn from_json(value: &Value) -> Self {
+        Self {
+            user_ids: value
+                .get("user_ids")
+                .and_then(Value::as_array)
+                .map(|ids| {
+                    ids.iter()
+                        .filter_map(Value::as_str)
+                        .map(ToOwned::to_owned)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            room: value.get("room").and_then(Value::as_bool).unwrap_or(false),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let mut mentions = json!({ "user_ids": self.user_ids });
+        if self.room {
+            mentions["room"] = json!(true);
+        }
+        mentions
+    }
 }
 
 pub struct BridgeAppserviceHandler {
@@ -162,9 +279,22 @@ pub struct MatrixAppservice {
     config: Arc<Config>,
     pub appservice: Appservice,
     handler: Arc<RwLock<BridgeAppserviceHandler>>,
+    pacer: Arc<GhostPacer>,
+    /// For media uploads, whose bodies are not JSON.
+    http: reqwest::Client,
 }
 
 const GHOST_USER_PREFIX: &str = "_zulip_";
+/// Shortcode of a custom emoji reaction, as sent by clients that support image packs.
+const REACTION_SHORTCODE_KEY: &str = "com.beeper.reaction.shortcode";
+/// Used when `M_LIMIT_EXCEEDED` comes without `retry_after_ms`.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+const MAX_RATE_LIMIT_RETRIES: u32 = 5;
+
+/// Registration namespace regex covering every ghost user.
+pub fn ghost_user_regex() -> String {
+    format!("@{}.*", GHOST_USER_PREFIX)
+}
 
 fn ghost_user_localpart(zulip_user_id: i64) -> String {
     format!("{}{}", GHOST_USER_PREFIX, zulip_user_id)
@@ -178,9 +308,12 @@ fn is_namespaced_user(user_id: &str) -> bool {
     user_id.contains(&format!("@{}", GHOST_USER_PREFIX))
 }
 
+/// Always sets `m.mentions`, even when empty, so clients notify only the users listed there
+/// instead of matching their names in the body.
 fn build_matrix_message_content(
     body: &str,
     formatted_body: Option<&str>,
+    mentions: &MessageMentions,
     reply_to: Option<&str>,
     edit_of: Option<&str>,
 ) -> Value {
@@ -197,6 +330,7 @@ fn build_matrix_message_content(
             "body": body,
         })
     };
+    content["m.mentions"] = mentions.to_json();
 
     if let Some(reply_id) = reply_to {
         content["m.relates_to"] = json!({
@@ -220,16 +354,80 @@ fn build_matrix_message_content(
                 "body": body,
             })
         };
+        content["m.new_content"]["m.mentions"] = mentions.to_json();
         content["m.relates_to"] = json!({
             "rel_type": "m.replace",
             "event_id": edit_event_id,
         });
-        content["body"] = format!("* {body}");
+        content["body"] = json!(format!("* {body}"));
     }
 
     content
 }
 
+/// An `m.image`, `m.video`, `m.audio` or `m.file` message. The file name doubles as the body,
+/// as there is no caption.
+pub fn build_media_content(msgtype: &str, file_name: &str, url: &str, info: Value) -> Value {
+    json!({
+        "msgtype": msgtype,
+        "body": file_name,
+        "filename": file_name,
+        "url": url,
+        "info": info,
+    })
+}
+
+/// Custom emoji reactions carry an `mxc://` key, so their `:shortcode:` goes alongside for
+/// clients that cannot show the image.
+fn build_reaction_content(event_id: &str, key: &str, shortcode: Option<&str>) -> Value {
+    let mut content = json!({
+        "m.relates_to": {
+            "rel_type": "m.annotation",
+            "event_id": event_id,
+            "key": key
+        }
+    });
+    if let Some(shortcode) = shortcode {
+        content[REACTION_SHORTCODE_KEY] = json!(shortcode);
+    }
+    content
+}
+
+/// Query string used to act as a namespaced user, optionally overriding the event timestamp
+/// (appservices only, see the `ts` parameter of the client-server API).
+fn as_user_query(user_id: &str, ts: Option<i64>) -> String {
+    let mut query = format!("user_id={}", encode_path_component(user_id));
+    if let Some(ts) = ts {
+        query.push_str(&format!("&ts={}", ts));
+    }
+    query
+}
+
+/// How long to wait before retrying, if the response is an `M_LIMIT_EXCEEDED` error.
+fn rate_limit_delay(response: &Value) -> Option<Duration> {
+    if response.get("errcode").and_then(Value::as_str) != Some("M_LIMIT_EXCEEDED") {
+        return None;
+    }
+    Some(
+        response
+            .get("retry_after_ms")
+            .and_then(Value::as_u64)
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_RETRY_AFTER),
+    )
+}
+
+fn check_response(response: Value) -> Result<Value> {
+    if let Some(errcode) = response.get("errcode").and_then(Value::as_str) {
+        let error = response
+            .get("error")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        return Err(BridgeError::Matrix(format!("{}: {}", errcode, error)));
+    }
+    Ok(response)
+}
+
 impl MatrixAppservice {
     pub async fn new(config: Arc<Config>) -> Result<Self> {
         info!(
@@ -263,6 +461,8 @@ impl MatrixAppservice {
             config,
             appservice,
             handler,
+            pacer: Arc::new(GhostPacer::new()),
+            http: reqwest::Client::new(),
         })
     }
 
@@ -294,6 +494,17 @@ impl MatrixAppservice {
         Ok(())
     }
 
+    pub async fn check_homeserver(&self) -> Result<()> {
+        let versions = self.appservice.client.get_server_versions().await?;
+        if versions.get("versions").is_none() {
+            return Err(BridgeError::Matrix(format!(
+                "unexpected /versions response: {}",
+                versions
+            )));
+        }
+        Ok(())
+    }
+
     pub async fn ensure_bot_joined_room(&self, room_id: &str) -> Result<bool> {
         let bot_user_id = self.bot_user_id();
         let membership = self
@@ -312,10 +523,10 @@ impl MatrixAppservice {
         match membership.as_deref() {
             Some("join") => Ok(false),
             Some("invite") => {
-                let joined = self.appservice.client.join_room(room_id).await?;
+                self.join_room_as(room_id, &bot_user_id).await?;
                 info!(
                     "auto-joined invited room {} as {}",
-                    joined, bot_user_id
+                    room_id, bot_user_id
                 );
                 Ok(true)
             }
@@ -350,8 +561,75 @@ impl MatrixAppservice {
             ..Default::default()
         };
 
-        let room_id = self.appservice.client.create_room(&opt).await?;
-        Ok(room_id)
+        self.create_room_with(&opt).await
+    }
+
+    /// Creates a private Space (a room with type `m.space`) owned by the bridge bot.
+    pub async fn create_space(&self, name: &str, topic: Option<&str>) -> Result<String> {
+        let opt = CreateRoom {
+            visibility: Some("private".to_string()),
+            name: Some(name.to_owned()),
+            topic: topic.map(ToOwned::to_owned),
+            creation_content: Some(json!({ "type": "m.space" })),
+            ..Default::default()
+        };
+
+        self.create_room_with(&opt).await
+    }
+
+    async fn create_room_with(&self, opt: &CreateRoom) -> Result<String> {
+        let body = serde_json::to_value(opt)?;
+        let response = self
+            .raw_json_as(
+                &self.bot_user_id(),
+                Method::POST,
+                "/_matrix/client/v3/createRoom",
+                Some(body),
+            )
+            .await?;
+
+        check_response(response)?
+            .get("room_id")
+            .and_then(Value::as_str)
+            .map(ToOwned::to_owned)
+            .ok_or_else(|| {
+                BridgeError::Matrix("missing room_id in createRoom response".to_string())
+            })
+    }
+
+    /// Uploads `bytes` to the media repository as the bridge bot and returns the `mxc://` URI.
+    pub async fn upload_media(
+        &self,
+        bytes: Vec<u8>,
+        content_type: &str,
+        filename: Option<&str>,
+    ) -> Result<String> {
+        let mut url =
+            Url::parse(&self.config.bridge.homeserver_url)?.join("_matrix/media/v3/upload")?;
+        if let Some(filename) = filename {
+            url.query_pairs_mut().append_pair("filename", filename);
+        }
+
+        let response = self
+            .http
+            .post(url)
+            .bearer_auth(&self.config.registration.appservice_token)
+            .header(reqwest::header::CONTENT_TYPE, content_type)
+            .body(bytes)
+            .send()
+            .await?;
+        let status = response.status();
+        let response: Value = response.json().await.map_err(|e| {
+            BridgeError::Matrix(format!("upload failed with status {}: {}", status, e))
+        })?;
+
+        check_response(response)?
+            .get("content_uri")
+            .and_then(Value::as_str)
+            .map(ToOwned::to_owned)
+            .ok_or_else(|| {
+                BridgeError::Matrix("missing content_uri in upload response".to_string())
+            })
     }
 
     pub async fn send_message(
@@ -360,30 +638,61 @@ impl MatrixAppservice {
         sender: &str,
         content: &str,
         formatted_content: Option<&str>,
+        mentions: &MessageMentions,
     ) -> Result<String> {
-        let matrix_content = build_matrix_message_content(content, formatted_content, None, None);
-        
-        let client = self.get_ghost_client(sender).await?;
-        let event_id = client.send_room_message(room_id, &matrix_content).await?;
-        
-        Ok(event_id)
+        let matrix_content =
+            build_matrix_message_content(content, formatted_content, mentions, None, None);
+        self.send_event_as(room_id, sender, "m.room.message", &matrix_content, None)
+            .await
     }
 
-    pub async fn send_message_with_reply(
+    /// Sends a message with its origin timestamp set to `ts` (milliseconds), used for backfill.
+    pub async fn send_message_at(
         &self,
         room_id: &str,
         sender: &str,
         content: &str,
         formatted_content: Option<&str>,
-        reply_to: &str,
+        mentions: &MessageMentions,
+        ts: i64,
     ) -> Result<String> {
         let matrix_content =
-            build_matrix_message_content(content, formatted_content, Some(reply_to), None);
+            build_matrix_message_content(content, formatted_content, mentions, None, None);
+        self.send_event_as(room_id, sender, "m.room.message", &matrix_content, Some(ts))
+            .await
+    }
 
-        let client = self.get_ghost_client(sender).await?;
-        let event_id = client.send_room_message(room_id, &matrix_content).await?;
+    /// Sends a media message built by [`build_media_content`] with its origin timestamp set to
+    /// `ts` (milliseconds), used for backfill.
+    pub async fn send_media_at(
+        &self,
+        room_id: &str,
+        sender: &str,
+        content: &Value,
+        ts: i64,
+    ) -> Result<String> {
+        self.send_event_as(room_id, sender, "m.room.message", content, Some(ts))
+            .await
+    }
 
-        Ok(event_id)
+    pub async fn send_message_with_reply(
+        &self,
+        room_id: &str,
+        sender: &str,
+        content: &str,
+        formatted_content: Option<&str>,
+        mentions: &MessageMentions,
+        reply_to: &str,
+    ) -> Result<String> {
+        let matrix_content = build_matrix_message_content(
+            content,
+            formatted_content,
+            mentions,
+            Some(reply_to),
+            None,
+        );
+        self.send_event_as(room_id, sender, "m.room.message", &matrix_content, None)
+            .await
     }
 
     pub async fn send_message_edit(
@@ -392,15 +701,18 @@ impl MatrixAppservice {
         sender: &str,
         content: &str,
         formatted_content: Option<&str>,
+        mentions: &MessageMentions,
         edit_of: &str,
     ) -> Result<String> {
-        let matrix_content =
-            build_matrix_message_content(content, formatted_content, None, Some(edit_of));
-
-        let client = self.get_ghost_client(sender).await?;
-        let event_id = client.send_room_message(room_id, &matrix_content).await?;
-
-        Ok(event_id)
+        let matrix_content = build_matrix_message_content(
+            content,
+            formatted_content,
+            mentions,
+            None,
+            Some(edit_of),
+        );
+        self.send_event_as(room_id, sender, "m.room.message", &matrix_content, None)
+            .await
     }
 
     pub async fn send_reaction(
@@ -409,21 +721,107 @@ impl MatrixAppservice {
         sender: &str,
         event_id: &str,
         key: &str,
+        shortcode: Option<&str>,
     ) -> Result<String> {
-        let content = json!({
-            "m.relates_to": {
-                "rel_type": "m.annotation",
-                "event_id": event_id,
-                "key": key
-            }
-        });
+        let content = build_reaction_content(event_id, key, shortcode);
+        self.send_event_as(room_id, sender, "m.reaction", &content, None)
+            .await
+    }
+
+    pub async fn send_reaction_at(
+        &self,
+        room_id: &str,
+        sender: &str,
+        event_id: &str,
+        key: &str,
+        shortcode: Option<&str>,
+        ts: i64,
+    ) -> Result<String> {
+        let content = build_reaction_content(event_id, key, shortcode);
+        self.send_event_as(room_id, sender, "m.reaction", &content, Some(ts))
+            .await
+    }
+
+    pub async fn send_notice(&self, room_id: &str, body: &str) -> Result<String> {
+        let content = json!({ "msgtype": "m.notice", "body": body });
+        self.send_event_as(room_id, &self.bot_user_id(), "m.room.message", &content, None)
+            .await
+    }
+
+    async fn send_event_as(
+        &self,
+        room_id: &str,
+        sender: &str,
+        event_type: &str,
+        content: &Value,
+        ts: Option<i64>,
+    ) -> Result<String> {
+        let endpoint = format!(
+            "/_matrix/client/v3/rooms/{}/send/{}/{}?{}",
+            encode_path_component(room_id),
+            encode_path_component(event_type),
+            uuid::Uuid::new_v4(),
+            as_user_query(sender, ts)
+        );
+
+        let response = self
+            .raw_json_as(sender, Method::PUT, &endpoint, Some(content.clone()))
+            .await?;
+
+        check_response(response)?
+            .get("event_id")
+            .and_then(Value::as_str)
+            .map(ToOwned::to_owned)
+            .ok_or_else(|| BridgeError::Matrix("missing event_id in send response".to_string()))
+    }
+
+    /// Registers a namespaced user through the appservice registration flow. Users that already
+    /// exist are not an error.
+    pub async fn register_ghost(&self, localpart: &str) -> Result<()> {
+        let response = self
+            .raw_json_as(
+                &self.bot_user_id(),
+                Method::POST,
+                "/_matrix/client/v3/register",
+                Some(json!({
+                    "type": "m.login.application_service",
+                    "username": localpart,
+                    "inhibit_login": true,
+                })),
+            )
+            .await?;
+
+        if response.get("errcode").and_then(Value::as_str) == Some("M_USER_IN_USE") {
+            return Ok(());
+        }
+        check_response(response)?;
+        Ok(())
+    }
 
-        let client = self.get_ghost_client(sender).await?;
-        let reaction_event_id = client
-            .send_room_event(room_id, "m.reaction", &content)
+    pub async fn set_display_name_as(&self, user_id: &str, display_name: &str) -> Result<()> {
+        let endpoint = format!(
+            "/_matrix/client/v3/profile/{}/displayname?{}",
+            encode_path_component(user_id),
+            as_user_query(user_id, None)
+        );
+        let response = self
+            .raw_json_as(user_id, Method::PUT, &endpoint, Some(json!({ "displayname": display_name })))
             .await?;
+        check_response(response)?;
+        Ok(())
+    }
 
-        Ok(reaction_event_id)
+    pub async fn join_room_as(&self, room_id: &str, user_id: &str) -> Result<()> {
+        let endpoint = format!(
+            "/_matrix/client/v3/join/{}?{}",
+            encode_path_component(room_id),
+            as_user_query(user_id, None)
+        );
+        let response = self
+            .raw_json_as(user_id, Method::POST, &endpoint, Some(json!({})))
+            .await?;
+        check_response(response)?;
+        Ok(())
     }
 
     pub async fn redact_event(
@@ -433,49 +831,211 @@ impl MatrixAppservice {
         event_id: &str,
         reason: Option<&str>,
     ) -> Result<()> {
-        let client = self.get_ghost_client(sender).await?;
-        client
-            .redact_room_event(room_id, event_id, reason)
+        let endpoint = format!(
+            "/_matrix/client/v3/rooms/{}/redact/{}/{}?{}",
+            encode_path_component(room_id),
+            encode_path_component(event_id),
+            uuid::Uuid::new_v4(),
+            as_user_query(sender, None)
+        );
+        let body = match reason {
+            Some(reason) => json!({ "reason": reason }),
+            None => json!({}),
+        };
+        let response = self
+            .raw_json_as(sender, Method::PUT, &endpoint, Some(body))
             .await?;
+        check_response(response)?;
         Ok(())
     }
 
-    pub async fn set_room_name(&self, room_id: &str, name: &str) -> Result<()> {
-        self.appservice.client.set_room_name(room_id, name).await?;
+    pub async fn set_room_state(
+        &self,
+        room_id: &str,
+        event_type: &str,
+        state_key: &str,
+        content: &Value,
+    ) -> Result<()> {
+        let endpoint = format!(
+            "/_matrix/client/v3/rooms/{}/state/{}/{}",
+            encode_path_component(room_id),
+            encode_path_component(event_type),
+            encode_path_component(state_key)
+        );
+        self.bot_request(Method::PUT, &endpoint, Some(content.clone()))
+            .await?;
         Ok(())
     }
 
+    pub async fn set_room_name(&self, room_id: &str, name: &str) -> Result<()> {
+        self.set_room_state(room_id, "m.room.name", "", &json!({ "name": name }))
+            .await
+    }
+
     pub async fn set_room_topic(&self, room_id: &str, topic: &str) -> Result<()> {
-        self.appservice.client.set_room_topic(room_id, topic).await?;
-        Ok(())
+        self.set_room_state(room_id, "m.room.topic", "", &json!({ "topic": topic }))
+            .await
     }
 
     pub async fn get_room_members(&self, room_id: &str) -> Result<Vec<String>> {
-        let members = self.appservice.client.get_room_members(room_id).await?;
+        let endpoint = format!(
+            "/_matrix/client/v3/rooms/{}/joined_members",
+            encode_path_component(room_id)
+        );
+        let response = self.bot_request(Method::GET, &endpoint, None).await?;
+        let members = response
+            .get("joined")
+            .and_then(Value::as_object)
+            .map(|joined| joined.keys().cloned().collect())
+            .unwrap_or_default();
         Ok(members)
     }
 
     pub async fn invite_user(&self, room_id: &str, user_id: &str) -> Result<()> {
-        self.appservice.client.invite_user(room_id, user_id).await?;
+        let endpoint = format!(
+            "/_matrix/client/v3/rooms/{}/invite",
+            encode_path_component(room_id)
+        );
+        self.bot_request(Method::POST, &endpoint, Some(json!({ "user_id": user_id })))
+            .await?;
         Ok(())
     }
 
     pub async fn kick_user(&self, room_id: &str, user_id: &str, reason: Option<&str>) -> Result<()> {
-        self.appservice
-            .client
-            .kick_user(room_id, user_id, reason)
-            .await?;
+        let endpoint = format!(
+            "/_matrix/client/v3/rooms/{}/kick",
+            encode_path_component(room_id)
+        );
+        let mut body = json!({ "user_id": user_id });
+        if let Some(reason) = reason {
+            body["reason"] = json!(reason);
+        }
+        self.bot_request(Method::POST, &endpoint, Some(body)).await?;
         Ok(())
     }
 
     pub async fn leave_room(&self, room_id: &str) -> Result<()> {
-        self.appservice.client.leave_room(room_id).await?;
+        self.leave_room_as(room_id, &self.bot_user_id()).await
+    }
+
+    pub async fn leave_room_as(&self, room_id: &str, user_id: &str) -> Result<()> {
+        let endpoint = format!(
+            "/_matrix/client/v3/rooms/{}/leave?{}",
+            encode_path_component(room_id),
+            as_user_query(user_id, None)
+        );
+        let response = self
+            .raw_json_as(user_id, Method::POST, &endpoint, Some(json!({})))
+            .await?;
+        check_response(response)?;
         Ok(())
     }
 
-    async fn get_ghost_client(&self, user_id: &str) -> Result<MatrixClient> {
-        let client = self.appservice.client.clone();
-        client.impersonate_user_id(Some(user_id), None::<&str>).await;
-        Ok(client)
+    /// Sends a request as the bridge bot and fails on an error response.
+    async fn bot_request(
+        &self,
+        method: Method,
+        endpoint: &str,
+        body: Option<Value>,
+    ) -> Result<Value> {
+        let response = self
+            .raw_json_as(&self.bot_user_id(), method, endpoint, body)
+            .await?;
+        check_response(response)
+    }
+
+    /// Sends a raw request on behalf of `user_id`. `M_LIMIT_EXCEEDED` answers are retried after
+    /// the homeserver's `retry_after_ms`, pausing only that user in the meantime. Endpoints with a
+    /// transaction id are safe to resend as is.
+    async fn raw_json_as(
+        &self,
+        user_id: &str,
+        method: Method,
+        endpoint: &str,
+        body: Option<Value>,
+    ) -> Result<Value> {
+        let mut attempts = 0;
+
+        loop {
+            self.pacer.wait(user_id).await;
+            let response = self
+                .appservice
+                .client
+                .raw_json(method.clone(), endpoint, body.clone())
+                .await?;
+
+            let Some(delay) = rate_limit_delay(&response) else {
+                return Ok(response);
+            };
+            attempts += 1;
+            if attempts > MAX_RATE_LIMIT_RETRIES {
+                return Ok(response);
+            }
+
+            warn!(
+                "rate limited as {}, retrying in {:?} (attempt {})",
+                user_id, delay, attempts
+            );
+            self.pacer.back_off(user_id, delay);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, FakeHomeserver};
+
+    async fn appservice(homeserver: &FakeHomeserver) -> MatrixAppservice {
+        let config = Arc::new(testing::test_config(homeserver).unwrap());
+        MatrixAppservice::new(config).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn room_helpers_fail_on_error_responses() {
+        let homeserver = FakeHomeserver::start().await.unwrap();
+        let matrix = appservice(&homeserver).await;
+        let ghost = matrix.ghost_user_id(7);
+
+        // The bot is not in a room someone else created, so it may not invite into it.
+        let foreign = homeserver.create_room("@alice:localhost");
+        assert!(matrix.invite_user(&foreign, &ghost).await.is_err());
+
+        let state = format!("/_matrix/client/v3/rooms/{}/state", foreign);
+        homeserver.fail_next(&state, 403, "M_FORBIDDEN", "Not allowed");
+        assert!(matrix.set_room_name(&foreign, "renamed").await.is_err());
+
+        homeserver.fail_next("/_matrix/client/v3/createRoom", 403, "M_FORBIDDEN", "No rooms");
+        assert!(matrix.create_room("general", None, None, false).await.is_err());
+        homeserver.fail_next("/_matrix/media/v3/upload", 413, "M_TOO_LARGE", "Too large");
+        let upload = matrix.upload_media(b"data".to_vec(), "text/plain", Some("a.txt")).await;
+        assert!(upload.is_err());
+    }
+
+    #[tokio::test]
+    async fn room_helpers_act_as_the_right_user() {
+        let homeserver = FakeHomeserver::start().await.unwrap();
+        let matrix = appservice(&homeserver).await;
+        let ghost = matrix.ghost_user_id(7);
+
+        let room_id = matrix.create_room("general", None, Some("chat"), false).await.unwrap();
+        matrix.invite_user(&room_id, &ghost).await.unwrap();
+        matrix.join_room_as(&room_id, &ghost).await.unwrap();
+        let mut members = matrix.get_room_members(&room_id).await.unwrap();
+        members.sort();
+        let mut expected = vec![matrix.bot_user_id(), ghost.clone()];
+        expected.sort();
+        assert_eq!(members, expected);
+
+        matrix.leave_room_as(&room_id, &ghost).await.unwrap();
+        let members = matrix.get_room_members(&room_id).await.unwrap();
+        assert_eq!(members, vec![matrix.bot_user_id()]);
+
+        let mxc_url = matrix
+            .upload_media(b"data".to_vec(), "text/plain", Some("a b.txt"))
+            .await
+            .unwrap();
+        let media = homeserver.media(&mxc_url).unwrap();
+        assert_eq!(media, ("text/plain".to_string(), b"data".to_vec()));
     }
 }
@@ -6,6 +6,7 @@ use tracing::{debug, info, warn};
 
 use super::MatrixEvent;
 use crate::utils::Result;
+use crate::utils::metrics::{self, Outcome};
 
 const DEFAULT_AGE_LIMIT_MS: i64 = 900_000;
 
@@ -134,6 +135,7 @@ impl MatrixEventProcessor {
 
     pub async fn process_event(&self, event: MatrixEvent) -> Result<()> {
         if !Self::check_event_age(&event, self.age_limit_ms) {
+            metrics::record_matrix_event(&event.event_type, Outcome::Skipped);
             return Ok(());
         }
 
@@ -142,34 +144,29 @@ impl MatrixEventProcessor {
             event.event_type, event.room_id, event.sender
         );
 
-        match event.event_type.as_str() {
-            "m.room.message" => {
-                self.event_handler.handle_room_message(&event).await?;
-            }
-            "m.room.member" => {
-                self.event_handler.handle_room_member(&event).await?;
-            }
-            "m.room.redaction" => {
-                self.event_handler.handle_room_redaction(&event).await?;
-            }
-            "m.reaction" => {
-                self.event_handler.handle_reaction(&event).await?;
-            }
-            "m.room.encryption" => {
-                self.event_handler.handle_room_encryption(&event).await?;
-            }
-            "m.room.name" => {
-                self.event_handler.handle_room_name(&event).await?;
-            }
-            "m.room.topic" => {
-                self.event_handler.handle_room_topic(&event).await?;
-            }
-            "m.room.avatar" => {
-                self.event_handler.handle_room_avatar(&event).await?;
-            }
+        let result = match event.event_type.as_str() {
+            "m.room.message" => self.event_handler.handle_room_message(&event).await,
+            "m.room.member" => self.event_handler.handle_room_member(&event).await,
+            "m.room.redaction" => self.event_handler.handle_room_redaction(&event).await,
+            "m.reaction" => self.event_handler.handle_reaction(&event).await,
+            "m.room.encryption" => self.event_handler.handle_room_encryption(&event).await,
+            "m.room.name" => self.event_handler.handle_room_name(&event).await,
+            "m.room.topic" => self.event_handler.handle_room_topic(&event).await,
+            "m.room.avatar" => self.event_handler.handle_room_avatar(&event).await,
             _ => {
                 debug!("ignoring event type: {}", event.event_type);
+                metrics::record_matrix_event(&event.event_type, Outcome::Ignored);
+                return Ok(());
             }
+        };
+
+        metrics::record_matrix_event(&event.event_type, Outcome::from_result(&result));
+        result?;
+
+        if event.is_message()
+            && let Some(ts) = event.timestamp
+        {
+            metrics::record_bridge_delay("matrix_to_zulip", metrics::delay_since_millis(ts));
         }
 
         Ok(())
@@ -8,6 +8,7 @@ use super::MatrixAppservice;
 use crate::db::stores::UserStore;
 use crate::db::models::{NewUserMapping, UserMapping, UserMappingChangeset};
 use crate::utils::Result;
+use crate::utils::metrics::{self, Outcome};
 
 const GHOST_USER_PREFIX: &str = "_zulip_";
 
@@ -68,25 +69,12 @@ impl GhostUserManager {
         }
 
         let localpart = format!("{}{}", GHOST_USER_PREFIX, zulip_user_id);
-        
-        let ghost_client = self.appservice.appservice.client.clone();
-        ghost_client
-            .impersonate_user_id(Some(&matrix_user_id), None::<&str>)
-            .await;
-
-        let register_result = ghost_client
-            .password_register(&localpart, "", display_name)
-            .await;
-
-        if register_result.is_err() {
-            debug!(
-                "ghost user {} may already exist, attempting login",
-                matrix_user_id
-            );
-        }
+        self.appservice.register_ghost(&localpart).await?;
 
-        if let Some(name) = display_name {
-            let _ = ghost_client.set_display_name(name).await;
+        if let Some(name) = display_name
+            && let Err(e) = self.appservice.set_display_name_as(&matrix_user_id, name).await
+        {
+            warn!("failed to set display name for {}: {}", matrix_user_id, e);
         }
 
         let new_mapping = NewUserMapping {
@@ -98,7 +86,9 @@ impl GhostUserManager {
             is_bot,
         };
 
-        self.user_store.create(new_mapping).await?;
+        let created = self.user_store.create(new_mapping).await;
+        metrics::record_ghost_created(Outcome::from_result(&created));
+        created?;
 
         let info = GhostUserInfo {
             zulip_user_id,
@@ -125,13 +115,10 @@ impl GhostUserManager {
     ) -> Result<()> {
         let matrix_user_id = self.ghost_user_id(zulip_user_id);
 
-        let ghost_client = self.appservice.appservice.client.clone();
-        ghost_client
-            .impersonate_user_id(Some(&matrix_user_id), None::<&str>)
-            .await;
-
         if let Some(name) = display_name {
-            ghost_client.set_display_name(name).await?;
+            self.appservice
+                .set_display_name_as(&matrix_user_id, name)
+                .await?;
         }
 
         if let Some(_url) = avatar_url {
@@ -150,7 +137,7 @@ impl GhostUserManager {
             .update_by_matrix_user(&matrix_user_id, changeset)
             .await?;
 
-        if let Some(mut cached) = self.cache.lock().get_mut(&zulip_user_id) {
+        if let Some(cached) = self.cache.lock().get_mut(&zulip_user_id) {
             if let Some(name) = display_name {
                 cached.display_name = Some(name.to_string());
             }
@@ -175,9 +162,10 @@ impl GhostUserManager {
         }
 
         self.appservice.invite_user(room_id, &matrix_user_id).await?;
+        self.appservice.join_room_as(room_id, &matrix_user_id).await?;
 
         debug!(
-            "invited ghost user {} to room {}",
+            "ghost user {} joined room {}",
             matrix_user_id, room_id
         );
 
@@ -191,12 +179,7 @@ impl GhostUserManager {
     ) -> Result<()> {
         let matrix_user_id = self.ghost_user_id(zulip_user_id);
 
-        let ghost_client = self.appservice.appservice.client.clone();
-        ghost_client
-            .impersonate_user_id(Some(&matrix_user_id), None::<&str>)
-            .await;
-
-        ghost_client.leave_room(room_id).await?;
+        self.appservice.leave_room_as(room_id, &matrix_user_id).await?;
 
         debug!(
             "ghost user {} left room {}",
@@ -228,12 +211,11 @@ impl GhostUserManager {
         }
 
         let prefix = format!("@{}", GHOST_USER_PREFIX);
-        if let Some(rest) = matrix_user_id.strip_prefix(&prefix) {
-            if let Some(localpart) = rest.split(':').next() {
-                if let Ok(id) = localpart.parse::<i64>() {
-                    return Ok(Some(id));
-                }
-            }
+        if let Some(rest) = matrix_user_id.strip_prefix(&prefix)
+            && let Some(localpart) = rest.split(':').next()
+            && let Ok(id) = localpart.parse::<i64>()
+        {
+            return Ok(Some(id));
         }
 
         Ok(None)
@@ -0,0 +1,40 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// Tracks homeserver rate limits per user, so one ghost hitting `M_LIMIT_EXCEEDED` during a
+/// backfill only slows down that ghost and not the rest of the bridge.
+#[derive(Default)]
+pub struct GhostPacer {
+    blocked_until: Mutex<HashMap<String, Instant>>,
+}
+
+impl GhostPacer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Waits until `user_id` may send again.
+    pub async fn wait(&self, user_id: &str) {
+        let until = {
+            let now = Instant::now();
+            let mut blocked = self.blocked_until.lock();
+            blocked.retain(|_, until| *until > now);
+            blocked.get(user_id).copied()
+        };
+
+        if let Some(until) = until {
+            tokio::time::sleep_until(until.into()).await;
+        }
+    }
+
+    pub fn back_off(&self, user_id: &str, delay: Duration) {
+        let until = Instant::now() + delay;
+        self.blocked_until
+            .lock()
+            .entry(user_id.to_string())
+            .and_modify(|current| *current = (*current).max(until))
+            .or_insert(until);
+    }
+}
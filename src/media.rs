@@ -1,7 +1,635 @@
-pub struct MediaHandler;
+pub mod container;
+pub mod thumbnail;
+
+use bytes::Bytes;
+use matrix_bot_sdk::client::encode_path_component;
+use reqwest::StatusCode;
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::db::models::{MediaCacheEntry, MediaDirection, NewMediaCacheEntry};
+use crate::db::stores::MediaCacheStore;
+use crate::matrix::{MatrixAppservice, MatrixEvent};
+use crate::utils::{BridgeError, Result};
+use crate::zulip::ZulipClient;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Content fetched from the Matrix media repository.
+#[derive(Debug, Clone)]
+pub struct MatrixMedia {
+    pub content: Bytes,
+    /// As reported by the homeserver, if at all.
+    pub content_type: Option<String>,
+}
+
+/// A Zulip upload stored in the Matrix media repository.
+#[derive(Debug, Clone)]
+pub struct MatrixUpload {
+    pub mxc_url: String,
+    pub content_type: String,
+    pub size: u64,
+    pub metadata: MediaMetadata,
+}
+
+/// What local analysis found out about a file, for the `info` of its Matrix event.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MediaMetadata {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub duration_ms: Option<u64>,
+    pub blurhash: Option<String>,
+    pub thumbnail: Option<MatrixThumbnail>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MatrixThumbnail {
+    pub mxc_url: String,
+    pub content_type: String,
+    pub size: u64,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl MatrixUpload {
+    fn from_cache(entry: MediaCacheEntry) -> Self {
+        Self {
+            mxc_url: entry.target_url,
+            content_type: entry.content_type,
+            size: entry.size as u64,
+            metadata: cached_metadata(entry.metadata.as_deref()),
+        }
+    }
+
+    /// The `info` object of a media event carrying this upload.
+    pub fn info(&self) -> Value {
+        let metadata = &self.metadata;
+        let mut info = json!({
+            "mimetype": self.content_type,
+            "size": self.size,
+        });
+        if let (Some(width), Some(height)) = (metadata.width, metadata.height) {
+            info["w"] = json!(width);
+            info["h"] = json!(height);
+        }
+        if let Some(duration) = metadata.duration_ms {
+            info["duration"] = json!(duration);
+        }
+        if let Some(blurhash) = &metadata.blurhash {
+            info["xyz.amorgan.blurhash"] = json!(blurhash);
+        }
+        if let Some(thumbnail) = &metadata.thumbnail {
+            info["thumbnail_url"] = json!(thumbnail.mxc_url);
+            info["thumbnail_info"] = json!({
+                "mimetype": thumbnail.content_type,
+                "size": thumbnail.size,
+                "w": thumbnail.width,
+                "h": thumbnail.height,
+            });
+        }
+        info
+    }
+}
+
+/// Moves media between Matrix and Zulip. Transfers are held in memory, so every one is capped
+/// at `limits.max_media_size`.
+///
+/// Every transfer is remembered per organization by its source URL and content hash, so a file
+/// that comes up again, under the same URL or reposted under a new one, is not uploaded twice.
+/// The cache only forgets the mapping; uploaded files are never deleted.
+pub struct MediaHandler {
+    matrix: Arc<MatrixAppservice>,
+    cache: Arc<dyn MediaCacheStore>,
+    http: reqwest::Client,
+    homeserver_url: String,
+    access_token: String,
+    max_size: u64,
+    cache_size: u64,
+}
 
 impl MediaHandler {
-    pub fn new(_homeserver_url: &str) -> Self {
-        Self
+    pub fn new(
+        config: &Config,
+        matrix: Arc<MatrixAppservice>,
+        cache: Arc<dyn MediaCacheStore>,
+    ) -> Self {
+        Self {
+            matrix,
+            cache,
+            // No overall timeout, which would cut off large files on slow links; a source that
+            // stops sending fails on the read timeout instead.
+            http: reqwest::Client::builder()
+                .connect_timeout(CONNECT_TIMEOUT)
+                .read_timeout(READ_TIMEOUT)
+                .build()
+                .expect("failed to build the media HTTP client"),
+            homeserver_url: config.bridge.homeserver_url.trim_end_matches('/').to_string(),
+            access_token: config.registration.appservice_token.clone(),
+            max_size: config.limits.max_media_size,
+            cache_size: config.limits.media_cache_size,
+        }
+    }
+
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    /// Downloads `mxc://server/media_id` through the authenticated media API, falling back to
+    /// the legacy endpoint on homeservers that do not have it yet.
+    pub async fn download(&self, mxc_url: &str) -> Result<MatrixMedia> {
+        let (server, media_id) = mxc_url
+            .strip_prefix("mxc://")
+            .and_then(|rest| rest.split_once('/'))
+            .ok_or_else(|| BridgeError::Parse(format!("invalid mxc URI {}", mxc_url)))?;
+        let path = format!(
+            "{}/{}",
+            encode_path_component(server),
+            encode_path_component(media_id)
+        );
+
+        let response = self
+            .get(&format!("_matrix/client/v1/media/download/{}", path))
+            .await?;
+        let response = match response.status() {
+            StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED => {
+                let status = response.status();
+                let errcode = response
+                    .json::<Value>()
+                    .await
+                    .ok()
+                    .and_then(|body| body.get("errcode")?.as_str().map(ToOwned::to_owned));
+                if status == StatusCode::NOT_FOUND && errcode.as_deref() != Some("M_UNRECOGNIZED")
+                {
+                    return Err(BridgeError::Matrix(format!("media {} not found", mxc_url)));
+                }
+                debug!("authenticated media unsupported, downloading {} unauthenticated", mxc_url);
+                self.get(&format!("_matrix/media/v3/download/{}", path)).await?
+            }
+            _ => response,
+        };
+
+        if !response.status().is_success() {
+            return Err(BridgeError::Matrix(format!(
+                "downloading {} failed with status {}",
+                mxc_url,
+                response.status()
+            )));
+        }
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(ToOwned::to_owned);
+        let content = read_limited(response, self.max_size).await?;
+
+        Ok(MatrixMedia {
+            content,
+            content_type,
+        })
+    }
+
+    /// Re-uploads the file of a Matrix media message to Zulip and returns the message to post:
+    /// the caption, if any, followed by a link to the upload, which Zulip previews inline for
+    /// images. `caption` is the already converted Zulip Markdown of the event's caption. Voice
+    /// messages are captioned with their duration instead.
+    pub async fn upload_to_zulip(
+        &self,
+        client: &ZulipClient,
+        organization_id: &str,
+        event: &MatrixEvent,
+        caption: Option<&str>,
+    ) -> Result<String> {
+        let mxc_url = event.media_url().ok_or_else(|| {
+            BridgeError::NotImplemented("media messages without an unencrypted url".to_string())
+        })?;
+        let name = event
+            .media_file_name()
+            .map(sanitize_file_name)
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| "file".to_string());
+        let direction = MediaDirection::MatrixToZulip;
+
+        let (uri, content_type, metadata) =
+            match self.cached(organization_id, direction, mxc_url).await {
+                Some(entry) => {
+                    let metadata = cached_metadata(entry.metadata.as_deref());
+                    (entry.target_url, entry.content_type, metadata)
+                }
+                None => {
+                    if let Some(size) = event.media_info("size").and_then(Value::as_u64) {
+                        check_size(size, self.max_size)?;
+                    }
+                    let media = self.download(mxc_url).await?;
+                    let content_type = event
+                        .media_info("mimetype")
+                        .and_then(Value::as_str)
+                        .or(media.content_type.as_deref())
+                        .unwrap_or_else(|| content_type_for(&name))
+                        .to_string();
+                    let metadata = MediaMetadata {
+                        duration_ms: event
+                            .is_voice_message()
+                            .then(|| container::probe(&media.content).duration_ms)
+                            .flatten(),
+                        ..Default::default()
+                    };
+
+                    let content_hash = content_hash(&media.content);
+                    let size = media.content.len() as i64;
+                    let uri = match self
+                        .cached_content(organization_id, direction, &content_hash)
+                        .await
+                    {
+                        Some(entry) => entry.target_url,
+                        None => {
+                            let file_name = file_name_for(&name, &content_type);
+                            client
+                                .upload_bytes(media.content, &file_name, &content_type)
+                                .await?
+                        }
+                    };
+                    self.remember(NewMediaCacheEntry {
+                        organization_id: organization_id.to_string(),
+                        direction: direction.as_str().to_string(),
+                        source_url: mxc_url.to_string(),
+                        content_hash,
+                        target_url: uri.clone(),
+                        content_type: content_type.clone(),
+                        size,
+                        metadata: serde_json::to_string(&metadata).ok(),
+                    })
+                    .await;
+                    (uri, content_type, metadata)
+                }
+            };
+
+        let file_name = file_name_for(&name, &content_type);
+        let voice_caption = event.is_voice_message().then(|| {
+            voice_message_caption(event.media_duration_ms().or(metadata.duration_ms))
+        });
+        Ok(zulip_upload_message(
+            &file_name,
+            &uri,
+            voice_caption.as_deref().or(caption),
+        ))
+    }
+
+    /// Copies a Zulip upload or emoji, given its `/user_uploads/...` path or absolute URL, to
+    /// the Matrix media repository. The type Zulip serves it with wins over the one `file_name`
+    /// suggests. Images are decoded for their size, BlurHash and thumbnail, audio and video
+    /// containers are read for their duration; analysis that fails only leaves the metadata out.
+    pub async fn upload_to_matrix(
+        &self,
+        client: &ZulipClient,
+        organization_id: &str,
+        path_or_url: &str,
+        file_name: &str,
+    ) -> Result<MatrixUpload> {
+        let source_url = client.file_url(path_or_url)?.to_string();
+        let direction = MediaDirection::ZulipToMatrix;
+        if let Some(entry) = self.cached(organization_id, direction, &source_url).await {
+            return Ok(MatrixUpload::from_cache(entry));
+        }
+
+        let (content, content_type) = self
+            .download_from_zulip(client, &source_url, file_name)
+            .await?;
+        let content_hash = content_hash(&content);
+        let upload = match self
+            .cached_content(organization_id, direction, &content_hash)
+            .await
+        {
+            Some(entry) => MatrixUpload::from_cache(entry),
+            None => {
+                let metadata = self.analyze(content.clone(), &content_type).await;
+                self.upload(content, content_type, file_name, metadata)
+                    .await?
+            }
+        };
+
+        self.remember(NewMediaCacheEntry {
+            organization_id: organization_id.to_string(),
+            direction: direction.as_str().to_string(),
+            source_url,
+            content_hash,
+            target_url: upload.mxc_url.clone(),
+            content_type: upload.content_type.clone(),
+            size: upload.size as i64,
+            metadata: serde_json::to_string(&upload.metadata).ok(),
+        })
+        .await;
+        Ok(upload)
+    }
+
+    /// The earlier transfer of `source_url`, if remembered. The cache is an optimization, so
+    /// failing to read it only means transferring again.
+    async fn cached(
+        &self,
+        organization_id: &str,
+        direction: MediaDirection,
+        source_url: &str,
+    ) -> Option<MediaCacheEntry> {
+        if self.cache_size == 0 {
+            return None;
+        }
+        let entry = match self
+            .cache
+            .get_by_source(organization_id, direction, source_url)
+            .await
+        {
+            Ok(entry) => entry?,
+            Err(e) => {
+                warn!("failed to look up {} in the media cache: {}", source_url, e);
+                return None;
+            }
+        };
+        if let Err(e) = self.cache.touch(entry.id).await {
+            warn!("failed to touch media cache entry {}: {}", entry.id, e);
+        }
+        debug!("reusing {} for {}", entry.target_url, source_url);
+        Some(entry)
+    }
+
+    /// An earlier transfer of the same content under another source URL.
+    async fn cached_content(
+        &self,
+        organization_id: &str,
+        direction: MediaDirection,
+        content_hash: &str,
+    ) -> Option<MediaCacheEntry> {
+        if self.cache_size == 0 {
+            return None;
+        }
+        match self
+            .cache
+            .get_by_hash(organization_id, direction, content_hash)
+            .await
+        {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("failed to look up content in the media cache: {}", e);
+                None
+            }
+        }
+    }
+
+    async fn remember(&self, entry: NewMediaCacheEntry) {
+        if self.cache_size == 0 {
+            return;
+        }
+        let source_url = entry.source_url.clone();
+        if let Err(e) = self.cache.upsert(entry).await {
+            warn!("failed to cache transfer of {}: {}", source_url, e);
+            return;
+        }
+        let max_size = i64::try_from(self.cache_size).unwrap_or(i64::MAX);
+        match self.cache.evict(max_size).await {
+            Ok(0) => {}
+            Ok(evicted) => debug!("evicted {} media cache entries", evicted),
+            Err(e) => warn!("failed to evict media cache entries: {}", e),
+        }
     }
+
+    async fn download_from_zulip(
+        &self,
+        client: &ZulipClient,
+        path_or_url: &str,
+        file_name: &str,
+    ) -> Result<(Bytes, String)> {
+        let file = client.download_file(path_or_url, self.max_size).await?;
+        let content_type = file
+            .content_type
+            .as_deref()
+            .and_then(|content_type| content_type.split(';').next())
+            .map(str::trim)
+            .filter(|content_type| {
+                !content_type.is_empty() && *content_type != "application/octet-stream"
+            })
+            .map(ToOwned::to_owned)
+            .unwrap_or_else(|| content_type_for(file_name).to_string());
+        Ok((file.content, content_type))
+    }
+
+    async fn upload(
+        &self,
+        content: Bytes,
+        content_type: String,
+        file_name: &str,
+        metadata: MediaMetadata,
+    ) -> Result<MatrixUpload> {
+        let size = content.len() as u64;
+        let mxc_url = self
+            .matrix
+            .upload_media(content.to_vec(), &content_type, Some(file_name))
+            .await?;
+        debug!("uploaded {} as {}", file_name, mxc_url);
+        Ok(MatrixUpload {
+            mxc_url,
+            content_type,
+            size,
+            metadata,
+        })
+    }
+
+    async fn analyze(&self, content: Bytes, content_type: &str) -> MediaMetadata {
+        let mut metadata = MediaMetadata::default();
+        if content_type.starts_with("image/") {
+            let image = tokio::task::spawn_blocking(move || thumbnail::analyze(&content))
+                .await
+                .ok()
+                .flatten();
+            let Some(image) = image else {
+                return metadata;
+            };
+            metadata.width = Some(image.width);
+            metadata.height = Some(image.height);
+            metadata.blurhash = image.blurhash;
+            if let Some(thumbnail) = image.thumbnail {
+                metadata.thumbnail = self.upload_thumbnail(thumbnail).await;
+            }
+        } else if content_type.starts_with("video/") || content_type.starts_with("audio/") {
+            let info = container::probe(&content);
+            metadata.width = info.width;
+            metadata.height = info.height;
+            metadata.duration_ms = info.duration_ms;
+        }
+        metadata
+    }
+
+    async fn upload_thumbnail(&self, thumbnail: thumbnail::Thumbnail) -> Option<MatrixThumbnail> {
+        let size = thumbnail.content.len() as u64;
+        match self
+            .matrix
+            .upload_media(thumbnail.content, thumbnail.content_type, None)
+            .await
+        {
+            Ok(mxc_url) => Some(MatrixThumbnail {
+                mxc_url,
+                content_type: thumbnail.content_type.to_string(),
+                size,
+                width: thumbnail.width,
+                height: thumbnail.height,
+            }),
+            Err(e) => {
+                warn!("failed to upload thumbnail: {}", e);
+                None
+            }
+        }
+    }
+
+    async fn get(&self, path: &str) -> Result<reqwest::Response> {
+        let url = format!("{}/{}", self.homeserver_url, path);
+        debug!("GET {}", url);
+        self.http
+            .get(url)
+            .bearer_auth(&self.access_token)
+            .send()
+            .await
+            .map_err(|e| BridgeError::Network(e.to_string()))
+    }
+}
+
+/// Reads the body chunk by chunk so an oversized download is abandoned as soon as it crosses
+/// `max_size`, even when the server does not announce its length.
+pub(crate) async fn read_limited(mut response: reqwest::Response, max_size: u64) -> Result<Bytes> {
+    if let Some(length) = response.content_length() {
+        check_size(length, max_size)?;
+    }
+
+    let mut content = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|e| BridgeError::Network(e.to_string()))?
+    {
+        check_size((content.len() + chunk.len()) as u64, max_size)?;
+        content.extend_from_slice(&chunk);
+    }
+    Ok(content.into())
+}
+
+fn check_size(size: u64, limit: u64) -> Result<()> {
+    if size > limit {
+        return Err(BridgeError::MediaTooLarge { size, limit });
+    }
+    Ok(())
+}
+
+/// MIME type for a file, judged by its extension. Unknown types are sent as opaque bytes.
+pub fn content_type_for(file_name: &str) -> &'static str {
+    let extension = file_name
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_ascii_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "jpg" | "jpeg" => "image/jpeg",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mov" => "video/quicktime",
+        "mp3" => "audio/mpeg",
+        "ogg" | "oga" | "opus" => "audio/ogg",
+        "m4a" => "audio/mp4",
+        "wav" => "audio/wav",
+        "pdf" => "application/pdf",
+        "txt" => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Hex SHA-256 of a file, the key the cache recognizes reposted content by.
+fn content_hash(content: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(content))
+}
+
+/// Entries written before metadata was recorded, or unreadable ones, simply carry none.
+fn cached_metadata(metadata: Option<&str>) -> MediaMetadata {
+    metadata
+        .and_then(|metadata| serde_json::from_str(metadata).ok())
+        .unwrap_or_default()
+}
+
+/// Names without an extension get the usual one for their type, so Zulip recognizes them.
+fn file_name_for(name: &str, content_type: &str) -> String {
+    match extension_for(content_type) {
+        Some(extension) if !name.contains('.') => format!("{}.{}", name, extension),
+        _ => name.to_string(),
+    }
+}
+
+/// The usual extension for a MIME type, for files sent without a name.
+fn extension_for(content_type: &str) -> Option<&'static str> {
+    let extension = match content_type.split(';').next()?.trim() {
+        "image/png" => "png",
+        "image/gif" => "gif",
+        "image/jpeg" => "jpg",
+        "image/webp" => "webp",
+        "video/mp4" => "mp4",
+        "video/webm" => "webm",
+        "video/quicktime" => "mov",
+        "audio/mpeg" => "mp3",
+        "audio/ogg" => "ogg",
+        "audio/mp4" => "m4a",
+        "audio/wav" => "wav",
+        "application/pdf" => "pdf",
+        "text/plain" => "txt",
+        _ => return None,
+    };
+    Some(extension)
+}
+
+fn voice_message_caption(duration_ms: Option<u64>) -> String {
+    match duration_ms {
+        Some(duration) => format!("Voice message ({})", format_duration(duration)),
+        None => "Voice message".to_string(),
+    }
+}
+
+/// `m:ss`, or `h:mm:ss` past an hour.
+fn format_duration(duration_ms: u64) -> String {
+    let seconds = duration_ms / 1000;
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
+
+/// Zulip Markdown linking an upload, below its caption. The link text is the file name with
+/// the characters that would end the link escaped.
+pub fn zulip_upload_message(file_name: &str, uri: &str, caption: Option<&str>) -> String {
+    let name = file_name
+        .replace('\\', "\\\\")
+        .replace('[', "\\[")
+        .replace(']', "\\]");
+    let link = format!("[{}]({})", name, uri);
+    match caption.map(str::trim).filter(|caption| !caption.is_empty()) {
+        Some(caption) => format!("{}\n{}", caption, link),
+        None => link,
+    }
+}
+
+/// Keeps only the last path component and drops control characters, since the name comes
+/// from the sender.
+fn sanitize_file_name(name: &str) -> String {
+    name.rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect::<String>()
+        .trim()
+        .to_string()
 }
@@ -0,0 +1,527 @@
+//! Just enough of the common audio and video containers to read a file's duration (and, for
+//! MP4, its picture size) without decoding it. MP3 has no container to read this from and is
+//! left alone.
+
+/// What the container headers say about a file.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ContainerInfo {
+    pub duration_ms: Option<u64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+/// Probes MP4/QuickTime, Ogg (Opus and Vorbis), WebM/Matroska and WAV by their signature.
+pub fn probe(content: &[u8]) -> ContainerInfo {
+    if content.get(4..8) == Some(b"ftyp") {
+        mp4(content)
+    } else if content.starts_with(b"OggS") {
+        ContainerInfo {
+            duration_ms: ogg_duration(content),
+            ..Default::default()
+        }
+    } else if content.starts_with(&[0x1a, 0x45, 0xdf, 0xa3]) {
+        ContainerInfo {
+            duration_ms: matroska_duration(content),
+            ..Default::default()
+        }
+    } else if content.starts_with(b"RIFF") && content.get(8..12) == Some(b"WAVE") {
+        ContainerInfo {
+            duration_ms: wav_duration(content),
+            ..Default::default()
+        }
+    } else {
+        ContainerInfo::default()
+    }
+}
+
+fn be_u16(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(bytes.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn be_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn be_u64(bytes: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(bytes.get(at..at + 8)?.try_into().ok()?))
+}
+
+fn le_u16(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn le_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn le_u64(bytes: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(at..at + 8)?.try_into().ok()?))
+}
+
+/// Duration from the movie header, size from the first track header with a picture.
+fn mp4(content: &[u8]) -> ContainerInfo {
+    let mut info = ContainerInfo::default();
+    let Some(moov) = mp4_box(content, b"moov") else {
+        return info;
+    };
+
+    if let Some(mvhd) = mp4_box(moov, b"mvhd") {
+        let (timescale, duration) = match mvhd.first() {
+            Some(1) => (be_u32(mvhd, 20), be_u64(mvhd, 24)),
+            _ => (be_u32(mvhd, 12), be_u32(mvhd, 16).map(u64::from)),
+        };
+        info.duration_ms = match (timescale, duration) {
+            (Some(timescale), Some(duration)) if timescale > 0 && duration != u64::MAX => {
+                Some(duration.saturating_mul(1000) / u64::from(timescale))
+            }
+            _ => None,
+        };
+    }
+
+    for trak in mp4_boxes(moov).filter(|(kind, _)| kind == b"trak") {
+        // Width and height close the track header as 16.16 fixed point numbers.
+        let Some(tkhd) = mp4_box(trak.1, b"tkhd") else {
+            continue;
+        };
+        let (Some(width), Some(height)) = (
+            tkhd.len().checked_sub(8).and_then(|at| be_u16(tkhd, at)),
+            tkhd.len().checked_sub(4).and_then(|at| be_u16(tkhd, at)),
+        ) else {
+            continue;
+        };
+        if width > 0 && height > 0 {
+            info.width = Some(width.into());
+            info.height = Some(height.into());
+            break;
+        }
+    }
+    info
+}
+
+fn mp4_box<'a>(content: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    mp4_boxes(content)
+        .find(|(box_kind, _)| box_kind == kind)
+        .map(|(_, payload)| payload)
+}
+
+/// The `(type, payload)` boxes directly inside `content`.
+fn mp4_boxes(content: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    let mut at = 0;
+    std::iter::from_fn(move || {
+        // A 64-bit size can carry `at` to the end of the address space.
+        if at >= content.len() {
+            return None;
+        }
+        let size = be_u32(content, at)?;
+        let kind: [u8; 4] = content.get(at + 4..at + 8)?.try_into().ok()?;
+        let (header, size) = match size {
+            0 => (8, content.len() - at),
+            1 => (16, usize::try_from(be_u64(content, at + 8)?).ok()?),
+            size => (8, size as usize),
+        };
+        if size < header {
+            return None;
+        }
+        let payload = content.get(at + header..at.checked_add(size)?.min(content.len()))?;
+        at += size;
+        Some((kind, payload))
+    })
+}
+
+/// The granule position of the last page counts samples at the codec's rate; Opus starts
+/// counting before `pre_skip` samples that are never played.
+fn ogg_duration(content: &[u8]) -> Option<u64> {
+    let segments = *content.get(26)? as usize;
+    let header = content.get(27 + segments..)?;
+    let (rate, pre_skip) = if header.starts_with(b"OpusHead") {
+        (48_000, u64::from(le_u16(header, 10)?))
+    } else if header.starts_with(b"\x01vorbis") {
+        (u64::from(le_u32(header, 12)?), 0)
+    } else {
+        return None;
+    };
+
+    let last_page = content.windows(4).rposition(|window| window == b"OggS")?;
+    let granule = le_u64(content, last_page + 6)?;
+    if rate == 0 || granule == u64::MAX {
+        return None;
+    }
+    Some(granule.saturating_sub(pre_skip).saturating_mul(1000) / rate)
+}
+
+fn wav_duration(content: &[u8]) -> Option<u64> {
+    let mut byte_rate = None;
+    let mut at = 12;
+    while let Some(size) = le_u32(content, at + 4) {
+        match content.get(at..at + 4)? {
+            b"fmt " => byte_rate = le_u32(content, at + 16),
+            b"data" => {
+                let byte_rate = u64::from(byte_rate.filter(|rate| *rate > 0)?);
+                return Some(u64::from(size).saturating_mul(1000) / byte_rate);
+            }
+            _ => {}
+        }
+        // Chunks are padded to an even length.
+        at += 8 + size as usize + (size as usize & 1);
+    }
+    None
+}
+
+const EBML_SEGMENT: u64 = 0x1853_8067;
+const EBML_INFO: u64 = 0x1549_a966;
+const EBML_TIMECODE_SCALE: u64 = 0x2a_d7b1;
+const EBML_DURATION: u64 = 0x4489;
+const EBML_CLUSTER: u64 = 0x1f43_b675;
+
+/// `Segment > Info > Duration`, counted in `TimecodeScale` nanoseconds (1ms by default).
+fn matroska_duration(content: &[u8]) -> Option<u64> {
+    let (_, header, size) = ebml_element(content, 0)?;
+    let mut at = size.map_or(content.len(), |size| header + size);
+
+    // The Info element sits near the start of the segment, before any cluster.
+    while at < content.len() {
+        let (id, header, size) = ebml_element(content, at)?;
+        if id == EBML_SEGMENT {
+            at += header;
+            continue;
+        }
+        if id == EBML_CLUSTER {
+            return None;
+        }
+        let size = size?;
+        if id == EBML_INFO {
+            return matroska_info_duration(content.get(at + header..at + header + size)?);
+        }
+        at += header + size;
+    }
+    None
+}
+
+fn matroska_info_duration(info: &[u8]) -> Option<u64> {
+    let mut scale = 1_000_000u64;
+    let mut duration = None;
+    let mut at = 0;
+    while at < info.len() {
+        let (id, header, size) = ebml_element(info, at)?;
+        let size = size?;
+        let data = info.get(at + header..at + header + size)?;
+        match id {
+            EBML_TIMECODE_SCALE => {
+                scale = data.iter().fold(0, |value, byte| (value << 8) | u64::from(*byte));
+            }
+            EBML_DURATION => {
+                duration = match data.len() {
+                    4 => Some(f64::from(f32::from_be_bytes(data.try_into().ok()?))),
+                    8 => Some(f64::from_be_bytes(data.try_into().ok()?)),
+                    _ => None,
+                };
+            }
+            _ => {}
+        }
+        at += header + size;
+    }
+
+    let duration = duration.filter(|duration| duration.is_finite() && *duration >= 0.0)?;
+    Some((duration * scale as f64 / 1_000_000.0) as u64)
+}
+
+/// The id, header length and data size (`None` when unknown) of the element at `at`.
+fn ebml_element(content: &[u8], at: usize) -> Option<(u64, usize, Option<usize>)> {
+    let (id, id_length) = ebml_vint(content, at)?;
+    let (size, size_length) = ebml_vint(content, at + id_length)?;
+    // Ids keep their length marker; sizes drop it, and all ones means unknown.
+    let id = id | (1 << (7 * id_length));
+    let unknown = size == (1 << (7 * size_length)) - 1;
+    let size = if unknown {
+        None
+    } else {
+        Some(usize::try_from(size).ok()?)
+    };
+    Some((id, id_length + size_length, size))
+}
+
+fn ebml_vint(content: &[u8], at: usize) -> Option<(u64, usize)> {
+    let first = *content.get(at)?;
+    let length = first.leading_zeros() as usize + 1;
+    if length > 8 {
+        return None;
+    }
+    let mut value = u64::from(first) & (0xff >> length);
+    for byte in content.get(at + 1..at + length)? {
+        value = (value << 8) | u64::from(*byte);
+    }
+    Some((value, length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mp4_box(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut data = (8 + payload.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(kind);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    /// Version 0 movie header: `timescale` units per second, `duration` of them.
+    fn mvhd(timescale: u32, duration: u32) -> Vec<u8> {
+        let mut payload = vec![0; 12];
+        payload.extend_from_slice(&timescale.to_be_bytes());
+        payload.extend_from_slice(&duration.to_be_bytes());
+        payload.resize(100, 0);
+        mp4_box(b"mvhd", &payload)
+    }
+
+    /// Track header closing with the 16.16 fixed point picture size.
+    fn trak(width: u16, height: u16) -> Vec<u8> {
+        let mut tkhd = vec![0; 76];
+        tkhd.extend_from_slice(&[(width >> 8) as u8, width as u8, 0, 0]);
+        tkhd.extend_from_slice(&[(height >> 8) as u8, height as u8, 0, 0]);
+        mp4_box(b"trak", &mp4_box(b"tkhd", &tkhd))
+    }
+
+    fn mp4(moov: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = mp4_box(b"ftyp", b"isom\0\0\0\0isom");
+        data.extend(mp4_box(b"moov", &moov.concat()));
+        data
+    }
+
+    fn ogg_page(granule: u64, packet: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS\0\x02".to_vec();
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&[0; 12]);
+        page.push(1);
+        page.push(packet.len() as u8);
+        page.extend_from_slice(packet);
+        page
+    }
+
+    fn opus(pre_skip: u16, granule: u64) -> Vec<u8> {
+        let mut head = b"OpusHead\x01\x02".to_vec();
+        head.extend_from_slice(&pre_skip.to_le_bytes());
+        head.extend_from_slice(&48_000u32.to_le_bytes());
+        head.extend_from_slice(&[0, 0, 0]);
+        let mut data = ogg_page(0, &head);
+        data.extend(ogg_page(granule, b"audio"));
+        data
+    }
+
+    fn vorbis(rate: u32, granule: u64) -> Vec<u8> {
+        let mut head = b"\x01vorbis\0\0\0\0\x02".to_vec();
+        head.extend_from_slice(&rate.to_le_bytes());
+        head.extend_from_slice(&[0; 13]);
+        let mut data = ogg_page(0, &head);
+        data.extend(ogg_page(granule, b"audio"));
+        data
+    }
+
+    fn riff_chunk(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut data = kind.to_vec();
+        data.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        data.extend_from_slice(payload);
+        if payload.len() % 2 == 1 {
+            data.push(0);
+        }
+        data
+    }
+
+    /// A WAV file whose data chunk claims `data_size` bytes, of which only a few are there.
+    fn wav(byte_rate: u32, chunks: &[Vec<u8>], data_size: u32) -> Vec<u8> {
+        let mut fmt = vec![1, 0, 2, 0];
+        fmt.extend_from_slice(&44_100u32.to_le_bytes());
+        fmt.extend_from_slice(&byte_rate.to_le_bytes());
+        fmt.extend_from_slice(&[4, 0, 16, 0]);
+        let mut data = b"RIFF\0\0\0\0WAVE".to_vec();
+        data.extend(riff_chunk(b"fmt ", &fmt));
+        data.extend(chunks.concat());
+        data.extend_from_slice(b"data");
+        data.extend_from_slice(&data_size.to_le_bytes());
+        data.extend_from_slice(&[0; 16]);
+        data
+    }
+
+    /// An EBML element with a one byte size.
+    fn ebml(id: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut data = id.to_vec();
+        data.push(0x80 | payload.len() as u8);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    /// A WebM file whose segment has an unknown size, as live recordings do.
+    fn matroska(info: &[Vec<u8>], before_info: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = ebml(&[0x1a, 0x45, 0xdf, 0xa3], &ebml(&[0x42, 0x82], b"webm"));
+        data.extend_from_slice(&[0x18, 0x53, 0x80, 0x67]);
+        data.extend_from_slice(&[0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        data.extend(before_info.concat());
+        data.extend(ebml(&[0x15, 0x49, 0xa9, 0x66], &info.concat()));
+        data.extend(ebml(&[0x1f, 0x43, 0xb6, 0x75], &[0; 8]));
+        data
+    }
+
+    fn duration(value: f64) -> Vec<u8> {
+        ebml(&[0x44, 0x89], &value.to_be_bytes())
+    }
+
+    fn duration_ms(content: &[u8]) -> Option<u64> {
+        probe(content).duration_ms
+    }
+
+    #[test]
+    fn reads_mp4_duration_and_picture_size() {
+        let info = probe(&mp4(&[mvhd(600, 4500), trak(0, 0), trak(1280, 720)]));
+        assert_eq!(
+            info,
+            ContainerInfo {
+                duration_ms: Some(7500),
+                width: Some(1280),
+                height: Some(720),
+            }
+        );
+    }
+
+    #[test]
+    fn reads_version_1_mp4_headers() {
+        let mut payload = vec![1, 0, 0, 0];
+        payload.extend_from_slice(&[0; 16]);
+        payload.extend_from_slice(&1000u32.to_be_bytes());
+        payload.extend_from_slice(&90_061_000u64.to_be_bytes());
+        payload.resize(112, 0);
+        assert_eq!(duration_ms(&mp4(&[mp4_box(b"mvhd", &payload)])), Some(90_061_000));
+    }
+
+    #[test]
+    fn reads_mp4_boxes_with_64_bit_sizes() {
+        let moov = mp4_box(b"moov", &mvhd(1000, 2000));
+        let mut data = mp4_box(b"ftyp", b"isom");
+        data.extend_from_slice(&1u32.to_be_bytes());
+        data.extend_from_slice(b"moov");
+        data.extend_from_slice(&(16 + moov.len() as u64 - 8).to_be_bytes());
+        data.extend_from_slice(&moov[8..]);
+        assert_eq!(duration_ms(&data), Some(2000));
+    }
+
+    #[test]
+    fn ignores_unusable_mp4_headers() {
+        assert_eq!(duration_ms(&mp4(&[mvhd(0, 4500)])), None);
+        assert_eq!(probe(&mp4(&[trak(640, 480)])).width, Some(640));
+        assert_eq!(probe(&mp4(&[mvhd(600, 600)])).width, None);
+        assert_eq!(duration_ms(&mp4_box(b"ftyp", b"isom")), None);
+
+        // A box claiming to reach the end of the address space.
+        let mut data = mp4_box(b"ftyp", b"isom");
+        data.extend_from_slice(&1u32.to_be_bytes());
+        data.extend_from_slice(b"free");
+        data.extend_from_slice(&(u64::MAX - 12).to_be_bytes());
+        data.extend(mp4_box(b"moov", &mvhd(1000, 1000)));
+        assert_eq!(probe(&data), ContainerInfo::default());
+
+        // Box sizes smaller than their own header.
+        let mut data = mp4_box(b"ftyp", b"isom");
+        data.extend_from_slice(&4u32.to_be_bytes());
+        data.extend_from_slice(b"moov");
+        assert_eq!(probe(&data), ContainerInfo::default());
+    }
+
+    #[test]
+    fn reads_ogg_durations() {
+        // Opus counts at 48kHz from before the pre-skip.
+        assert_eq!(duration_ms(&opus(312, 312 + 48_000 * 3 / 2)), Some(1500));
+        assert_eq!(duration_ms(&opus(312, 100)), Some(0));
+        assert_eq!(duration_ms(&vorbis(44_100, 44_100 * 4)), Some(4000));
+    }
+
+    #[test]
+    fn ignores_unusable_ogg_headers() {
+        assert_eq!(duration_ms(&vorbis(0, 44_100)), None);
+        assert_eq!(duration_ms(&opus(0, u64::MAX)), None);
+        assert_eq!(duration_ms(&ogg_page(48_000, b"\x80theora")), None);
+        // A segment count running past the end of the page.
+        assert_eq!(duration_ms(b"OggS\0\x02\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\xff"), None);
+    }
+
+    #[test]
+    fn reads_wav_durations() {
+        assert_eq!(duration_ms(&wav(176_400, &[], 176_400 * 2)), Some(2000));
+        // Odd-sized chunks before the data are padded to an even length.
+        let list = riff_chunk(b"LIST", b"INFOx");
+        assert_eq!(duration_ms(&wav(88_200, &[list], 44_100)), Some(500));
+    }
+
+    #[test]
+    fn ignores_unusable_wav_headers() {
+        assert_eq!(duration_ms(&wav(0, &[], 1000)), None);
+        // A chunk whose size skips past the data chunk.
+        let mut data = b"RIFF\0\0\0\0WAVE".to_vec();
+        data.extend_from_slice(b"junk");
+        data.extend_from_slice(&u32::MAX.to_le_bytes());
+        data.extend(wav(176_400, &[], 176_400)[12..].to_vec());
+        assert_eq!(duration_ms(&data), None);
+    }
+
+    #[test]
+    fn reads_matroska_durations() {
+        assert_eq!(duration_ms(&matroska(&[duration(2500.0)], &[])), Some(2500));
+        // Info may come after other top level elements, with its own timecode scale.
+        let seek_head = ebml(&[0x11, 0x4d, 0x9b, 0x74], &[0; 4]);
+        let scale = ebml(&[0x2a, 0xd7, 0xb1], &[0x98, 0x96, 0x80]);
+        let info = [scale, duration(250.0)];
+        assert_eq!(duration_ms(&matroska(&info, &[seek_head])), Some(2500));
+        let single = ebml(&[0x44, 0x89], &1500f32.to_be_bytes());
+        assert_eq!(duration_ms(&matroska(&[single], &[])), Some(1500));
+    }
+
+    #[test]
+    fn ignores_unusable_matroska_headers() {
+        assert_eq!(duration_ms(&matroska(&[], &[])), None);
+        assert_eq!(duration_ms(&matroska(&[duration(f64::NAN)], &[])), None);
+        assert_eq!(duration_ms(&matroska(&[duration(-1.0)], &[])), None);
+        assert_eq!(duration_ms(&matroska(&[duration(f64::INFINITY)], &[])), None);
+        // Clusters before the info mean there is none to find.
+        let cluster = ebml(&[0x1f, 0x43, 0xb6, 0x75], &[0; 8]);
+        assert_eq!(duration_ms(&matroska(&[duration(2500.0)], &[cluster])), None);
+        // Lengths with no marker bit, and info of unknown size.
+        let mut data = matroska(&[], &[]);
+        data.truncate(data.len() - 18);
+        data.push(0);
+        assert_eq!(duration_ms(&data), None);
+        let mut data = matroska(&[], &[]);
+        data.truncate(data.len() - 18);
+        data.extend_from_slice(&[0x15, 0x49, 0xa9, 0x66, 0xff]);
+        assert_eq!(duration_ms(&data), None);
+    }
+
+    #[test]
+    fn survives_truncated_and_corrupt_files() {
+        let files = [
+            mp4(&[mvhd(600, 4500), trak(1280, 720)]),
+            opus(312, 48_000),
+            vorbis(44_100, 44_100),
+            wav(176_400, &[riff_chunk(b"LIST", b"INFOx")], 176_400),
+            matroska(&[duration(2500.0)], &[]),
+        ];
+        // A fixed xorshift sequence, so failures reproduce.
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for file in &files {
+            for end in 0..file.len() {
+                probe(&file[..end]);
+            }
+            for _ in 0..2000 {
+                let mut corrupt = file.clone();
+                for _ in 0..=next() % 4 {
+                    let at = next() as usize % corrupt.len();
+                    corrupt[at] = next() as u8;
+                }
+                probe(&corrupt);
+            }
+        }
+    }
+}
+
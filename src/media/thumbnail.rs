@@ -0,0 +1,100 @@
+use std::io::Cursor;
+
+use image::{DynamicImage, ImageFormat, ImageReader, Limits};
+
+/// Images larger than this get a thumbnail of this size, the one clients ask for by default.
+const THUMBNAIL_WIDTH: u32 = 800;
+const THUMBNAIL_HEIGHT: u32 = 600;
+/// BlurHash components along each axis; 4x3 suits the usual landscape photo.
+const BLURHASH_COMPONENTS: (u32, u32) = (4, 3);
+/// The BlurHash is computed from a copy this small, which is plenty for a blur.
+const BLURHASH_SIZE: u32 = 64;
+/// Guards against decompression bombs: a small file can claim huge dimensions.
+const MAX_DIMENSION: u32 = 16_384;
+const MAX_ALLOC: u64 = 256 * 1024 * 1024;
+
+#[derive(Debug, Clone, Default)]
+pub struct ImageMetadata {
+    pub width: u32,
+    pub height: u32,
+    pub blurhash: Option<String>,
+    pub thumbnail: Option<Thumbnail>,
+}
+
+/// A scaled-down copy of an image, encoded and ready for upload.
+#[derive(Debug, Clone)]
+pub struct Thumbnail {
+    pub content: Vec<u8>,
+    pub content_type: &'static str,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Decodes an image to find its dimensions, BlurHash and, when it is larger than
+/// `THUMBNAIL_WIDTH`x`THUMBNAIL_HEIGHT`, a thumbnail. Formats that cannot be decoded still
+/// report their dimensions when the header can be read. CPU bound, so run it off the runtime.
+pub fn analyze(content: &[u8]) -> Option<ImageMetadata> {
+    let image = match reader(content)?.decode() {
+        Ok(image) => image,
+        Err(_) => {
+            let (width, height) = reader(content)?.into_dimensions().ok()?;
+            return Some(ImageMetadata {
+                width,
+                height,
+                ..Default::default()
+            });
+        }
+    };
+
+    let (width, height) = (image.width(), image.height());
+    let thumbnail = (width > THUMBNAIL_WIDTH || height > THUMBNAIL_HEIGHT)
+        .then(|| thumbnail(&image))
+        .flatten();
+    Some(ImageMetadata {
+        width,
+        height,
+        blurhash: blurhash(&image),
+        thumbnail,
+    })
+}
+
+fn reader(content: &[u8]) -> Option<ImageReader<Cursor<&[u8]>>> {
+    let mut reader = ImageReader::new(Cursor::new(content))
+        .with_guessed_format()
+        .ok()?;
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    limits.max_alloc = Some(MAX_ALLOC);
+    reader.limits(limits);
+    Some(reader)
+}
+
+/// PNG keeps transparency; everything else becomes a JPEG.
+fn thumbnail(image: &DynamicImage) -> Option<Thumbnail> {
+    let scaled = image.thumbnail(THUMBNAIL_WIDTH, THUMBNAIL_HEIGHT);
+    let (scaled, format, content_type) = if scaled.color().has_alpha() {
+        (scaled, ImageFormat::Png, "image/png")
+    } else {
+        (
+            DynamicImage::ImageRgb8(scaled.to_rgb8()),
+            ImageFormat::Jpeg,
+            "image/jpeg",
+        )
+    };
+
+    let mut content = Cursor::new(Vec::new());
+    scaled.write_to(&mut content, format).ok()?;
+    Some(Thumbnail {
+        content: content.into_inner(),
+        content_type,
+        width: scaled.width(),
+        height: scaled.height(),
+    })
+}
+
+fn blurhash(image: &DynamicImage) -> Option<String> {
+    let small = image.thumbnail(BLURHASH_SIZE, BLURHASH_SIZE).to_rgba8();
+    let (x, y) = BLURHASH_COMPONENTS;
+    blurhash::encode(x, y, small.width(), small.height(), small.as_raw()).ok()
+}
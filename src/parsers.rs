@@ -1,2 +1,4 @@
+pub mod html;
+pub mod links;
 pub mod matrix_parser;
 pub mod zulip_parser;
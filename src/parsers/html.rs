@@ -0,0 +1,205 @@
+use std::cell::RefCell;
+
+use html5ever::tendril::StrTendril;
+use html5ever::tokenizer::{
+    BufferQueue, TagKind, Token, TokenSink, TokenSinkResult, Tokenizer, TokenizerOpts,
+};
+
+/// Elements that never have children or an end tag.
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
+/// A node of an HTML fragment, as found in message bodies.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HtmlNode {
+    Element(HtmlElement),
+    Text(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HtmlElement {
+    pub name: String,
+    pub attrs: Vec<(String, String)>,
+    pub children: Vec<HtmlNode>,
+}
+
+impl HtmlElement {
+    fn new(name: &str, attrs: Vec<(String, String)>) -> Self {
+        Self {
+            name: name.to_string(),
+            attrs,
+            children: Vec::new(),
+        }
+    }
+
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn has_class(&self, class: &str) -> bool {
+        self.attr("class")
+            .is_some_and(|classes| classes.split_whitespace().any(|c| c == class))
+    }
+
+    /// Concatenated text of all descendants, whitespace untouched.
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        collect_text(&self.children, &mut text);
+        text
+    }
+
+    pub fn child_elements(&self) -> impl Iterator<Item = &HtmlElement> {
+        self.children.iter().filter_map(|child| match child {
+            HtmlNode::Element(element) => Some(element),
+            HtmlNode::Text(_) => None,
+        })
+    }
+}
+
+fn collect_text(nodes: &[HtmlNode], text: &mut String) {
+    for node in nodes {
+        match node {
+            HtmlNode::Text(content) => text.push_str(content),
+            HtmlNode::Element(element) if element.name == "br" => text.push('\n'),
+            HtmlNode::Element(element) => collect_text(&element.children, text),
+        }
+    }
+}
+
+/// Builds a tree from tokens, closing elements left open the way message HTML usually
+/// omits them (`<li>`, `<p>`, table cells) and ignoring stray end tags.
+#[derive(Default)]
+struct TreeBuilder {
+    /// Open elements; the first entry is a synthetic root.
+    stack: Vec<HtmlElement>,
+}
+
+impl TreeBuilder {
+    fn new() -> Self {
+        Self {
+            stack: vec![HtmlElement::new("", Vec::new())],
+        }
+    }
+
+    fn current(&mut self) -> &mut HtmlElement {
+        self.stack.last_mut().expect("root is never popped")
+    }
+
+    fn pop(&mut self) {
+        if self.stack.len() > 1
+            && let Some(element) = self.stack.pop()
+        {
+            self.current().children.push(HtmlNode::Element(element));
+        }
+    }
+
+    fn is_open(&self, name: &str) -> bool {
+        self.stack.iter().skip(1).any(|element| element.name == name)
+    }
+
+    fn close(&mut self, name: &str) {
+        if !self.is_open(name) {
+            return;
+        }
+        while let Some(element) = self.stack.last() {
+            let done = element.name == name;
+            self.pop();
+            if done {
+                break;
+            }
+        }
+    }
+
+    /// Closes the innermost open `names` element unless one of `scope` is opened after it.
+    fn close_implied(&mut self, names: &[&str], scope: &[&str]) {
+        for element in self.stack.iter().skip(1).rev() {
+            if scope.contains(&element.name.as_str()) {
+                return;
+            }
+            if names.contains(&element.name.as_str()) {
+                let name = element.name.clone();
+                self.close(&name);
+                return;
+            }
+        }
+    }
+
+    fn start(&mut self, name: &str, attrs: Vec<(String, String)>, self_closing: bool) {
+        match name {
+            "li" => self.close_implied(&["li"], &["ul", "ol"]),
+            "td" | "th" => self.close_implied(&["td", "th"], &["tr", "table"]),
+            "tr" => self.close_implied(&["tr"], &["table"]),
+            "p" | "ul" | "ol" | "pre" | "blockquote" | "table" | "h1" | "h2" | "h3" | "h4"
+            | "h5" | "h6" | "hr" => self.close_implied(&["p"], &["li", "blockquote", "td", "th"]),
+            _ => {}
+        }
+
+        let element = HtmlElement::new(name, attrs);
+        if self_closing || VOID_ELEMENTS.contains(&name) {
+            self.current().children.push(HtmlNode::Element(element));
+        } else {
+            self.stack.push(element);
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        let children = &mut self.current().children;
+        if let Some(HtmlNode::Text(last)) = children.last_mut() {
+            last.push_str(text);
+        } else {
+            children.push(HtmlNode::Text(text.to_string()));
+        }
+    }
+
+    fn finish(mut self) -> Vec<HtmlNode> {
+        while self.stack.len() > 1 {
+            self.pop();
+        }
+        self.stack.pop().map(|root| root.children).unwrap_or_default()
+    }
+}
+
+struct Sink(RefCell<TreeBuilder>);
+
+impl TokenSink for Sink {
+    type Handle = ();
+
+    fn process_token(&self, token: Token, _line_number: u64) -> TokenSinkResult<()> {
+        let mut builder = self.0.borrow_mut();
+        match token {
+            Token::TagToken(tag) => {
+                let name = tag.name.to_string();
+                match tag.kind {
+                    TagKind::StartTag => {
+                        let attrs = tag
+                            .attrs
+                            .iter()
+                            .map(|attr| (attr.name.local.to_string(), attr.value.to_string()))
+                            .collect();
+                        builder.start(&name, attrs, tag.self_closing);
+                    }
+                    TagKind::EndTag => builder.close(&name),
+                }
+            }
+            Token::CharacterTokens(text) => builder.text(&text),
+            _ => {}
+        }
+        TokenSinkResult::Continue
+    }
+}
+
+/// Parses an HTML fragment such as a Matrix `formatted_body` or Zulip `rendered_content`.
+/// Never fails; malformed markup is repaired as well as it can be.
+pub fn parse_fragment(html: &str) -> Vec<HtmlNode> {
+    let tokenizer = Tokenizer::new(Sink(RefCell::new(TreeBuilder::new())), TokenizerOpts::default());
+    let input = BufferQueue::default();
+    input.push_back(StrTendril::from_slice(html));
+    let _ = tokenizer.feed(&input);
+    tokenizer.end();
+    tokenizer.sink.0.into_inner().finish()
+}
@@ -0,0 +1,138 @@
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
+
+/// Characters `encodeURIComponent` leaves alone, which Zulip's hash encoding builds on.
+const URI_COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'!')
+    .remove(b'~')
+    .remove(b'*')
+    .remove(b'\'')
+    .remove(b'(')
+    .remove(b')');
+
+/// Sigils and server separators stay readable in matrix.to links, as clients write them.
+const MATRIX_ID: &AsciiSet = &URI_COMPONENT.remove(b':').remove(b'$').remove(b'@');
+
+/// A Zulip stream, topic or message, as addressed by a `#narrow/...` URL.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ZulipLink {
+    pub stream_id: i64,
+    pub topic: Option<String>,
+    pub message_id: Option<i64>,
+}
+
+impl ZulipLink {
+    /// Parses a narrow URL such as `/#narrow/channel/5-general/topic/lunch/near/123`, relative
+    /// or absolute. `stream/` and `subject/` are accepted as the older spellings, `with/` like
+    /// `near/`.
+    pub fn parse(url: &str) -> Option<Self> {
+        let (_, narrow) = url.split_once("#narrow/")?;
+        let mut segments = narrow.split('/');
+
+        let mut link = None;
+        while let Some(operator) = segments.next() {
+            let operand = segments.next()?;
+            match operator {
+                "channel" | "stream" => {
+                    let id = operand.split('-').next()?.parse().ok()?;
+                    link = Some(ZulipLink {
+                        stream_id: id,
+                        topic: None,
+                        message_id: None,
+                    });
+                }
+                "topic" | "subject" => link.as_mut()?.topic = Some(decode_hash_component(operand)?),
+                "near" | "with" => link.as_mut()?.message_id = Some(operand.parse().ok()?),
+                _ => return None,
+            }
+        }
+        link
+    }
+
+    /// The narrow URL for this link on `site`.
+    pub fn to_url(&self, site: &str, stream_name: &str) -> String {
+        let slug = format!("{}-{}", self.stream_id, stream_name.replace(' ', "-"));
+        let mut url = format!(
+            "{}/#narrow/channel/{}",
+            site.trim_end_matches('/'),
+            encode_hash_component(&slug)
+        );
+        if let Some(topic) = &self.topic {
+            url.push_str(&format!("/topic/{}", encode_hash_component(topic)));
+        }
+        if let Some(message_id) = self.message_id {
+            url.push_str(&format!("/near/{}", message_id));
+        }
+        url
+    }
+}
+
+/// A Matrix room or event, as addressed by a permalink.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MatrixLink {
+    pub room_id: String,
+    pub event_id: Option<String>,
+}
+
+impl MatrixLink {
+    /// Parses `https://matrix.to/#/!room:server/$event` or `matrix:roomid/room:server/e/event`.
+    /// Room aliases are not resolved, so links by alias are not recognised.
+    pub fn parse(url: &str) -> Option<Self> {
+        if let Some(rest) = url.strip_prefix("https://matrix.to/#/") {
+            let rest = rest.split('?').next().unwrap_or(rest);
+            let mut parts = rest.splitn(2, '/');
+            let room_id = decode(parts.next()?)?;
+            let event_id = match parts.next() {
+                Some(event) => Some(decode(event)?),
+                None => None,
+            };
+            return (room_id.starts_with('!') && event_id.as_ref().is_none_or(|e| e.starts_with('$')))
+                .then_some(MatrixLink { room_id, event_id });
+        }
+
+        let rest = url.strip_prefix("matrix:roomid/")?;
+        let rest = rest.split('?').next().unwrap_or(rest);
+        let mut parts = rest.split('/');
+        let room_id = format!("!{}", decode(parts.next()?)?);
+        let event_id = match (parts.next(), parts.next()) {
+            (Some("e"), Some(event)) => Some(format!("${}", decode(event)?)),
+            (None, _) => None,
+            _ => return None,
+        };
+        Some(MatrixLink { room_id, event_id })
+    }
+
+    pub fn to_url(&self) -> String {
+        let mut url = format!(
+            "https://matrix.to/#/{}",
+            utf8_percent_encode(&self.room_id, MATRIX_ID)
+        );
+        if let Some(event_id) = &self.event_id {
+            url.push_str(&format!("/{}", utf8_percent_encode(event_id, MATRIX_ID)));
+        }
+        url
+    }
+}
+
+fn decode(text: &str) -> Option<String> {
+    percent_decode_str(text)
+        .decode_utf8()
+        .ok()
+        .map(|decoded| decoded.into_owned())
+}
+
+/// Zulip's `encodeHashComponent`: `encodeURIComponent` with `%` written as `.`.
+fn encode_hash_component(text: &str) -> String {
+    utf8_percent_encode(text, URI_COMPONENT)
+        .to_string()
+        .replace('.', ".2E")
+        .replace('%', ".")
+        .replace('(', ".28")
+        .replace(')', ".29")
+}
+
+fn decode_hash_component(text: &str) -> Option<String> {
+    decode(&text.replace('.', "%"))
+}
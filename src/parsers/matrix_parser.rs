@@ -1,3 +1,669 @@
-pub fn parse_matrix_message(_content: &str) -> String {
-    _content.to_string()
+use std::collections::HashMap;
+
+use super::html::{HtmlElement, HtmlNode, parse_fragment};
+use super::links::MatrixLink;
+use crate::matrix::MessageMentions;
+
+/// Invisible character used to break up Zulip syntax that has no backslash escape.
+const WORD_JOINER: char = '\u{2060}';
+
+/// Stands in for a resolved pill between parsing and rendering. Elements of this name in the
+/// input are renamed first, so a message cannot smuggle in mentions of its own.
+const MENTION_TAG: &str = "x-zulip-mention";
+
+const BLOCK_ELEMENTS: &[&str] = &[
+    "p", "div", "blockquote", "pre", "ul", "ol", "li", "table", "thead", "tbody", "tfoot", "tr",
+    "h1", "h2", "h3", "h4", "h5", "h6", "hr", "details", "summary",
+];
+
+/// A Matrix user's account on Zulip.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZulipUserRef {
+    pub id: i64,
+    pub name: String,
+}
+
+/// What a Matrix message's mentions resolve to on Zulip, looked up before conversion.
+#[derive(Debug, Clone, Default)]
+pub struct MentionTargets {
+    /// Zulip account of each pilled or mentioned Matrix user that has one (ghosts and puppets).
+    pub users: HashMap<String, ZulipUserRef>,
+    /// The message's `m.mentions`.
+    pub mentions: MessageMentions,
+}
+
+/// Converts a Matrix `formatted_body` (`org.matrix.custom.html`) to Zulip Markdown. Reply
+/// fallbacks (`<mx-reply>`) are dropped, and text is escaped so it cannot turn into mentions,
+/// stream links, emphasis or widgets on the Zulip side. Pills of users known to Zulip and
+/// `m.mentions` become Zulip mentions, and permalinks to bridged rooms and events the Zulip URLs
+/// in `links`.
+pub fn parse_matrix_message(
+    html: &str,
+    targets: &MentionTargets,
+    links: &HashMap<MatrixLink, String>,
+) -> String {
+    let mut nodes = parse_fragment(html);
+    resolve_pills(&mut nodes, targets);
+    resolve_permalinks(&mut nodes, links);
+    finish(add_mentions(render_blocks(&nodes, "\n\n"), targets))
+}
+
+/// Converts a plain Matrix `body`, escaping everything Zulip would render as markup.
+pub fn plain_to_zulip(body: &str, targets: &MentionTargets) -> String {
+    let escaped: Vec<String> = body
+        .lines()
+        .map(|line| {
+            let indent = &line[..line.len() - line.trim_start().len()];
+            format!("{}{}", indent, escape_line_start(&escape_text(line.trim_start())))
+        })
+        .collect();
+    finish(add_mentions(escaped.join("\n"), targets))
+}
+
+/// The user a pill links to: `https://matrix.to/#/@user:server` or `matrix:u/user:server`.
+pub fn pill_user_id(href: &str) -> Option<String> {
+    let target = href
+        .strip_prefix("https://matrix.to/#/")
+        .map(|rest| rest.split('?').next().unwrap_or(rest).to_string())
+        .or_else(|| {
+            href.strip_prefix("matrix:u/")
+                .map(|rest| format!("@{}", rest.split(['?', '/']).next().unwrap_or(rest)))
+        })?;
+    let target = target.replace("%40", "@").replace("%3A", ":").replace("%3a", ":");
+    (target.starts_with('@') && target.contains(':')).then_some(target)
+}
+
+/// Matrix users pilled in a `formatted_body`, for looking up their `MentionTargets`.
+pub fn find_pills(html: &str) -> Vec<String> {
+    fn collect(nodes: &[HtmlNode], pills: &mut Vec<String>) {
+        for node in nodes {
+            let HtmlNode::Element(element) = node else {
+                continue;
+            };
+            if element.name == "a"
+                && let Some(user_id) = element.attr("href").and_then(pill_user_id)
+                && !pills.contains(&user_id)
+            {
+                pills.push(user_id);
+            }
+            collect(&element.children, pills);
+        }
+    }
+
+    let mut pills = Vec::new();
+    collect(&parse_fragment(html), &mut pills);
+    pills
+}
+
+/// Permalinks to rooms and events in a `formatted_body`, for looking up their Zulip URLs.
+pub fn find_permalinks(html: &str) -> Vec<MatrixLink> {
+    fn collect(nodes: &[HtmlNode], links: &mut Vec<MatrixLink>) {
+        for node in nodes {
+            let HtmlNode::Element(element) = node else {
+                continue;
+            };
+            if element.name == "a"
+                && let Some(link) = element.attr("href").and_then(MatrixLink::parse)
+                && !links.contains(&link)
+            {
+                links.push(link);
+            }
+            collect(&element.children, links);
+        }
+    }
+
+    let mut links = Vec::new();
+    collect(&parse_fragment(html), &mut links);
+    links
+}
+
+/// Points permalinks at their Zulip counterparts; a link showing its own URL shows the new one.
+fn resolve_permalinks(nodes: &mut [HtmlNode], links: &HashMap<MatrixLink, String>) {
+    for node in nodes {
+        let HtmlNode::Element(element) = node else {
+            continue;
+        };
+
+        let target = element
+            .attr("href")
+            .filter(|_| element.name == "a")
+            .and_then(|href| Some((href.to_string(), links.get(&MatrixLink::parse(href)?)?)));
+        let Some((href, url)) = target else {
+            resolve_permalinks(&mut element.children, links);
+            continue;
+        };
+
+        if element.text().trim() == href {
+            element.children = vec![HtmlNode::Text(url.clone())];
+        }
+        element.attrs.retain(|(key, _)| key != "href");
+        element.attrs.push(("href".to_string(), url.clone()));
+    }
+}
+
+fn resolve_pills(nodes: &mut [HtmlNode], targets: &MentionTargets) {
+    for node in nodes {
+        let HtmlNode::Element(element) = node else {
+            continue;
+        };
+        if element.name == MENTION_TAG {
+            element.name = "span".to_string();
+            element.attrs.clear();
+        }
+
+        let user = element
+            .attr("href")
+            .filter(|_| element.name == "a")
+            .and_then(pill_user_id)
+            .and_then(|user_id| targets.users.get(&user_id));
+        match user {
+            Some(user) => {
+                *element = HtmlElement {
+                    name: MENTION_TAG.to_string(),
+                    attrs: vec![("markdown".to_string(), zulip_mention(user))],
+                    children: Vec::new(),
+                }
+            }
+            None => resolve_pills(&mut element.children, targets),
+        }
+    }
+}
+
+fn zulip_mention(user: &ZulipUserRef) -> String {
+    // The name is only informative next to the id, but must not end the mention early.
+    let name: String = user.name.chars().filter(|c| !"*|".contains(*c)).collect();
+    format!("@**{}|{}**", name.trim(), user.id)
+}
+
+/// Turns `@room` into `@**all**` when the message mentions the room, and appends mentions for
+/// `m.mentions` users that have no pill in the text.
+fn add_mentions(markdown: String, targets: &MentionTargets) -> String {
+    let mut markdown = markdown;
+    let mut missing = Vec::new();
+
+    if targets.mentions.room {
+        if markdown.contains("@room") {
+            markdown = markdown.replacen("@room", "@**all**", 1);
+        } else {
+            missing.push("@**all**".to_string());
+        }
+    }
+
+    for user in targets
+        .mentions
+        .user_ids
+        .iter()
+        .filter_map(|user_id| targets.users.get(user_id))
+    {
+        if !markdown.contains(&format!("|{}**", user.id)) {
+            missing.push(zulip_mention(user));
+        }
+    }
+
+    if missing.is_empty() {
+        markdown
+    } else {
+        format!("{}\n{}", markdown.trim_end(), missing.join(" "))
+    }
+}
+
+fn finish(markdown: String) -> String {
+    let markdown = markdown.trim_end().trim_start_matches('\n');
+    // Zulip turns a message starting with `/poll`, `/todo` or `/me` into a widget or an action.
+    if markdown.starts_with('/') {
+        format!("{}{}", WORD_JOINER, markdown)
+    } else {
+        markdown.to_string()
+    }
+}
+
+fn is_block(element: &HtmlElement) -> bool {
+    BLOCK_ELEMENTS.contains(&element.name.as_str()) || element.child_elements().any(is_block)
+}
+
+/// Renders a sequence of nodes, grouping runs of inline content into paragraphs.
+fn render_blocks(nodes: &[HtmlNode], separator: &str) -> String {
+    let mut blocks = Vec::new();
+    let mut inline = String::new();
+
+    for node in nodes {
+        match node {
+            HtmlNode::Element(element) if element.name == "mx-reply" => {}
+            HtmlNode::Element(element) if is_block(element) => {
+                push_paragraph(&mut blocks, &mut inline);
+                let block = render_block(element);
+                if !block.trim().is_empty() {
+                    blocks.push(block);
+                }
+            }
+            node => inline.push_str(&render_inline(node)),
+        }
+    }
+    push_paragraph(&mut blocks, &mut inline);
+
+    blocks.join(separator)
+}
+
+fn push_paragraph(blocks: &mut Vec<String>, inline: &mut String) {
+    let paragraph = clean_paragraph(&std::mem::take(inline));
+    if !paragraph.is_empty() {
+        blocks.push(paragraph);
+    }
+}
+
+/// Drops the whitespace HTML formatting leaves around line breaks and escapes text that would
+/// start a list or quote.
+fn clean_paragraph(text: &str) -> String {
+    let lines: Vec<String> = text
+        .split('\n')
+        .map(|line| escape_line_start(line.trim()))
+        .collect();
+    lines.join("\n").trim_matches('\n').to_string()
+}
+
+fn render_block(element: &HtmlElement) -> String {
+    match element.name.as_str() {
+        // Zulip has no headings.
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+            let text = clean_paragraph(&render_inlines(&element.children)).replace('\n', " ");
+            wrap(&text, "**")
+        }
+        "blockquote" => quote(&render_blocks(&element.children, "\n\n")),
+        "pre" => code_block(element),
+        "ul" | "ol" => render_list(element),
+        "table" => render_table(element),
+        "hr" => "---".to_string(),
+        _ => render_blocks(&element.children, "\n\n"),
+    }
+}
+
+fn quote(text: &str) -> String {
+    text.lines()
+        .map(|line| if line.is_empty() { ">".to_string() } else { format!("> {}", line) })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn code_block(pre: &HtmlElement) -> String {
+    let language = pre
+        .child_elements()
+        .find(|child| child.name == "code")
+        .and_then(|code| code.attr("class"))
+        .and_then(|classes| {
+            classes
+                .split_whitespace()
+                .find_map(|class| class.strip_prefix("language-"))
+        })
+        .filter(|language| {
+            language
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+-_.#".contains(c))
+        })
+        .unwrap_or_default();
+
+    let text = pre.text();
+    let text = text.strip_prefix('\n').unwrap_or(&text);
+    let text = text.strip_suffix('\n').unwrap_or(text);
+    let fence = "`".repeat(longest_run(text, '`').max(2) + 1);
+    format!("{}{}\n{}\n{}", fence, language, text, fence)
+}
+
+fn render_list(list: &HtmlElement) -> String {
+    let ordered = list.name == "ol";
+    let mut number = list
+        .attr("start")
+        .and_then(|start| start.parse::<i64>().ok())
+        .unwrap_or(1);
+    let mut items = Vec::new();
+
+    for child in &list.children {
+        match child {
+            HtmlNode::Element(item) if item.name == "li" => {
+                let marker = if ordered {
+                    format!("{}. ", number)
+                } else {
+                    "- ".to_string()
+                };
+                number += 1;
+                items.push(indent_item(&marker, &render_blocks(&item.children, "\n")));
+            }
+            // A list nested directly in a list instead of in an item belongs to the previous item.
+            HtmlNode::Element(nested) if nested.name == "ul" || nested.name == "ol" => {
+                items.push(indent_item("  ", &render_list(nested)));
+            }
+            HtmlNode::Text(text) if text.trim().is_empty() => {}
+            other => {
+                let text = clean_paragraph(&render_inline(other));
+                if !text.is_empty() {
+                    items.push(indent_item("- ", &text));
+                }
+            }
+        }
+    }
+
+    items.join("\n")
+}
+
+/// Prefixes the first line with the list marker and aligns the others under it.
+fn indent_item(marker: &str, content: &str) -> String {
+    let padding = " ".repeat(marker.len());
+    content
+        .lines()
+        .enumerate()
+        .map(|(i, line)| match (i, line.is_empty()) {
+            (0, _) => format!("{}{}", marker, line),
+            (_, true) => String::new(),
+            _ => format!("{}{}", padding, line),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn render_table(table: &HtmlElement) -> String {
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    collect_rows(table, &mut rows);
+
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+    if columns == 0 {
+        return String::new();
+    }
+
+    let line = |cells: &[String]| {
+        let mut cells = cells.to_vec();
+        cells.resize(columns, String::new());
+        format!("| {} |", cells.join(" | "))
+    };
+
+    let mut lines = vec![line(&rows[0]), line(&vec!["---".to_string(); columns])];
+    lines.extend(rows[1..].iter().map(|row| line(row)));
+    lines.join("\n")
+}
+
+fn collect_rows(element: &HtmlElement, rows: &mut Vec<Vec<String>>) {
+    for child in element.child_elements() {
+        match child.name.as_str() {
+            "thead" | "tbody" | "tfoot" => collect_rows(child, rows),
+            "tr" => rows.push(
+                child
+                    .child_elements()
+                    .filter(|cell| cell.name == "td" || cell.name == "th")
+                    .map(|cell| {
+                        clean_paragraph(&render_inlines(&cell.children))
+                            .replace('\n', " ")
+                            .replace('|', "\\|")
+                    })
+                    .collect(),
+            ),
+            _ => {}
+        }
+    }
+}
+
+fn render_inlines(nodes: &[HtmlNode]) -> String {
+    nodes.iter().map(render_inline).collect()
+}
+
+fn render_inline(node: &HtmlNode) -> String {
+    let element = match node {
+        HtmlNode::Text(text) => return escape_text(&collapse_whitespace(text)),
+        HtmlNode::Element(element) => element,
+    };
+
+    match element.name.as_str() {
+        "br" => "\n".to_string(),
+        "strong" | "b" => wrap(&render_inlines(&element.children), "**"),
+        "em" | "i" => wrap(&render_inlines(&element.children), "*"),
+        "del" | "s" | "strike" => wrap(&render_inlines(&element.children), "~~"),
+        "code" => inline_code(&collapse_whitespace(&element.text())),
+        "a" => render_link(element),
+        "img" => element
+            .attr("alt")
+            .or_else(|| element.attr("title"))
+            .map(escape_text)
+            .unwrap_or_default(),
+        "mx-reply" => String::new(),
+        MENTION_TAG => element.attr("markdown").unwrap_or_default().to_string(),
+        _ if is_block(element) => format!("\n{}\n", render_block(element)),
+        _ => render_inlines(&element.children),
+    }
+}
+
+/// Puts `marker` around the text, keeping surrounding spaces outside so the emphasis still
+/// parses.
+fn wrap(inner: &str, marker: &str) -> String {
+    let trimmed = inner.trim();
+    if trimmed.is_empty() {
+        return inner.to_string();
+    }
+    let leading = &inner[..inner.len() - inner.trim_start().len()];
+    let trailing = &inner[inner.trim_end().len()..];
+    format!("{}{}{}{}{}", leading, marker, trimmed, marker, trailing)
+}
+
+fn inline_code(text: &str) -> String {
+    if text.is_empty() {
+        return String::new();
+    }
+    let fence = "`".repeat(longest_run(text, '`') + 1);
+    if text.starts_with('`') || text.ends_with('`') {
+        format!("{} {} {}", fence, text, fence)
+    } else {
+        format!("{}{}{}", fence, text, fence)
+    }
+}
+
+fn render_link(link: &HtmlElement) -> String {
+    let text = render_inlines(&link.children);
+    let Some(href) = link.attr("href").map(str::trim) else {
+        return text;
+    };
+    let allowed = ["https://", "http://", "mailto:"]
+        .iter()
+        .any(|scheme| href.to_ascii_lowercase().starts_with(scheme));
+    if !allowed {
+        return text;
+    }
+
+    // Zulip links bare URLs itself.
+    let label = collapse_whitespace(&link.text());
+    let label = label.trim();
+    if label.is_empty() || label == href || Some(label) == href.strip_prefix("mailto:") {
+        return href.to_string();
+    }
+
+    let href = href
+        .replace(' ', "%20")
+        .replace('(', "%28")
+        .replace(')', "%29");
+    format!("[{}]({})", text.trim(), href)
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    let mut collapsed = String::with_capacity(text.len());
+    let mut in_space = false;
+    for c in text.chars() {
+        if c.is_ascii_whitespace() {
+            if !in_space {
+                collapsed.push(' ');
+            }
+            in_space = true;
+        } else {
+            collapsed.push(c);
+            in_space = false;
+        }
+    }
+    collapsed
+}
+
+fn longest_run(text: &str, target: char) -> usize {
+    let mut longest = 0;
+    let mut current = 0;
+    for c in text.chars() {
+        current = if c == target { current + 1 } else { 0 };
+        longest = longest.max(current);
+    }
+    longest
+}
+
+/// Escapes text so Zulip shows it literally: emphasis, code, links, and through `*` also
+/// mentions (`@**name**`), stream links (`#**stream**`) and silent mentions. Syntax without a
+/// backslash escape (`~~`, `$$`, `<time:`) is broken up with an invisible word joiner.
+fn escape_text(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut escaped = String::with_capacity(text.len());
+
+    for (i, &c) in chars.iter().enumerate() {
+        match c {
+            '\\' | '`' | '*' | '[' | ']' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '~' | '$' if chars.get(i + 1) == Some(&c) => {
+                escaped.push(c);
+                escaped.push(WORD_JOINER);
+            }
+            '<' if chars[i + 1..].starts_with(&['t', 'i', 'm', 'e', ':']) => {
+                escaped.push(c);
+                escaped.push(WORD_JOINER);
+            }
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Escapes a quote, list or heading marker at the start of a line of text.
+fn escape_line_start(line: &str) -> String {
+    if line.starts_with('>') || line.starts_with("- ") || line.starts_with("+ ") {
+        return format!("\\{}", line);
+    }
+
+    let hashes = line.chars().take_while(|c| *c == '#').count();
+    let rest = &line[hashes..];
+    if (1..=6).contains(&hashes) && (rest.is_empty() || rest.starts_with(' ')) {
+        return format!("\\{}", line);
+    }
+
+    let digits = line.chars().take_while(char::is_ascii_digit).count();
+    let rest = &line[digits..];
+    if digits > 0 && (rest.starts_with(". ") || rest.starts_with(") ") || rest == "." || rest == ")") {
+        return format!("{}\\{}", &line[..digits], rest);
+    }
+
+    line.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Case {
+        name: String,
+        html: String,
+        markdown: String,
+    }
+
+    #[test]
+    fn converts_fixture_corpus() {
+        let cases: Vec<Case> =
+            serde_json::from_str(include_str!("../../fixtures/parsers/matrix_to_zulip.json"))
+                .unwrap();
+        for case in cases {
+            let markdown =
+                parse_matrix_message(&case.html, &MentionTargets::default(), &HashMap::new());
+            assert_eq!(markdown, case.markdown, "{}", case.name);
+        }
+    }
+
+    #[test]
+    fn escapes_headings_in_plain_text() {
+        let targets = MentionTargets::default();
+        assert_eq!(plain_to_zulip("# not a heading", &targets), "\\# not a heading");
+        assert_eq!(plain_to_zulip("### three", &targets), "\\### three");
+        assert_eq!(plain_to_zulip("#hashtag", &targets), "#hashtag");
+    }
+
+    fn targets(users: &[(&str, i64, &str)], mentions: MessageMentions) -> MentionTargets {
+        MentionTargets {
+            users: users
+                .iter()
+                .map(|(user_id, id, name)| {
+                    let user = ZulipUserRef {
+                        id: *id,
+                        name: name.to_string(),
+                    };
+                    (user_id.to_string(), user)
+                })
+                .collect(),
+            mentions,
+        }
+    }
+
+    #[test]
+    fn finds_pills_once_each() {
+        let html = concat!(
+            "<a href=\"https://matrix.to/#/@alice:example.org\">Alice</a> ",
+            "<a href=\"https://matrix.to/#/%40bob%3Aexample.org?via=example.org\">Bob</a> ",
+            "<blockquote><a href=\"matrix:u/carol:example.org?action=chat\">Carol</a>",
+            "</blockquote> ",
+            "<a href=\"https://matrix.to/#/@alice:example.org\">again</a> ",
+            "<a href=\"https://matrix.to/#/#room:example.org\">a room</a> ",
+            "<a href=\"https://example.org/@dave:example.org\">not a pill</a>",
+        );
+        assert_eq!(
+            find_pills(html),
+            ["@alice:example.org", "@bob:example.org", "@carol:example.org"]
+        );
+    }
+
+    #[test]
+    fn turns_known_pills_into_mentions() {
+        let mentions = MessageMentions {
+            user_ids: vec!["@alice:example.org".to_string()],
+            room: false,
+        };
+        let targets = targets(&[("@alice:example.org", 8, "Alice *Admin* | Ops")], mentions);
+        let html = concat!(
+            "<a href=\"https://matrix.to/#/@alice:example.org\">Alice</a>: ask ",
+            "<a href=\"https://matrix.to/#/@zoe:example.org\">Zoe</a>",
+        );
+        assert_eq!(
+            parse_matrix_message(html, &targets, &HashMap::new()),
+            "@**Alice Admin  Ops|8**: ask [Zoe](https://matrix.to/#/@zoe:example.org)"
+        );
+    }
+
+    #[test]
+    fn appends_mentions_without_pills() {
+        let mentions = MessageMentions {
+            user_ids: vec![
+                "@alice:example.org".to_string(),
+                "@ghost:example.org".to_string(),
+                "@unknown:example.org".to_string(),
+            ],
+            room: true,
+        };
+        let targets = targets(
+            &[("@alice:example.org", 8, "Alice"), ("@ghost:example.org", 9, "")],
+            mentions,
+        );
+        assert_eq!(
+            plain_to_zulip("hello everyone", &targets),
+            "hello everyone\n@**all** @**Alice|8** @**|9**"
+        );
+        assert_eq!(
+            plain_to_zulip("@room: hello Alice", &targets),
+            "@**all**: hello Alice\n@**Alice|8** @**|9**"
+        );
+    }
+
+    #[test]
+    fn ignores_smuggled_mention_elements() {
+        let targets = MentionTargets::default();
+        let html = format!("<{} markdown=\"@**all**\">hi</{}>", MENTION_TAG, MENTION_TAG);
+        assert_eq!(parse_matrix_message(&html, &targets, &HashMap::new()), "hi");
+    }
 }
@@ -1,3 +1,884 @@
-pub fn parse_zulip_message(_content: &str) -> String {
-    _content.to_string()
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use url::Url;
+
+use super::html::{HtmlElement, HtmlNode, parse_fragment};
+use super::links::{MatrixLink, ZulipLink};
+use crate::matrix::MessageMentions;
+
+/// Tags a Matrix `formatted_body` may contain, per the client-server spec.
+const MATRIX_TAGS: &[&str] = &[
+    "font", "del", "h1", "h2", "h3", "h4", "h5", "h6", "blockquote", "p", "a", "ul", "ol", "sup",
+    "sub", "li", "b", "i", "u", "strong", "em", "s", "code", "hr", "br", "div", "table", "thead",
+    "tbody", "tr", "th", "td", "caption", "pre", "span", "img", "details", "summary",
+];
+
+/// Elements dropped together with everything inside them.
+const DROPPED_TAGS: &[&str] = &[
+    "script", "style", "head", "title", "iframe", "object", "embed", "noscript", "template",
+    "audio", "video", "svg", "math",
+];
+
+const VOID_TAGS: &[&str] = &["br", "hr", "img"];
+
+/// Width handed to html2text; wide enough that the plain body is never wrapped.
+const PLAIN_TEXT_WIDTH: usize = 10_000;
+
+/// A Zulip message rendered for a Matrix `m.text` event.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MatrixContent {
+    pub body: String,
+    /// `None` when the message has no formatting worth sending.
+    pub formatted_body: Option<String>,
+    pub mentions: MessageMentions,
+    /// Uploads the message links to or previews, sent as media events of their own.
+    pub attachments: Vec<ZulipAttachment>,
+}
+
+impl MatrixContent {
+    /// Appends a link to `attachment` as its own paragraph, for uploads that could not be sent
+    /// as media.
+    pub fn push_link(&mut self, attachment: &ZulipAttachment) {
+        let link = format!(
+            "<a href=\"{}\">{}</a>",
+            escape_html(&attachment.url),
+            escape_html(&attachment.name)
+        );
+        let html = match self.formatted_body.take() {
+            Some(html) if !html.is_empty() => format!("{}<p>{}</p>", html, link),
+            _ if self.body.trim().is_empty() => link,
+            _ => format!("<p>{}</p><p>{}</p>", escape_html(&self.body).replace('\n', "<br>"), link),
+        };
+        self.formatted_body = Some(html);
+
+        let text = format!("{}: {}", attachment.name, attachment.url);
+        if self.body.trim().is_empty() {
+            self.body = text;
+        } else {
+            self.body = format!("{}\n\n{}", self.body, text);
+        }
+    }
+}
+
+/// A file uploaded to the realm that a message links to or previews.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZulipAttachment {
+    /// Absolute URL of the upload.
+    pub url: String,
+    pub name: String,
+    /// Original size of a previewed image (`data-original-dimensions`), in pixels.
+    pub dimensions: Option<(u32, u32)>,
+}
+
+/// Users and user groups a rendered Zulip message mentions.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ZulipMentions {
+    pub user_ids: Vec<i64>,
+    /// Groups mentioned with notification; silent group mentions notify nobody.
+    pub group_ids: Vec<i64>,
+}
+
+/// What Zulip mentions resolve to on the Matrix side, looked up before conversion.
+#[derive(Debug, Clone, Default)]
+pub struct MentionTargets {
+    /// Matrix user for each Zulip user: their ghost, or the real user for puppets.
+    pub users: HashMap<i64, String>,
+    /// Members of each mentioned user group.
+    pub groups: HashMap<i64, Vec<i64>>,
+    /// The room bridges a single topic, so `@**topic**` addresses everyone in it.
+    pub topic_room: bool,
+}
+
+/// Collects the mentions of a rendered Zulip message, for looking up their `MentionTargets`.
+pub fn find_mentions(rendered: &str) -> ZulipMentions {
+    let mut mentions = ZulipMentions::default();
+    collect_mentions(&parse_fragment(rendered), &mut mentions);
+    mentions
+}
+
+fn collect_mentions(nodes: &[HtmlNode], mentions: &mut ZulipMentions) {
+    for node in nodes {
+        let HtmlNode::Element(element) = node else {
+            continue;
+        };
+
+        let id = |attr| {
+            element
+                .attr(attr)
+                .and_then(|id: &str| id.parse::<i64>().ok())
+        };
+        if element.has_class("user-mention")
+            && let Some(id) = id("data-user-id")
+            && !mentions.user_ids.contains(&id)
+        {
+            mentions.user_ids.push(id);
+        } else if element.has_class("user-group-mention")
+            && !element.has_class("silent")
+            && let Some(id) = id("data-user-group-id")
+            && !mentions.group_ids.contains(&id)
+        {
+            mentions.group_ids.push(id);
+        }
+
+        collect_mentions(&element.children, mentions);
+    }
+}
+
+/// Links in a rendered Zulip message to streams, topics or messages of the realm, for looking up
+/// the bridged rooms and events they correspond to.
+pub fn find_links(rendered: &str, realm_url: &str) -> Vec<ZulipLink> {
+    fn collect(converter: &Converter, nodes: &[HtmlNode], links: &mut Vec<ZulipLink>) {
+        for node in nodes {
+            let HtmlNode::Element(element) = node else {
+                continue;
+            };
+            if element.name == "a"
+                && let Some(link) = element.attr("href").and_then(|href| converter.realm_link(href))
+                && !links.contains(&link)
+            {
+                links.push(link);
+            }
+            collect(converter, &element.children, links);
+        }
+    }
+
+    let targets = MentionTargets::default();
+    let converter = Converter {
+        realm: Url::parse(realm_url).ok(),
+        plain: false,
+        targets: &targets,
+        links: &HashMap::new(),
+        emoji: &HashMap::new(),
+        mentions: RefCell::default(),
+        attachments: RefCell::default(),
+    };
+    let mut links = Vec::new();
+    collect(&converter, &parse_fragment(rendered), &mut links);
+    links
+}
+
+/// Converts Zulip's `rendered_content` into Matrix-safe HTML and a plain-text `body`. Only
+/// tags and attributes allowed by the Matrix spec survive; Zulip-specific markup (mentions,
+/// stream links, emoji, highlighted code, `<time>`, embeds, spoilers, math) is translated.
+/// Links to bridged streams, topics and messages point at their Matrix rooms and events
+/// (`links`); other relative links are resolved against `realm_url`. Custom realm emoji found
+/// in `emoji` (name to `mxc://` URI) are inlined as Matrix emoticons. Links to uploads and
+/// their previews become `attachments`: a paragraph holding nothing but upload links is left
+/// out, and upload links within text keep only their label.
+pub fn parse_zulip_message(
+    rendered: &str,
+    realm_url: &str,
+    targets: &MentionTargets,
+    links: &HashMap<ZulipLink, MatrixLink>,
+    emoji: &HashMap<String, String>,
+) -> MatrixContent {
+    let nodes = parse_fragment(rendered);
+    let realm = Url::parse(realm_url).ok();
+    let render = |plain| {
+        let converter = Converter {
+            realm: realm.clone(),
+            plain,
+            targets,
+            links,
+            emoji,
+            mentions: RefCell::default(),
+            attachments: RefCell::default(),
+        };
+        let mut html = String::new();
+        converter.nodes(&nodes, &mut html);
+        (
+            html.trim().to_string(),
+            converter.mentions.into_inner(),
+            converter.attachments.into_inner(),
+        )
+    };
+
+    let (html, mentions, attachments) = render(false);
+    let (plain_html, _, _) = render(true);
+    let body = html2text::config::plain()
+        .string_from_read(plain_html.as_bytes(), PLAIN_TEXT_WIDTH)
+        .map(|text| text.trim().to_string())
+        .unwrap_or(plain_html);
+
+    let escaped = escape_html(&body);
+    let formatted_body = (html != escaped && html != format!("<p>{}</p>", escaped)).then_some(html);
+    MatrixContent {
+        body,
+        formatted_body,
+        mentions,
+        attachments,
+    }
+}
+
+/// Turns a Zulip reaction into a Matrix annotation key. Unicode emoji are sent as the emoji
+/// itself and uploaded realm emoji as their `mxc://` URI, as clients send custom emoji
+/// reactions; anything else falls back to `:name:`.
+pub fn reaction_key(
+    emoji_name: &str,
+    emoji_code: &str,
+    reaction_type: &str,
+    emoji: &HashMap<String, String>,
+) -> String {
+    match reaction_type {
+        "unicode_emoji" => {
+            if let Some(emoji) = emoji_from_code(emoji_code) {
+                return emoji;
+            }
+        }
+        "realm_emoji" => {
+            if let Some(mxc_url) = emoji.get(emoji_name) {
+                return mxc_url.clone();
+            }
+        }
+        _ => {}
+    }
+    format!(":{}:", emoji_name)
+}
+
+/// Decodes a Zulip emoji code such as `1f44d` or `1f468-200d-1f4bb`.
+fn emoji_from_code(code: &str) -> Option<String> {
+    code.split('-')
+        .map(|code| u32::from_str_radix(code, 16).ok().and_then(char::from_u32))
+        .collect()
+}
+
+struct Converter<'a> {
+    realm: Option<Url>,
+    /// Writes links as `text (url)` for html2text, which would otherwise turn them into
+    /// footnotes.
+    plain: bool,
+    targets: &'a MentionTargets,
+    links: &'a HashMap<ZulipLink, MatrixLink>,
+    emoji: &'a HashMap<String, String>,
+    mentions: RefCell<MessageMentions>,
+    attachments: RefCell<Vec<ZulipAttachment>>,
+}
+
+impl Converter<'_> {
+    fn nodes(&self, nodes: &[HtmlNode], out: &mut String) {
+        for node in nodes {
+            match node {
+                HtmlNode::Text(text) => out.push_str(&escape_html(text)),
+                HtmlNode::Element(element) => self.element(element, out),
+            }
+        }
+    }
+
+    fn element(&self, element: &HtmlElement, out: &mut String) {
+        let name = element.name.as_str();
+        if DROPPED_TAGS.contains(&name) {
+            return;
+        }
+
+        match name {
+            "span" if element.has_class("emoji") => self.emoji(element, out),
+            "img" if element.has_class("emoji") => self.realm_emoji(element, out),
+            "span" if element.has_class("user-mention")
+                || element.has_class("user-group-mention")
+                || element.has_class("topic-mention") =>
+            {
+                self.mention(element, out)
+            }
+            "span" if element.has_class("katex") || element.has_class("katex-display") => {
+                self.maths(element, out)
+            }
+            "div" if element.has_class("codehilite") => self.code_block(element, out),
+            "div" if element.has_class("spoiler-block") => self.spoiler(element, out),
+            "div" if element.has_class("message_embed") => self.embed(element, out),
+            "div" if element.has_class("message_inline_image")
+                || element.has_class("message_inline_ref")
+                || element.has_class("youtube-video")
+                || element.has_class("message_inline_video") =>
+            {
+                self.inline_preview(element, out)
+            }
+            "p" if self.is_upload_paragraph(element) => {
+                for link in element.child_elements() {
+                    self.upload(link, None);
+                }
+            }
+            "time" => self.time(element, out),
+            "a" => self.link(element, out),
+            "img" => self.image(element, out),
+            _ if MATRIX_TAGS.contains(&name) => {
+                out.push('<');
+                out.push_str(name);
+                for (key, value) in self.allowed_attributes(element) {
+                    out.push_str(&format!(" {}=\"{}\"", key, escape_html(&value)));
+                }
+                out.push('>');
+                if !VOID_TAGS.contains(&name) {
+                    self.nodes(&element.children, out);
+                    out.push_str(&format!("</{}>", name));
+                }
+            }
+            _ => self.nodes(&element.children, out),
+        }
+    }
+
+    fn allowed_attributes(&self, element: &HtmlElement) -> Vec<(&'static str, String)> {
+        match element.name.as_str() {
+            "ol" => element
+                .attr("start")
+                .filter(|start| start.parse::<u32>().is_ok())
+                .map(|start| vec![("start", start.to_string())])
+                .unwrap_or_default(),
+            "code" => element
+                .attr("class")
+                .and_then(|classes| {
+                    classes
+                        .split_whitespace()
+                        .find(|class| class.starts_with("language-"))
+                })
+                .map(|class| vec![("class", class.to_string())])
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// `<span class="emoji emoji-1f604" title="smile">:smile:</span>`
+    fn emoji(&self, element: &HtmlElement, out: &mut String) {
+        let emoji = element
+            .attr("class")
+            .unwrap_or_default()
+            .split_whitespace()
+            .find_map(|class| class.strip_prefix("emoji-"))
+            .and_then(emoji_from_code);
+        match emoji {
+            Some(emoji) => out.push_str(&emoji),
+            None => out.push_str(&escape_html(&element.text())),
+        }
+    }
+
+    /// `<img alt=":parrot:" class="emoji" src="/user_avatars/2/emoji/images/1.gif" title="parrot">`
+    fn realm_emoji(&self, element: &HtmlElement, out: &mut String) {
+        let alt = element.attr("alt").unwrap_or_default();
+        let mxc_url = alt
+            .strip_prefix(':')
+            .and_then(|name| name.strip_suffix(':'))
+            .and_then(|name| self.emoji.get(name));
+        match mxc_url {
+            Some(mxc_url) if !self.plain => out.push_str(&format!(
+                "<img data-mx-emoticon src=\"{}\" alt=\"{}\" title=\"{}\" height=\"32\">",
+                escape_html(mxc_url),
+                escape_html(alt),
+                escape_html(alt)
+            )),
+            _ => out.push_str(&escape_html(alt)),
+        }
+    }
+
+    /// Users become pills, wildcards `@room`, and groups stay text but notify their members.
+    /// Silent mentions (`@_**Name**`) are rendered the same way without notifying anyone.
+    fn mention(&self, element: &HtmlElement, out: &mut String) {
+        let text = element.text();
+        let text = text.trim();
+        let notify = !element.has_class("silent");
+        let id = |attr| {
+            element
+                .attr(attr)
+                .and_then(|id: &str| id.parse::<i64>().ok())
+        };
+
+        if element.has_class("user-group-mention") {
+            let members = id("data-user-group-id").and_then(|id| self.targets.groups.get(&id));
+            if notify && let Some(members) = members {
+                let mut mentions = self.mentions.borrow_mut();
+                for user_id in members.iter().filter_map(|id| self.targets.users.get(id)) {
+                    mentions.add_user(user_id);
+                }
+            }
+            out.push_str(&escape_html(text));
+            return;
+        }
+
+        // `@**all**`, `@**everyone**`, `@**stream**` and `@**channel**` carry `data-user-id="*"`.
+        let topic = element.has_class("topic-mention");
+        if topic || element.attr("data-user-id") == Some("*") {
+            if notify && (!topic || self.targets.topic_room) {
+                self.mentions.borrow_mut().room = true;
+                out.push_str("@room");
+            } else {
+                out.push_str(&escape_html(text));
+            }
+            return;
+        }
+
+        let Some(user_id) = id("data-user-id").and_then(|id| self.targets.users.get(&id)) else {
+            out.push_str(&escape_html(text));
+            return;
+        };
+        if notify {
+            self.mentions.borrow_mut().add_user(user_id);
+        }
+        let name = escape_html(text.trim_start_matches('@'));
+        if self.plain {
+            out.push_str(&name);
+        } else {
+            out.push_str(&format!(
+                "<a href=\"https://matrix.to/#/{}\">{}</a>",
+                escape_html(user_id),
+                name
+            ));
+        }
+    }
+
+    /// KaTeX output keeps the TeX source in an `application/x-tex` annotation.
+    fn maths(&self, element: &HtmlElement, out: &mut String) {
+        let Some(tex) = find_element(element, &|e| {
+            e.name == "annotation" && e.attr("encoding") == Some("application/x-tex")
+        })
+        .map(HtmlElement::text) else {
+            return;
+        };
+
+        let tex = escape_html(tex.trim());
+        if element.has_class("katex-display") {
+            out.push_str(&format!(
+                "<div data-mx-maths=\"{}\"><pre><code>{}</code></pre></div>",
+                tex, tex
+            ));
+        } else {
+            out.push_str(&format!(
+                "<span data-mx-maths=\"{}\"><code>{}</code></span>",
+                tex, tex
+            ));
+        }
+    }
+
+    /// `<div class="codehilite" data-code-language="Python"><pre><span></span><code>…`
+    fn code_block(&self, element: &HtmlElement, out: &mut String) {
+        let code = find_element(element, &|e| e.name == "pre")
+            .map(HtmlElement::text)
+            .unwrap_or_else(|| element.text());
+        let language = element
+            .attr("data-code-language")
+            .map(str::to_lowercase)
+            .filter(|language| {
+                !language.is_empty()
+                    && language
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "+-_.#".contains(c))
+            });
+
+        if self.plain {
+            out.push_str(&format!("<pre>{}</pre>", escape_html(&code)));
+            return;
+        }
+
+        match language {
+            Some(language) => out.push_str(&format!(
+                "<pre><code class=\"language-{}\">",
+                escape_html(&language)
+            )),
+            None => out.push_str("<pre><code>"),
+        }
+        out.push_str(&escape_html(&code));
+        out.push_str("</code></pre>");
+    }
+
+    fn spoiler(&self, element: &HtmlElement, out: &mut String) {
+        let section = |class: &str| element.child_elements().find(|child| child.has_class(class));
+
+        out.push_str("<details><summary>");
+        match section("spoiler-header") {
+            Some(header) if !header.text().trim().is_empty() => {
+                let mut summary = String::new();
+                self.nodes(&header.children, &mut summary);
+                out.push_str(strip_paragraph(summary.trim()));
+            }
+            _ => out.push_str("Spoiler"),
+        }
+        out.push_str("</summary>");
+        if let Some(content) = section("spoiler-content") {
+            self.nodes(&content.children, out);
+        }
+        out.push_str("</details>");
+    }
+
+    /// Link previews become a quote of the title and description.
+    fn embed(&self, element: &HtmlElement, out: &mut String) {
+        let title = find_element(element, &|e| e.has_class("message_embed_title"));
+        let description = find_element(element, &|e| e.has_class("message_embed_description"));
+        if title.is_none() && description.is_none() {
+            return;
+        }
+
+        out.push_str("<blockquote>");
+        if let Some(title) = title {
+            out.push_str("<strong>");
+            self.nodes(&title.children, out);
+            out.push_str("</strong>");
+        }
+        if let Some(description) = description {
+            if title.is_some() {
+                out.push_str("<br>");
+            }
+            self.nodes(&description.children, out);
+        }
+        out.push_str("</blockquote>");
+    }
+
+    /// Image, video and link previews become a plain link to what they preview.
+    fn inline_preview(&self, element: &HtmlElement, out: &mut String) {
+        let Some(link) = find_element(element, &|e| e.name == "a") else {
+            return;
+        };
+        let dimensions = find_element(element, &|e| e.name == "img")
+            .and_then(|img| img.attr("data-original-dimensions"))
+            .and_then(|dimensions| dimensions.split_once('x'))
+            .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)));
+        if self.upload(link, dimensions) {
+            return;
+        }
+        let Some(href) = link.attr("href").and_then(|href| self.resolve(href)) else {
+            return;
+        };
+        // Uploads are previewed right after the link to them.
+        if out.contains(&escape_html(&href)) {
+            return;
+        }
+        let label = link
+            .attr("title")
+            .filter(|title| !title.trim().is_empty())
+            .unwrap_or(href.as_str());
+        out.push_str("<p>");
+        self.write_link(&href, &escape_html(label), out);
+        out.push_str("</p>");
+    }
+
+    /// `<time datetime="2024-01-01T00:00:00Z">` carries the instant; Zulip clients show it in
+    /// the viewer's timezone, which Matrix cannot, so it is spelled out in UTC.
+    fn time(&self, element: &HtmlElement, out: &mut String) {
+        match element
+            .attr("datetime")
+            .and_then(|datetime| DateTime::parse_from_rfc3339(datetime).ok())
+        {
+            Some(datetime) => out.push_str(
+                &datetime
+                    .with_timezone(&Utc)
+                    .format("%a, %b %-d %Y, %H:%M UTC")
+                    .to_string(),
+            ),
+            None => out.push_str(&escape_html(&element.text())),
+        }
+    }
+
+    fn link(&self, element: &HtmlElement, out: &mut String) {
+        let mut label = String::new();
+        self.nodes(&element.children, &mut label);
+        let Some(href) = element.attr("href") else {
+            out.push_str(&label);
+            return;
+        };
+        if self.upload(element, None) {
+            out.push_str(&label);
+            return;
+        }
+
+        let bridged = self
+            .realm_link(href)
+            .and_then(|link| self.links.get(&link))
+            .map(MatrixLink::to_url);
+        match bridged.or_else(|| self.resolve(href)) {
+            Some(href) => self.write_link(&href, &label, out),
+            None => out.push_str(&label),
+        }
+    }
+
+    /// Records the upload `link` points at as an attachment. Returns false for other links.
+    fn upload(&self, link: &HtmlElement, dimensions: Option<(u32, u32)>) -> bool {
+        let Some(url) = link.attr("href").and_then(|href| self.upload_url(href)) else {
+            return false;
+        };
+        let name = Some(link.text().trim().to_string())
+            .filter(|name| !name.is_empty())
+            .or_else(|| link.attr("title").map(ToOwned::to_owned))
+            .filter(|name| !name.trim().is_empty())
+            .unwrap_or_else(|| upload_file_name(&url));
+
+        let mut attachments = self.attachments.borrow_mut();
+        match attachments.iter_mut().find(|attachment| attachment.url == url) {
+            Some(attachment) => attachment.dimensions = attachment.dimensions.or(dimensions),
+            None => attachments.push(ZulipAttachment {
+                url,
+                name,
+                dimensions,
+            }),
+        }
+        true
+    }
+
+    /// The absolute URL of `href` if it is a file uploaded to this realm.
+    fn upload_url(&self, href: &str) -> Option<String> {
+        let url = Url::parse(&self.resolve(href)?).ok()?;
+        (url.origin() == self.realm.as_ref()?.origin() && url.path().starts_with("/user_uploads/"))
+            .then(|| url.to_string())
+    }
+
+    /// A paragraph made of upload links only, as Zulip writes a message of attached files.
+    fn is_upload_paragraph(&self, element: &HtmlElement) -> bool {
+        let mut links = 0;
+        for child in &element.children {
+            match child {
+                HtmlNode::Text(text) if text.trim().is_empty() => {}
+                HtmlNode::Element(child) if child.name == "br" => {}
+                HtmlNode::Element(child)
+                    if child.name == "a"
+                        && child.attr("href").and_then(|href| self.upload_url(href)).is_some() =>
+                {
+                    links += 1
+                }
+                _ => return false,
+            }
+        }
+        links > 0
+    }
+
+    /// The stream, topic or message a link points at, if it is a narrow URL of this realm.
+    fn realm_link(&self, href: &str) -> Option<ZulipLink> {
+        let url = Url::parse(&self.resolve(href)?).ok()?;
+        if url.origin() != self.realm.as_ref()?.origin() {
+            return None;
+        }
+        ZulipLink::parse(url.as_str())
+    }
+
+    fn write_link(&self, href: &str, label: &str, out: &mut String) {
+        let href = escape_html(href);
+        if !self.plain {
+            out.push_str(&format!("<a href=\"{}\">{}</a>", href, label));
+        } else if label.is_empty() || label == href || href.trim_end_matches('/') == label {
+            out.push_str(&href);
+        } else {
+            out.push_str(&format!("{} ({})", label, href));
+        }
+    }
+
+    /// Matrix clients only load `mxc://` images, so others are linked instead.
+    fn image(&self, element: &HtmlElement, out: &mut String) {
+        let label = element
+            .attr("alt")
+            .or_else(|| element.attr("title"))
+            .filter(|label| !label.trim().is_empty());
+        match element.attr("src").and_then(|src| self.resolve(src)) {
+            Some(src) => self.write_link(&src, &escape_html(label.unwrap_or(&src)), out),
+            None => out.push_str(&escape_html(label.unwrap_or_default())),
+        }
+    }
+
+    /// Makes realm-relative links absolute and rejects schemes other than web and mail links.
+    fn resolve(&self, href: &str) -> Option<String> {
+        let url = match Url::parse(href) {
+            Ok(url) => url,
+            Err(url::ParseError::RelativeUrlWithoutBase) => self.realm.as_ref()?.join(href).ok()?,
+            Err(_) => return None,
+        };
+        matches!(url.scheme(), "http" | "https" | "mailto").then(|| url.to_string())
+    }
+}
+
+fn find_element<'a>(
+    element: &'a HtmlElement,
+    predicate: &dyn Fn(&HtmlElement) -> bool,
+) -> Option<&'a HtmlElement> {
+    element.child_elements().find_map(|child| {
+        if predicate(child) {
+            Some(child)
+        } else {
+            find_element(child, predicate)
+        }
+    })
+}
+
+/// The decoded last path segment of an upload URL.
+fn upload_file_name(url: &str) -> String {
+    let segment = url.rsplit('/').next().unwrap_or_default();
+    percent_encoding::percent_decode_str(segment)
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+fn strip_paragraph(html: &str) -> &str {
+    html.strip_prefix("<p>")
+        .and_then(|html| html.strip_suffix("</p>"))
+        .filter(|inner| !inner.contains("<p>"))
+        .unwrap_or(html)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    const REALM: &str = "https://chat.example.org";
+
+    fn convert(html: &str, targets: &MentionTargets) -> MatrixContent {
+        parse_zulip_message(html, REALM, targets, &HashMap::new(), &HashMap::new())
+    }
+
+    fn targets(
+        users: &[(i64, &str)],
+        groups: &[(i64, &[i64])],
+        topic_room: bool,
+    ) -> MentionTargets {
+        MentionTargets {
+            users: users
+                .iter()
+                .map(|(id, user_id)| (*id, user_id.to_string()))
+                .collect(),
+            groups: groups
+                .iter()
+                .map(|(id, members)| (*id, members.to_vec()))
+                .collect(),
+            topic_room,
+        }
+    }
+
+    #[test]
+    fn finds_notifying_mentions() {
+        let html = concat!(
+            "<p><span class=\"user-mention\" data-user-id=\"8\">@Alice</span> ",
+            "<span class=\"user-mention silent\" data-user-id=\"9\">Bob</span> ",
+            "<span class=\"user-mention\" data-user-id=\"8\">@Alice</span> ",
+            "<span class=\"user-group-mention\" data-user-group-id=\"3\">@support</span> ",
+            "<span class=\"user-group-mention silent\" data-user-group-id=\"4\">ops</span></p>",
+        );
+        let mentions = find_mentions(html);
+        assert_eq!(mentions.user_ids, [8, 9]);
+        assert_eq!(mentions.group_ids, [3]);
+    }
+
+    #[test]
+    fn turns_user_mentions_into_pills() {
+        let targets = targets(&[(8, "@_zulip_8:example.org"), (9, "@bob:example.org")], &[], false);
+        let html = concat!(
+            "<p><span class=\"user-mention\" data-user-id=\"8\">@Alice</span> and ",
+            "<span class=\"user-mention silent\" data-user-id=\"9\">Bob</span> and ",
+            "<span class=\"user-mention\" data-user-id=\"10\">@Carol</span></p>",
+        );
+        let content = convert(html, &targets);
+        assert_eq!(content.body, "Alice and Bob and @Carol");
+        assert_eq!(
+            content.formatted_body.as_deref(),
+            Some(concat!(
+                "<p><a href=\"https://matrix.to/#/@_zulip_8:example.org\">Alice</a> and ",
+                "<a href=\"https://matrix.to/#/@bob:example.org\">Bob</a> and @Carol</p>",
+            ))
+        );
+        // Silent mentions keep the pill but notify nobody.
+        assert_eq!(content.mentions.user_ids, ["@_zulip_8:example.org"]);
+        assert!(!content.mentions.room);
+    }
+
+    #[test]
+    fn turns_wildcards_into_room_mentions() {
+        let html = "<p><span class=\"user-mention\" data-user-id=\"*\">@all</span> hi</p>";
+        let content = convert(html, &MentionTargets::default());
+        assert_eq!(content.body, "@room hi");
+        assert!(content.mentions.room);
+
+        let html = "<p><span class=\"user-mention silent\" data-user-id=\"*\">everyone</span></p>";
+        let content = convert(html, &MentionTargets::default());
+        assert_eq!(content.body, "everyone");
+        assert!(!content.mentions.room);
+    }
+
+    #[test]
+    fn mentions_the_room_for_topic_wildcards_only_in_topic_rooms() {
+        let html = "<p><span class=\"topic-mention\">@topic</span> hi</p>";
+        let content = convert(html, &targets(&[], &[], true));
+        assert_eq!(content.body, "@room hi");
+        assert!(content.mentions.room);
+
+        let content = convert(html, &MentionTargets::default());
+        assert_eq!(content.body, "@topic hi");
+        assert!(!content.mentions.room);
+    }
+
+    #[test]
+    fn notifies_user_group_members() {
+        let targets = targets(
+            &[(8, "@_zulip_8:example.org"), (9, "@bob:example.org")],
+            &[(3, &[8, 9, 10]), (4, &[8])],
+            false,
+        );
+        let html = concat!(
+            "<p><span class=\"user-group-mention\" data-user-group-id=\"3\">@support</span> ",
+            "<span class=\"user-group-mention silent\" data-user-group-id=\"4\">ops</span></p>",
+        );
+        let content = convert(html, &targets);
+        assert_eq!(content.body, "@support ops");
+        assert_eq!(content.formatted_body, None);
+        assert_eq!(
+            content.mentions.user_ids,
+            ["@_zulip_8:example.org", "@bob:example.org"]
+        );
+    }
+
+    #[derive(Deserialize)]
+    struct Case {
+        name: String,
+        html: String,
+        body: String,
+        formatted_body: Option<String>,
+        #[serde(default)]
+        attachments: Vec<Attachment>,
+    }
+
+    #[derive(Deserialize)]
+    struct Attachment {
+        url: String,
+        name: String,
+        dimensions: Option<(u32, u32)>,
+    }
+
+    #[test]
+    fn converts_fixture_corpus() {
+        let cases: Vec<Case> =
+            serde_json::from_str(include_str!("../../fixtures/parsers/zulip_to_matrix.json"))
+                .unwrap();
+        let targets = targets(&[(8, "@_zulip_8:example.org")], &[], false);
+        let links = HashMap::from([(
+            ZulipLink {
+                stream_id: 5,
+                topic: Some("greetings".to_string()),
+                message_id: None,
+            },
+            MatrixLink {
+                room_id: "!portal:example.org".to_string(),
+                event_id: None,
+            },
+        )]);
+        let emoji = HashMap::from([("parrot".to_string(), "mxc://example.org/parrot".to_string())]);
+        for case in cases {
+            let content = parse_zulip_message(&case.html, REALM, &targets, &links, &emoji);
+            assert_eq!(content.body, case.body, "{}", case.name);
+            assert_eq!(content.formatted_body, case.formatted_body, "{}", case.name);
+            let attachments: Vec<_> = case
+                .attachments
+                .into_iter()
+                .map(|attachment| ZulipAttachment {
+                    url: attachment.url,
+                    name: attachment.name,
+                    dimensions: attachment.dimensions,
+                })
+                .collect();
+            assert_eq!(content.attachments, attachments, "{}", case.name);
+            let formatted = content.formatted_body.unwrap_or_default().to_ascii_lowercase();
+            for hostile in ["<script", "javascript:", "data:"] {
+                assert!(!formatted.contains(hostile), "{}: {formatted}", case.name);
+            }
+        }
+    }
 }
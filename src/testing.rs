@@ -0,0 +1,84 @@
+//! Local stand-ins for the services the bridge talks to, so the event loop, reconnection,
+//! backfill and relay paths can be driven without network access. Only built for tests and with
+//! the `test-support` feature.
+
+pub mod fake_matrix;
+pub mod fake_zulip;
+
+pub use self::fake_matrix::FakeHomeserver;
+pub use self::fake_zulip::FakeZulip;
+
+use std::sync::Arc;
+
+use chrono::Utc;
+use tokio::sync::OnceCell;
+
+use crate::config::Config;
+use crate::db::DatabaseManager;
+use crate::db::models::Organization;
+use crate::utils::{BridgeError, Result};
+
+/// Environment variable naming the Postgres database tests that need one run against.
+pub const DATABASE_URL_VAR: &str = "TEST_DATABASE_URL";
+
+static MIGRATED: OnceCell<()> = OnceCell::const_new();
+
+/// Bridge configuration pointing at `homeserver` and, when `TEST_DATABASE_URL` is set, the
+/// test database.
+pub fn test_config(homeserver: &FakeHomeserver) -> Result<Config> {
+    let database_url = std::env::var(DATABASE_URL_VAR).unwrap_or_default();
+    let settings = serde_json::json!({
+        "bridge": { "homeserver_url": "", "domain": "", "bind_address": "127.0.0.1", "port": 0 },
+        "database": { "db_type": "postgres", "url": database_url, "max_connections": 4 },
+        "registration": {
+            "bridge_id": "zulipbridge",
+            "sender_localpart": "zulipbridge",
+            "appservice_token": "",
+            "homeserver_token": "",
+        },
+        "zulip": {},
+        "room": {},
+        "limits": {},
+    });
+
+    let mut config: Config = serde_json::from_value(settings)?;
+    homeserver.configure(&mut config);
+    Ok(config)
+}
+
+/// Connects to the test database, migrating it once per test binary. Rows are not cleaned up
+/// between tests, so each test works in an organization of its own.
+pub async fn test_database(config: &Config) -> Result<Arc<DatabaseManager>> {
+    if config.database.url.is_empty() {
+        return Err(BridgeError::Config(format!("{} is not set", DATABASE_URL_VAR)));
+    }
+    let db_manager = Arc::new(DatabaseManager::new(&config.database).await?);
+    MIGRATED.get_or_try_init(|| db_manager.migrate()).await?;
+    Ok(db_manager)
+}
+
+/// Stores a connected organization for `zulip`'s bot account under a fresh id.
+pub async fn create_organization(
+    db_manager: &DatabaseManager,
+    zulip: &FakeZulip,
+) -> Result<Organization> {
+    let now = Utc::now();
+    let org = Organization {
+        id: format!("test-{}", uuid::Uuid::new_v4()),
+        name: "Fake Zulip".to_string(),
+        site: zulip.url().to_string(),
+        email: fake_zulip::BOT_EMAIL.to_string(),
+        api_key: fake_zulip::BOT_API_KEY.to_string(),
+        connected: true,
+        max_backfill_amount: 100,
+        created_at: now,
+        updated_at: now,
+        event_queue_id: None,
+        last_event_id: None,
+        zulip_version: None,
+        zulip_feature_level: None,
+        webhook_token: None,
+        space_room_id: None,
+    };
+    Ok(db_manager.organization_store().create(org).await?)
+}
@@ -0,0 +1,687 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use parking_lot::Mutex;
+use salvo::conn::{Acceptor, TcpListener};
+use salvo::http::StatusCode;
+use salvo::prelude::*;
+use salvo::server::ServerHandle;
+use serde_json::{Value, json};
+
+use crate::config::Config;
+use crate::utils::{BridgeError, Result};
+
+pub const SERVER_NAME: &str = "matrix.example.com";
+pub const AS_TOKEN: &str = "fake-as-token";
+pub const HS_TOKEN: &str = "fake-hs-token";
+
+/// A client-server API call made by the bridge, with the user it acted as (`?user_id=`, or
+/// the appservice bot when absent) and the `ts` override, if any.
+#[derive(Debug, Clone)]
+pub struct RecordedCall {
+    pub method: String,
+    pub path: String,
+    pub user_id: Option<String>,
+    pub ts: Option<i64>,
+    pub body: Value,
+}
+
+/// Returned instead of the real response to the next call whose percent-decoded path starts with
+/// `path`, so room ids can be written as is.
+#[derive(Debug, Clone)]
+struct ScriptedFailure {
+    path: String,
+    status: StatusCode,
+    body: Value,
+}
+
+#[derive(Default)]
+struct FakeRoom {
+    /// Latest membership per user.
+    members: BTreeMap<String, String>,
+    state: HashMap<(String, String), Value>,
+    events: Vec<Value>,
+}
+
+#[derive(Default)]
+struct State {
+    bot_user_id: String,
+    users: HashSet<String>,
+    display_names: HashMap<String, String>,
+    avatar_urls: HashMap<String, String>,
+    rooms: BTreeMap<String, FakeRoom>,
+    aliases: HashMap<String, String>,
+    /// Event ids of already handled `(user, txn_id)` pairs, so retried sends are idempotent.
+    transactions: HashMap<(String, String), String>,
+    media: HashMap<String, (String, Vec<u8>)>,
+    failures: VecDeque<ScriptedFailure>,
+    calls: Vec<RecordedCall>,
+    appservice_url: Option<String>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    next_id: AtomicU64,
+    /// Random per homeserver, so room and event ids stay unique when tests share a database.
+    id_prefix: String,
+}
+
+/// An in-process homeserver for appservice tests. It answers the client-server API calls the
+/// bridge makes as its bot and as ghosts, keeps rooms, memberships, events, profiles and media,
+/// records every call, and pushes appservice transactions to the bridge's `WebServer`.
+pub struct FakeHomeserver {
+    url: String,
+    shared: Arc<Shared>,
+    handle: ServerHandle,
+    http: reqwest::Client,
+}
+
+impl FakeHomeserver {
+    pub async fn start() -> Result<Self> {
+        let acceptor = TcpListener::new("127.0.0.1:0").bind().await;
+        let addr = acceptor
+            .holdings()
+            .first()
+            .and_then(|holding| holding.local_addr.clone().into_std())
+            .ok_or_else(|| BridgeError::InvalidState("fake homeserver has no local address".to_string()))?;
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                bot_user_id: format!("@zulipbot:{}", SERVER_NAME),
+                ..Default::default()
+            }),
+            next_id: AtomicU64::new(1),
+            id_prefix: uuid::Uuid::new_v4().simple().to_string()[..8].to_string(),
+        });
+
+        let router = Router::with_path("{**rest}").goal(FakeHomeserverHandler(shared.clone()));
+        let server = Server::new(acceptor);
+        let handle = server.handle();
+        tokio::spawn(server.serve(router));
+
+        Ok(Self {
+            url: format!("http://{}", addr),
+            shared,
+            handle,
+            http: reqwest::Client::new(),
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Points `config` at this homeserver: URL, server name and both appservice tokens.
+    pub fn configure(&self, config: &mut Config) {
+        config.bridge.homeserver_url = self.url.clone();
+        config.bridge.domain = SERVER_NAME.to_string();
+        config.registration.appservice_token = AS_TOKEN.to_string();
+        config.registration.homeserver_token = HS_TOKEN.to_string();
+
+        let bot_user_id = format!("@{}:{}", config.registration.sender_localpart, SERVER_NAME);
+        let mut state = self.shared.state.lock();
+        state.users.insert(bot_user_id.clone());
+        state.bot_user_id = bot_user_id;
+    }
+
+    /// Base URL of the bridge's `WebServer`, where transactions are pushed.
+    pub fn set_appservice_url(&self, url: &str) {
+        self.shared.state.lock().appservice_url = Some(url.trim_end_matches('/').to_string());
+    }
+
+    pub fn create_room(&self, creator: &str) -> String {
+        self.shared.create_room(creator, &json!({}))
+    }
+
+    /// Stores an event from a real Matrix user and pushes it to the bridge. Returns its id.
+    pub async fn send_event(&self, room_id: &str, sender: &str, event_type: &str, content: Value) -> Result<String> {
+        let event = self.shared.append_event(room_id, sender, event_type, None, content, None);
+        let event_id = event["event_id"].as_str().unwrap_or_default().to_string();
+        self.push_transaction(vec![event]).await?;
+        Ok(event_id)
+    }
+
+    pub async fn send_text(&self, room_id: &str, sender: &str, body: &str) -> Result<String> {
+        self.send_event(room_id, sender, "m.room.message", json!({ "msgtype": "m.text", "body": body }))
+            .await
+    }
+
+    pub async fn send_reaction(&self, room_id: &str, sender: &str, event_id: &str, key: &str) -> Result<String> {
+        let content = json!({
+            "m.relates_to": { "rel_type": "m.annotation", "event_id": event_id, "key": key }
+        });
+        self.send_event(room_id, sender, "m.reaction", content).await
+    }
+
+    pub async fn send_redaction(&self, room_id: &str, sender: &str, event_id: &str) -> Result<String> {
+        let event = self.shared.append_event(
+            room_id,
+            sender,
+            "m.room.redaction",
+            None,
+            json!({ "redacts": event_id }),
+            Some(event_id),
+        );
+        let redaction_id = event["event_id"].as_str().unwrap_or_default().to_string();
+        self.push_transaction(vec![event]).await?;
+        Ok(redaction_id)
+    }
+
+    pub async fn set_membership(&self, room_id: &str, user_id: &str, membership: &str) -> Result<String> {
+        let event = self.shared.append_event(
+            room_id,
+            user_id,
+            "m.room.member",
+            Some(user_id),
+            json!({ "membership": membership }),
+            None,
+        );
+        let event_id = event["event_id"].as_str().unwrap_or_default().to_string();
+        self.push_transaction(vec![event]).await?;
+        Ok(event_id)
+    }
+
+    /// Sends raw events to the bridge as one appservice transaction.
+    pub async fn push_transaction(&self, events: Vec<Value>) -> Result<()> {
+        let base = self
+            .shared
+            .state
+            .lock()
+            .appservice_url
+            .clone()
+            .ok_or_else(|| BridgeError::InvalidState("no appservice URL configured".to_string()))?;
+        let txn_id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+        let url = format!("{}/_matrix/app/v1/transactions/{}", base, txn_id);
+
+        let response = self
+            .http
+            .put(url)
+            .bearer_auth(HS_TOKEN)
+            .json(&json!({ "events": events }))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(BridgeError::Matrix(format!(
+                "appservice rejected transaction {}: HTTP {}",
+                txn_id,
+                response.status()
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn fail_next(&self, path: &str, status: u16, errcode: &str, error: &str) {
+        self.shared.state.lock().failures.push_back(ScriptedFailure {
+            path: path.to_string(),
+            status: StatusCode::from_u16(status).unwrap_or(StatusCode::BAD_REQUEST),
+            body: json!({ "errcode": errcode, "error": error }),
+        });
+    }
+
+    pub fn rate_limit_next(&self, path: &str, retry_after_ms: u64) {
+        self.shared.state.lock().failures.push_back(ScriptedFailure {
+            path: path.to_string(),
+            status: StatusCode::TOO_MANY_REQUESTS,
+            body: json!({
+                "errcode": "M_LIMIT_EXCEEDED",
+                "error": "Too many requests",
+                "retry_after_ms": retry_after_ms,
+            }),
+        });
+    }
+
+    pub fn calls(&self) -> Vec<RecordedCall> {
+        self.shared.state.lock().calls.clone()
+    }
+
+    pub fn rooms(&self) -> Vec<String> {
+        self.shared.state.lock().rooms.keys().cloned().collect()
+    }
+
+    /// Timeline of a room, oldest first, including state and redaction events.
+    pub fn events(&self, room_id: &str) -> Vec<Value> {
+        self.shared
+            .state
+            .lock()
+            .rooms
+            .get(room_id)
+            .map(|room| room.events.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn event(&self, event_id: &str) -> Option<Value> {
+        let state = self.shared.state.lock();
+        state
+            .rooms
+            .values()
+            .flat_map(|room| room.events.iter())
+            .find(|event| event["event_id"] == event_id)
+            .cloned()
+    }
+
+    pub fn state_event(&self, room_id: &str, event_type: &str, state_key: &str) -> Option<Value> {
+        let state = self.shared.state.lock();
+        state
+            .rooms
+            .get(room_id)?
+            .state
+            .get(&(event_type.to_string(), state_key.to_string()))
+            .cloned()
+    }
+
+    pub fn joined_members(&self, room_id: &str) -> Vec<String> {
+        let state = self.shared.state.lock();
+        state
+            .rooms
+            .get(room_id)
+            .map(|room| {
+                room.members
+                    .iter()
+                    .filter(|(_, membership)| *membership == "join")
+                    .map(|(user_id, _)| user_id.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn is_registered(&self, user_id: &str) -> bool {
+        self.shared.state.lock().users.contains(user_id)
+    }
+
+    pub fn display_name(&self, user_id: &str) -> Option<String> {
+        self.shared.state.lock().display_names.get(user_id).cloned()
+    }
+
+    pub fn media(&self, mxc_url: &str) -> Option<(String, Vec<u8>)> {
+        let media_id = mxc_url.rsplit('/').next()?;
+        self.shared.state.lock().media.get(media_id).cloned()
+    }
+
+    pub fn stop(&self) {
+        self.handle.stop_forcible();
+    }
+}
+
+impl Drop for FakeHomeserver {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn ok(body: Value) -> (StatusCode, Value) {
+    (StatusCode::OK, body)
+}
+
+fn error(status: StatusCode, errcode: &str, error: &str) -> (StatusCode, Value) {
+    (status, json!({ "errcode": errcode, "error": error }))
+}
+
+fn not_found(error_msg: &str) -> (StatusCode, Value) {
+    error(StatusCode::NOT_FOUND, "M_NOT_FOUND", error_msg)
+}
+
+fn forbidden(error_msg: &str) -> (StatusCode, Value) {
+    error(StatusCode::FORBIDDEN, "M_FORBIDDEN", error_msg)
+}
+
+impl Shared {
+    fn next_id(&self) -> String {
+        format!("{}_{}", self.id_prefix, self.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn create_room(&self, creator: &str, options: &Value) -> String {
+        let room_id = format!("!room{}:{}", self.next_id(), SERVER_NAME);
+        self.state.lock().rooms.insert(room_id.clone(), FakeRoom::default());
+
+        self.append_event(&room_id, creator, "m.room.create", Some(""), json!({ "creator": creator }), None);
+        self.append_event(&room_id, creator, "m.room.member", Some(creator), json!({ "membership": "join" }), None);
+        if let Some(name) = options.get("name").filter(|name| name.is_string()) {
+            self.append_event(&room_id, creator, "m.room.name", Some(""), json!({ "name": name }), None);
+        }
+        if let Some(topic) = options.get("topic").filter(|topic| topic.is_string()) {
+            self.append_event(&room_id, creator, "m.room.topic", Some(""), json!({ "topic": topic }), None);
+        }
+        if let Some(alias) = options.get("room_alias_name").and_then(Value::as_str) {
+            let alias = format!("#{}:{}", alias, SERVER_NAME);
+            self.state.lock().aliases.insert(alias, room_id.clone());
+        }
+        for invitee in options.get("invite").and_then(Value::as_array).into_iter().flatten() {
+            if let Some(invitee) = invitee.as_str() {
+                self.append_event(&room_id, creator, "m.room.member", Some(invitee), json!({ "membership": "invite" }), None);
+            }
+        }
+        room_id
+    }
+
+    fn append_event(
+        &self,
+        room_id: &str,
+        sender: &str,
+        event_type: &str,
+        state_key: Option<&str>,
+        content: Value,
+        redacts: Option<&str>,
+    ) -> Value {
+        self.append_event_at(room_id, sender, event_type, state_key, content, redacts, None)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn append_event_at(
+        &self,
+        room_id: &str,
+        sender: &str,
+        event_type: &str,
+        state_key: Option<&str>,
+        content: Value,
+        redacts: Option<&str>,
+        ts: Option<i64>,
+    ) -> Value {
+        let mut event = json!({
+            "event_id": format!("$event{}:{}", self.next_id(), SERVER_NAME),
+            "room_id": room_id,
+            "sender": sender,
+            "type": event_type,
+            "content": content,
+            "origin_server_ts": ts.unwrap_or_else(|| chrono::Utc::now().timestamp_millis()),
+        });
+        if let Some(state_key) = state_key {
+            event["state_key"] = json!(state_key);
+        }
+        if let Some(redacts) = redacts {
+            event["redacts"] = json!(redacts);
+        }
+
+        let mut state = self.state.lock();
+        let room = state.rooms.entry(room_id.to_string()).or_default();
+        if let Some(state_key) = state_key {
+            if event_type == "m.room.member"
+                && let Some(membership) = event["content"]["membership"].as_str()
+            {
+                room.members.insert(state_key.to_string(), membership.to_string());
+            }
+            room.state
+                .insert((event_type.to_string(), state_key.to_string()), event["content"].clone());
+        }
+        room.events.push(event.clone());
+        event
+    }
+
+    fn resolve_room(&self, room_id_or_alias: &str) -> Option<String> {
+        let state = self.state.lock();
+        if room_id_or_alias.starts_with('#') {
+            return state.aliases.get(room_id_or_alias).cloned();
+        }
+        state
+            .rooms
+            .contains_key(room_id_or_alias)
+            .then(|| room_id_or_alias.to_string())
+    }
+
+    fn membership(&self, room_id: &str, user_id: &str) -> Option<String> {
+        self.state.lock().rooms.get(room_id)?.members.get(user_id).cloned()
+    }
+
+    fn take_failure(&self, path: &str) -> Option<ScriptedFailure> {
+        let mut state = self.state.lock();
+        let index = state
+            .failures
+            .iter()
+            .position(|failure| path.starts_with(&failure.path))?;
+        state.failures.remove(index)
+    }
+
+    fn client_api(
+        &self,
+        method: &str,
+        segments: &[String],
+        user_id: &str,
+        ts: Option<i64>,
+        body: &Value,
+    ) -> (StatusCode, Value) {
+        let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+
+        match (method, segments.as_slice()) {
+            ("POST", ["register"]) => {
+                let Some(username) = body.get("username").and_then(Value::as_str) else {
+                    return error(StatusCode::BAD_REQUEST, "M_MISSING_PARAM", "Missing username");
+                };
+                let new_user_id = format!("@{}:{}", username, SERVER_NAME);
+                if !self.state.lock().users.insert(new_user_id.clone()) {
+                    return error(StatusCode::BAD_REQUEST, "M_USER_IN_USE", "User ID already taken.");
+                }
+                ok(json!({ "user_id": new_user_id }))
+            }
+            ("GET", ["profile", target, field]) => {
+                let state = self.state.lock();
+                let value = match *field {
+                    "displayname" => state.display_names.get(*target),
+                    "avatar_url" => state.avatar_urls.get(*target),
+                    _ => None,
+                };
+                match value {
+                    Some(value) => ok(json!({ *field: value })),
+                    None => not_found("Profile was not found"),
+                }
+            }
+            ("PUT", ["profile", target, field]) => {
+                if *target != user_id {
+                    return forbidden("Cannot set another user's profile");
+                }
+                let mut state = self.state.lock();
+                let value = body.get(*field).and_then(Value::as_str).unwrap_or_default().to_string();
+                match *field {
+                    "displayname" => state.display_names.insert(target.to_string(), value),
+                    "avatar_url" => state.avatar_urls.insert(target.to_string(), value),
+                    _ => return not_found("Unknown profile field"),
+                };
+                ok(json!({}))
+            }
+            ("POST", ["createRoom"]) => ok(json!({ "room_id": self.create_room(user_id, body) })),
+            ("POST", ["join", room]) | ("POST", ["rooms", room, "join"]) => {
+                let Some(room_id) = self.resolve_room(room) else {
+                    return not_found("No known servers");
+                };
+                self.append_event(&room_id, user_id, "m.room.member", Some(user_id), json!({ "membership": "join" }), None);
+                ok(json!({ "room_id": room_id }))
+            }
+            ("POST", ["rooms", room_id, action @ ("invite" | "kick" | "ban")]) => {
+                let Some(target) = body.get("user_id").and_then(Value::as_str) else {
+                    return error(StatusCode::BAD_REQUEST, "M_MISSING_PARAM", "Missing user_id");
+                };
+                if self.membership(room_id, user_id).as_deref() != Some("join") {
+                    return forbidden("You are not in this room");
+                }
+                let membership = match *action {
+                    "invite" => "invite",
+                    "kick" => "leave",
+                    _ => "ban",
+                };
+                let mut content = json!({ "membership": membership });
+                if let Some(reason) = body.get("reason") {
+                    content["reason"] = reason.clone();
+                }
+                self.append_event(room_id, user_id, "m.room.member", Some(target), content, None);
+                ok(json!({}))
+            }
+            ("POST", ["rooms", room_id, "leave"]) => {
+                self.append_event(room_id, user_id, "m.room.member", Some(user_id), json!({ "membership": "leave" }), None);
+                ok(json!({}))
+            }
+            ("PUT", ["rooms", room_id, "send", event_type, txn_id]) => {
+                if self.membership(room_id, user_id).as_deref() != Some("join") {
+                    return forbidden("User is not in the room");
+                }
+                let key = (user_id.to_string(), txn_id.to_string());
+                if let Some(event_id) = self.state.lock().transactions.get(&key) {
+                    return ok(json!({ "event_id": event_id }));
+                }
+                let event = self.append_event_at(room_id, user_id, event_type, None, body.clone(), None, ts);
+                self.state.lock().transactions.insert(key, event["event_id"].as_str().unwrap_or_default().to_string());
+                ok(json!({ "event_id": event["event_id"] }))
+            }
+            ("PUT", ["rooms", room_id, "redact", event_id, txn_id]) => {
+                if self.membership(room_id, user_id).as_deref() != Some("join") {
+                    return forbidden("User is not in the room");
+                }
+                let key = (user_id.to_string(), txn_id.to_string());
+                if let Some(redaction_id) = self.state.lock().transactions.get(&key) {
+                    return ok(json!({ "event_id": redaction_id }));
+                }
+                let event = self.append_event(room_id, user_id, "m.room.redaction", None, body.clone(), Some(event_id));
+                self.state.lock().transactions.insert(key, event["event_id"].as_str().unwrap_or_default().to_string());
+                ok(json!({ "event_id": event["event_id"] }))
+            }
+            ("PUT", ["rooms", room_id, "state", event_type, state_key @ ..]) => {
+                let state_key = state_key.first().copied().unwrap_or_default();
+                let event = self.append_event(room_id, user_id, event_type, Some(state_key), body.clone(), None);
+                ok(json!({ "event_id": event["event_id"] }))
+            }
+            ("GET", ["rooms", room_id, "state", event_type, state_key @ ..]) => {
+                let state_key = state_key.first().copied().unwrap_or_default();
+                let state = self.state.lock();
+                match state
+                    .rooms
+                    .get(*room_id)
+                    .and_then(|room| room.state.get(&(event_type.to_string(), state_key.to_string())))
+                {
+                    Some(content) => ok(content.clone()),
+                    None => not_found("Event not found."),
+                }
+            }
+            ("GET", ["rooms", room_id, "joined_members"]) => {
+                let state = self.state.lock();
+                let Some(room) = state.rooms.get(*room_id) else {
+                    return forbidden("User not in room");
+                };
+                let joined: serde_json::Map<String, Value> = room
+                    .members
+                    .iter()
+                    .filter(|(_, membership)| *membership == "join")
+                    .map(|(member, _)| {
+                        let display_name = state.display_names.get(member).cloned();
+                        (member.clone(), json!({ "display_name": display_name, "avatar_url": null }))
+                    })
+                    .collect();
+                ok(json!({ "joined": joined }))
+            }
+            ("GET", ["account", "whoami"]) => ok(json!({ "user_id": user_id })),
+            _ => error(StatusCode::NOT_FOUND, "M_UNRECOGNIZED", "Unrecognized request"),
+        }
+    }
+
+    fn upload_media(&self, content_type: &str, bytes: Vec<u8>) -> (StatusCode, Value) {
+        let media_id = format!("media{}", self.next_id());
+        self.state
+            .lock()
+            .media
+            .insert(media_id.clone(), (content_type.to_string(), bytes));
+        ok(json!({ "content_uri": format!("mxc://{}/{}", SERVER_NAME, media_id) }))
+    }
+}
+
+/// Splits a request path into percent-decoded segments.
+fn decoded_segments(path: &str) -> Vec<String> {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(percent_decode)
+        .collect()
+}
+
+fn percent_decode(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[derive(Clone)]
+struct FakeHomeserverHandler(Arc<Shared>);
+
+#[handler]
+impl FakeHomeserverHandler {
+    async fn handle(&self, req: &mut Request, res: &mut Response) {
+        let method = req.method().as_str().to_string();
+        let path = req.uri().path().to_string();
+        let query: HashMap<String, String> = req
+            .uri()
+            .query()
+            .map(|query| url::form_urlencoded::parse(query.as_bytes()).into_owned().collect())
+            .unwrap_or_default();
+        let content_type = req
+            .headers()
+            .get("content-type")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("application/octet-stream")
+            .to_string();
+        let authorized = req
+            .headers()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            == Some(format!("Bearer {}", AS_TOKEN).as_str());
+        let payload = req.payload().await.map(|bytes| bytes.to_vec()).unwrap_or_default();
+        let body: Value = serde_json::from_slice(&payload).unwrap_or(Value::Null);
+
+        let ts = query.get("ts").and_then(|ts| ts.parse().ok());
+        let user_id = query.get("user_id").cloned();
+        self.0.state.lock().calls.push(RecordedCall {
+            method: method.clone(),
+            path: path.clone(),
+            user_id: user_id.clone(),
+            ts,
+            body: body.clone(),
+        });
+
+        let segments = decoded_segments(&path);
+        let failure = self.0.take_failure(&percent_decode(&path));
+        let (status, response) = if let Some(failure) = failure {
+            (failure.status, failure.body)
+        } else if path == "/_matrix/client/versions" {
+            ok(json!({ "versions": ["v1.1", "v1.11"], "unstable_features": {} }))
+        } else if !authorized {
+            error(StatusCode::UNAUTHORIZED, "M_UNKNOWN_TOKEN", "Unrecognised access token")
+        } else {
+            let user_id = user_id.unwrap_or_else(|| self.0.state.lock().bot_user_id.clone());
+            match segments.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+                ["_matrix", "client", "v3" | "r0", ..] => {
+                    self.0.client_api(&method, &segments[3..], &user_id, ts, &body)
+                }
+                ["_matrix", "media", "v3", "upload"] if method == "POST" => {
+                    self.0.upload_media(&content_type, payload)
+                }
+                ["_matrix", "client", "v1", "media", "download", _, media_id, ..]
+                | ["_matrix", "media", "v3", "download", _, media_id, ..] => {
+                    let media = self.0.state.lock().media.get(*media_id).cloned();
+                    match media {
+                        Some((content_type, bytes)) => {
+                            res.add_header("content-type", content_type, true).ok();
+                            res.write_body(bytes).ok();
+                            return;
+                        }
+                        None => not_found("Media not found"),
+                    }
+                }
+                _ => error(StatusCode::NOT_FOUND, "M_UNRECOGNIZED", "Unrecognized request"),
+            }
+        };
+
+        res.status_code(status);
+        res.render(Json(response));
+    }
+}
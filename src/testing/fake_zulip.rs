@@ -0,0 +1,944 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use salvo::conn::{Acceptor, TcpListener};
+use salvo::http::StatusCode;
+use salvo::prelude::*;
+use salvo::server::ServerHandle;
+use serde_json::{Value, json};
+use tokio::sync::Notify;
+
+use crate::utils::{BridgeError, Result};
+use crate::zulip::{Anchor, ZulipClient, ZulipMessage, ZulipReaction, ZulipStream, ZulipUser};
+
+pub const BOT_USER_ID: i64 = 1;
+pub const BOT_EMAIL: &str = "bridge-bot@zulip.example.com";
+pub const BOT_API_KEY: &str = "fake-zulip-api-key";
+const REALM_ID: i64 = 2;
+const DEFAULT_VERSION: &str = "9.0";
+const DEFAULT_FEATURE_LEVEL: i64 = 278;
+/// Real servers send a heartbeat roughly every minute; tests should not have to wait that long.
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub path: String,
+    pub params: HashMap<String, String>,
+}
+
+/// Returned instead of the real response to the next request whose API path starts with `path`.
+#[derive(Debug, Clone)]
+struct ScriptedFailure {
+    path: String,
+    status: StatusCode,
+    body: Value,
+}
+
+#[derive(Default)]
+struct FakeQueue {
+    events: Vec<Value>,
+    next_event_id: i64,
+}
+
+struct State {
+    version: String,
+    feature_level: i64,
+    heartbeat_interval: Duration,
+    users: BTreeMap<i64, ZulipUser>,
+    streams: BTreeMap<i64, ZulipStream>,
+    messages: BTreeMap<i64, ZulipMessage>,
+    next_message_id: i64,
+    queues: HashMap<String, FakeQueue>,
+    next_queue_id: u64,
+    uploads: HashMap<String, Vec<u8>>,
+    failures: VecDeque<ScriptedFailure>,
+    requests: Vec<RecordedRequest>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    events: Notify,
+}
+
+/// Where a message goes, as understood by the fake.
+enum Recipient {
+    Stream { stream_id: i64, topic: String },
+    Direct(Vec<i64>),
+}
+
+/// An in-process Zulip server implementing the parts of the API `ZulipClient` uses, with state
+/// that tests can inspect and script: users, streams, message history, event queues, uploads and
+/// one-off failures such as rate limits or expired queues.
+///
+/// The bot account is user [`BOT_USER_ID`]; [`FakeZulip::client`] returns a client logged in
+/// as it.
+pub struct FakeZulip {
+    url: String,
+    shared: Arc<Shared>,
+    handle: ServerHandle,
+}
+
+impl FakeZulip {
+    pub async fn start() -> Result<Self> {
+        let acceptor = TcpListener::new("127.0.0.1:0").bind().await;
+        let addr = acceptor
+            .holdings()
+            .first()
+            .and_then(|holding| holding.local_addr.clone().into_std())
+            .ok_or_else(|| BridgeError::InvalidState("fake zulip has no local address".to_string()))?;
+
+        let mut users = BTreeMap::new();
+        users.insert(BOT_USER_ID, fake_user(BOT_USER_ID, "Bridge Bot", BOT_EMAIL, true));
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                version: DEFAULT_VERSION.to_string(),
+                feature_level: DEFAULT_FEATURE_LEVEL,
+                heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+                users,
+                streams: BTreeMap::new(),
+                messages: BTreeMap::new(),
+                next_message_id: 1,
+                queues: HashMap::new(),
+                next_queue_id: 1,
+                uploads: HashMap::new(),
+                failures: VecDeque::new(),
+                requests: Vec::new(),
+            }),
+            events: Notify::new(),
+        });
+
+        let router = Router::with_path("{**rest}").goal(FakeZulipHandler(shared.clone()));
+        let server = Server::new(acceptor);
+        let handle = server.handle();
+        tokio::spawn(server.serve(router));
+
+        Ok(Self {
+            url: format!("http://{}", addr),
+            shared,
+            handle,
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn client(&self) -> Result<ZulipClient> {
+        ZulipClient::new(&self.url, BOT_EMAIL, BOT_API_KEY)
+    }
+
+    pub fn set_server_version(&self, version: &str, feature_level: i64) {
+        let mut state = self.shared.state.lock();
+        state.version = version.to_string();
+        state.feature_level = feature_level;
+    }
+
+    pub fn set_heartbeat_interval(&self, interval: Duration) {
+        self.shared.state.lock().heartbeat_interval = interval;
+    }
+
+    pub fn add_user(&self, user_id: i64, full_name: &str) -> ZulipUser {
+        let email = format!("user{}@zulip.example.com", user_id);
+        let user = fake_user(user_id, full_name, &email, false);
+        self.shared.state.lock().users.insert(user_id, user.clone());
+        user
+    }
+
+    pub fn add_stream(&self, stream_id: i64, name: &str) -> ZulipStream {
+        let stream = ZulipStream {
+            stream_id,
+            name: name.to_string(),
+            description: Some(String::new()),
+            rendered_description: Some(String::new()),
+            invite_only: false,
+            is_announcement_only: false,
+            is_web_public: false,
+            history_public_to_subscribers: true,
+            first_message_id: None,
+            stream_post_policy: Some(1),
+            message_retention_days: None,
+        };
+        self.shared.state.lock().streams.insert(stream_id, stream.clone());
+        stream
+    }
+
+    /// Posts a stream message as `sender_id` and delivers it to every registered queue.
+    pub fn post_message(&self, sender_id: i64, stream_id: i64, topic: &str, content: &str) -> i64 {
+        let recipient = Recipient::Stream {
+            stream_id,
+            topic: topic.to_string(),
+        };
+        self.shared.send(sender_id, recipient, content, true)
+    }
+
+    pub fn post_direct_message(&self, sender_id: i64, recipients: &[i64], content: &str) -> i64 {
+        self.shared
+            .send(sender_id, Recipient::Direct(recipients.to_vec()), content, true)
+    }
+
+    /// Adds a stream message to the history without emitting an event, as if it had been sent
+    /// before the bridge registered its queue.
+    pub fn import_message(&self, sender_id: i64, stream_id: i64, topic: &str, content: &str) -> i64 {
+        let recipient = Recipient::Stream {
+            stream_id,
+            topic: topic.to_string(),
+        };
+        self.shared.send(sender_id, recipient, content, false)
+    }
+
+    pub fn add_reaction(&self, message_id: i64, user_id: i64, emoji_name: &str, emoji_code: &str) -> bool {
+        self.shared
+            .react(message_id, user_id, emoji_name, emoji_code, "unicode_emoji", true)
+    }
+
+    /// Delivers a raw event to every registered queue. The event id is filled in per queue.
+    pub fn push_event(&self, event: Value) {
+        self.shared.broadcast(event);
+    }
+
+    /// Forgets every event queue, so the next poll fails with `BAD_EVENT_QUEUE_ID`.
+    pub fn expire_queues(&self) {
+        self.shared.state.lock().queues.clear();
+        self.shared.events.notify_waiters();
+    }
+
+    pub fn queue_count(&self) -> usize {
+        self.shared.state.lock().queues.len()
+    }
+
+    pub fn fail_next(&self, path: &str, status: u16, code: &str, msg: &str) {
+        self.shared.state.lock().failures.push_back(ScriptedFailure {
+            path: path.to_string(),
+            status: StatusCode::from_u16(status).unwrap_or(StatusCode::BAD_REQUEST),
+            body: json!({ "result": "error", "msg": msg, "code": code }),
+        });
+    }
+
+    pub fn rate_limit_next(&self, path: &str, retry_after: Duration) {
+        self.shared.state.lock().failures.push_back(ScriptedFailure {
+            path: path.to_string(),
+            status: StatusCode::TOO_MANY_REQUESTS,
+            body: json!({
+                "result": "error",
+                "msg": "API usage exceeded rate limit",
+                "code": "RATE_LIMIT_HIT",
+                "retry-after": retry_after.as_secs_f64(),
+            }),
+        });
+    }
+
+    pub fn message(&self, message_id: i64) -> Option<ZulipMessage> {
+        self.shared.state.lock().messages.get(&message_id).cloned()
+    }
+
+    pub fn messages(&self) -> Vec<ZulipMessage> {
+        self.shared.state.lock().messages.values().cloned().collect()
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.shared.state.lock().requests.clone()
+    }
+
+    pub fn upload(&self, path: &str) -> Option<Vec<u8>> {
+        let path = path.trim_start_matches('/').trim_start_matches("user_uploads/");
+        self.shared.state.lock().uploads.get(path).cloned()
+    }
+
+    pub fn stop(&self) {
+        self.handle.stop_forcible();
+    }
+}
+
+impl Drop for FakeZulip {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn fake_user(user_id: i64, full_name: &str, email: &str, is_bot: bool) -> ZulipUser {
+    ZulipUser {
+        user_id,
+        full_name: full_name.to_string(),
+        email: email.to_string(),
+        avatar_url: None,
+        avatar_version: Some(1),
+        is_active: true,
+        is_bot,
+        role: 400,
+        timezone: Some(String::new()),
+        date_joined: Some("2024-01-01T00:00:00+00:00".to_string()),
+    }
+}
+
+fn success(data: Value) -> (StatusCode, Value) {
+    let mut body = json!({ "result": "success", "msg": "" });
+    if let (Some(body), Value::Object(data)) = (body.as_object_mut(), data) {
+        body.extend(data);
+    }
+    (StatusCode::OK, body)
+}
+
+fn error(status: StatusCode, code: &str, msg: &str) -> (StatusCode, Value) {
+    (status, json!({ "result": "error", "msg": msg, "code": code }))
+}
+
+fn bad_request(msg: &str) -> (StatusCode, Value) {
+    error(StatusCode::BAD_REQUEST, "BAD_REQUEST", msg)
+}
+
+/// Parses `[1, 2]`, `1,2` or `1` into user ids.
+fn parse_id_list(value: &str) -> Vec<i64> {
+    match serde_json::from_str::<Value>(value) {
+        Ok(Value::Array(ids)) => ids.iter().filter_map(Value::as_i64).collect(),
+        Ok(Value::Number(id)) => id.as_i64().into_iter().collect(),
+        _ => value
+            .split(',')
+            .filter_map(|id| id.trim().parse().ok())
+            .collect(),
+    }
+}
+
+impl State {
+    fn stream_id(&self, operand: &Value) -> Option<i64> {
+        operand.as_i64().or_else(|| {
+            let name = operand.as_str()?;
+            self.streams
+                .values()
+                .find(|stream| stream.name == name)
+                .map(|stream| stream.stream_id)
+        })
+    }
+
+    fn matches_term(&self, msg: &ZulipMessage, term: &Value) -> bool {
+        let operator = term.get("operator").and_then(Value::as_str).unwrap_or_default();
+        let operand = term.get("operand").cloned().unwrap_or(Value::Null);
+        let negated = term.get("negated").and_then(Value::as_bool).unwrap_or(false);
+
+        let matched = match operator {
+            "stream" | "channel" => msg.stream_id.is_some() && msg.stream_id == self.stream_id(&operand),
+            "topic" => msg
+                .subject
+                .as_deref()
+                .zip(operand.as_str())
+                .is_some_and(|(topic, wanted)| topic.eq_ignore_ascii_case(wanted)),
+            "sender" => operand.as_i64() == Some(msg.sender_id),
+            "id" => operand.as_i64() == Some(msg.id),
+            "search" => operand
+                .as_str()
+                .is_some_and(|text| msg.content.to_lowercase().contains(&text.to_lowercase())),
+            "has" => operand.as_str() == Some("attachment") && msg.content.contains("/user_uploads/"),
+            "dm" | "pm-with" => {
+                let mut wanted = match &operand {
+                    Value::Array(ids) => ids.iter().filter_map(Value::as_i64).collect(),
+                    other => parse_id_list(other.as_str().unwrap_or_default()),
+                };
+                wanted.push(BOT_USER_ID);
+                wanted.sort_unstable();
+                wanted.dedup();
+                msg.is_private() && direct_participants(msg) == wanted
+            }
+            "near" => true,
+            _ => false,
+        };
+
+        matched != negated
+    }
+}
+
+fn direct_participants(msg: &ZulipMessage) -> Vec<i64> {
+    let mut ids: Vec<i64> = msg
+        .display_recipient
+        .as_ref()
+        .and_then(Value::as_array)
+        .map(|users| users.iter().filter_map(|user| user.get("id")?.as_i64()).collect())
+        .unwrap_or_default();
+    ids.sort_unstable();
+    ids
+}
+
+impl Shared {
+    fn broadcast(&self, event: Value) {
+        let mut state = self.state.lock();
+        for queue in state.queues.values_mut() {
+            let mut event = event.clone();
+            event["id"] = json!(queue.next_event_id);
+            queue.next_event_id += 1;
+            queue.events.push(event);
+        }
+        drop(state);
+        self.events.notify_waiters();
+    }
+
+    fn send(&self, sender_id: i64, recipient: Recipient, content: &str, emit: bool) -> i64 {
+        let mut state = self.state.lock();
+        let id = state.next_message_id;
+        state.next_message_id += 1;
+
+        let sender = state
+            .users
+            .get(&sender_id)
+            .cloned()
+            .unwrap_or_else(|| fake_user(sender_id, &format!("User {}", sender_id), "", false));
+
+        let (msg_type, stream_id, subject, display_recipient) = match recipient {
+            Recipient::Stream { stream_id, topic } => {
+                let name = state
+                    .streams
+                    .get(&stream_id)
+                    .map(|stream| stream.name.clone())
+                    .unwrap_or_default();
+                ("stream", Some(stream_id), Some(topic), json!(name))
+            }
+            Recipient::Direct(mut user_ids) => {
+                user_ids.push(sender_id);
+                user_ids.sort_unstable();
+                user_ids.dedup();
+                let users: Vec<Value> = user_ids
+                    .iter()
+                    .map(|user_id| {
+                        let user = state.users.get(user_id);
+                        json!({
+                            "id": user_id,
+                            "email": user.map(|user| user.email.clone()).unwrap_or_default(),
+                            "full_name": user.map(|user| user.full_name.clone()).unwrap_or_default(),
+                        })
+                    })
+                    .collect();
+                ("private", None, Some(String::new()), Value::Array(users))
+            }
+        };
+
+        let msg = ZulipMessage {
+            id,
+            sender_id,
+            sender_full_name: sender.full_name.clone(),
+            sender_email: sender.email.clone(),
+            sender_realm_str: Some("zulip".to_string()),
+            content: content.to_string(),
+            rendered_content: Some(format!("<p>{}</p>", html_escape(content))),
+            content_type: "text/x-markdown".to_string(),
+            timestamp: chrono::Utc::now().timestamp(),
+            msg_type: msg_type.to_string(),
+            stream_id,
+            subject,
+            subject_links: Some(Vec::new()),
+            display_recipient: Some(display_recipient),
+            reactions: Some(Vec::new()),
+            flags: Some(Vec::new()),
+            last_edit_timestamp: None,
+            edit_history: None,
+        };
+        state.messages.insert(id, msg.clone());
+        drop(state);
+
+        if emit {
+            self.broadcast(json!({ "type": "message", "message": msg, "flags": [] }));
+        }
+        id
+    }
+
+    fn react(
+        &self,
+        message_id: i64,
+        user_id: i64,
+        emoji_name: &str,
+        emoji_code: &str,
+        reaction_type: &str,
+        add: bool,
+    ) -> bool {
+        let mut state = self.state.lock();
+        let user = state.users.get(&user_id).cloned();
+        let Some(msg) = state.messages.get_mut(&message_id) else {
+            return false;
+        };
+        let reactions = msg.reactions.get_or_insert_with(Vec::new);
+        let existing = reactions.iter().position(|reaction| {
+            reaction.user_id == user_id
+                && reaction.emoji_code == emoji_code
+                && reaction.reaction_type == reaction_type
+        });
+
+        match (add, existing) {
+            (true, None) => reactions.push(ZulipReaction {
+                emoji_name: emoji_name.to_string(),
+                emoji_code: emoji_code.to_string(),
+                reaction_type: reaction_type.to_string(),
+                user_id,
+                user: user.clone(),
+            }),
+            (false, Some(index)) => {
+                reactions.remove(index);
+            }
+            _ => return false,
+        }
+        drop(state);
+
+        self.broadcast(json!({
+            "type": "reaction",
+            "op": if add { "add" } else { "remove" },
+            "message_id": message_id,
+            "emoji_name": emoji_name,
+            "emoji_code": emoji_code,
+            "reaction_type": reaction_type,
+            "user_id": user_id,
+            "user": user,
+        }));
+        true
+    }
+
+    fn take_failure(&self, api_path: &str) -> Option<ScriptedFailure> {
+        let mut state = self.state.lock();
+        let index = state
+            .failures
+            .iter()
+            .position(|failure| api_path.starts_with(&failure.path))?;
+        state.failures.remove(index)
+    }
+
+    async fn api(
+        &self,
+        method: &str,
+        path: &str,
+        params: &HashMap<String, String>,
+        upload: Option<(String, Vec<u8>)>,
+    ) -> (StatusCode, Value) {
+        let segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+        let param = |key: &str| params.get(key).map(String::as_str);
+
+        match (method, segments.as_slice()) {
+            ("GET", ["server_settings"]) => {
+                let state = self.state.lock();
+                success(json!({
+                    "zulip_version": state.version,
+                    "zulip_feature_level": state.feature_level,
+                    "realm_name": "Fake Zulip",
+                    "realm_url": "",
+                    "authentication_methods": { "password": true },
+                }))
+            }
+            ("POST", ["register"]) => self.register(),
+            ("GET", ["events"]) => self.get_events(params).await,
+            ("GET", ["users"]) => {
+                let users: Vec<ZulipUser> = self.state.lock().users.values().cloned().collect();
+                success(json!({ "members": users }))
+            }
+            ("GET", ["users", "me"]) => {
+                let bot = self.state.lock().users.get(&BOT_USER_ID).cloned();
+                success(json!(bot))
+            }
+            ("GET", ["users", user_id]) => {
+                let user = user_id
+                    .parse()
+                    .ok()
+                    .and_then(|user_id: i64| self.state.lock().users.get(&user_id).cloned());
+                match user {
+                    Some(user) => success(json!({ "user": user })),
+                    None => bad_request("No such user"),
+                }
+            }
+            ("GET", ["streams"]) => {
+                let streams: Vec<ZulipStream> = self.state.lock().streams.values().cloned().collect();
+                success(json!({ "streams": streams }))
+            }
+            ("GET", ["get_stream_id"]) => {
+                let state = self.state.lock();
+                match state.stream_id(&json!(param("stream").unwrap_or_default())) {
+                    Some(stream_id) => success(json!({ "stream_id": stream_id })),
+                    None => error(StatusCode::BAD_REQUEST, "STREAM_DOES_NOT_EXIST", "Invalid stream name"),
+                }
+            }
+            ("GET", ["messages"]) => self.get_messages(params),
+            ("POST", ["messages"]) => self.send_message(params),
+            ("POST", ["messages", "flags"]) => self.update_flags(params),
+            ("GET", ["messages", message_id]) => {
+                let msg = message_id
+                    .parse()
+                    .ok()
+                    .and_then(|id: i64| self.state.lock().messages.get(&id).cloned());
+                match msg {
+                    Some(msg) => success(json!({ "raw_content": msg.content, "message": msg })),
+                    None => bad_request("Invalid message(s)"),
+                }
+            }
+            ("PATCH", ["messages", message_id]) => self.edit_message(message_id, params),
+            ("DELETE", ["messages", message_id]) => self.delete_message(message_id),
+            ("POST" | "DELETE", ["messages", message_id, "reactions"]) => {
+                let Ok(message_id) = message_id.parse() else {
+                    return bad_request("Invalid message(s)");
+                };
+                let add = method == "POST";
+                let changed = self.react(
+                    message_id,
+                    BOT_USER_ID,
+                    param("emoji_name").unwrap_or_default(),
+                    param("emoji_code").unwrap_or_default(),
+                    param("reaction_type").unwrap_or("unicode_emoji"),
+                    add,
+                );
+                match (changed, add) {
+                    (true, _) => success(json!({})),
+                    (false, true) => error(StatusCode::BAD_REQUEST, "REACTION_ALREADY_EXISTS", "Reaction already exists."),
+                    (false, false) => error(StatusCode::BAD_REQUEST, "REACTION_DOES_NOT_EXIST", "Reaction doesn't exist."),
+                }
+            }
+            ("POST", ["typing"]) | ("POST", ["users", "me", "presence"]) | ("POST", ["users", "me", "status"]) => {
+                success(json!({}))
+            }
+            ("POST", ["users", "me", "subscriptions"]) => {
+                success(json!({ "subscribed": {}, "already_subscribed": {} }))
+            }
+            ("DELETE", ["users", "me", "subscriptions"]) => {
+                let names: Vec<String> = param("subscriptions")
+                    .and_then(|names| serde_json::from_str(names).ok())
+                    .unwrap_or_default();
+                success(json!({ "removed": names, "not_removed": [] }))
+            }
+            ("GET", ["realm", "emoji"]) => success(json!({ "emoji": {} })),
+            ("GET", ["user_groups"]) => success(json!({ "user_groups": [] })),
+            ("POST", ["user_uploads"]) => match upload {
+                Some((name, bytes)) => self.store_upload(&name, bytes),
+                None => bad_request("You must specify a file to upload"),
+            },
+            ("GET", ["user_uploads", rest @ ..]) => {
+                let path = rest.join("/");
+                if self.state.lock().uploads.contains_key(&path) {
+                    success(json!({ "url": format!("/user_uploads/temporary/{}", path) }))
+                } else {
+                    bad_request("Invalid upload path")
+                }
+            }
+            _ => error(StatusCode::NOT_FOUND, "BAD_REQUEST", "Endpoint not found"),
+        }
+    }
+
+    fn register(&self) -> (StatusCode, Value) {
+        let mut state = self.state.lock();
+        let queue_id = format!("fake-queue-{}", state.next_queue_id);
+        state.next_queue_id += 1;
+        state.queues.insert(queue_id.clone(), FakeQueue::default());
+
+        let max_message_id = state.messages.keys().next_back().copied().unwrap_or(-1);
+        success(json!({
+            "queue_id": queue_id,
+            "last_event_id": -1,
+            "zulip_version": state.version,
+            "zulip_feature_level": state.feature_level,
+            "max_message_id": max_message_id,
+        }))
+    }
+
+    async fn get_events(&self, params: &HashMap<String, String>) -> (StatusCode, Value) {
+        let queue_id = params.get("queue_id").cloned().unwrap_or_default();
+        let last_event_id: i64 = params
+            .get("last_event_id")
+            .and_then(|id| id.parse().ok())
+            .unwrap_or(-1);
+        let dont_block = params.get("dont_block").is_some_and(|value| value == "true");
+
+        let notified = self.events.notified();
+        tokio::pin!(notified);
+        let mut waited = false;
+
+        loop {
+            notified.as_mut().enable();
+            let heartbeat_interval = {
+                let mut state = self.state.lock();
+                let heartbeat_interval = state.heartbeat_interval;
+                let Some(queue) = state.queues.get_mut(&queue_id) else {
+                    return error(
+                        StatusCode::BAD_REQUEST,
+                        "BAD_EVENT_QUEUE_ID",
+                        &format!("Bad event queue ID: {}", queue_id),
+                    );
+                };
+
+                queue.events.retain(|event| event["id"].as_i64().unwrap_or(-1) > last_event_id);
+                if !queue.events.is_empty() || dont_block {
+                    return success(json!({ "events": queue.events }));
+                }
+                if waited {
+                    let heartbeat = json!({ "type": "heartbeat", "id": queue.next_event_id });
+                    queue.next_event_id += 1;
+                    return success(json!({ "events": [heartbeat] }));
+                }
+                heartbeat_interval
+            };
+
+            tokio::select! {
+                _ = notified.as_mut() => notified.set(self.events.notified()),
+                _ = tokio::time::sleep(heartbeat_interval) => waited = true,
+            }
+        }
+    }
+
+    fn get_messages(&self, params: &HashMap<String, String>) -> (StatusCode, Value) {
+        let narrow: Vec<Value> = match params.get("narrow").map(|narrow| serde_json::from_str(narrow)) {
+            Some(Ok(narrow)) => narrow,
+            Some(Err(_)) => return bad_request("Invalid narrow"),
+            None => Vec::new(),
+        };
+        let count = |key: &str| params.get(key).and_then(|n| n.parse::<usize>().ok()).unwrap_or(0);
+        let num_before = count("num_before");
+        let num_after = count("num_after");
+        let include_anchor = params.get("include_anchor").is_none_or(|value| value == "true");
+        let apply_markdown = params.get("apply_markdown").is_none_or(|value| value == "true");
+
+        let state = self.state.lock();
+        let matching: Vec<&ZulipMessage> = state
+            .messages
+            .values()
+            .filter(|msg| narrow.iter().all(|term| state.matches_term(msg, term)))
+            .collect();
+
+        let anchor = params.get("anchor").map_or(Some(Anchor::Newest), |a| Anchor::from_param(a));
+        let anchor = match anchor {
+            Some(Anchor::Newest | Anchor::FirstUnread) => i64::MAX,
+            Some(Anchor::Oldest) => i64::MIN,
+            Some(Anchor::Message(id)) => id,
+            None => return bad_request("Invalid anchor"),
+        };
+
+        let before: Vec<&ZulipMessage> = matching.iter().copied().filter(|msg| msg.id < anchor).collect();
+        let after: Vec<&ZulipMessage> = matching.iter().copied().filter(|msg| msg.id > anchor).collect();
+        let at_anchor = matching.iter().copied().find(|msg| msg.id == anchor);
+
+        let mut messages: Vec<ZulipMessage> = before[before.len().saturating_sub(num_before)..]
+            .iter()
+            .chain(at_anchor.filter(|_| include_anchor).as_ref())
+            .chain(after.iter().take(num_after))
+            .map(|msg| {
+                let mut msg = (*msg).clone();
+                if apply_markdown {
+                    msg.content = msg.rendered_content.clone().unwrap_or_default();
+                    msg.content_type = "text/html".to_string();
+                }
+                msg
+            })
+            .collect();
+        messages.sort_by_key(|msg| msg.id);
+
+        success(json!({
+            "anchor": anchor,
+            "found_anchor": at_anchor.is_some(),
+            "found_oldest": before.len() <= num_before,
+            "found_newest": after.len() <= num_after,
+            "history_limited": false,
+            "messages": messages,
+        }))
+    }
+
+    fn send_message(&self, params: &HashMap<String, String>) -> (StatusCode, Value) {
+        let content = params.get("content").cloned().unwrap_or_default();
+        let recipient = match params.get("type").map(String::as_str) {
+            Some("stream" | "channel") => {
+                let target = params
+                    .get("stream_id")
+                    .or_else(|| params.get("to"))
+                    .cloned()
+                    .unwrap_or_default();
+                let operand = target.parse::<i64>().map(|id| json!(id)).unwrap_or(json!(target));
+                let Some(stream_id) = self.state.lock().stream_id(&operand) else {
+                    return error(StatusCode::BAD_REQUEST, "STREAM_DOES_NOT_EXIST", "Stream does not exist");
+                };
+                Recipient::Stream {
+                    stream_id,
+                    topic: params.get("topic").cloned().unwrap_or_default(),
+                }
+            }
+            Some("private" | "direct") => {
+                Recipient::Direct(parse_id_list(params.get("to").map(String::as_str).unwrap_or_default()))
+            }
+            _ => return bad_request("Invalid message type"),
+        };
+
+        let id = self.send(BOT_USER_ID, recipient, &content, true);
+        success(json!({ "id": id }))
+    }
+
+    fn edit_message(&self, message_id: &str, params: &HashMap<String, String>) -> (StatusCode, Value) {
+        let Ok(message_id) = message_id.parse::<i64>() else {
+            return bad_request("Invalid message(s)");
+        };
+
+        let mut state = self.state.lock();
+        let Some(msg) = state.messages.get_mut(&message_id) else {
+            return bad_request("Invalid message(s)");
+        };
+        let orig_content = msg.content.clone();
+        if let Some(content) = params.get("content") {
+            msg.content = content.clone();
+            msg.rendered_content = Some(format!("<p>{}</p>", html_escape(content)));
+        }
+        if let Some(topic) = params.get("topic") {
+            msg.subject = Some(topic.clone());
+        }
+        let now = chrono::Utc::now().timestamp();
+        msg.last_edit_timestamp = Some(now);
+        let event = json!({
+            "type": "update_message",
+            "user_id": BOT_USER_ID,
+            "message_id": message_id,
+            "message_ids": [message_id],
+            "orig_content": orig_content,
+            "content": msg.content,
+            "rendered_content": msg.rendered_content,
+            "subject": msg.subject,
+            "edit_timestamp": now,
+            "flags": [],
+        });
+        drop(state);
+
+        self.broadcast(event);
+        success(json!({}))
+    }
+
+    fn delete_message(&self, message_id: &str) -> (StatusCode, Value) {
+        let Ok(message_id) = message_id.parse::<i64>() else {
+            return bad_request("Invalid message(s)");
+        };
+        let Some(msg) = self.state.lock().messages.remove(&message_id) else {
+            return bad_request("Invalid message(s)");
+        };
+
+        self.broadcast(json!({
+            "type": "delete_message",
+            "message_ids": [message_id],
+            "message_type": msg.msg_type,
+            "stream_id": msg.stream_id,
+            "topic": msg.subject,
+        }));
+        success(json!({}))
+    }
+
+    fn update_flags(&self, params: &HashMap<String, String>) -> (StatusCode, Value) {
+        let ids = parse_id_list(params.get("messages").map(String::as_str).unwrap_or_default());
+        let flag = params.get("flag").cloned().unwrap_or_default();
+        let add = params.get("op").map(String::as_str) == Some("add");
+
+        let mut state = self.state.lock();
+        let mut changed = Vec::new();
+        for id in ids {
+            let Some(flags) = state.messages.get_mut(&id).map(|msg| msg.flags.get_or_insert_with(Vec::new)) else {
+                continue;
+            };
+            let present = flags.contains(&flag);
+            if add && !present {
+                flags.push(flag.clone());
+                changed.push(id);
+            } else if !add && present {
+                flags.retain(|existing| existing != &flag);
+                changed.push(id);
+            }
+        }
+        success(json!({ "messages": changed }))
+    }
+
+    fn store_upload(&self, name: &str, bytes: Vec<u8>) -> (StatusCode, Value) {
+        let mut state = self.state.lock();
+        let path = format!("{}/{:02x}/{}", REALM_ID, state.uploads.len(), name);
+        state.uploads.insert(path.clone(), bytes);
+
+        let uri = format!("/user_uploads/{}", path);
+        success(json!({ "uri": uri, "url": uri, "filename": name }))
+    }
+
+    fn download(&self, path: &str, authenticated: bool) -> Option<Vec<u8>> {
+        let path = match path.strip_prefix("temporary/") {
+            Some(path) => path,
+            None if authenticated => path,
+            None => return None,
+        };
+        self.state.lock().uploads.get(path).cloned()
+    }
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[derive(Clone)]
+struct FakeZulipHandler(Arc<Shared>);
+
+#[handler]
+impl FakeZulipHandler {
+    async fn handle(&self, req: &mut Request, res: &mut Response) {
+        let method = req.method().as_str().to_string();
+        let path = req.uri().path().trim_start_matches('/').to_string();
+        let authenticated = is_authenticated(req);
+
+        let mut params: HashMap<String, String> = req
+            .uri()
+            .query()
+            .map(|query| url::form_urlencoded::parse(query.as_bytes()).into_owned().collect())
+            .unwrap_or_default();
+
+        let mut upload = None;
+        if method != "GET" {
+            if let Ok(form) = req.form_data().await {
+                for (key, value) in form.fields.iter() {
+                    params.insert(key.clone(), value.clone());
+                }
+            }
+            if let Some(file) = req.file("file").await {
+                let name = file.name().unwrap_or("file").to_string();
+                if let Ok(bytes) = std::fs::read(file.path()) {
+                    upload = Some((name, bytes));
+                }
+            }
+        }
+
+        self.0.state.lock().requests.push(RecordedRequest {
+            method: method.clone(),
+            path: path.clone(),
+            params: params.clone(),
+        });
+
+        if let Some(upload_path) = path.strip_prefix("user_uploads/") {
+            match self.0.download(upload_path, authenticated) {
+                Some(bytes) => {
+                    res.add_header("content-type", "application/octet-stream", true).ok();
+                    res.write_body(bytes).ok();
+                }
+                None => {
+                    res.status_code(StatusCode::NOT_FOUND);
+                }
+            }
+            return;
+        }
+
+        let Some(api_path) = path.strip_prefix("api/v1/") else {
+            res.status_code(StatusCode::NOT_FOUND);
+            return;
+        };
+
+        let (status, body) = if let Some(failure) = self.0.take_failure(api_path) {
+            (failure.status, failure.body)
+        } else if !authenticated && api_path != "server_settings" {
+            error(StatusCode::UNAUTHORIZED, "UNAUTHORIZED", "Invalid API key")
+        } else {
+            self.0.api(&method, api_path, &params, upload).await
+        };
+
+        res.status_code(status);
+        res.render(Json(body));
+    }
+}
+
+fn is_authenticated(req: &Request) -> bool {
+    use base64::Engine;
+    let expected = format!(
+        "Basic {}",
+        base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", BOT_EMAIL, BOT_API_KEY))
+    );
+    req.headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        == Some(expected.as_str())
+}
@@ -1,4 +1,5 @@
 pub mod error;
 pub mod logging;
+pub mod metrics;
 
 pub use error::{BridgeError, Result};
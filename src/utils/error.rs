@@ -12,7 +12,7 @@ pub enum BridgeError {
     Matrix(String),
 
     #[error("Zulip error: {0}")]
-    Zulip(String),
+    Zulip(#[from] crate::zulip::ZulipError),
 
     #[error("Network error: {0}")]
     Network(String),
@@ -41,6 +41,9 @@ pub enum BridgeError {
     #[error("Invalid state: {0}")]
     InvalidState(String),
 
+    #[error("Media of {size} bytes exceeds the {limit} byte limit")]
+    MediaTooLarge { size: u64, limit: u64 },
+
     #[error("Not implemented: {0}")]
     NotImplemented(String),
 
@@ -51,4 +54,25 @@ pub enum BridgeError {
     Other(#[from] anyhow::Error),
 }
 
+impl BridgeError {
+    pub fn as_zulip(&self) -> Option<&crate::zulip::ZulipError> {
+        match self {
+            BridgeError::Zulip(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<crate::db::DatabaseError> for BridgeError {
+    fn from(err: crate::db::DatabaseError) -> Self {
+        BridgeError::Database(err.to_string())
+    }
+}
+
+impl From<url::ParseError> for BridgeError {
+    fn from(err: url::ParseError) -> Self {
+        BridgeError::Parse(err.to_string())
+    }
+}
+
 pub type Result<T> = std::result::Result<T, BridgeError>;
@@ -0,0 +1,205 @@
+//! Prometheus instrumentation for the bridge.
+//!
+//! Every recording function is a no-op unless the crate is built with the `metrics` feature, so
+//! call sites never need their own `cfg` guards.
+
+use std::time::Duration;
+
+pub use self::imp::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Success,
+    Failure,
+    Skipped,
+    Ignored,
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::Failure => "failure",
+            Outcome::Skipped => "skipped",
+            Outcome::Ignored => "ignored",
+        }
+    }
+
+    pub fn from_result<T, E>(result: &Result<T, E>) -> Self {
+        if result.is_ok() {
+            Outcome::Success
+        } else {
+            Outcome::Failure
+        }
+    }
+}
+
+/// Collapses numeric path segments and strips the query string so Zulip endpoints can be used as
+/// a low-cardinality label, e.g. `messages/123/reactions?x=1` becomes `messages/:id/reactions`.
+pub fn endpoint_label(path: &str) -> String {
+    path.split('?')
+        .next()
+        .unwrap_or_default()
+        .trim_start_matches('/')
+        .split('/')
+        .map(|segment| {
+            if !segment.is_empty() && segment.chars().all(|c| c.is_ascii_digit()) {
+                ":id"
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(feature = "metrics")]
+mod imp {
+    use std::time::Duration;
+
+    use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+    use once_cell::sync::OnceCell;
+    use tracing::warn;
+
+    use super::Outcome;
+
+    static HANDLE: OnceCell<PrometheusHandle> = OnceCell::new();
+
+    const LATENCY_BUCKETS: &[f64] = &[
+        0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+    ];
+
+    pub fn init() {
+        let builder = match PrometheusBuilder::new()
+            .set_buckets_for_metric(Matcher::Suffix("_seconds".to_string()), LATENCY_BUCKETS)
+        {
+            Ok(builder) => builder,
+            Err(e) => {
+                warn!("invalid metrics bucket configuration: {}", e);
+                return;
+            }
+        };
+
+        match builder.install_recorder() {
+            Ok(handle) => {
+                let _ = HANDLE.set(handle);
+            }
+            Err(e) => warn!("failed to install prometheus recorder: {}", e),
+        }
+    }
+
+    pub fn render() -> Option<String> {
+        HANDLE.get().map(PrometheusHandle::render)
+    }
+
+    pub fn record_zulip_event(event_type: &str, outcome: Outcome) {
+        metrics::counter!(
+            "zulip_events_total",
+            "type" => event_type.to_string(),
+            "outcome" => outcome.as_str()
+        )
+        .increment(1);
+    }
+
+    pub fn record_matrix_event(event_type: &str, outcome: Outcome) {
+        metrics::counter!(
+            "matrix_events_total",
+            "type" => event_type.to_string(),
+            "outcome" => outcome.as_str()
+        )
+        .increment(1);
+    }
+
+    pub fn record_bridge_delay(direction: &'static str, delay: Duration) {
+        metrics::histogram!("bridge_delay_seconds", "direction" => direction)
+            .record(delay.as_secs_f64());
+    }
+
+    pub fn record_zulip_request(method: &'static str, endpoint: &str, status: u16, elapsed: Duration) {
+        let endpoint = super::endpoint_label(endpoint);
+        metrics::counter!(
+            "zulip_api_requests_total",
+            "method" => method,
+            "endpoint" => endpoint.clone(),
+            "status" => status.to_string()
+        )
+        .increment(1);
+        metrics::histogram!(
+            "zulip_api_request_duration_seconds",
+            "method" => method,
+            "endpoint" => endpoint
+        )
+        .record(elapsed.as_secs_f64());
+    }
+
+    pub fn record_zulip_reconnect(site: &str) {
+        metrics::counter!("zulip_reconnects_total", "site" => site.to_string()).increment(1);
+    }
+
+    pub fn record_zulip_rate_limited(site: &str) {
+        metrics::counter!("zulip_rate_limited_total", "site" => site.to_string()).increment(1);
+    }
+
+    pub fn set_zulip_queue_depth(organization_id: &str, depth: usize) {
+        metrics::gauge!("zulip_event_queue_depth", "organization" => organization_id.to_string())
+            .set(depth as f64);
+    }
+
+    pub fn record_db_query(store: &'static str, operation: &'static str, outcome: Outcome, elapsed: Duration) {
+        metrics::counter!(
+            "db_queries_total",
+            "store" => store,
+            "operation" => operation,
+            "outcome" => outcome.as_str()
+        )
+        .increment(1);
+        metrics::histogram!(
+            "db_query_duration_seconds",
+            "store" => store,
+            "operation" => operation
+        )
+        .record(elapsed.as_secs_f64());
+    }
+
+    pub fn record_ghost_created(outcome: Outcome) {
+        metrics::counter!("ghost_users_created_total", "outcome" => outcome.as_str()).increment(1);
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+mod imp {
+    use std::time::Duration;
+
+    use super::Outcome;
+
+    pub fn init() {}
+
+    pub fn render() -> Option<String> {
+        None
+    }
+
+    pub fn record_zulip_event(_event_type: &str, _outcome: Outcome) {}
+
+    pub fn record_matrix_event(_event_type: &str, _outcome: Outcome) {}
+
+    pub fn record_bridge_delay(_direction: &'static str, _delay: Duration) {}
+
+    pub fn record_zulip_request(_method: &'static str, _endpoint: &str, _status: u16, _elapsed: Duration) {}
+
+    pub fn record_zulip_reconnect(_site: &str) {}
+
+    pub fn record_zulip_rate_limited(_site: &str) {}
+
+    pub fn set_zulip_queue_depth(_organization_id: &str, _depth: usize) {}
+
+    pub fn record_db_query(_store: &'static str, _operation: &'static str, _outcome: Outcome, _elapsed: Duration) {}
+
+    pub fn record_ghost_created(_outcome: Outcome) {}
+}
+
+/// Converts a unix timestamp in milliseconds into the delay until now, clamping clock skew to
+/// zero.
+pub fn delay_since_millis(timestamp_ms: i64) -> Duration {
+    let now = chrono::Utc::now().timestamp_millis();
+    Duration::from_millis(now.saturating_sub(timestamp_ms).max(0) as u64)
+}
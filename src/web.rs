@@ -1,11 +1,433 @@
-pub struct WebServer;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use salvo::http::StatusCode;
+use salvo::prelude::*;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::bridge::BridgeCore;
+use crate::config::Config;
+use crate::db::{DatabaseManager, PoolStatus};
+use crate::matrix::MatrixAppservice;
+use crate::utils::{BridgeError, Result, metrics};
+use crate::zulip::{EventQueueStatus, OutgoingWebhookPayload};
+
+const READINESS_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize)]
+struct HomeserverStatus {
+    reachable: bool,
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ReadinessReport {
+    ready: bool,
+    database: PoolStatus,
+    homeserver: HomeserverStatus,
+    organizations: HashMap<String, EventQueueStatus>,
+}
+
+#[derive(Clone)]
+struct HealthHandler;
+
+#[handler]
+impl HealthHandler {
+    async fn handle(&self, res: &mut Response) {
+        res.render(Json(serde_json::json!({
+            "status": "ok",
+            "version": env!("CARGO_PKG_VERSION"),
+        })));
+    }
+}
+
+#[derive(Clone)]
+struct MetricsHandler;
+
+#[handler]
+impl MetricsHandler {
+    async fn handle(&self, res: &mut Response) {
+        match metrics::render() {
+            Some(body) => {
+                res.add_header("content-type", "text/plain; version=0.0.4", true).ok();
+                res.render(body);
+            }
+            None => {
+                res.status_code(StatusCode::NOT_FOUND);
+                res.render("metrics are not enabled in this build");
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+struct ReadyHandler {
+    matrix: Arc<MatrixAppservice>,
+    db_manager: Arc<DatabaseManager>,
+    bridge: Arc<BridgeCore>,
+}
+
+impl ReadyHandler {
+    async fn database_status(&self) -> PoolStatus {
+        tokio::time::timeout(READINESS_CHECK_TIMEOUT, self.db_manager.pool_status())
+            .await
+            .unwrap_or_else(|_| PoolStatus {
+                error: Some("database health check timed out".to_string()),
+                ..Default::default()
+            })
+    }
+
+    async fn homeserver_status(&self) -> HomeserverStatus {
+        let result = tokio::time::timeout(READINESS_CHECK_TIMEOUT, self.matrix.check_homeserver())
+            .await
+            .unwrap_or_else(|_| {
+                Err(BridgeError::Network(
+                    "homeserver health check timed out".to_string(),
+                ))
+            });
+
+        match result {
+            Ok(()) => HomeserverStatus {
+                reachable: true,
+                error: None,
+            },
+            Err(e) => HomeserverStatus {
+                reachable: false,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+#[handler]
+impl ReadyHandler {
+    async fn handle(&self, res: &mut Response) {
+        let (database, homeserver) = tokio::join!(self.database_status(), self.homeserver_status());
+        let organizations = self.bridge.event_queue_statuses();
+
+        let ready = database.healthy
+            && homeserver.reachable
+            && organizations.values().all(EventQueueStatus::is_healthy);
+
+        let report = ReadinessReport {
+            ready,
+            database,
+            homeserver,
+            organizations,
+        };
+
+        if !ready {
+            res.status_code(StatusCode::SERVICE_UNAVAILABLE);
+        }
+        res.render(Json(report));
+    }
+}
+
+/// Receives messages from organizations bridged through a Zulip outgoing-webhook bot, see
+/// `zulip::webhook`.
+#[derive(Clone)]
+struct ZulipWebhookHandler {
+    bridge: Arc<BridgeCore>,
+}
+
+#[handler]
+impl ZulipWebhookHandler {
+    async fn handle(&self, req: &mut Request, res: &mut Response) {
+        let org_id = req.param::<String>("org_id").unwrap_or_default();
+        let Some(webhook) = self.bridge.zulip_webhook(&org_id) else {
+            res.status_code(StatusCode::NOT_FOUND);
+            res.render(Json(serde_json::json!({ "error": "unknown organization" })));
+            return;
+        };
+
+        let payload = match req.parse_json::<OutgoingWebhookPayload>().await {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!("invalid outgoing webhook payload for {}: {}", org_id, e);
+                res.status_code(StatusCode::BAD_REQUEST);
+                res.render(Json(serde_json::json!({ "error": "invalid payload" })));
+                return;
+            }
+        };
+
+        if !webhook.verify_token(&payload.token) {
+            warn!("rejected outgoing webhook for {} with a wrong token", org_id);
+            res.status_code(StatusCode::UNAUTHORIZED);
+            res.render(Json(serde_json::json!({ "error": "invalid token" })));
+            return;
+        }
+
+        res.render(Json(webhook.deliver(&payload)));
+    }
+}
+
+pub struct WebServer {
+    config: Arc<Config>,
+    matrix: Arc<MatrixAppservice>,
+    db_manager: Arc<DatabaseManager>,
+    bridge: Arc<BridgeCore>,
+}
 
 impl WebServer {
-    pub fn new() -> crate::utils::Result<Self> {
-        Ok(Self)
+    pub fn new(
+        config: Arc<Config>,
+        matrix: Arc<MatrixAppservice>,
+        db_manager: Arc<DatabaseManager>,
+        bridge: Arc<BridgeCore>,
+    ) -> Result<Self> {
+        Ok(Self {
+            config,
+            matrix,
+            db_manager,
+            bridge,
+        })
     }
 
-    pub async fn start(&self) -> crate::utils::Result<()> {
+    fn router(&self) -> Router {
+        let ready = ReadyHandler {
+            matrix: self.matrix.clone(),
+            db_manager: self.db_manager.clone(),
+            bridge: self.bridge.clone(),
+        };
+
+        let zulip_webhook = ZulipWebhookHandler {
+            bridge: self.bridge.clone(),
+        };
+
+        Router::new()
+            .push(Router::with_path("health").get(HealthHandler))
+            .push(Router::with_path("ready").get(ready))
+            .push(Router::with_path("metrics").get(MetricsHandler))
+            .push(Router::with_path("zulip/webhook/{org_id}").post(zulip_webhook))
+            .push(self.matrix.appservice.router())
+    }
+
+    pub async fn start(&self) -> Result<()> {
+        let bind_address = format!(
+            "{}:{}",
+            self.config.bridge.bind_address, self.config.bridge.port
+        );
+        info!("web server listening on {}", bind_address);
+
+        let acceptor = TcpListener::new(bind_address).bind().await;
+        Server::new(acceptor).serve(self.router()).await;
+
         Ok(())
     }
+
+    /// Serves on an OS-assigned loopback port in the background, for the test harness.
+    #[cfg(any(test, feature = "test-support"))]
+    pub async fn start_local(&self) -> Result<(std::net::SocketAddr, salvo::server::ServerHandle)> {
+        use salvo::conn::Acceptor;
+
+        let acceptor = TcpListener::new("127.0.0.1:0").bind().await;
+        let addr = acceptor
+            .holdings()
+            .first()
+            .and_then(|holding| holding.local_addr.clone().into_std())
+            .ok_or_else(|| BridgeError::InvalidState("web server has no local address".to_string()))?;
+
+        let server = Server::new(acceptor);
+        let handle = server.handle();
+        tokio::spawn(server.serve(self.router()));
+        Ok((addr, handle))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::bridge::BridgeMatrixEventHandler;
+    use crate::db::models::{NewRoomMapping, RoomType};
+    use crate::matrix::MatrixEventProcessor;
+    use crate::testing::fake_matrix::SERVER_NAME;
+    use crate::testing::{self, FakeHomeserver, FakeZulip};
+
+    const SENDER_ID: i64 = 10;
+    const STREAM_ID: i64 = 5;
+
+    fn messages_from(homeserver: &FakeHomeserver, room_id: &str, sender: &str) -> Vec<Value> {
+        homeserver
+            .events(room_id)
+            .into_iter()
+            .filter(|event| event["type"] == "m.room.message" && event["sender"] == sender)
+            .collect()
+    }
+
+    /// A bridge connected to `zulip` whose web server receives `homeserver`'s transactions, with
+    /// a portal room for the `general` stream.
+    struct Bridged {
+        bridge: Arc<BridgeCore>,
+        matrix: Arc<MatrixAppservice>,
+        room_id: String,
+        handle: salvo::server::ServerHandle,
+    }
+
+    impl Bridged {
+        async fn start(zulip: &FakeZulip, homeserver: &FakeHomeserver) -> Self {
+            let config = Arc::new(testing::test_config(homeserver).unwrap());
+            let db_manager = testing::test_database(&config).await.unwrap();
+            let matrix = Arc::new(MatrixAppservice::new(config.clone()).await.unwrap());
+            let bridge =
+                Arc::new(BridgeCore::new(config.clone(), db_manager.clone(), matrix.clone()));
+
+            let org = testing::create_organization(&db_manager, zulip).await.unwrap();
+            bridge.connect_organization(&org).await.unwrap();
+            let room_id = matrix.create_room("general", None, None, false).await.unwrap();
+            db_manager
+                .room_store()
+                .create(NewRoomMapping {
+                    matrix_room_id: room_id.clone(),
+                    zulip_stream_id: STREAM_ID,
+                    zulip_stream_name: "general".to_string(),
+                    zulip_topic: None,
+                    organization_id: org.id.clone(),
+                    room_type: RoomType::Stream.as_str().to_string(),
+                })
+                .await
+                .unwrap();
+
+            let handler = Arc::new(BridgeMatrixEventHandler::new(bridge.clone()));
+            matrix
+                .set_processor(Arc::new(MatrixEventProcessor::with_age_limit(
+                    handler,
+                    config.limits.matrix_event_age_limit_ms,
+                )))
+                .await;
+            let web_server =
+                WebServer::new(config, matrix.clone(), db_manager, bridge.clone()).unwrap();
+            let (addr, handle) = web_server.start_local().await.unwrap();
+            homeserver.set_appservice_url(&format!("http://{}", addr));
+
+            Self {
+                bridge,
+                matrix,
+                room_id,
+                handle,
+            }
+        }
+
+        fn stop(self) {
+            self.bridge.stop();
+            self.handle.stop_forcible();
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn backfill_command_retries_rate_limited_sends() {
+        let zulip = FakeZulip::start().await.unwrap();
+        zulip.add_user(SENDER_ID, "Iago");
+        zulip.add_stream(STREAM_ID, "general");
+        zulip.import_message(SENDER_ID, STREAM_ID, "greetings", "hello");
+
+        let homeserver = FakeHomeserver::start().await.unwrap();
+        let bridged = Bridged::start(&zulip, &homeserver).await;
+        let (matrix, room_id) = (bridged.matrix.clone(), bridged.room_id.clone());
+
+        let alice = format!("@alice:{}", SERVER_NAME);
+        homeserver.set_membership(&room_id, &alice, "join").await.unwrap();
+        let send_path = format!("/_matrix/client/v3/rooms/{}/send/m.room.message/", room_id);
+        homeserver.rate_limit_next(&send_path, 50);
+        homeserver.send_text(&room_id, &alice, "!zulip backfill 10").await.unwrap();
+
+        let bot = matrix.bot_user_id();
+        let reply = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(reply) = messages_from(&homeserver, &room_id, &bot).pop() {
+                    return reply;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("no reply to the backfill command within 5s");
+        assert_eq!(
+            reply["content"]["body"],
+            "Backfilled 1 messages (0 already bridged or skipped)."
+        );
+
+        let ghost = matrix.ghost_user_id(SENDER_ID);
+        assert!(homeserver.joined_members(&room_id).contains(&ghost));
+        let messages = messages_from(&homeserver, &room_id, &ghost);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0]["content"]["body"], "hello");
+
+        // The rate-limited send is retried under the same transaction id.
+        let sends: Vec<String> = homeserver
+            .calls()
+            .into_iter()
+            .filter(|call| call.method == "PUT" && call.user_id.as_deref() == Some(ghost.as_str()))
+            .filter(|call| call.path.contains("/send/"))
+            .map(|call| call.path)
+            .collect();
+        assert_eq!(sends.len(), 2);
+        assert_eq!(sends[0], sends[1]);
+
+        bridged.stop();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Postgres database in TEST_DATABASE_URL"]
+    async fn relays_matrix_messages_with_mentions() {
+        // Mentioned by id only: no ghost is created for this user, so it has no stored name.
+        const MENTIONED_ID: i64 = 9001;
+        let zulip = FakeZulip::start().await.unwrap();
+        zulip.add_user(MENTIONED_ID, "Iago");
+        zulip.add_stream(STREAM_ID, "general");
+
+        let homeserver = FakeHomeserver::start().await.unwrap();
+        let bridged = Bridged::start(&zulip, &homeserver).await;
+        let room_id = &bridged.room_id;
+
+        let alice = format!("@alice:{}", SERVER_NAME);
+        let ghost = bridged.matrix.ghost_user_id(MENTIONED_ID);
+        homeserver.set_membership(room_id, &alice, "join").await.unwrap();
+        let pill = format!("<a href=\"https://matrix.to/#/{}\">Iago</a>", ghost);
+        let content = json!({
+            "msgtype": "m.text",
+            "body": "Iago: *look*",
+            "format": "org.matrix.custom.html",
+            "formatted_body": format!("{}: <em>look</em>", pill),
+            "m.mentions": { "user_ids": [ghost] },
+        });
+        homeserver.send_event(room_id, &alice, "m.room.message", content).await.unwrap();
+        let content = json!({ "msgtype": "m.emote", "body": "waves" });
+        homeserver.send_event(room_id, &alice, "m.room.message", content).await.unwrap();
+        let content = json!({
+            "msgtype": "m.text",
+            "body": "* fixed",
+            "m.new_content": { "msgtype": "m.text", "body": "fixed" },
+            "m.relates_to": { "rel_type": "m.replace", "event_id": "$original" },
+        });
+        homeserver.send_event(room_id, &alice, "m.room.message", content).await.unwrap();
+
+        let messages = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let messages = zulip.messages();
+                if messages.len() == 2 {
+                    return messages;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("messages not relayed within 5s");
+        let contents: Vec<&str> = messages.iter().map(|msg| msg.content.as_str()).collect();
+        assert_eq!(contents, [format!("@**|{}**: *look*", MENTIONED_ID).as_str(), "/me waves"]);
+        for msg in &messages {
+            assert_eq!(msg.stream_id, Some(STREAM_ID));
+            assert_eq!(msg.topic(), Some("Matrix"));
+        }
+
+        // The bridge's own messages are not echoed back into the room.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(messages_from(&homeserver, room_id, &ghost).is_empty());
+
+        bridged.stop();
+    }
 }
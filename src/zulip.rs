@@ -1,46 +1,155 @@
 pub mod types;
+pub mod capabilities;
+pub mod error;
 pub mod event_handler;
-pub mod websocket;
-
+pub mod event_source;
+pub mod long_poll;
+pub mod narrow;
+pub mod rate_limit;
+pub mod webhook;
+
+pub use self::capabilities::ServerCapabilities;
+pub use self::error::{ZulipError, ZulipErrorCode};
 pub use self::event_handler::ZulipEventHandler;
+pub use self::narrow::{Anchor, Narrow, NarrowTerm};
 pub use self::types::{
-    RegisterQueueRequest, SendMessageRequest, ZulipApiResponse, ZulipEvent, ZulipEventsResponse,
-    ZulipMessage, ZulipMessagesResponse, ZulipQueue, ZulipReaction, ZulipSendMessageResponse,
-    ZulipStream, ZulipStreamsResponse, ZulipUser, ZulipUsersResponse,
+    GetMessagesRequest, MessageFlagOp, PresenceStatus, RegisterQueueRequest, SendMessageRequest,
+    TypingOp, TypingRequest, UpdateMessageFlagsRequest, UpdatePresenceRequest, UpdateStreamRequest,
+    ZulipApiResponse, ZulipEvent, ZulipEventsResponse, ZulipMessage, ZulipMessagesResponse,
+    ZulipPresenceInfo, ZulipPresenceResponse, ZulipQueue, ZulipReaction, ZulipRealmEmoji,
+    ZulipRealmEmojiResponse, ZulipSendMessageResponse, ZulipServerSettings, ZulipStream,
+    ZulipStreamsResponse, ZulipTemporaryUrlResponse, ZulipUnsubscribeResponse,
+    ZulipUpdateMessageFlagsResponse, ZulipUser, ZulipUserGroup, ZulipUserGroupsResponse,
+    ZulipUserPresenceResponse, ZulipUserResponse, ZulipUserStatus, ZulipUserStatusResponse,
+    ZulipUsersResponse,
 };
-pub use self::websocket::ZulipWebSocketClient;
-
-use reqwest::header::{HeaderMap, AUTHORIZATION};
-use tracing::{debug, error, info};
+pub use self::event_source::{
+    ConnectionState, EventQueueStatus, EventSource, RecordingEventSource, ReplayEventSource,
+};
+pub use self::long_poll::{EventQueueCheckpoint, LongPollEventSource};
+pub use self::rate_limit::{RateLimiter, RequestPriority};
+pub use self::webhook::{OutgoingWebhookPayload, OutgoingWebhookResponse, WebhookEventSource};
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use reqwest::StatusCode;
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use serde::de::{DeserializeOwned, IgnoredAny};
+use tracing::{debug, error, info, warn};
 use url::Url;
 
-use crate::utils::{BridgeError, Result};
+use crate::media::{content_type_for, read_limited};
+use crate::utils::{BridgeError, Result, metrics};
+
+/// Used when a 429 carries neither a `retry-after` field nor header.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+const MAX_RATE_LIMIT_RETRIES: u32 = 8;
 
+/// An uploaded file fetched from the server.
+#[derive(Debug, Clone)]
+pub struct ZulipFile {
+    pub content: Bytes,
+    /// As served by Zulip, if at all.
+    pub content_type: Option<String>,
+}
+
+/// Clones share the rate limiter, so a clone with a different priority still counts against the
+/// same credentials.
+#[derive(Clone)]
 pub struct ZulipClient {
     site: String,
     email: String,
     api_key: String,
     client: reqwest::Client,
     base_url: Url,
+    limiter: Arc<RateLimiter>,
+    priority: RequestPriority,
+    capabilities: ServerCapabilities,
+    /// Set while replaying recorded events, so nothing reaches the recorded server.
+    offline: bool,
 }
 
 impl ZulipClient {
     pub fn new(site: &str, email: &str, api_key: &str) -> Result<Self> {
         let base_url = Url::parse(site)
-            .map_err(|e| BridgeError::Zulip(format!("Invalid site URL: {}", e)))?;
+            .map_err(|e| ZulipError::InvalidRequest(format!("Invalid site URL: {}", e)))?;
 
         Ok(Self {
             site: site.trim_end_matches('/').to_string(),
             email: email.to_string(),
             api_key: api_key.to_string(),
             client: reqwest::Client::builder()
-                .timeout(std::time::Duration::from_secs(30))
+                .timeout(Duration::from_secs(30))
                 .build()
                 .map_err(|e| BridgeError::Network(e.to_string()))?,
             base_url,
+            limiter: Arc::new(RateLimiter::new()),
+            priority: RequestPriority::Live,
+            capabilities: ServerCapabilities::default(),
+            offline: false,
         })
     }
 
+    /// Asks the server for its version and feature level and fails if it is too old to bridge.
+    pub async fn fetch_capabilities(&self) -> Result<ServerCapabilities> {
+        let settings = self.get_server_settings().await?;
+        let capabilities = ServerCapabilities::from_settings(&settings);
+        capabilities.check_supported()?;
+        Ok(capabilities)
+    }
+
+    /// Returns a client that phrases its requests for a server with `capabilities`.
+    pub fn with_capabilities(&self, capabilities: ServerCapabilities) -> Self {
+        Self {
+            capabilities,
+            ..self.clone()
+        }
+    }
+
+    /// The realm URL, used to resolve links in rendered messages.
+    pub fn site(&self) -> &str {
+        &self.site
+    }
+
+    /// The bot's own address, which its messages are sent from.
+    pub fn email(&self) -> &str {
+        &self.email
+    }
+
+    pub fn capabilities(&self) -> &ServerCapabilities {
+        &self.capabilities
+    }
+
+    /// Returns a client for the same credentials that fails every request without sending it,
+    /// for replaying recorded events offline.
+    pub fn offline(&self) -> Self {
+        Self {
+            offline: true,
+            ..self.clone()
+        }
+    }
+
+    fn check_online(&self, method: &str, path: &str) -> Result<()> {
+        if self.offline {
+            return Err(BridgeError::InvalidState(format!(
+                "{} {} not sent: zulip requests are disabled while replaying events",
+                method, path
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns a client for the same credentials whose requests are scheduled at `priority`.
+    pub fn with_priority(&self, priority: RequestPriority) -> Self {
+        Self {
+            priority,
+            ..self.clone()
+        }
+    }
+
     fn auth_header(&self) -> String {
         use base64::Engine;
         let credentials = format!("{}:{}", self.email, self.api_key);
@@ -50,148 +159,199 @@ impl ZulipClient {
     fn api_url(&self, path: &str) -> Result<Url> {
         self.base_url
             .join(&format!("/api/v1/{}", path.trim_start_matches('/')))
-            .map_err(|e| BridgeError::Zulip(format!("Invalid API URL: {}", e)))
+            .map_err(|e| ZulipError::InvalidRequest(format!("Invalid API URL: {}", e)).into())
     }
 
-    async fn get<T: serde::de::DeserializeOwned>(&self, path: &str) -> Result<T> {
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        self.get_with_timeout(path, None).await
+    }
+
+    /// Like `get`, but overrides the client-wide request timeout, e.g. for long-polling.
+    async fn get_with_timeout<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        timeout: Option<Duration>,
+    ) -> Result<T> {
         let url = self.api_url(path)?;
         debug!("GET {}", url);
 
-        let mut headers = HeaderMap::new();
-        headers.insert(
-            AUTHORIZATION,
-            self.auth_header().parse().map_err(|e| BridgeError::Zulip(format!("Invalid header: {}", e)))?,
-        );
+        self.execute("GET", path, || {
+            let request = self.client.get(url.clone());
+            match timeout {
+                Some(timeout) => request.timeout(timeout),
+                None => request,
+            }
+        })
+        .await
+    }
 
-        let response = self
-            .client
-            .get(url)
-            .headers(headers)
-            .send()
+    async fn post<T: DeserializeOwned, B: serde::Serialize>(&self, path: &str, body: &B) -> Result<T> {
+        let url = self.api_url(path)?;
+        debug!("POST {}", url);
+
+        self.execute("POST", path, || self.client.post(url.clone()).form(body))
             .await
-            .map_err(|e| BridgeError::Network(e.to_string()))?;
+    }
 
-        let status = response.status();
-        let body = response
-            .text()
+    async fn patch<T: DeserializeOwned, B: serde::Serialize>(&self, path: &str, body: &B) -> Result<T> {
+        let url = self.api_url(path)?;
+        debug!("PATCH {}", url);
+
+        self.execute("PATCH", path, || self.client.patch(url.clone()).form(body))
             .await
-            .map_err(|e| BridgeError::Network(e.to_string()))?;
+    }
 
-        debug!("Response status: {}, body: {}", status, body);
+    async fn delete<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let url = self.api_url(path)?;
+        debug!("DELETE {}", url);
 
-        serde_json::from_str(&body).map_err(|e| {
-            BridgeError::Zulip(format!("Failed to parse response: {} - {}", e, body))
-        })
+        self.execute("DELETE", path, || self.client.delete(url.clone()))
+            .await
     }
 
-    async fn post<T: serde::de::DeserializeOwned, B: serde::Serialize>(
+    /// Sends the request produced by `build` once the rate limiter allows it and decodes the
+    /// payload of a successful response. A 429 pauses the limiter for the server's
+    /// `retry-after` and the request is sent again, so callers only see rate limiting as latency.
+    async fn execute<T: DeserializeOwned>(
         &self,
+        method: &'static str,
         path: &str,
-        body: &B,
+        build: impl Fn() -> reqwest::RequestBuilder,
     ) -> Result<T> {
-        let url = self.api_url(path)?;
-        debug!("POST {}", url);
-
-        let mut headers = HeaderMap::new();
-        headers.insert(
-            AUTHORIZATION,
-            self.auth_header().parse().map_err(|e| BridgeError::Zulip(format!("Invalid header: {}", e)))?,
-        );
+        self.check_online(method, path)?;
+        let mut attempts = 0;
+
+        loop {
+            self.limiter.acquire(self.priority).await;
+            let started = std::time::Instant::now();
+
+            let response = build()
+                .header(AUTHORIZATION, self.auth_header())
+                .send()
+                .await
+                .map_err(|e| BridgeError::Network(e.to_string()))?;
+
+            let status = response.status();
+            metrics::record_zulip_request(method, path, status.as_u16(), started.elapsed());
+            self.limiter.update_from_headers(response.headers());
+            let headers = response.headers().clone();
+            let body = response
+                .text()
+                .await
+                .map_err(|e| BridgeError::Network(e.to_string()))?;
+
+            debug!("{} {} response status: {}, body: {}", method, path, status, body);
+
+            let retry_after = rate_limit::retry_after(&headers, &body);
+            if status != StatusCode::TOO_MANY_REQUESTS {
+                return parse_response(path, status, retry_after, &body);
+            }
+
+            attempts += 1;
+            metrics::record_zulip_rate_limited(&self.site);
+            if attempts >= MAX_RATE_LIMIT_RETRIES {
+                return parse_response(path, status, retry_after, &body);
+            }
+
+            let delay = retry_after.unwrap_or(DEFAULT_RETRY_AFTER);
+            warn!(
+                "Zulip rate limit hit on {} {}, retrying in {:?} (attempt {})",
+                method, path, delay, attempts
+            );
+            self.limiter.block_for(delay);
+        }
+    }
 
-        let response = self
-            .client
-            .post(url)
-            .headers(headers)
-            .form(body)
-            .send()
-            .await
-            .map_err(|e| BridgeError::Network(e.to_string()))?;
+    pub async fn get_profile(&self) -> Result<ZulipUser> {
+        self.get("users/me").await
+    }
 
-        let status = response.status();
-        let response_body = response
-            .text()
-            .await
-            .map_err(|e| BridgeError::Network(e.to_string()))?;
+    pub async fn get_users(&self) -> Result<Vec<ZulipUser>> {
+        let response: ZulipUsersResponse = self.get("users").await?;
+        Ok(response.members)
+    }
 
-        debug!("Response status: {}, body: {}", status, response_body);
+    pub async fn get_user(&self, user_id: i64) -> Result<ZulipUser> {
+        let response: ZulipUserResponse = self.get(&format!("users/{}", user_id)).await?;
+        Ok(response.user)
+    }
 
-        serde_json::from_str(&response_body).map_err(|e| {
-            BridgeError::Zulip(format!("Failed to parse response: {} - {}", e, response_body))
-        })
+    pub async fn get_user_groups(&self) -> Result<Vec<ZulipUserGroup>> {
+        let response: ZulipUserGroupsResponse = self.get("user_groups").await?;
+        Ok(response.user_groups)
     }
 
-    pub async fn get_profile(&self) -> Result<ZulipUser> {
-        let response: ZulipApiResponse<ZulipUser> = self.get("users/me").await?;
+    pub async fn update_presence(&self, status: PresenceStatus) -> Result<ZulipPresenceResponse> {
+        let request = UpdatePresenceRequest {
+            status,
+            ping_only: false,
+            new_user_input: status == PresenceStatus::Active,
+        };
+        self.post("users/me/presence", &request).await
+    }
 
-        if !response.is_success() {
-            return Err(BridgeError::Zulip(format!(
-                "Failed to get profile: {}",
-                response.msg
-            )));
-        }
+    pub async fn get_user_presence(&self, user_id: i64) -> Result<ZulipUserPresenceResponse> {
+        self.get(&format!("users/{}/presence", user_id)).await
+    }
 
-        response.data.ok_or_else(|| BridgeError::Zulip("No profile data".to_string()))
+    pub async fn update_status(&self, status: &ZulipUserStatus) -> Result<()> {
+        let _: IgnoredAny = self.post("users/me/status", status).await?;
+        Ok(())
     }
 
-    pub async fn get_users(&self) -> Result<Vec<ZulipUser>> {
-        let response: ZulipApiResponse<ZulipUsersResponse> = self.get("users").await?;
+    pub async fn get_user_status(&self, user_id: i64) -> Result<ZulipUserStatus> {
+        let response: ZulipUserStatusResponse =
+            self.get(&format!("users/{}/status", user_id)).await?;
+        Ok(response.status)
+    }
 
-        if !response.is_success() {
-            return Err(BridgeError::Zulip(format!(
-                "Failed to get users: {}",
-                response.msg
-            )));
+    pub async fn send_typing(&self, request: &TypingRequest) -> Result<()> {
+        let mut request = request.clone();
+        if matches!(request.recipient_type.as_str(), "direct" | "private") {
+            request.recipient_type = self.capabilities.direct_message_type().to_string();
         }
-
-        Ok(response.data.map(|d| d.members).unwrap_or_default())
+        let _: IgnoredAny = self.post("typing", &request).await?;
+        Ok(())
     }
 
-    pub async fn get_streams(&self) -> Result<Vec<ZulipStream>> {
-        let response: ZulipApiResponse<ZulipStreamsResponse> = self.get("streams").await?;
+    pub async fn get_server_settings(&self) -> Result<ZulipServerSettings> {
+        self.get("server_settings").await
+    }
 
-        if !response.is_success() {
-            return Err(BridgeError::Zulip(format!(
-                "Failed to get streams: {}",
-                response.msg
-            )));
-        }
+    pub async fn get_realm_emoji(&self) -> Result<HashMap<String, ZulipRealmEmoji>> {
+        let response: ZulipRealmEmojiResponse = self.get("realm/emoji").await?;
+        Ok(response.emoji)
+    }
 
-        Ok(response.data.map(|d| d.streams).unwrap_or_default())
+    pub async fn get_streams(&self) -> Result<Vec<ZulipStream>> {
+        let response: ZulipStreamsResponse = self.get("streams").await?;
+        Ok(response.streams)
     }
 
     pub async fn get_stream_id(&self, stream_name: &str) -> Result<i64> {
-        let response: ZulipApiResponse<serde_json::Value> = self
-            .get(&format!("get_stream_id?stream={}", stream_name))
-            .await?;
-
-        if !response.is_success() {
-            return Err(BridgeError::Zulip(format!(
-                "Failed to get stream ID: {}",
-                response.msg
-            )));
+        #[derive(serde::Deserialize)]
+        struct StreamIdResponse {
+            stream_id: i64,
         }
 
-        response
-            .data
-            .and_then(|d| d.get("stream_id")?.as_i64())
-            .ok_or_else(|| BridgeError::Zulip("No stream_id in response".to_string()))
+        let response: StreamIdResponse = self
+            .get(&format!("get_stream_id?stream={}", urlencoding::encode(stream_name)))
+            .await?;
+        Ok(response.stream_id)
     }
 
-    pub async fn send_message(&self, request: &SendMessageRequest) -> Result<i64> {
-        let response: ZulipApiResponse<ZulipSendMessageResponse> =
-            self.post("messages", request).await?;
+    pub async fn update_stream(&self, stream_id: i64, request: &UpdateStreamRequest) -> Result<()> {
+        let _: IgnoredAny = self.patch(&format!("streams/{}", stream_id), request).await?;
+        Ok(())
+    }
 
-        if !response.is_success() {
-            return Err(BridgeError::Zulip(format!(
-                "Failed to send message: {}",
-                response.msg
-            )));
+    pub async fn send_message(&self, request: &SendMessageRequest) -> Result<i64> {
+        let mut request = request.clone();
+        if matches!(request.msg_type.as_str(), "direct" | "private") {
+            request.msg_type = self.capabilities.direct_message_type().to_string();
         }
-
-        response
-            .data
-            .map(|d| d.id)
-            .ok_or_else(|| BridgeError::Zulip("No message ID in response".to_string()))
+        let response: ZulipSendMessageResponse = self.post("messages", &request).await?;
+        Ok(response.id)
     }
 
     pub async fn send_stream_message(
@@ -209,59 +369,20 @@ impl ZulipClient {
         self.send_message(&request).await
     }
 
-    pub async fn get_messages(
-        &self,
-        stream_id: i64,
-        topic: Option<&str>,
-        anchor: Option<i64>,
-        num_before: i32,
-        num_after: i32,
-    ) -> Result<Vec<ZulipMessage>> {
-        let narrow = if let Some(t) = topic {
-            format!(
-                r#"[{{"operator":"stream","operand":{}}},{{"operator":"topic","operand":"{}"}}]"#,
-                stream_id, t
-            )
-        } else {
-            format!(r#"[{{"operator":"stream","operand":{}}}]"#, stream_id)
-        };
-
-        let anchor_str = anchor.map(|a| a.to_string()).unwrap_or_else(|| "newest".to_string());
-
-        let path = format!(
-            "messages?narrow={}&anchor={}&num_before={}&num_after={}",
-            urlencoding::encode(&narrow),
-            anchor_str,
-            num_before,
-            num_after
-        );
-
-        let response: ZulipApiResponse<ZulipMessagesResponse> = self.get(&path).await?;
-
-        if !response.is_success() {
-            return Err(BridgeError::Zulip(format!(
-                "Failed to get messages: {}",
-                response.msg
-            )));
-        }
-
-        Ok(response.data.map(|d| d.messages).unwrap_or_default())
+    pub async fn get_messages(&self, request: &GetMessagesRequest) -> Result<ZulipMessagesResponse> {
+        let mut request = request.clone();
+        request.narrow = self.capabilities.adapt_narrow(request.narrow);
+        self.get(&format!("messages?{}", request.to_query())).await
     }
 
     pub async fn get_message(&self, message_id: i64) -> Result<ZulipMessage> {
-        let response: ZulipApiResponse<ZulipMessage> =
-            self.get(&format!("messages/{}", message_id)).await?;
-
-        if !response.is_success() {
-            return Err(BridgeError::Zulip(format!(
-                "Failed to get message: {}",
-                response.msg
-            )));
+        #[derive(serde::Deserialize)]
+        struct SingleMessageResponse {
+            message: ZulipMessage,
         }
 
-        response
-            .data
-            .ok_or_else(|| BridgeError::Zulip("No message data".to_string()))
+        let response: SingleMessageResponse = self.get(&format!("messages/{}", message_id)).await?;
+        Ok(response.message)
     }
 
     pub async fn edit_message(&self, message_id: i64, content: &str) -> Result<()> {
@@ -274,41 +395,31 @@ impl ZulipClient {
             content: content.to_string(),
         };
 
-        let response: ZulipApiResponse<()> = self
-            .post(&format!("messages/{}", message_id), &request)
+        let _: IgnoredAny = self
+            .patch(&format!("messages/{}", message_id), &request)
             .await?;
-
-        if !response.is_success() {
-            return Err(BridgeError::Zulip(format!(
-                "Failed to edit message: {}",
-                response.msg
-            )));
-        }
-
         Ok(())
     }
 
     pub async fn delete_message(&self, message_id: i64) -> Result<()> {
-        #[derive(serde::Serialize)]
-        struct DeleteMessageRequest {}
-
-        let request = DeleteMessageRequest {};
-
-        let response: ZulipApiResponse<()> = self
-            .post(&format!("messages/{}?allow_deleting_other=1", message_id), &request)
-            .await?;
-
-        if !response.is_success() {
-            return Err(BridgeError::Zulip(format!(
-                "Failed to delete message: {}",
-                response.msg
-            )));
-        }
-
+        let _: IgnoredAny = self.delete(&format!("messages/{}", message_id)).await?;
         Ok(())
     }
 
-    pub async fn add_reaction(&self, message_id: i64, emoji_name: &str, emoji_code: &str) -> Result<()> {
+    /// Returns the ids of the messages whose flag actually changed.
+    pub async fn update_message_flags(&self, request: &UpdateMessageFlagsRequest) -> Result<Vec<i64>> {
+        let response: ZulipUpdateMessageFlagsResponse = self.post("messages/flags", request).await?;
+        Ok(response.messages)
+    }
+
+    /// `reaction_type` is `unicode_emoji`, or `realm_emoji` with the emoji id as `emoji_code`.
+    pub async fn add_reaction(
+        &self,
+        message_id: i64,
+        emoji_name: &str,
+        emoji_code: &str,
+        reaction_type: &str,
+    ) -> Result<()> {
         #[derive(serde::Serialize)]
         struct AddReactionRequest {
             emoji_name: String,
@@ -319,65 +430,31 @@ impl ZulipClient {
         let request = AddReactionRequest {
             emoji_name: emoji_name.to_string(),
             emoji_code: emoji_code.to_string(),
-            reaction_type: "unicode_emoji".to_string(),
+            reaction_type: reaction_type.to_string(),
         };
 
-        let response: ZulipApiResponse<()> = self
+        let _: IgnoredAny = self
             .post(&format!("messages/{}/reactions", message_id), &request)
             .await?;
-
-        if !response.is_success() {
-            return Err(BridgeError::Zulip(format!(
-                "Failed to add reaction: {}",
-                response.msg
-            )));
-        }
-
         Ok(())
     }
 
-    pub async fn remove_reaction(&self, message_id: i64, emoji_name: &str, emoji_code: &str) -> Result<()> {
+    pub async fn remove_reaction(
+        &self,
+        message_id: i64,
+        emoji_name: &str,
+        emoji_code: &str,
+        reaction_type: &str,
+    ) -> Result<()> {
         let path = format!(
-            "messages/{}/reactions?emoji_name={}&emoji_code={}&reaction_type=unicode_emoji",
-            message_id, emoji_name, emoji_code
-        );
-
-        let url = self.api_url(&path)?;
-        
-        let mut headers = HeaderMap::new();
-        headers.insert(
-            AUTHORIZATION,
-            self.auth_header().parse().map_err(|e| BridgeError::Zulip(format!("Invalid header: {}", e)))?,
+            "messages/{}/reactions?emoji_name={}&emoji_code={}&reaction_type={}",
+            message_id,
+            urlencoding::encode(emoji_name),
+            urlencoding::encode(emoji_code),
+            urlencoding::encode(reaction_type)
         );
 
-        let response = self
-            .client
-            .delete(url)
-            .headers(headers)
-            .send()
-            .await
-            .map_err(|e| BridgeError::Network(e.to_string()))?;
-
-        let status = response.status();
-        let body = response
-            .text()
-            .await
-            .map_err(|e| BridgeError::Network(e.to_string()))?;
-
-        debug!("DELETE reaction response status: {}, body: {}", status, body);
-
-        let api_response: ZulipApiResponse<()> =
-            serde_json::from_str(&body).map_err(|e| {
-                BridgeError::Zulip(format!("Failed to parse response: {}", e))
-            })?;
-
-        if !api_response.is_success() {
-            return Err(BridgeError::Zulip(format!(
-                "Failed to remove reaction: {}",
-                api_response.msg
-            )));
-        }
-
+        let _: IgnoredAny = self.delete(&path).await?;
         Ok(())
     }
 
@@ -385,42 +462,24 @@ impl ZulipClient {
         &self,
         request: &RegisterQueueRequest,
     ) -> Result<ZulipQueue> {
-        let response: ZulipApiResponse<ZulipQueue> = self.post("register", request).await?;
-
-        if !response.is_success() {
-            return Err(BridgeError::Zulip(format!(
-                "Failed to register event queue: {}",
-                response.msg
-            )));
-        }
-
-        response
-            .data
-            .ok_or_else(|| BridgeError::Zulip("No queue data in response".to_string()))
+        self.post("register", request).await
     }
 
+    /// Long-polls the event queue. The server holds the request open until events arrive or a
+    /// heartbeat is due, so `timeout` must be longer than its heartbeat interval.
     pub async fn get_events(
         &self,
         queue_id: &str,
         last_event_id: i64,
+        timeout: Duration,
     ) -> Result<ZulipEventsResponse> {
         let path = format!(
-            "events?queue_id={}&last_event_id={}",
-            queue_id, last_event_id
+            "events?queue_id={}&last_event_id={}&dont_block=false",
+            urlencoding::encode(queue_id),
+            last_event_id
         );
 
-        let response: ZulipApiResponse<ZulipEventsResponse> = self.get(&path).await?;
-
-        if !response.is_success() {
-            return Err(BridgeError::Zulip(format!(
-                "Failed to get events: {}",
-                response.msg
-            )));
-        }
-
-        response
-            .data
-            .ok_or_else(|| BridgeError::Zulip("No events data in response".to_string()))
+        self.get_with_timeout(&path, Some(timeout)).await
     }
 
     pub async fn subscribe_to_streams(
@@ -442,88 +501,188 @@ impl ZulipClient {
                     })
                 })
                 .collect::<Vec<_>>(),
-        )
-        .map_err(|e| BridgeError::Zulip(format!("Failed to serialize subscriptions: {}", e)))?;
+        )?;
 
         let request = SubscribeRequest {
             subscriptions: subscriptions_json,
         };
 
-        let response: ZulipApiResponse<()> = self.post("users/me/subscriptions", &request).await?;
-
-        if !response.is_success() {
-            return Err(BridgeError::Zulip(format!(
-                "Failed to subscribe to streams: {}",
-                response.msg
-            )));
-        }
-
+        let _: IgnoredAny = self.post("users/me/subscriptions", &request).await?;
         Ok(())
     }
 
-    pub async fn upload_file(&self, file_path: &str) -> Result<String> {
-        let url = self.api_url("user_uploads")?;
-
-        let mut headers = HeaderMap::new();
-        headers.insert(
-            AUTHORIZATION,
-            self.auth_header().parse().map_err(|e| BridgeError::Zulip(format!("Invalid header: {}", e)))?,
+    pub async fn unsubscribe_from_streams(&self, stream_names: &[&str]) -> Result<ZulipUnsubscribeResponse> {
+        let path = format!(
+            "users/me/subscriptions?subscriptions={}",
+            urlencoding::encode(&serde_json::to_string(stream_names)?)
         );
+        self.delete(&path).await
+    }
 
-        let file_content = std::fs::read(file_path)
-            .map_err(|e| BridgeError::Io(e))?;
+    pub async fn upload_file(&self, file_path: &str) -> Result<String> {
+        let file_content = tokio::fs::read(file_path).await.map_err(BridgeError::Io)?;
 
         let file_name = std::path::Path::new(file_path)
             .file_name()
             .and_then(|n| n.to_str())
             .unwrap_or("file");
 
-        let part = reqwest::multipart::Part::bytes(file_content)
-            .file_name(file_name.to_string())
-            .mime_str("application/octet-stream")
-            .map_err(|e| BridgeError::Network(e.to_string()))?;
+        self.upload_bytes(file_content.into(), file_name, content_type_for(file_name))
+            .await
+    }
 
-        let form = reqwest::multipart::Form::new().part("file", part);
+    /// Uploads in-memory content and returns its `/user_uploads/...` path.
+    pub async fn upload_bytes(
+        &self,
+        content: Bytes,
+        file_name: &str,
+        content_type: &str,
+    ) -> Result<String> {
+        #[derive(serde::Deserialize)]
+        struct UploadResponse {
+            uri: String,
+        }
+
+        let url = self.api_url("user_uploads")?;
+        let length = content.len() as u64;
+
+        // Multipart bodies cannot be cloned, so every attempt builds a fresh form around the
+        // shared buffer.
+        let response: UploadResponse = self
+            .execute("POST", "user_uploads", || {
+                let part = reqwest::multipart::Part::stream_with_length(content.clone(), length)
+                    .file_name(file_name.to_string())
+                    .mime_str(content_type)
+                    .unwrap_or_else(|_| {
+                        reqwest::multipart::Part::stream_with_length(content.clone(), length)
+                            .file_name(file_name.to_string())
+                    });
+                let form = reqwest::multipart::Form::new().part("file", part);
+                self.client.post(url.clone()).multipart(form)
+            })
+            .await?;
+
+        Ok(response.uri)
+    }
 
-        let response = self
-            .client
-            .post(url)
-            .headers(headers)
-            .multipart(form)
+    /// Exchanges an upload path (`/user_uploads/...`) for a short-lived URL that can be fetched
+    /// without credentials.
+    pub async fn get_temporary_file_url(&self, upload_path: &str) -> Result<Url> {
+        let path = upload_path
+            .trim_start_matches('/')
+            .trim_start_matches("user_uploads/");
+        let response: ZulipTemporaryUrlResponse =
+            self.get(&format!("user_uploads/{}", path)).await?;
+
+        self.base_url.join(&response.url).map_err(|e| {
+            ZulipError::InvalidResponse {
+                endpoint: "user_uploads".to_string(),
+                reason: format!("invalid temporary URL {}: {}", response.url, e),
+            }
+            .into()
+        })
+    }
+
+    /// The absolute URL of a file given its `/user_uploads/...` path or absolute URL.
+    pub fn file_url(&self, path_or_url: &str) -> Result<Url> {
+        self.base_url
+            .join(path_or_url)
+            .map_err(|e| ZulipError::InvalidRequest(format!("Invalid file URL: {}", e)).into())
+    }
+
+    /// Downloads an uploaded file given its `/user_uploads/...` path or absolute URL, giving up
+    /// once it exceeds `max_size` bytes. Credentials are only sent to this organization's own
+    /// server.
+    pub async fn download_file(&self, path_or_url: &str, max_size: u64) -> Result<ZulipFile> {
+        self.check_online("GET", "user_uploads")?;
+        let url = self.file_url(path_or_url)?;
+        let same_origin = url.origin() == self.base_url.origin();
+        debug!("GET {}", url);
+
+        let mut request = self.client.get(url);
+        if same_origin {
+            self.limiter.acquire(self.priority).await;
+            request = request.header(AUTHORIZATION, self.auth_header());
+        }
+
+        let started = std::time::Instant::now();
+        let response = request
             .send()
             .await
             .map_err(|e| BridgeError::Network(e.to_string()))?;
 
         let status = response.status();
-        let body = response
-            .text()
-            .await
-            .map_err(|e| BridgeError::Network(e.to_string()))?;
-
-        debug!("Upload response status: {}, body: {}", status, body);
-
-        #[derive(serde::Deserialize)]
-        struct UploadResponse {
-            uri: Option<String>,
+        if same_origin {
+            metrics::record_zulip_request("GET", "user_uploads", status.as_u16(), started.elapsed());
+            self.limiter.update_from_headers(response.headers());
+        }
+        if !status.is_success() {
+            return Err(ZulipError::Http {
+                endpoint: "user_uploads".to_string(),
+                status: status.as_u16(),
+            }
+            .into());
         }
 
-        let api_response: ZulipApiResponse<UploadResponse> =
-            serde_json::from_str(&body).map_err(|e| {
-                BridgeError::Zulip(format!("Failed to parse upload response: {}", e))
-            })?;
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(ToOwned::to_owned);
+        let content = read_limited(response, max_size).await?;
+        Ok(ZulipFile {
+            content,
+            content_type,
+        })
+    }
+}
 
-        if !api_response.is_success() {
-            return Err(BridgeError::Zulip(format!(
-                "Failed to upload file: {}",
-                api_response.msg
-            )));
+/// Turns a Zulip response into either its payload or a [`ZulipError`] carrying the error code,
+/// HTTP status and retry hint.
+fn parse_response<T: DeserializeOwned>(
+    path: &str,
+    status: StatusCode,
+    retry_after: Option<Duration>,
+    body: &str,
+) -> Result<T> {
+    let endpoint = path.split('?').next().unwrap_or_default().to_string();
+
+    let response: ZulipApiResponse<serde_json::Value> = match serde_json::from_str(body) {
+        Ok(response) => response,
+        Err(_) if !status.is_success() => {
+            return Err(ZulipError::Http {
+                endpoint,
+                status: status.as_u16(),
+            }
+            .into());
         }
-
-        api_response
-            .data
-            .and_then(|d| d.uri)
-            .ok_or_else(|| BridgeError::Zulip("No URI in upload response".to_string()))
+        Err(e) => {
+            return Err(ZulipError::InvalidResponse {
+                endpoint,
+                reason: format!("{} - {}", e, body),
+            }
+            .into());
+        }
+    };
+
+    if !response.is_success() {
+        return Err(ZulipError::Api {
+            endpoint,
+            code: ZulipErrorCode::from_code(response.code.as_deref()),
+            msg: response.msg,
+            status: status.as_u16(),
+            retry_after,
+        }
+        .into());
     }
+
+    serde_json::from_value(response.data.unwrap_or_default()).map_err(|e| {
+        ZulipError::InvalidResponse {
+            endpoint,
+            reason: e.to_string(),
+        }
+        .into()
+    })
 }
 
 mod urlencoding {
@@ -531,3 +690,202 @@ mod urlencoding {
         url::form_urlencoded::byte_serialize(s.as_bytes()).collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FakeZulip;
+    use crate::testing::fake_zulip::BOT_USER_ID;
+    use reqwest::header::HeaderMap;
+
+    fn decode<T: DeserializeOwned>(body: &str) -> T {
+        parse_response("test", StatusCode::OK, None, body).unwrap()
+    }
+
+    fn decode_error(status: StatusCode, body: &str) -> ZulipError {
+        let retry_after = rate_limit::retry_after(&HeaderMap::new(), body);
+        let err = parse_response::<IgnoredAny>("test", status, retry_after, body).unwrap_err();
+        err.as_zulip().cloned().unwrap()
+    }
+
+    #[test]
+    fn decodes_user() {
+        let response: ZulipUserResponse =
+            decode(include_str!("../fixtures/zulip/get_user.json"));
+        let user = response.user;
+        assert_eq!(user.user_id, 10);
+        assert_eq!(user.full_name, "Iago");
+        assert_eq!(user.email, "iago@zulip.example.com");
+        assert_eq!(user.avatar_version, Some(1));
+        assert_eq!(user.role, 200);
+        assert_eq!(user.timezone.as_deref(), Some("America/New_York"));
+        assert!(user.is_active);
+        assert!(!user.is_bot);
+    }
+
+    #[test]
+    fn decodes_user_groups() {
+        let response: ZulipUserGroupsResponse =
+            decode(include_str!("../fixtures/zulip/get_user_groups.json"));
+        let groups = response.user_groups;
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].name, "role:owners");
+        assert!(groups[0].is_system_group);
+        assert_eq!(groups[1].id, 24);
+        assert_eq!(groups[1].name, "backend");
+        assert_eq!(groups[1].description, "Backend team");
+        assert_eq!(groups[1].members, vec![12, 13]);
+        assert!(!groups[1].is_system_group);
+    }
+
+    #[test]
+    fn decodes_user_presence() {
+        let response: ZulipUserPresenceResponse =
+            decode(include_str!("../fixtures/zulip/get_user_presence.json"));
+        let aggregated = &response.presence["aggregated"];
+        assert_eq!(aggregated.status, PresenceStatus::Active);
+        assert_eq!(aggregated.timestamp, 1532697622);
+        assert_eq!(aggregated.client, None);
+
+        let mobile = &response.presence["ZulipMobile"];
+        assert_eq!(mobile.client.as_deref(), Some("ZulipMobile"));
+        assert_eq!(mobile.pushable, Some(false));
+        assert_eq!(mobile.timestamp, 1522687421);
+    }
+
+    #[test]
+    fn decodes_user_status() {
+        let response: ZulipUserStatusResponse =
+            decode(include_str!("../fixtures/zulip/get_user_status.json"));
+        let status = response.status;
+        assert_eq!(status.status_text.as_deref(), Some("on vacation"));
+        assert_eq!(status.emoji_name.as_deref(), Some("car"));
+        assert_eq!(status.emoji_code.as_deref(), Some("1f697"));
+        assert_eq!(status.reaction_type.as_deref(), Some("unicode_emoji"));
+    }
+
+    #[test]
+    fn decodes_realm_emoji() {
+        let response: ZulipRealmEmojiResponse =
+            decode(include_str!("../fixtures/zulip/get_realm_emoji.json"));
+        assert_eq!(response.emoji.len(), 2);
+
+        let tick = &response.emoji["1"];
+        assert_eq!(tick.name, "green_tick");
+        assert_eq!(tick.source_url, "/user_avatars/1/emoji/images/1.png");
+        assert_eq!(tick.still_url, None);
+        assert_eq!(tick.author_id, Some(5));
+
+        let parrot = &response.emoji["2"];
+        assert_eq!(parrot.id, "2");
+        assert_eq!(parrot.name, "partyparrot");
+        assert_eq!(
+            parrot.still_url.as_deref(),
+            Some("/user_avatars/1/emoji/images/still/2.png")
+        );
+        assert!(!parrot.deactivated);
+    }
+
+    #[test]
+    fn decodes_temporary_file_url() {
+        let response: ZulipTemporaryUrlResponse =
+            decode(include_str!("../fixtures/zulip/get_temporary_file_url.json"));
+        assert!(response.url.starts_with("/user_uploads/temporary/"));
+        assert!(response.url.ends_with("/image.png"));
+    }
+
+    #[test]
+    fn decodes_server_settings() {
+        let settings: ZulipServerSettings =
+            decode(include_str!("../fixtures/zulip/server_settings.json"));
+        assert_eq!(settings.zulip_version, "9.0");
+        assert_eq!(settings.zulip_feature_level, 278);
+        assert_eq!(settings.zulip_merge_base.as_deref(), Some("9.0"));
+        assert_eq!(settings.realm_name.as_deref(), Some("Zulip Dev"));
+        assert_eq!(settings.realm_url.as_deref(), Some("http://localhost:9991"));
+        assert_eq!(settings.authentication_methods.get("github"), Some(&true));
+        assert_eq!(settings.authentication_methods.get("openid connect"), Some(&false));
+        assert!(ServerCapabilities::from_settings(&settings).check_supported().is_ok());
+    }
+
+    #[test]
+    fn decodes_legacy_server_settings() {
+        let settings: ZulipServerSettings =
+            decode(include_str!("../fixtures/zulip/server_settings_legacy.json"));
+        assert_eq!(settings.zulip_version, "2.1.7");
+        assert_eq!(settings.zulip_feature_level, 0);
+        assert_eq!(settings.zulip_merge_base, None);
+        assert_eq!(settings.realm_url, None);
+        assert_eq!(settings.realm_uri.as_deref(), Some("https://chat.example.com"));
+        assert!(ServerCapabilities::from_settings(&settings).check_supported().is_err());
+    }
+
+    #[test]
+    fn decodes_unsubscribe() {
+        let response: ZulipUnsubscribeResponse =
+            decode(include_str!("../fixtures/zulip/unsubscribe.json"));
+        assert_eq!(response.removed, vec!["new stream".to_string()]);
+        assert!(response.not_removed.is_empty());
+    }
+
+    #[test]
+    fn decodes_update_message_flags() {
+        let response: ZulipUpdateMessageFlagsResponse =
+            decode(include_str!("../fixtures/zulip/update_message_flags.json"));
+        assert_eq!(response.messages, vec![4, 18, 15]);
+    }
+
+    #[test]
+    fn decodes_update_presence() {
+        let response: ZulipPresenceResponse =
+            decode(include_str!("../fixtures/zulip/update_presence.json"));
+        assert_eq!(response.server_timestamp, Some(1656958539.6287155));
+        assert_eq!(response.presences["10"]["idle_timestamp"], 1656958530);
+    }
+
+    #[test]
+    fn decodes_empty_success() {
+        let _: IgnoredAny = decode(include_str!("../fixtures/zulip/empty_success.json"));
+    }
+
+    #[test]
+    fn maps_bad_event_queue_id() {
+        let err = decode_error(
+            StatusCode::BAD_REQUEST,
+            include_str!("../fixtures/zulip/error_bad_event_queue_id.json"),
+        );
+        assert_eq!(err.code(), Some(&ZulipErrorCode::BadEventQueueId));
+        assert_eq!(err.retry_after(), None);
+        let ZulipError::Api { msg, status, .. } = err else {
+            panic!("expected an API error, got {:?}", err);
+        };
+        assert_eq!(msg, "Bad event queue ID: fb67bf8a-c031-47cc-84cf-ed80accacda8");
+        assert_eq!(status, 400);
+    }
+
+    #[test]
+    fn maps_rate_limit_hit() {
+        let err = decode_error(
+            StatusCode::TOO_MANY_REQUESTS,
+            include_str!("../fixtures/zulip/error_rate_limit_hit.json"),
+        );
+        assert_eq!(err.code(), Some(&ZulipErrorCode::RateLimitHit));
+        assert_eq!(err.retry_after(), Some(Duration::from_secs_f64(28.706807374954224)));
+    }
+
+    #[tokio::test]
+    async fn retries_after_rate_limit() {
+        let fake = FakeZulip::start().await.unwrap();
+        fake.rate_limit_next("users/me", Duration::from_millis(200));
+        let client = fake.client().unwrap();
+
+        let started = std::time::Instant::now();
+        let profile = client.get_profile().await.unwrap();
+        assert_eq!(profile.user_id, BOT_USER_ID);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+
+        let requests = fake.requests();
+        let attempts = requests.iter().filter(|request| request.path == "api/v1/users/me");
+        assert_eq!(attempts.count(), 2);
+    }
+}
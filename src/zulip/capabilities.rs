@@ -0,0 +1,99 @@
+use serde_json::json;
+
+use super::error::ZulipError;
+use super::narrow::Narrow;
+use super::types::{RegisterQueueRequest, ZulipServerSettings};
+
+/// Oldest server the bridge talks to: Zulip 4.0.
+pub const MIN_FEATURE_LEVEL: i64 = 65;
+pub const MIN_VERSION: &str = "4.0";
+
+// Feature levels of the API changes the bridge adapts to.
+const STREAM_TYPING_NOTIFICATIONS: i64 = 58;
+const USER_SETTINGS_OBJECT: i64 = 89;
+const DIRECT_MESSAGE_TYPE: i64 = 174;
+const DM_NARROW_OPERATOR: i64 = 177;
+const CHANNEL_NARROW_OPERATOR: i64 = 250;
+
+/// What a Zulip server understands, derived from its `zulip_feature_level`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerCapabilities {
+    pub version: String,
+    pub feature_level: i64,
+}
+
+impl Default for ServerCapabilities {
+    /// Until the server has been asked, assume the oldest supported release.
+    fn default() -> Self {
+        Self {
+            version: MIN_VERSION.to_string(),
+            feature_level: MIN_FEATURE_LEVEL,
+        }
+    }
+}
+
+impl ServerCapabilities {
+    pub fn from_settings(settings: &ZulipServerSettings) -> Self {
+        Self {
+            version: settings.zulip_version.clone(),
+            feature_level: settings.zulip_feature_level,
+        }
+    }
+
+    pub fn supports(&self, feature_level: i64) -> bool {
+        self.feature_level >= feature_level
+    }
+
+    pub fn check_supported(&self) -> Result<(), ZulipError> {
+        if self.supports(MIN_FEATURE_LEVEL) {
+            return Ok(());
+        }
+        Err(ZulipError::UnsupportedServer {
+            version: self.version.clone(),
+            feature_level: self.feature_level,
+        })
+    }
+
+    /// Recipient type for direct messages and direct typing notifications.
+    pub fn direct_message_type(&self) -> &'static str {
+        if self.supports(DIRECT_MESSAGE_TYPE) {
+            "direct"
+        } else {
+            "private"
+        }
+    }
+
+    /// Rewrites narrow operators to the names this server knows.
+    pub fn adapt_narrow(&self, narrow: Narrow) -> Narrow {
+        let (stream, legacy_stream) = if self.supports(CHANNEL_NARROW_OPERATOR) {
+            ("channel", "stream")
+        } else {
+            ("stream", "channel")
+        };
+        let (dm, legacy_dm) = if self.supports(DM_NARROW_OPERATOR) {
+            ("dm", "pm-with")
+        } else {
+            ("pm-with", "dm")
+        };
+
+        narrow
+            .rename_operator(legacy_stream, stream)
+            .rename_operator(legacy_dm, dm)
+    }
+
+    /// Queue registration opting into the event formats the bridge parses. Deletions always
+    /// arrive in the bulk `message_ids` form.
+    pub fn register_request(&self) -> RegisterQueueRequest {
+        let client_capabilities = json!({
+            "notification_settings_null": true,
+            "bulk_message_deletion": true,
+            "stream_typing_notifications": self.supports(STREAM_TYPING_NOTIFICATIONS),
+            "user_settings_object": self.supports(USER_SETTINGS_OBJECT),
+        });
+
+        RegisterQueueRequest {
+            client_capabilities: Some(client_capabilities.to_string()),
+            ..Default::default()
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use std::fmt;
+use std::time::Duration;
+
+use thiserror::Error;
+
+/// The `code` field of a Zulip error response. Codes the bridge does not act on are kept
+/// verbatim in `Other`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ZulipErrorCode {
+    BadEventQueueId,
+    RateLimitHit,
+    Unauthorized,
+    UnauthenticatedUser,
+    InvalidApiKey,
+    UserDeactivated,
+    RealmDeactivated,
+    StreamDoesNotExist,
+    BadRequest,
+    BadNarrow,
+    RequestVariableMissing,
+    RequestVariableInvalid,
+    ReactionAlreadyExists,
+    ReactionDoesNotExist,
+    ServerNotReady,
+    /// Error responses from servers too old to send a code.
+    Missing,
+    Other(String),
+}
+
+impl ZulipErrorCode {
+    pub fn from_code(code: Option<&str>) -> Self {
+        let Some(code) = code else {
+            return ZulipErrorCode::Missing;
+        };
+
+        match code {
+            "BAD_EVENT_QUEUE_ID" => ZulipErrorCode::BadEventQueueId,
+            "RATE_LIMIT_HIT" => ZulipErrorCode::RateLimitHit,
+            "UNAUTHORIZED" => ZulipErrorCode::Unauthorized,
+            "UNAUTHENTICATED_USER" => ZulipErrorCode::UnauthenticatedUser,
+            "INVALID_API_KEY" => ZulipErrorCode::InvalidApiKey,
+            "USER_DEACTIVATED" => ZulipErrorCode::UserDeactivated,
+            "REALM_DEACTIVATED" => ZulipErrorCode::RealmDeactivated,
+            "STREAM_DOES_NOT_EXIST" => ZulipErrorCode::StreamDoesNotExist,
+            "BAD_REQUEST" => ZulipErrorCode::BadRequest,
+            "BAD_NARROW" => ZulipErrorCode::BadNarrow,
+            "REQUEST_VARIABLE_MISSING" => ZulipErrorCode::RequestVariableMissing,
+            "REQUEST_VARIABLE_INVALID" => ZulipErrorCode::RequestVariableInvalid,
+            "REACTION_ALREADY_EXISTS" => ZulipErrorCode::ReactionAlreadyExists,
+            "REACTION_DOES_NOT_EXIST" => ZulipErrorCode::ReactionDoesNotExist,
+            "SERVER_NOT_READY" => ZulipErrorCode::ServerNotReady,
+            other => ZulipErrorCode::Other(other.to_string()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            ZulipErrorCode::BadEventQueueId => "BAD_EVENT_QUEUE_ID",
+            ZulipErrorCode::RateLimitHit => "RATE_LIMIT_HIT",
+            ZulipErrorCode::Unauthorized => "UNAUTHORIZED",
+            ZulipErrorCode::UnauthenticatedUser => "UNAUTHENTICATED_USER",
+            ZulipErrorCode::InvalidApiKey => "INVALID_API_KEY",
+            ZulipErrorCode::UserDeactivated => "USER_DEACTIVATED",
+            ZulipErrorCode::RealmDeactivated => "REALM_DEACTIVATED",
+            ZulipErrorCode::StreamDoesNotExist => "STREAM_DOES_NOT_EXIST",
+            ZulipErrorCode::BadRequest => "BAD_REQUEST",
+            ZulipErrorCode::BadNarrow => "BAD_NARROW",
+            ZulipErrorCode::RequestVariableMissing => "REQUEST_VARIABLE_MISSING",
+            ZulipErrorCode::RequestVariableInvalid => "REQUEST_VARIABLE_INVALID",
+            ZulipErrorCode::ReactionAlreadyExists => "REACTION_ALREADY_EXISTS",
+            ZulipErrorCode::ReactionDoesNotExist => "REACTION_DOES_NOT_EXIST",
+            ZulipErrorCode::ServerNotReady => "SERVER_NOT_READY",
+            ZulipErrorCode::Missing => "none",
+            ZulipErrorCode::Other(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for ZulipErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Error)]
+pub enum ZulipError {
+    /// The server answered with `"result": "error"`.
+    #[error("{endpoint}: {msg} (code {code}, HTTP {status})")]
+    Api {
+        endpoint: String,
+        code: ZulipErrorCode,
+        msg: String,
+        status: u16,
+        retry_after: Option<Duration>,
+    },
+
+    /// A failed request without a Zulip error body, typically from a proxy in front of Zulip.
+    #[error("{endpoint}: HTTP {status}")]
+    Http { endpoint: String, status: u16 },
+
+    #[error("invalid response from {endpoint}: {reason}")]
+    InvalidResponse { endpoint: String, reason: String },
+
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+
+    #[error(
+        "Zulip server {version} (feature level {feature_level}) is not supported, the bridge \
+         needs Zulip {} (feature level {}) or newer",
+        super::capabilities::MIN_VERSION,
+        super::capabilities::MIN_FEATURE_LEVEL
+    )]
+    UnsupportedServer { version: String, feature_level: i64 },
+}
+
+impl ZulipError {
+    pub fn code(&self) -> Option<&ZulipErrorCode> {
+        match self {
+            ZulipError::Api { code, .. } => Some(code),
+            _ => None,
+        }
+    }
+
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            ZulipError::Api { status, .. } | ZulipError::Http { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    /// How long the server asked us to wait before trying again, if it said so.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ZulipError::Api { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    pub fn is_queue_expired(&self) -> bool {
+        self.code() == Some(&ZulipErrorCode::BadEventQueueId)
+    }
+
+    pub fn is_rate_limited(&self) -> bool {
+        self.code() == Some(&ZulipErrorCode::RateLimitHit) || self.status() == Some(429)
+    }
+
+    /// The credentials are wrong or the account is gone; retrying will not help until an
+    /// operator steps in.
+    pub fn is_auth_failure(&self) -> bool {
+        matches!(
+            self.code(),
+            Some(
+                ZulipErrorCode::Unauthorized
+                    | ZulipErrorCode::UnauthenticatedUser
+                    | ZulipErrorCode::InvalidApiKey
+                    | ZulipErrorCode::UserDeactivated
+                    | ZulipErrorCode::RealmDeactivated
+            )
+        ) || self.status() == Some(401)
+    }
+
+    /// Whether the same request may succeed later without any change on our side.
+    pub fn is_retryable(&self) -> bool {
+        if self.is_rate_limited() || self.code() == Some(&ZulipErrorCode::ServerNotReady) {
+            return true;
+        }
+        match self {
+            ZulipError::Api { status, .. } | ZulipError::Http { status, .. } => *status >= 500,
+            ZulipError::InvalidResponse { .. } => true,
+            ZulipError::InvalidRequest(_) | ZulipError::UnsupportedServer { .. } => false,
+        }
+    }
+}
@@ -1,10 +1,11 @@
 use std::sync::Arc;
 
 use async_trait::async_trait;
-use tracing::{debug, info, warn};
+use tracing::{debug, error, info, warn};
 
-use super::ZulipEvent;
+use super::{EventSource, ZulipEvent};
 use crate::utils::Result;
+use crate::utils::metrics::{self, Outcome};
 
 #[async_trait]
 pub trait ZulipEventHandler: Send + Sync {
@@ -14,6 +15,8 @@ pub trait ZulipEventHandler: Send + Sync {
     async fn handle_delete_message(&self, event: &ZulipEvent) -> Result<()>;
     async fn handle_subscription(&self, event: &ZulipEvent) -> Result<()>;
     async fn handle_realm_user(&self, event: &ZulipEvent) -> Result<()>;
+    async fn handle_realm_emoji(&self, event: &ZulipEvent) -> Result<()>;
+    async fn handle_user_group(&self, event: &ZulipEvent) -> Result<()>;
 }
 
 pub struct DefaultZulipEventHandler;
@@ -47,7 +50,7 @@ impl ZulipEventHandler for DefaultZulipEventHandler {
     }
 
     async fn handle_delete_message(&self, event: &ZulipEvent) -> Result<()> {
-        if let Some(msg_id) = event.message_id {
+        for msg_id in event.message_ids() {
             debug!("Zulip message {} deleted", msg_id);
         }
         Ok(())
@@ -66,10 +69,27 @@ impl ZulipEventHandler for DefaultZulipEventHandler {
         }
         Ok(())
     }
+
+    async fn handle_realm_emoji(&self, event: &ZulipEvent) -> Result<()> {
+        if let Some(emoji) = event.realm_emoji() {
+            debug!("Zulip realm emoji changed, {} emoji", emoji.len());
+        }
+        Ok(())
+    }
+
+    async fn handle_user_group(&self, event: &ZulipEvent) -> Result<()> {
+        if let Some(op) = &event.op {
+            debug!("Zulip user group changed: {}", op);
+        }
+        Ok(())
+    }
 }
 
 pub struct ZulipEventProcessor {
     handler: Arc<dyn ZulipEventHandler>,
+    /// Event ids are numbered per queue, starting over on every queue the source registers, so
+    /// they only identify events of `queue_id`.
+    queue_id: Option<String>,
     processed_events: std::collections::HashSet<i64>,
     max_processed_events: usize,
 }
@@ -78,48 +98,88 @@ impl ZulipEventProcessor {
     pub fn new(handler: Arc<dyn ZulipEventHandler>) -> Self {
         Self {
             handler,
+            queue_id: None,
             processed_events: std::collections::HashSet::new(),
             max_processed_events: 10000,
         }
     }
 
+    /// Forgets the processed event ids when events start coming from another queue.
+    pub fn set_queue(&mut self, queue_id: Option<String>) {
+        if queue_id != self.queue_id {
+            debug!("Zulip event queue changed to {:?}", queue_id);
+            self.processed_events.clear();
+            self.queue_id = queue_id;
+        }
+    }
+
+    /// Processes events from `source` until it is stopped or exhausted.
+    pub async fn run(&mut self, organization_id: &str, source: &dyn EventSource) -> Result<()> {
+        while let Some(events) = source.next_events().await? {
+            self.set_queue(source.status().queue_id);
+            let mut remaining = events.len();
+            for raw in events {
+                remaining -= 1;
+                metrics::set_zulip_queue_depth(organization_id, remaining);
+
+                let event = match serde_json::from_value::<ZulipEvent>(raw) {
+                    Ok(event) => event,
+                    Err(e) => {
+                        warn!("Failed to parse Zulip event for {}: {}", organization_id, e);
+                        metrics::record_zulip_event("unparsable", Outcome::Failure);
+                        continue;
+                    }
+                };
+
+                if let Err(e) = self.process_event(event).await {
+                    error!("Error processing Zulip event for {}: {}", organization_id, e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     pub async fn process_event(&mut self, event: ZulipEvent) -> Result<()> {
         let event_id = event.id.unwrap_or(-1);
-        
+
         if event_id >= 0 && self.processed_events.contains(&event_id) {
             debug!("Skipping already processed event {}", event_id);
+            metrics::record_zulip_event(&event.event_type, Outcome::Skipped);
             return Ok(());
         }
 
         debug!("Processing Zulip event type={}", event.event_type);
 
-        match event.event_type.as_str() {
-            "message" => {
-                self.handler.handle_message(&event).await?;
-            }
-            "reaction" => {
-                self.handler.handle_reaction(&event).await?;
-            }
-            "update_message" => {
-                self.handler.handle_update_message(&event).await?;
-            }
-            "delete_message" => {
-                self.handler.handle_delete_message(&event).await?;
-            }
-            "subscription" => {
-                self.handler.handle_subscription(&event).await?;
-            }
-            "realm_user" => {
-                self.handler.handle_realm_user(&event).await?;
-            }
+        let result = match event.event_type.as_str() {
+            "message" => self.handler.handle_message(&event).await,
+            "reaction" => self.handler.handle_reaction(&event).await,
+            "update_message" => self.handler.handle_update_message(&event).await,
+            "delete_message" => self.handler.handle_delete_message(&event).await,
+            "subscription" => self.handler.handle_subscription(&event).await,
+            "realm_user" => self.handler.handle_realm_user(&event).await,
+            "realm_emoji" => self.handler.handle_realm_emoji(&event).await,
+            "user_group" => self.handler.handle_user_group(&event).await,
             _ => {
                 debug!("Ignoring unhandled event type: {}", event.event_type);
+                metrics::record_zulip_event(&event.event_type, Outcome::Ignored);
+                return Ok(());
             }
+        };
+
+        metrics::record_zulip_event(&event.event_type, Outcome::from_result(&result));
+        result?;
+
+        if let Some(msg) = &event.message {
+            metrics::record_bridge_delay(
+                "zulip_to_matrix",
+                metrics::delay_since_millis(msg.timestamp * 1000),
+            );
         }
 
         if event_id >= 0 {
             self.processed_events.insert(event_id);
-            
+
             if self.processed_events.len() > self.max_processed_events {
                 self.processed_events.clear();
             }
@@ -128,3 +188,139 @@ impl ZulipEventProcessor {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::testing::FakeZulip;
+    use crate::zulip::{LongPollEventSource, RecordingEventSource, ReplayEventSource};
+
+    const SENDER_ID: i64 = 10;
+    const STREAM_ID: i64 = 5;
+
+    /// Passes the id of every message it handles on to the test.
+    struct MessageSink(mpsc::UnboundedSender<i64>);
+
+    #[async_trait]
+    impl ZulipEventHandler for MessageSink {
+        async fn handle_message(&self, event: &ZulipEvent) -> Result<()> {
+            if let Some(msg) = &event.message {
+                let _ = self.0.send(msg.id);
+            }
+            Ok(())
+        }
+
+        async fn handle_reaction(&self, _event: &ZulipEvent) -> Result<()> {
+            Ok(())
+        }
+
+        async fn handle_update_message(&self, _event: &ZulipEvent) -> Result<()> {
+            Ok(())
+        }
+
+        async fn handle_delete_message(&self, _event: &ZulipEvent) -> Result<()> {
+            Ok(())
+        }
+
+        async fn handle_subscription(&self, _event: &ZulipEvent) -> Result<()> {
+            Ok(())
+        }
+
+        async fn handle_realm_user(&self, _event: &ZulipEvent) -> Result<()> {
+            Ok(())
+        }
+
+        async fn handle_realm_emoji(&self, _event: &ZulipEvent) -> Result<()> {
+            Ok(())
+        }
+
+        async fn handle_user_group(&self, _event: &ZulipEvent) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    async fn wait_for_queue(fake: &FakeZulip) {
+        while fake.queue_count() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    async fn next_message(handled: &mut mpsc::UnboundedReceiver<i64>) -> i64 {
+        tokio::time::timeout(Duration::from_secs(5), handled.recv())
+            .await
+            .expect("no message handled within 5s")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn handles_repeated_event_ids_from_a_new_queue() {
+        let fake = FakeZulip::start().await.unwrap();
+        fake.add_user(SENDER_ID, "Iago");
+        fake.add_stream(STREAM_ID, "general");
+
+        let (sink, mut handled) = mpsc::unbounded_channel();
+        let source = Arc::new(LongPollEventSource::new(Arc::new(fake.client().unwrap())));
+        let mut processor = ZulipEventProcessor::new(Arc::new(MessageSink(sink)));
+        let run = tokio::spawn({
+            let source = source.clone();
+            async move { processor.run("org", source.as_ref()).await }
+        });
+
+        wait_for_queue(&fake).await;
+        let first = fake.post_message(SENDER_ID, STREAM_ID, "greetings", "before");
+        assert_eq!(next_message(&mut handled).await, first);
+
+        // The new queue numbers its events from 0 again.
+        fake.expire_queues();
+        wait_for_queue(&fake).await;
+        let second = fake.post_message(SENDER_ID, STREAM_ID, "greetings", "after");
+        assert_eq!(next_message(&mut handled).await, second);
+
+        source.stop();
+        run.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn replays_a_recording_across_queues() {
+        let fake = FakeZulip::start().await.unwrap();
+        fake.add_user(SENDER_ID, "Iago");
+        fake.add_stream(STREAM_ID, "general");
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("org.jsonl");
+
+        let (sink, mut handled) = mpsc::unbounded_channel();
+        let live = Arc::new(LongPollEventSource::new(Arc::new(fake.client().unwrap())));
+        let source = Arc::new(RecordingEventSource::create(live, &path).await.unwrap());
+        let mut processor = ZulipEventProcessor::new(Arc::new(MessageSink(sink)));
+        let run = tokio::spawn({
+            let source = source.clone();
+            async move { processor.run("org", source.as_ref()).await }
+        });
+
+        wait_for_queue(&fake).await;
+        let first = fake.post_message(SENDER_ID, STREAM_ID, "greetings", "before");
+        assert_eq!(next_message(&mut handled).await, first);
+        fake.expire_queues();
+        wait_for_queue(&fake).await;
+        let second = fake.post_message(SENDER_ID, STREAM_ID, "greetings", "after");
+        assert_eq!(next_message(&mut handled).await, second);
+        source.stop();
+        run.await.unwrap().unwrap();
+        fake.stop();
+
+        // Both messages were event 0 of their queue, and both come back out of the recording.
+        let (sink, mut handled) = mpsc::unbounded_channel();
+        let source = ReplayEventSource::open(&path).await.unwrap();
+        let mut processor = ZulipEventProcessor::new(Arc::new(MessageSink(sink)));
+        processor.run("org", &source).await.unwrap();
+        let mut replayed = Vec::new();
+        while let Ok(message_id) = handled.try_recv() {
+            replayed.push(message_id);
+        }
+        assert_eq!(replayed, [first, second]);
+    }
+}
@@ -0,0 +1,235 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use parking_lot::RwLock;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::sync::Mutex;
+use tracing::info;
+
+use crate::utils::{BridgeError, Result};
+
+const REPLAY_BATCH_SIZE: usize = 100;
+/// Added to every recorded event: event ids are numbered per queue, so a replay has to know
+/// which queue each event came from.
+const RECORDED_QUEUE_KEY: &str = "bridge_queue_id";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    #[default]
+    Stopped,
+    Connecting,
+    Connected,
+    Backoff,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct EventQueueStatus {
+    pub running: bool,
+    pub state: ConnectionState,
+    pub queue_id: Option<String>,
+    pub last_event_id: Option<i64>,
+    pub last_poll_at: Option<DateTime<Utc>>,
+    pub last_heartbeat_at: Option<DateTime<Utc>>,
+    pub reconnect_attempts: u32,
+    pub last_error: Option<String>,
+}
+
+impl EventQueueStatus {
+    pub fn is_healthy(&self) -> bool {
+        self.running && self.state == ConnectionState::Connected && self.queue_id.is_some()
+    }
+}
+
+/// A stream of raw Zulip events, as returned by `GET /events`.
+#[async_trait]
+pub trait EventSource: Send + Sync {
+    /// Waits for the next batch of events. Returns `Ok(None)` once the source has been stopped
+    /// or is exhausted. Asking for the next batch acknowledges the previous one.
+    async fn next_events(&self) -> Result<Option<Vec<Value>>>;
+
+    fn status(&self) -> EventQueueStatus;
+
+    fn stop(&self);
+}
+
+/// Wraps another source and appends every event it yields to a JSONL file, tagged with the queue
+/// it was delivered through.
+pub struct RecordingEventSource {
+    inner: Arc<dyn EventSource>,
+    file: Mutex<tokio::fs::File>,
+}
+
+impl RecordingEventSource {
+    pub async fn create(inner: Arc<dyn EventSource>, path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+
+        info!("recording zulip events to {}", path.display());
+        Ok(Self {
+            inner,
+            file: Mutex::new(file),
+        })
+    }
+}
+
+#[async_trait]
+impl EventSource for RecordingEventSource {
+    async fn next_events(&self) -> Result<Option<Vec<Value>>> {
+        let Some(events) = self.inner.next_events().await? else {
+            return Ok(None);
+        };
+
+        let queue_id = self.inner.status().queue_id;
+        let mut lines = String::new();
+        for event in &events {
+            let mut recorded = event.clone();
+            if let (Some(queue_id), Some(object)) = (&queue_id, recorded.as_object_mut()) {
+                object.insert(RECORDED_QUEUE_KEY.to_string(), Value::from(queue_id.as_str()));
+            }
+            lines.push_str(&serde_json::to_string(&recorded)?);
+            lines.push('\n');
+        }
+
+        let mut file = self.file.lock().await;
+        file.write_all(lines.as_bytes()).await?;
+        file.flush().await?;
+
+        Ok(Some(events))
+    }
+
+    fn status(&self) -> EventQueueStatus {
+        self.inner.status()
+    }
+
+    fn stop(&self) {
+        self.inner.stop();
+    }
+}
+
+/// Feeds a file written by [`RecordingEventSource`] back into the bridge. A batch never spans two
+/// recorded queues, and the status reports the queue of the batch last handed out.
+pub struct ReplayEventSource {
+    lines: Mutex<ReplayReader>,
+    status: RwLock<EventQueueStatus>,
+}
+
+struct ReplayReader {
+    lines: Lines<BufReader<tokio::fs::File>>,
+    /// First event of the next queue, read while finishing the previous batch.
+    pending: Option<(Option<String>, Value)>,
+}
+
+impl ReplayReader {
+    async fn next_event(&mut self) -> Result<Option<(Option<String>, Value)>> {
+        if let Some(pending) = self.pending.take() {
+            return Ok(Some(pending));
+        }
+
+        while let Some(line) = self.lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut event: Value = serde_json::from_str(&line)
+                .map_err(|e| BridgeError::Parse(format!("invalid recorded event: {}", e)))?;
+            let queue_id = event
+                .as_object_mut()
+                .and_then(|object| object.remove(RECORDED_QUEUE_KEY))
+                .and_then(|queue_id| queue_id.as_str().map(ToOwned::to_owned));
+            return Ok(Some((queue_id, event)));
+        }
+        Ok(None)
+    }
+}
+
+impl ReplayEventSource {
+    pub async fn open(path: &Path) -> Result<Self> {
+        let file = tokio::fs::File::open(path).await?;
+
+        info!("replaying zulip events from {}", path.display());
+        Ok(Self {
+            lines: Mutex::new(ReplayReader {
+                lines: BufReader::new(file).lines(),
+                pending: None,
+            }),
+            status: RwLock::new(EventQueueStatus {
+                running: true,
+                state: ConnectionState::Connected,
+                queue_id: Some(format!("replay:{}", path.display())),
+                ..Default::default()
+            }),
+        })
+    }
+
+    /// The next batch and the recorded queue it came from. Recordings made before queues were
+    /// recorded replay as a single queue.
+    async fn read_batch(&self) -> Result<(Option<String>, Vec<Value>)> {
+        let mut reader = self.lines.lock().await;
+        let mut batch = Vec::new();
+        let mut batch_queue = None;
+
+        while batch.len() < REPLAY_BATCH_SIZE {
+            let Some((queue_id, event)) = reader.next_event().await? else {
+                break;
+            };
+            if batch.is_empty() {
+                batch_queue = queue_id;
+            } else if queue_id != batch_queue {
+                reader.pending = Some((queue_id, event));
+                break;
+            }
+            batch.push(event);
+        }
+
+        Ok((batch_queue, batch))
+    }
+}
+
+#[async_trait]
+impl EventSource for ReplayEventSource {
+    async fn next_events(&self) -> Result<Option<Vec<Value>>> {
+        if !self.status.read().running {
+            return Ok(None);
+        }
+
+        let (queue_id, events) = self.read_batch().await?;
+        if events.is_empty() {
+            info!("event replay finished");
+            self.stop();
+            return Ok(None);
+        }
+
+        let mut status = self.status.write();
+        if let Some(queue_id) = queue_id {
+            status.queue_id = Some(queue_id);
+        }
+        status.last_event_id = events
+            .iter()
+            .filter_map(|event| event.get("id").and_then(Value::as_i64))
+            .max()
+            .or(status.last_event_id);
+        status.last_poll_at = Some(Utc::now());
+
+        Ok(Some(events))
+    }
+
+    fn status(&self) -> EventQueueStatus {
+        self.status.read().clone()
+    }
+
+    fn stop(&self) {
+        let mut status = self.status.write();
+        status.running = false;
+        status.state = ConnectionState::Stopped;
+    }
+}
@@ -0,0 +1,390 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use parking_lot::RwLock;
+use rand::Rng;
+use serde_json::Value;
+use tokio::sync::{Mutex, Notify};
+use tracing::{debug, error, info, warn};
+
+use super::event_source::{ConnectionState, EventQueueStatus, EventSource};
+use super::{ZulipClient, ZulipQueue};
+use crate::utils::metrics;
+use crate::utils::{BridgeError, Result};
+
+/// Zulip answers an idle long-poll with a heartbeat well within a minute; anything slower than
+/// this means the connection is dead.
+const LONG_POLL_TIMEOUT: Duration = Duration::from_secs(90);
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Persists the event queue position so the bridge can resume the same queue after a restart.
+#[async_trait]
+pub trait EventQueueCheckpoint: Send + Sync {
+    async fn load(&self) -> Result<Option<ZulipQueue>>;
+    async fn save(&self, queue: &ZulipQueue) -> Result<()>;
+    async fn clear(&self) -> Result<()>;
+    /// Called when a new queue had to be registered. Anything sent since the previous queue
+    /// stopped is not delivered through the new one and has to be fetched separately. The new
+    /// queue is not polled until this returns.
+    async fn on_fresh_queue(&self);
+}
+
+/// Exponential backoff with jitter, so many bridges restarting against one server do not
+/// reconnect in lockstep.
+struct Backoff {
+    attempts: u32,
+}
+
+impl Backoff {
+    fn new() -> Self {
+        Self { attempts: 0 }
+    }
+
+    fn reset(&mut self) {
+        self.attempts = 0;
+    }
+
+    fn next_delay(&mut self) -> Duration {
+        let exponent = self.attempts.min(16);
+        self.attempts = self.attempts.saturating_add(1);
+        let ceiling = INITIAL_BACKOFF
+            .saturating_mul(1 << exponent)
+            .min(MAX_BACKOFF);
+        ceiling.mul_f64(rand::rng().random_range(0.5..=1.0))
+    }
+
+    /// A retry hint from the server wins over the exponential schedule. Errors that will not go
+    /// away on their own (bad credentials, deactivated accounts, rejected requests) wait the
+    /// maximum so a misconfigured organization does not hammer the server.
+    fn delay_for(&mut self, error: &BridgeError) -> Duration {
+        let scheduled = self.next_delay();
+        match error.as_zulip() {
+            Some(e) if !e.is_retryable() => MAX_BACKOFF,
+            Some(e) => e.retry_after().unwrap_or(scheduled),
+            None => scheduled,
+        }
+    }
+}
+
+struct PollState {
+    queue: Option<ZulipQueue>,
+    /// Position after the last batch handed out, saved once the consumer asks for more.
+    unacknowledged: Option<ZulipQueue>,
+    backoff: Backoff,
+}
+
+/// Event source backed by a Zulip event queue, using blocking long-polls on `GET /events`.
+pub struct LongPollEventSource {
+    client: Arc<ZulipClient>,
+    running: AtomicBool,
+    shutdown: Notify,
+    state: Mutex<PollState>,
+    status: RwLock<EventQueueStatus>,
+    checkpoint: Option<Arc<dyn EventQueueCheckpoint>>,
+}
+
+impl LongPollEventSource {
+    pub fn new(client: Arc<ZulipClient>) -> Self {
+        Self {
+            client,
+            running: AtomicBool::new(true),
+            shutdown: Notify::new(),
+            state: Mutex::new(PollState {
+                queue: None,
+                unacknowledged: None,
+                backoff: Backoff::new(),
+            }),
+            status: RwLock::new(EventQueueStatus {
+                running: true,
+                ..Default::default()
+            }),
+            checkpoint: None,
+        }
+    }
+
+    pub fn with_checkpoint(mut self, checkpoint: Arc<dyn EventQueueCheckpoint>) -> Self {
+        self.checkpoint = Some(checkpoint);
+        self
+    }
+
+    async fn save_checkpoint(&self, queue: &ZulipQueue) {
+        if let Some(checkpoint) = &self.checkpoint
+            && let Err(e) = checkpoint.save(queue).await
+        {
+            warn!("Failed to save event queue checkpoint: {}", e);
+        }
+    }
+
+    async fn wait_backoff(&self, backoff: &mut Backoff, error: &BridgeError) {
+        let delay = backoff.delay_for(error);
+        metrics::record_zulip_reconnect(&self.client.site);
+        {
+            let mut status = self.status.write();
+            status.state = ConnectionState::Backoff;
+            status.reconnect_attempts = backoff.attempts;
+            status.last_error = Some(error.to_string());
+        }
+        if error.as_zulip().is_some_and(|e| e.is_auth_failure()) {
+            error!(
+                "Zulip rejected the bot credentials for {}, retrying in {:?}: {}",
+                self.client.site, delay, error
+            );
+        } else {
+            warn!(
+                "Zulip event queue error (attempt {}), retrying in {:?}: {}",
+                backoff.attempts, delay, error
+            );
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = self.shutdown.notified() => {}
+        }
+    }
+
+    async fn resume_or_register(&self) -> Result<ZulipQueue> {
+        if let Some(checkpoint) = &self.checkpoint {
+            match checkpoint.load().await {
+                Ok(Some(queue)) => {
+                    info!(
+                        "Resuming event queue: queue_id={}, last_event_id={}",
+                        queue.queue_id, queue.last_event_id
+                    );
+                    return Ok(queue);
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Failed to load event queue checkpoint: {}", e),
+            }
+        }
+
+        info!("Registering Zulip event queue...");
+
+        let request = self.client.capabilities().register_request();
+        let queue = self.client.register_event_queue(&request).await?;
+
+        info!(
+            "Registered event queue: queue_id={}, last_event_id={}",
+            queue.queue_id, queue.last_event_id
+        );
+
+        if let Some(checkpoint) = &self.checkpoint {
+            self.save_checkpoint(&queue).await;
+            checkpoint.on_fresh_queue().await;
+        }
+
+        Ok(queue)
+    }
+
+    /// Drops heartbeats from a batch and advances the queue position past every event in it.
+    fn accept(&self, queue: &mut ZulipQueue, events: Vec<Value>) -> Vec<Value> {
+        let mut heartbeat = false;
+        let mut accepted = Vec::with_capacity(events.len());
+
+        for event in events {
+            if let Some(id) = event.get("id").and_then(Value::as_i64) {
+                queue.last_event_id = queue.last_event_id.max(id);
+            }
+
+            if event.get("type").and_then(Value::as_str) == Some("heartbeat") {
+                heartbeat = true;
+                continue;
+            }
+            accepted.push(event);
+        }
+
+        let now = Utc::now();
+        let mut status = self.status.write();
+        status.state = ConnectionState::Connected;
+        status.queue_id = Some(queue.queue_id.clone());
+        status.last_event_id = Some(queue.last_event_id);
+        status.last_poll_at = Some(now);
+        if heartbeat {
+            status.last_heartbeat_at = Some(now);
+            debug!("Zulip heartbeat received");
+        }
+        status.reconnect_attempts = 0;
+        status.last_error = None;
+
+        accepted
+    }
+}
+
+#[async_trait]
+impl EventSource for LongPollEventSource {
+    async fn next_events(&self) -> Result<Option<Vec<Value>>> {
+        let mut state = self.state.lock().await;
+
+        if let Some(queue) = state.unacknowledged.take() {
+            self.save_checkpoint(&queue).await;
+        }
+
+        while self.running.load(Ordering::SeqCst) {
+            let mut current = match state.queue.take() {
+                Some(current) => current,
+                None => {
+                    self.status.write().state = ConnectionState::Connecting;
+                    match self.resume_or_register().await {
+                        Ok(registered) => {
+                            let mut status = self.status.write();
+                            status.state = ConnectionState::Connected;
+                            status.queue_id = Some(registered.queue_id.clone());
+                            status.last_event_id = Some(registered.last_event_id);
+                            registered
+                        }
+                        Err(e) => {
+                            self.wait_backoff(&mut state.backoff, &e).await;
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            let poll = tokio::select! {
+                result = self.client.get_events(&current.queue_id, current.last_event_id, LONG_POLL_TIMEOUT) => result,
+                _ = self.shutdown.notified() => {
+                    state.queue = Some(current);
+                    break;
+                }
+            };
+
+            match poll {
+                Ok(response) => {
+                    state.backoff.reset();
+                    let events = self.accept(&mut current, response.events);
+                    state.queue = Some(current.clone());
+                    if !events.is_empty() {
+                        state.unacknowledged = Some(current);
+                        return Ok(Some(events));
+                    }
+                }
+                Err(e) if e.as_zulip().is_some_and(|e| e.is_queue_expired()) => {
+                    warn!("Event queue {} expired, registering a new queue: {}", current.queue_id, e);
+                    metrics::record_zulip_reconnect(&self.client.site);
+                    self.status.write().queue_id = None;
+                    if let Some(checkpoint) = &self.checkpoint
+                        && let Err(e) = checkpoint.clear().await
+                    {
+                        warn!("Failed to clear event queue checkpoint: {}", e);
+                    }
+                }
+                Err(e) => {
+                    state.queue = Some(current);
+                    self.wait_backoff(&mut state.backoff, &e).await;
+                }
+            }
+        }
+
+        let mut status = self.status.write();
+        status.running = false;
+        status.state = ConnectionState::Stopped;
+        Ok(None)
+    }
+
+    fn status(&self) -> EventQueueStatus {
+        self.status.read().clone()
+    }
+
+    fn stop(&self) {
+        self.running.store(false, Ordering::SeqCst);
+        self.shutdown.notify_one();
+        self.status.write().running = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::testing::FakeZulip;
+
+    const SENDER_ID: i64 = 10;
+    const STREAM_ID: i64 = 5;
+
+    /// Keeps the queue position in memory and counts fresh registrations.
+    #[derive(Default)]
+    struct MemoryCheckpoint {
+        queue: parking_lot::Mutex<Option<ZulipQueue>>,
+        fresh_queues: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl EventQueueCheckpoint for MemoryCheckpoint {
+        async fn load(&self) -> Result<Option<ZulipQueue>> {
+            Ok(self.queue.lock().clone())
+        }
+
+        async fn save(&self, queue: &ZulipQueue) -> Result<()> {
+            *self.queue.lock() = Some(queue.clone());
+            Ok(())
+        }
+
+        async fn clear(&self) -> Result<()> {
+            *self.queue.lock() = None;
+            Ok(())
+        }
+
+        async fn on_fresh_queue(&self) {
+            self.fresh_queues.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Polls `source` in the background, posts a message once a queue is registered and returns
+    /// the message id with the batch that delivered it.
+    async fn poll_message(
+        fake: &FakeZulip,
+        source: &Arc<LongPollEventSource>,
+    ) -> (i64, Vec<Value>) {
+        let poll = tokio::spawn({
+            let source = source.clone();
+            async move { source.next_events().await }
+        });
+        while fake.queue_count() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let message_id = fake.post_message(SENDER_ID, STREAM_ID, "greetings", "hello");
+        let events = tokio::time::timeout(Duration::from_secs(5), poll)
+            .await
+            .expect("no events within 5s")
+            .unwrap()
+            .unwrap()
+            .expect("event source stopped");
+        (message_id, events)
+    }
+
+    #[tokio::test]
+    async fn registers_a_new_queue_after_expiry() {
+        let fake = FakeZulip::start().await.unwrap();
+        fake.add_user(SENDER_ID, "Iago");
+        fake.add_stream(STREAM_ID, "general");
+
+        let checkpoint = Arc::new(MemoryCheckpoint::default());
+        let source = Arc::new(
+            LongPollEventSource::new(Arc::new(fake.client().unwrap()))
+                .with_checkpoint(checkpoint.clone()),
+        );
+
+        let (first_id, events) = poll_message(&fake, &source).await;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["type"], "message");
+        assert_eq!(events[0]["message"]["id"], first_id);
+        let first_queue = source.status().queue_id.unwrap();
+
+        fake.expire_queues();
+        let (second_id, events) = poll_message(&fake, &source).await;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["message"]["id"], second_id);
+
+        let status = source.status();
+        assert!(status.is_healthy());
+        assert_ne!(status.queue_id.unwrap(), first_queue);
+        assert_eq!(checkpoint.fresh_queues.load(Ordering::SeqCst), 2);
+
+        source.stop();
+    }
+}
@@ -0,0 +1,186 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use tracing::debug;
+
+/// Zulip's default API limit until the server tells us otherwise.
+const DEFAULT_LIMIT: f64 = 200.0;
+const DEFAULT_WINDOW: Duration = Duration::from_secs(60);
+/// Share of the bucket background requests leave untouched, so live traffic always finds tokens.
+const LIVE_RESERVE: f64 = 0.2;
+const MIN_WAIT: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RequestPriority {
+    /// Bridging live messages and anything a user is waiting on.
+    #[default]
+    Live,
+    /// Backfill, catch-up and other bulk work that can yield to live traffic.
+    Background,
+}
+
+struct Bucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    updated_at: Instant,
+    blocked_until: Option<Instant>,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.updated_at = now;
+    }
+
+    fn wait_for(&self, needed: f64) -> Duration {
+        let missing = (needed - self.tokens).max(0.0);
+        Duration::from_secs_f64(missing / self.refill_per_sec).max(MIN_WAIT)
+    }
+}
+
+/// Token bucket for one set of Zulip credentials (the organization bot or a puppet), kept in
+/// line with the server's `X-RateLimit-*` headers.
+///
+/// Requests wait for a token instead of failing. Live requests take priority: background
+/// requests wait while any live request is queued and never dip into the live reserve.
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+    live_waiting: AtomicUsize,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self {
+            bucket: Mutex::new(Bucket {
+                capacity: DEFAULT_LIMIT,
+                tokens: DEFAULT_LIMIT,
+                refill_per_sec: DEFAULT_LIMIT / DEFAULT_WINDOW.as_secs_f64(),
+                updated_at: Instant::now(),
+                blocked_until: None,
+            }),
+            live_waiting: AtomicUsize::new(0),
+        }
+    }
+
+    pub async fn acquire(&self, priority: RequestPriority) {
+        let _queued = (priority == RequestPriority::Live).then(|| LiveWaiter::new(&self.live_waiting));
+
+        loop {
+            let wait = {
+                let now = Instant::now();
+                let mut bucket = self.bucket.lock();
+                bucket.refill(now);
+
+                match bucket.blocked_until {
+                    Some(until) if until > now => until - now,
+                    _ => {
+                        let (needed, yielding) = match priority {
+                            RequestPriority::Live => (1.0, false),
+                            RequestPriority::Background => (
+                                1.0 + bucket.capacity * LIVE_RESERVE,
+                                self.live_waiting.load(Ordering::SeqCst) > 0,
+                            ),
+                        };
+
+                        if bucket.tokens >= needed && !yielding {
+                            bucket.tokens -= 1.0;
+                            return;
+                        }
+                        bucket.wait_for(needed)
+                    }
+                }
+            };
+
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Aligns the bucket with `X-RateLimit-Limit`, `X-RateLimit-Remaining` and
+    /// `X-RateLimit-Reset` (a unix timestamp) from a response.
+    pub fn update_from_headers(&self, headers: &HeaderMap) {
+        let limit = header_f64(headers, "x-ratelimit-limit").filter(|limit| *limit > 0.0);
+        let remaining = header_f64(headers, "x-ratelimit-remaining");
+        let reset = header_f64(headers, "x-ratelimit-reset");
+        if limit.is_none() && remaining.is_none() {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut bucket = self.bucket.lock();
+        bucket.refill(now);
+
+        if let Some(limit) = limit {
+            bucket.capacity = limit;
+            bucket.refill_per_sec = limit / DEFAULT_WINDOW.as_secs_f64();
+        }
+        if let Some(remaining) = remaining {
+            bucket.tokens = bucket.tokens.min(remaining.max(0.0));
+        }
+
+        if remaining.is_some_and(|remaining| remaining < 1.0)
+            && let Some(reset) = reset
+        {
+            let until_reset = reset - chrono::Utc::now().timestamp_millis() as f64 / 1000.0;
+            if until_reset > 0.0 {
+                debug!("zulip rate limit exhausted, pausing for {:.1}s", until_reset);
+                block(&mut bucket, now + Duration::from_secs_f64(until_reset));
+            }
+        }
+    }
+
+    /// Holds every request back for `delay`, e.g. after a 429.
+    pub fn block_for(&self, delay: Duration) {
+        let now = Instant::now();
+        let mut bucket = self.bucket.lock();
+        bucket.tokens = 0.0;
+        bucket.updated_at = now;
+        block(&mut bucket, now + delay);
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn block(bucket: &mut Bucket, until: Instant) {
+    bucket.blocked_until = Some(bucket.blocked_until.map_or(until, |current| current.max(until)));
+}
+
+/// Counts a queued live request for as long as it waits, including when the wait is cancelled.
+struct LiveWaiter<'a>(&'a AtomicUsize);
+
+impl<'a> LiveWaiter<'a> {
+    fn new(counter: &'a AtomicUsize) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Self(counter)
+    }
+}
+
+impl Drop for LiveWaiter<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn header_f64(headers: &HeaderMap, name: &str) -> Option<f64> {
+    headers.get(name)?.to_str().ok()?.trim().parse().ok()
+}
+
+/// How long Zulip asked us to wait after a 429: the `retry-after` field of the JSON error body,
+/// falling back to the `Retry-After` header.
+pub fn retry_after(headers: &HeaderMap, body: &str) -> Option<Duration> {
+    let from_body = serde_json::from_str::<serde_json::Value>(body)
+        .ok()
+        .and_then(|body| body.get("retry-after")?.as_f64());
+
+    from_body
+        .or_else(|| header_f64(headers, RETRY_AFTER.as_str()))
+        .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+        .map(Duration::from_secs_f64)
+}
@@ -1,5 +1,9 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 
+use super::narrow::{Anchor, Narrow};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ZulipUser {
     pub user_id: i64,
@@ -38,11 +42,14 @@ pub struct ZulipMessage {
     pub sender_realm_str: Option<String>,
     pub content: String,
     pub rendered_content: Option<String>,
+    /// Absent from outgoing webhook payloads.
+    #[serde(default)]
     pub content_type: String,
     pub timestamp: i64,
     #[serde(rename = "type")]
     pub msg_type: String,
     pub stream_id: Option<i64>,
+    #[serde(alias = "topic")]
     pub subject: Option<String>,
     pub subject_links: Option<Vec<String>>,
     pub display_recipient: Option<serde_json::Value>,
@@ -61,15 +68,22 @@ impl ZulipMessage {
         self.msg_type == "private"
     }
 
+    /// The message as HTML rendered by Zulip, if the payload carries it.
+    pub fn rendered_html(&self) -> Option<&str> {
+        self.rendered_content
+            .as_deref()
+            .or((self.content_type == "text/html").then_some(self.content.as_str()))
+    }
+
     pub fn topic(&self) -> Option<&str> {
         self.subject.as_deref()
     }
 
     pub fn recipient_user_ids(&self) -> Vec<i64> {
-        if let Some(recipients) = &self.display_recipient {
-            if let Some(arr) = recipients.as_array() {
-                return arr.iter().filter_map(|r| r.get("id")?.as_i64()).collect();
-            }
+        if let Some(recipients) = &self.display_recipient
+            && let Some(arr) = recipients.as_array()
+        {
+            return arr.iter().filter_map(|r| r.get("id")?.as_i64()).collect();
         }
         vec![]
     }
@@ -126,6 +140,55 @@ impl ZulipEvent {
     pub fn is_realm_user(&self) -> bool {
         self.event_type == "realm_user"
     }
+
+    pub fn is_realm_emoji(&self) -> bool {
+        self.event_type == "realm_emoji"
+    }
+
+    /// The complete custom emoji list carried by a `realm_emoji` update, keyed by emoji id.
+    pub fn realm_emoji(&self) -> Option<HashMap<String, ZulipRealmEmoji>> {
+        let emoji = self.extra.get("realm_emoji")?;
+        serde_json::from_value(emoji.clone()).ok()
+    }
+
+    /// The new rendering of an `update_message` event that edited the message content.
+    pub fn rendered_content(&self) -> Option<&str> {
+        self.extra.get("rendered_content")?.as_str()
+    }
+
+    /// The reaction added or removed by a `reaction` event. Older servers only identify the
+    /// user in the deprecated `user` object.
+    pub fn emoji_reaction(&self) -> Option<ZulipReaction> {
+        let user = self.extra.get("user");
+        Some(ZulipReaction {
+            emoji_name: self.emoji_name.clone()?,
+            emoji_code: self.emoji_code.clone()?,
+            reaction_type: self
+                .extra
+                .get("reaction_type")
+                .and_then(|reaction_type| reaction_type.as_str())
+                .unwrap_or("unicode_emoji")
+                .to_string(),
+            user_id: self
+                .user_id
+                .or_else(|| user?.get("user_id")?.as_i64())?,
+            user: None,
+        })
+    }
+
+    /// The address of the user a `reaction` event is about.
+    pub fn user_email(&self) -> Option<&str> {
+        self.extra.get("user")?.get("email")?.as_str()
+    }
+
+    /// Messages affected by a `delete_message` event, in either the bulk `message_ids` or the
+    /// legacy single `message_id` form.
+    pub fn message_ids(&self) -> Vec<i64> {
+        if let Some(ids) = self.extra.get("message_ids").and_then(|ids| ids.as_array()) {
+            return ids.iter().filter_map(|id| id.as_i64()).collect();
+        }
+        self.message_id.into_iter().collect()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -138,6 +201,8 @@ pub struct ZulipQueue {
 pub struct ZulipApiResponse<T> {
     pub result: String,
     pub msg: String,
+    #[serde(default)]
+    pub code: Option<String>,
     #[serde(flatten)]
     pub data: Option<T>,
 }
@@ -151,6 +216,14 @@ impl<T> ZulipApiResponse<T> {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ZulipMessagesResponse {
     pub messages: Vec<ZulipMessage>,
+    #[serde(default)]
+    pub found_anchor: bool,
+    #[serde(default)]
+    pub found_oldest: bool,
+    #[serde(default)]
+    pub found_newest: bool,
+    #[serde(default)]
+    pub anchor: Option<i64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -168,9 +241,10 @@ pub struct ZulipSendMessageResponse {
     pub id: i64,
 }
 
+/// Events are kept as raw JSON so they can be recorded verbatim and parsed one at a time.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ZulipEventsResponse {
-    pub events: Vec<ZulipEvent>,
+    pub events: Vec<serde_json::Value>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -222,8 +296,65 @@ impl SendMessageRequest {
     }
 }
 
+/// Parameters of `GET /messages`. Markdown is not applied by default so the bridge gets the
+/// original message source.
+#[derive(Debug, Clone)]
+pub struct GetMessagesRequest {
+    pub narrow: Narrow,
+    pub anchor: Anchor,
+    pub num_before: u32,
+    pub num_after: u32,
+    pub include_anchor: bool,
+    pub apply_markdown: bool,
+}
+
+impl GetMessagesRequest {
+    pub fn new(narrow: Narrow, anchor: Anchor) -> Self {
+        Self {
+            narrow,
+            anchor,
+            num_before: 0,
+            num_after: 0,
+            include_anchor: true,
+            apply_markdown: false,
+        }
+    }
+
+    pub fn before(mut self, num_before: u32) -> Self {
+        self.num_before = num_before;
+        self
+    }
+
+    pub fn after(mut self, num_after: u32) -> Self {
+        self.num_after = num_after;
+        self
+    }
+
+    pub fn include_anchor(mut self, include_anchor: bool) -> Self {
+        self.include_anchor = include_anchor;
+        self
+    }
+
+    pub fn apply_markdown(mut self, apply_markdown: bool) -> Self {
+        self.apply_markdown = apply_markdown;
+        self
+    }
+
+    pub fn to_query(&self) -> String {
+        url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("narrow", &self.narrow.to_json())
+            .append_pair("anchor", &self.anchor.as_param())
+            .append_pair("num_before", &self.num_before.to_string())
+            .append_pair("num_after", &self.num_after.to_string())
+            .append_pair("include_anchor", &self.include_anchor.to_string())
+            .append_pair("apply_markdown", &self.apply_markdown.to_string())
+            .finish()
+    }
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct RegisterQueueRequest {
+    #[serde(serialize_with = "serialize_as_json")]
     pub event_types: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub all_public_streams: Option<bool>,
@@ -233,6 +364,15 @@ pub struct RegisterQueueRequest {
     pub client_gravatar: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub slim_presence: Option<bool>,
+    /// JSON object of event format opt-ins, see `ServerCapabilities::register_request`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_capabilities: Option<String>,
+}
+
+/// Form-encoded endpoints take list parameters as a JSON string.
+fn serialize_as_json<T: Serialize, S: serde::Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    let json = serde_json::to_string(value).map_err(serde::ser::Error::custom)?;
+    serializer.serialize_str(&json)
 }
 
 impl Default for RegisterQueueRequest {
@@ -245,11 +385,14 @@ impl Default for RegisterQueueRequest {
                 "delete_message".to_string(),
                 "subscription".to_string(),
                 "realm_user".to_string(),
+                "realm_emoji".to_string(),
+                "user_group".to_string(),
             ],
             all_public_streams: Some(true),
             include_subscribers: Some(false),
             client_gravatar: Some(true),
             slim_presence: Some(true),
+            client_capabilities: None,
         }
     }
 }
@@ -278,3 +421,230 @@ impl MessageFlag {
         }
     }
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ZulipUserResponse {
+    pub user: ZulipUser,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TypingOp {
+    Start,
+    Stop,
+}
+
+/// Body of `POST /typing`. Servers before feature level 174 expect `private` instead of `direct`.
+#[derive(Debug, Clone, Serialize)]
+pub struct TypingRequest {
+    #[serde(rename = "type")]
+    pub recipient_type: String,
+    pub op: TypingOp,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_id: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+}
+
+impl TypingRequest {
+    pub fn direct(user_ids: &[i64], op: TypingOp) -> Self {
+        Self {
+            recipient_type: "direct".to_string(),
+            op,
+            to: Some(serde_json::json!(user_ids).to_string()),
+            stream_id: None,
+            topic: None,
+        }
+    }
+
+    pub fn stream(stream_id: i64, topic: &str, op: TypingOp) -> Self {
+        Self {
+            recipient_type: "stream".to_string(),
+            op,
+            to: None,
+            stream_id: Some(stream_id),
+            topic: Some(topic.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PresenceStatus {
+    Active,
+    Idle,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UpdatePresenceRequest {
+    pub status: PresenceStatus,
+    pub ping_only: bool,
+    pub new_user_input: bool,
+}
+
+/// Response of `POST /users/me/presence`. The shape of `presences` depends on the server's
+/// presence format, so it is left as raw JSON keyed by user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ZulipPresenceResponse {
+    #[serde(default)]
+    pub presences: HashMap<String, serde_json::Value>,
+    pub server_timestamp: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ZulipPresenceInfo {
+    pub status: PresenceStatus,
+    pub timestamp: i64,
+    pub client: Option<String>,
+    pub pushable: Option<bool>,
+}
+
+/// Presence of one user per client, plus the `aggregated` entry summarizing all of them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ZulipUserPresenceResponse {
+    pub presence: HashMap<String, ZulipPresenceInfo>,
+}
+
+impl ZulipUserPresenceResponse {
+    pub fn aggregated(&self) -> Option<&ZulipPresenceInfo> {
+        self.presence.get("aggregated")
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ZulipUserStatus {
+    #[serde(default)]
+    pub status_text: Option<String>,
+    #[serde(default)]
+    pub emoji_name: Option<String>,
+    #[serde(default)]
+    pub emoji_code: Option<String>,
+    #[serde(default)]
+    pub reaction_type: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ZulipUserStatusResponse {
+    pub status: ZulipUserStatus,
+}
+
+/// Body of `PATCH /streams/{stream_id}`; only the fields that are set are changed.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UpdateStreamRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ZulipUnsubscribeResponse {
+    #[serde(default)]
+    pub removed: Vec<String>,
+    #[serde(default)]
+    pub not_removed: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ZulipRealmEmoji {
+    pub id: String,
+    pub name: String,
+    pub source_url: String,
+    #[serde(default)]
+    pub still_url: Option<String>,
+    #[serde(default)]
+    pub deactivated: bool,
+    #[serde(default)]
+    pub author_id: Option<i64>,
+}
+
+/// Custom emoji keyed by emoji id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ZulipRealmEmojiResponse {
+    pub emoji: HashMap<String, ZulipRealmEmoji>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ZulipUserGroup {
+    pub id: i64,
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub members: Vec<i64>,
+    #[serde(default)]
+    pub direct_subgroup_ids: Vec<i64>,
+    #[serde(default)]
+    pub is_system_group: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ZulipUserGroupsResponse {
+    pub user_groups: Vec<ZulipUserGroup>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageFlagOp {
+    Add,
+    Remove,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UpdateMessageFlagsRequest {
+    pub messages: String,
+    pub op: MessageFlagOp,
+    pub flag: String,
+}
+
+impl UpdateMessageFlagsRequest {
+    pub fn new(message_ids: &[i64], op: MessageFlagOp, flag: &str) -> Self {
+        Self {
+            messages: serde_json::json!(message_ids).to_string(),
+            op,
+            flag: flag.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ZulipUpdateMessageFlagsResponse {
+    pub messages: Vec<i64>,
+}
+
+/// Response of `GET /server_settings`, which needs no authentication.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ZulipServerSettings {
+    pub zulip_version: String,
+    /// Absent on servers older than Zulip 3.0, which are feature level 0.
+    #[serde(default)]
+    pub zulip_feature_level: i64,
+    #[serde(default)]
+    pub zulip_merge_base: Option<String>,
+    #[serde(default)]
+    pub push_notifications_enabled: Option<bool>,
+    #[serde(default)]
+    pub realm_name: Option<String>,
+    #[serde(default)]
+    pub realm_icon: Option<String>,
+    #[serde(default)]
+    pub realm_url: Option<String>,
+    /// Older name of `realm_url`, still sent alongside it.
+    #[serde(default)]
+    pub realm_uri: Option<String>,
+    #[serde(default)]
+    pub authentication_methods: HashMap<String, bool>,
+}
+
+impl ZulipServerSettings {
+    pub fn realm_url(&self) -> Option<&str> {
+        self.realm_url.as_deref().or(self.realm_uri.as_deref())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ZulipTemporaryUrlResponse {
+    pub url: String,
+}
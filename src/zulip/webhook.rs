@@ -0,0 +1,245 @@
+//! Ingress through a Zulip outgoing-webhook bot, for organizations that will not give the
+//! bridge a full bot with its own event queue.
+//!
+//! Zulip only posts messages that mention the bot in a stream or are sent to it directly, and
+//! never posts edits, deletions or reactions. In this mode the bridge therefore:
+//! - relays only those messages to Matrix;
+//! - does not mirror Zulip edits, deletions or reactions;
+//! - has no queue position to catch up from after downtime, so messages posted while the bridge
+//!   was unreachable are lost.
+//!
+//! Direct messages to the bot get an inline reply saying they are not bridged.
+
+use async_trait::async_trait;
+use chrono::Utc;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tokio::sync::{Mutex, mpsc, watch};
+
+use super::event_source::{ConnectionState, EventQueueStatus, EventSource};
+use super::types::ZulipMessage;
+use crate::utils::Result;
+
+const DIRECT_MESSAGE_REPLY: &str =
+    "This bridge only relays stream messages that mention it; direct messages are not bridged.";
+
+/// Body of the `POST` Zulip sends for every message that triggers the bot.
+#[derive(Debug, Clone, Deserialize)]
+pub struct OutgoingWebhookPayload {
+    pub token: String,
+    /// `mention`, or `direct_message` (`private_message` before Zulip 8.0).
+    pub trigger: String,
+    #[serde(default)]
+    pub data: String,
+    pub bot_email: String,
+    #[serde(default)]
+    pub bot_full_name: Option<String>,
+    pub message: ZulipMessage,
+}
+
+impl OutgoingWebhookPayload {
+    pub fn is_direct(&self) -> bool {
+        matches!(self.trigger.as_str(), "direct_message" | "private_message")
+    }
+
+    /// The `message` event an event queue would have delivered. The message id doubles as the
+    /// event id, so Zulip's retries of a failed delivery are processed only once.
+    pub fn to_event(&self) -> Value {
+        let flags = if self.is_direct() {
+            json!([])
+        } else {
+            json!(["mentioned"])
+        };
+        json!({
+            "type": "message",
+            "id": self.message.id,
+            "message": self.message,
+            "flags": flags,
+        })
+    }
+}
+
+/// What the bot answers; `content` is posted by Zulip as a reply in the same conversation.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct OutgoingWebhookResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub response_not_required: bool,
+}
+
+impl OutgoingWebhookResponse {
+    pub fn silent() -> Self {
+        Self {
+            content: None,
+            response_not_required: true,
+        }
+    }
+
+    pub fn reply(content: &str) -> Self {
+        Self {
+            content: Some(content.to_string()),
+            response_not_required: false,
+        }
+    }
+}
+
+/// Event source fed by the bridge's webhook endpoint instead of a long-poll.
+pub struct WebhookEventSource {
+    token: String,
+    sender: mpsc::UnboundedSender<Value>,
+    receiver: Mutex<mpsc::UnboundedReceiver<Value>>,
+    stopped: watch::Sender<bool>,
+    status: RwLock<EventQueueStatus>,
+}
+
+impl WebhookEventSource {
+    pub fn new(token: &str) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Self {
+            token: token.to_string(),
+            sender,
+            receiver: Mutex::new(receiver),
+            stopped: watch::Sender::new(false),
+            status: RwLock::new(EventQueueStatus {
+                running: true,
+                state: ConnectionState::Connected,
+                queue_id: Some("outgoing-webhook".to_string()),
+                ..Default::default()
+            }),
+        }
+    }
+
+    pub fn verify_token(&self, token: &str) -> bool {
+        // Compare every byte so the time taken does not reveal the matching prefix.
+        self.token.len() == token.len()
+            && self
+                .token
+                .bytes()
+                .zip(token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    /// Queues the message for the event processor and decides the inline reply.
+    pub fn deliver(&self, payload: &OutgoingWebhookPayload) -> OutgoingWebhookResponse {
+        if self.sender.send(payload.to_event()).is_err() {
+            return OutgoingWebhookResponse::silent();
+        }
+
+        let mut status = self.status.write();
+        status.last_event_id = Some(payload.message.id);
+        status.last_poll_at = Some(Utc::now());
+        drop(status);
+
+        if payload.is_direct() {
+            OutgoingWebhookResponse::reply(DIRECT_MESSAGE_REPLY)
+        } else {
+            OutgoingWebhookResponse::silent()
+        }
+    }
+}
+
+#[async_trait]
+impl EventSource for WebhookEventSource {
+    async fn next_events(&self) -> Result<Option<Vec<Value>>> {
+        let mut stopped = self.stopped.subscribe();
+        let mut receiver = self.receiver.lock().await;
+
+        tokio::select! {
+            event = receiver.recv() => {
+                let Some(event) = event else {
+                    return Ok(None);
+                };
+                let mut events = vec![event];
+                while let Ok(event) = receiver.try_recv() {
+                    events.push(event);
+                }
+                Ok(Some(events))
+            }
+            _ = stopped.wait_for(|stopped| *stopped) => Ok(None),
+        }
+    }
+
+    fn status(&self) -> EventQueueStatus {
+        self.status.read().clone()
+    }
+
+    fn stop(&self) {
+        self.stopped.send_replace(true);
+        let mut status = self.status.write();
+        status.running = false;
+        status.state = ConnectionState::Stopped;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &str = include_str!("../../fixtures/zulip/outgoing_webhook.json");
+    const TOKEN: &str = "xvOzfurIutdRRVLzpXrIIHXJvNfaJLJ0";
+
+    fn payload() -> OutgoingWebhookPayload {
+        serde_json::from_str(FIXTURE).unwrap()
+    }
+
+    #[test]
+    fn parses_mention_payload() {
+        let payload = payload();
+        assert_eq!(payload.token, TOKEN);
+        assert_eq!(payload.trigger, "mention");
+        assert!(!payload.is_direct());
+        assert_eq!(payload.bot_email, "outgoing-bot@zulip.example.com");
+        assert_eq!(payload.message.id, 112);
+        assert_eq!(payload.message.sender_id, 5);
+        assert_eq!(payload.message.stream_id, Some(5));
+        assert_eq!(payload.message.subject.as_deref(), Some("greetings"));
+    }
+
+    #[test]
+    fn verifies_token() {
+        let source = WebhookEventSource::new(TOKEN);
+        assert!(source.verify_token(&payload().token));
+        assert!(!source.verify_token("xvOzfurIutdRRVLzpXrIIHXJvNfaJLJ1"));
+        assert!(!source.verify_token("xvOzfurIutdRRVLzpXrIIHXJvNfaJLJ"));
+        assert!(!source.verify_token(""));
+    }
+
+    #[tokio::test]
+    async fn delivers_mention_as_message_event() {
+        let source = WebhookEventSource::new(TOKEN);
+        let response = source.deliver(&payload());
+        assert!(response.content.is_none());
+        assert!(response.response_not_required);
+
+        let events = source.next_events().await.unwrap().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["type"], "message");
+        assert_eq!(events[0]["id"], 112);
+        assert_eq!(events[0]["message"]["id"], 112);
+        assert_eq!(events[0]["flags"], json!(["mentioned"]));
+        assert_eq!(source.status().last_event_id, Some(112));
+    }
+
+    #[tokio::test]
+    async fn replies_to_direct_messages() {
+        let mut payload = payload();
+        payload.trigger = "private_message".to_string();
+        let source = WebhookEventSource::new(TOKEN);
+
+        let response = source.deliver(&payload);
+        assert_eq!(response.content.as_deref(), Some(DIRECT_MESSAGE_REPLY));
+        let events = source.next_events().await.unwrap().unwrap();
+        assert_eq!(events[0]["flags"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn stop_ends_the_stream() {
+        let source = WebhookEventSource::new(TOKEN);
+        source.stop();
+        assert!(source.next_events().await.unwrap().is_none());
+        assert_eq!(source.status().state, ConnectionState::Stopped);
+    }
+}